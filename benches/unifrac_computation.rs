@@ -7,7 +7,8 @@ use ndarray_rand::{
     RandomExt,
 };
 use unifrac::compute::{
-    parallel_elementwise_sum, parallel_elementwise_sum2, vectorized_elementwise_sum,
+    dispatched_elementwise_sum, parallel_elementwise_sum, parallel_elementwise_sum2,
+    vectorized_elementwise_sum,
 };
 
 pub fn bench_rayon_vs_ndarray(c: &mut Criterion) {
@@ -46,6 +47,12 @@ pub fn bench_rayon_vs_ndarray(c: &mut Criterion) {
             &(&p_a, &p_b, &brlens),
             |b, (p_a, p_b, brlens)| b.iter(|| vectorized_elementwise_sum(p_a, p_b, brlens)),
         );
+
+        group.bench_with_input(
+            BenchmarkId::new("Dispatched SIMD kernel", n_branches),
+            &(&p_a, &p_b, &brlens),
+            |b, (p_a, p_b, brlens)| b.iter(|| dispatched_elementwise_sum(p_a, p_b, brlens)),
+        );
     }
 }
 
@@ -0,0 +1,69 @@
+use std::{env, process::Command};
+
+fn main() {
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/unifrac.proto")
+        .expect("failed to compile proto/unifrac.proto");
+
+    build_info();
+}
+
+/// Details reported by --version: commit, cargo features, CPU features, BLAS
+fn build_info() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let dirty = Command::new("git")
+        .args(["status", "--porcelain", "--untracked-files=no"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success() && !out.stdout.is_empty())
+        .is_some();
+    let commit = if dirty && commit != "unknown" {
+        format!("{}-dirty", commit)
+    } else {
+        commit
+    };
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(str::to_string))
+        .map(|f| f.to_lowercase().replace('_', "-"))
+        .collect();
+    features.sort();
+
+    let blas = if env::var_os("CARGO_FEATURE_BLAS").is_some() {
+        "openblas (blas-src)"
+    } else {
+        "none (ndarray built-in matrixmultiply)"
+    };
+
+    println!("cargo:rustc-env=UNIFRAC_GIT_COMMIT={}", commit);
+    println!(
+        "cargo:rustc-env=UNIFRAC_FEATURES={}",
+        or_none(&features.join(","))
+    );
+    println!(
+        "cargo:rustc-env=UNIFRAC_TARGET={}",
+        env::var("TARGET").unwrap_or_default()
+    );
+    println!(
+        "cargo:rustc-env=UNIFRAC_TARGET_FEATURES={}",
+        or_none(&env::var("CARGO_CFG_TARGET_FEATURE").unwrap_or_default())
+    );
+    println!("cargo:rustc-env=UNIFRAC_BLAS={}", blas);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+fn or_none(list: &str) -> &str {
+    if list.is_empty() {
+        "none"
+    } else {
+        list
+    }
+}
@@ -0,0 +1,302 @@
+//! The branch representation behind every UniFrac variant here, for reuse
+//! by other crates.
+//!
+//! Indexing is the same throughout:
+//! - branch `b` is the edge from node `b` (its phylotree node id) to its
+//!   parent, so rows of B and entries of the branch lengths are indexed by
+//!   node id, `0..tree.size()`; the root's row has length 0 unless
+//!   `BranchOptions::include_root_edge` is set
+//! - tip columns come from [`leaf_layout`], in `tree.get_leaves()` order
+//! - B[b, t] is 1 when tip `t` lies below branch `b`
+//!
+//! [`construct_b`] builds B densely (branches x tips bytes);
+//! [`construct_b_sparse`] stores only the tips below each branch, which is
+//! what large trees need.
+
+use anyhow::{bail, Result};
+use ndarray::{Array1, Array2};
+use phylotree::tree::Tree;
+use std::collections::{HashMap, HashSet};
+
+/// What to do with branches that have no length in the tree
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissingLength {
+    /// Refuse to compute
+    Error,
+    /// Treat the branch as length 0 (it never contributes)
+    #[default]
+    Zero,
+    /// Treat the branch as length 1
+    One,
+}
+
+impl MissingLength {
+    pub fn from_name(name: &str) -> Option<MissingLength> {
+        match name {
+            "error" => Some(MissingLength::Error),
+            "zero" => Some(MissingLength::Zero),
+            "one" => Some(MissingLength::One),
+            _ => None,
+        }
+    }
+}
+
+/// How tree branch lengths are turned into the branch weights used in B
+#[derive(Clone, Debug, Default)]
+pub struct BranchOptions {
+    /// Multipliers keyed by node name (tip or internal label). A weight applies
+    /// to the named node's branch and to every branch below it; the innermost
+    /// named clade wins when entries are nested.
+    pub weights: Option<HashMap<String, f64>>,
+    /// Treat every branch as length 1 (purely topological UniFrac)
+    pub unit_lengths: bool,
+    /// Policy for branches without a length
+    pub missing_length: MissingLength,
+    /// Count the root's own edge (when the tree gives it a length) as a
+    /// branch covered by every non-empty sample
+    pub include_root_edge: bool,
+}
+
+/// Column of each leaf in B (indexed by node id) and the leaf names in column order
+pub fn leaf_layout(tree: &Tree) -> (Vec<usize>, Vec<String>) {
+    let leaves = tree.get_leaves();
+    let mut leaf_order = vec![0; tree.size()];
+    let mut leaf_names = Vec::new();
+    for (l_ord, l_idx) in leaves.into_iter().enumerate() {
+        leaf_order[l_idx] = l_ord;
+        leaf_names.push(tree.get(&l_idx).unwrap().name.clone().unwrap());
+    }
+    (leaf_order, leaf_names)
+}
+
+/// Construct B (branches x tips, see the module docs for indexing) and
+/// brlens, with branch lengths scaled according to `opts`
+pub fn construct_b(
+    tree: &Tree,
+    leaf_order: &[usize],
+    opts: &BranchOptions,
+) -> Result<(Array2<u8>, Array1<f64>)> {
+    let n_tips = tree.n_leaves();
+    let n_branches = tree.size();
+    let root = tree.get_root()?;
+    let order = tree.postorder(&root)?;
+
+    let mut mat_b = Array2::<u8>::zeros((n_branches, n_tips));
+    let brlens = Array1::from(branch_lengths(tree, opts)?);
+    for idx in order {
+        let node = tree.get(&idx)?;
+        if node.is_tip() {
+            let t_ord = leaf_order[idx];
+            mat_b[(idx, t_ord)] = 1;
+        } else {
+            for c in node.children.iter() {
+                let merged = &mat_b.row(idx) + &mat_b.row(*c);
+                mat_b.row_mut(idx).assign(&merged);
+            }
+        }
+    }
+
+    Ok((mat_b, brlens))
+}
+
+/// B in compressed sparse row form: the tips below branch `b` are
+/// `indices[indptr[b]..indptr[b + 1]]`, in increasing column order
+#[derive(Clone, Debug, Default)]
+pub struct SparseB {
+    pub indptr: Vec<usize>,
+    pub indices: Vec<usize>,
+    pub n_tips: usize,
+}
+
+impl SparseB {
+    /// Tip incidence of every branch of `tree`, with tip columns given by
+    /// `leaf_order` (see [`leaf_layout`])
+    pub fn from_tree(tree: &Tree, leaf_order: &[usize]) -> Result<SparseB> {
+        let root = tree.get_root()?;
+        let mut below: Vec<Vec<usize>> = vec![Vec::new(); tree.size()];
+        for idx in tree.postorder(&root)? {
+            let node = tree.get(&idx)?;
+            if node.is_tip() {
+                below[idx].push(leaf_order[idx]);
+            } else {
+                let mut tips: Vec<usize> = node
+                    .children
+                    .iter()
+                    .flat_map(|c| below[*c].iter().copied())
+                    .collect();
+                tips.sort_unstable();
+                below[idx] = tips;
+            }
+        }
+
+        let mut indptr = Vec::with_capacity(below.len() + 1);
+        let mut indices = Vec::new();
+        indptr.push(0);
+        for tips in below {
+            indices.extend(tips);
+            indptr.push(indices.len());
+        }
+        Ok(SparseB {
+            indptr,
+            indices,
+            n_tips: tree.n_leaves(),
+        })
+    }
+
+    pub fn n_branches(&self) -> usize {
+        self.indptr.len() - 1
+    }
+
+    /// Number of stored (tip, branch) incidences
+    pub fn nnz(&self) -> usize {
+        self.indices.len()
+    }
+
+    /// Tip columns below branch `b`
+    pub fn tips_below(&self, b: usize) -> &[usize] {
+        &self.indices[self.indptr[b]..self.indptr[b + 1]]
+    }
+
+    /// The same matrix as [`construct_b`] builds
+    pub fn to_dense(&self) -> Array2<u8> {
+        let mut mat_b = Array2::<u8>::zeros((self.n_branches(), self.n_tips));
+        for b in 0..self.n_branches() {
+            for t in self.tips_below(b) {
+                mat_b[(b, *t)] = 1;
+            }
+        }
+        mat_b
+    }
+}
+
+/// Sparse counterpart of [`construct_b`]
+pub fn construct_b_sparse(
+    tree: &Tree,
+    leaf_order: &[usize],
+    opts: &BranchOptions,
+) -> Result<(SparseB, Array1<f64>)> {
+    let mat_b = SparseB::from_tree(tree, leaf_order)?;
+    let brlens = Array1::from(branch_lengths(tree, opts)?);
+    Ok((mat_b, brlens))
+}
+
+/// Length of each node's parent branch (indexed by node id), scaled
+/// according to `opts`
+pub fn branch_lengths(tree: &Tree, opts: &BranchOptions) -> Result<Vec<f64>> {
+    let n_branches = tree.size();
+    let root = tree.get_root()?;
+    let order = tree.postorder(&root)?;
+
+    let weight = match &opts.weights {
+        Some(weights) => clade_weights(tree, weights)?,
+        None => vec![1.0; n_branches],
+    };
+
+    if !opts.unit_lengths && opts.missing_length == MissingLength::Error {
+        let missing = missing_branch_lengths(tree)?;
+        if missing > 0 {
+            bail!("{} branches of the tree have no length", missing);
+        }
+    }
+    let missing = match opts.missing_length {
+        MissingLength::One => 1.0,
+        _ => 0.0,
+    };
+
+    let mut lengths = vec![0.0; n_branches];
+    for idx in order {
+        let node = tree.get(&idx)?;
+        let length = match node.parent {
+            None if !opts.include_root_edge => 0.0,
+            // The root edge only exists if the tree gives the root a length
+            None => node
+                .parent_edge
+                .map(|l| if opts.unit_lengths { 1.0 } else { l })
+                .unwrap_or_default(),
+            Some(_) if opts.unit_lengths => 1.0,
+            Some(_) => node.parent_edge.unwrap_or(missing),
+        };
+        lengths[idx] = length * weight[idx];
+    }
+    Ok(lengths)
+}
+
+/// Multiplier of each node's parent branch (indexed by node id): the weight
+/// of the innermost named clade containing it, 1 outside every named clade
+fn clade_weights(tree: &Tree, weights: &HashMap<String, f64>) -> Result<Vec<f64>> {
+    let root = tree.get_root()?;
+    let order = tree.postorder(&root)?;
+
+    // Reverse postorder visits parents before children, so clade weights propagate down
+    let mut weight = vec![1.0; tree.size()];
+    for idx in order.iter().rev() {
+        let node = tree.get(idx)?;
+        let inherited = node.parent.map(|p| weight[p]).unwrap_or(1.0);
+        weight[*idx] = node
+            .name
+            .as_ref()
+            .and_then(|name| weights.get(name))
+            .copied()
+            .unwrap_or(inherited);
+    }
+    Ok(weight)
+}
+
+/// Multiply the tree's branch lengths in place by clade weights (see
+/// [`BranchOptions::weights`]). Applied to the whole tree before it is
+/// restricted to a clade or pruned, a weight still reaches the branches
+/// below a named node that the restriction removes. Fails on names that
+/// match no node of the tree.
+pub fn apply_branch_weights(tree: &mut Tree, weights: &HashMap<String, f64>) -> Result<()> {
+    let mut names = HashSet::new();
+    for idx in 0..tree.size() {
+        if let Some(name) = &tree.get(&idx)?.name {
+            names.insert(name.as_str());
+        }
+    }
+    let mut unknown: Vec<&str> = weights
+        .keys()
+        .map(|name| name.as_str())
+        .filter(|name| !names.contains(name))
+        .collect();
+    if !unknown.is_empty() {
+        unknown.sort_unstable();
+        bail!(
+            "{} weighted names match no node of the tree, e.g. '{}'",
+            unknown.len(),
+            unknown[0]
+        );
+    }
+
+    let weight = clade_weights(tree, weights)?;
+    for (idx, w) in weight.into_iter().enumerate() {
+        let node = tree.get_mut(&idx)?;
+        node.parent_edge = node.parent_edge.map(|l| l * w);
+    }
+    Ok(())
+}
+
+/// Set every branch that has a parent, and a root edge the tree gives a
+/// length, to length 1
+pub fn set_unit_lengths(tree: &mut Tree) -> Result<()> {
+    for idx in 0..tree.size() {
+        let node = tree.get_mut(&idx)?;
+        if node.parent.is_some() || node.parent_edge.is_some() {
+            node.parent_edge = Some(1.0);
+        }
+    }
+    Ok(())
+}
+
+/// Number of branches (edges to a parent) that have no length in the tree
+pub fn missing_branch_lengths(tree: &Tree) -> Result<usize> {
+    let root = tree.get_root()?;
+    let mut missing = 0;
+    for idx in tree.postorder(&root)? {
+        let node = tree.get(&idx)?;
+        if node.parent.is_some() && node.parent_edge.is_none() {
+            missing += 1;
+        }
+    }
+    Ok(missing)
+}
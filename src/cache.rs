@@ -0,0 +1,177 @@
+//! Content-addressed cache of computed distance matrices. A run is keyed by
+//! the SHA-256 of the program version, the content of its input files (each
+//! with the option it was given to) and the options that shape the result,
+//! so a changed input or option is a different entry and stale results are
+//! never returned.
+
+use crate::{io::OutputFile, summary::sha256_file};
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufReader, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// First bytes of a cache entry, with the layout version
+const MAGIC: &[u8; 8] = b"UFCACHE1";
+
+/// The matrices of one run, one per metric, as condensed upper triangles
+/// (pairs i < j, row by row). Entries store the distances as little-endian
+/// f64, so they come back exactly (NaN included).
+#[derive(Clone, Debug)]
+pub struct CachedResult {
+    pub sample_names: Vec<String>,
+    pub matrices: Vec<(String, Vec<f64>)>,
+}
+
+impl CachedResult {
+    /// From full (square, row-major) matrices
+    pub fn new(sample_names: &[String], matrices: &[(String, &[f64])]) -> CachedResult {
+        let n = sample_names.len();
+        CachedResult {
+            sample_names: sample_names.to_vec(),
+            matrices: matrices
+                .iter()
+                .map(|(metric, m)| {
+                    let condensed = (0..n)
+                        .flat_map(|i| (i + 1..n).map(move |j| m[i * n + j]))
+                        .collect();
+                    (metric.clone(), condensed)
+                })
+                .collect(),
+        }
+    }
+
+    /// The full matrix of `metric`, if this run computed it
+    pub fn matrix(&self, metric: &str) -> Option<Vec<f64>> {
+        let (_, condensed) = self.matrices.iter().find(|(m, _)| m == metric)?;
+        let n = self.sample_names.len();
+        let mut dist_matrix = vec![0.0; n * n];
+        let mut pairs = condensed.iter();
+        for i in 0..n {
+            for j in i + 1..n {
+                let d = *pairs.next()?;
+                dist_matrix[i * n + j] = d;
+                dist_matrix[j * n + i] = d; // symmetric
+            }
+        }
+        Some(dist_matrix)
+    }
+
+    fn write(&self, out: &mut impl Write) -> Result<()> {
+        out.write_all(MAGIC)?;
+        write_len(out, self.sample_names.len())?;
+        for name in &self.sample_names {
+            write_str(out, name)?;
+        }
+        write_len(out, self.matrices.len())?;
+        for (metric, condensed) in &self.matrices {
+            write_str(out, metric)?;
+            write_len(out, condensed.len())?;
+            for d in condensed {
+                out.write_all(&d.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read(input: &mut impl Read) -> Result<CachedResult> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("Not a cache entry");
+        }
+        let sample_names = (0..read_len(input)?)
+            .map(|_| read_str(input))
+            .collect::<Result<Vec<_>>>()?;
+        let n = sample_names.len();
+        let mut matrices = Vec::new();
+        for _ in 0..read_len(input)? {
+            let metric = read_str(input)?;
+            let len = read_len(input)?;
+            if len != n * n.saturating_sub(1) / 2 {
+                bail!(
+                    "Cached {} matrix has {} pairs for {} samples",
+                    metric,
+                    len,
+                    n
+                );
+            }
+            let mut condensed = Vec::with_capacity(len);
+            for _ in 0..len {
+                let mut bytes = [0; 8];
+                input.read_exact(&mut bytes)?;
+                condensed.push(f64::from_le_bytes(bytes));
+            }
+            matrices.push((metric, condensed));
+        }
+        Ok(CachedResult {
+            sample_names,
+            matrices,
+        })
+    }
+}
+
+fn write_len(out: &mut impl Write, len: usize) -> Result<()> {
+    out.write_all(&(len as u64).to_le_bytes())?;
+    Ok(())
+}
+
+fn read_len(input: &mut impl Read) -> Result<usize> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes) as usize)
+}
+
+fn write_str(out: &mut impl Write, s: &str) -> Result<()> {
+    write_len(out, s.len())?;
+    out.write_all(s.as_bytes())?;
+    Ok(())
+}
+
+fn read_str(input: &mut impl Read) -> Result<String> {
+    let mut bytes = vec![0; read_len(input)?];
+    input.read_exact(&mut bytes)?;
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// Hex key of a run: version, then each input file as the option it was
+/// given to with its content hash, then the options, all in a fixed order
+pub fn cache_key(files: &[(&str, &str)], options: &BTreeMap<String, String>) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+    for (id, file) in files {
+        hasher.update([0]);
+        hasher.update(id.as_bytes());
+        hasher.update([b'=']);
+        hasher.update(sha256_file(file)?.as_bytes());
+    }
+    for (id, value) in options {
+        hasher.update([0]);
+        hasher.update(id.as_bytes());
+        hasher.update([b'=']);
+        hasher.update(value.as_bytes());
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn entry_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{}.bin", key))
+}
+
+/// The cached result for `key`; a missing or unreadable entry is a miss
+pub fn load_cached(dir: &Path, key: &str) -> Option<CachedResult> {
+    let file = File::open(entry_path(dir, key)).ok()?;
+    CachedResult::read(&mut BufReader::new(file)).ok()
+}
+
+/// Store a result under `key`, replacing an unreadable entry
+pub fn store_cached(dir: &Path, key: &str, result: &CachedResult) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let path = entry_path(dir, key);
+    let mut file = OutputFile::create(&path.to_string_lossy(), true)?;
+    result.write(&mut file)?;
+    file.commit()
+}
@@ -0,0 +1,162 @@
+//! Clustering of samples straight from a distance matrix: k-medoids (PAM)
+//! for a fixed number of clusters, DBSCAN for density-based clusters with
+//! noise. Neither needs coordinates, so any UniFrac matrix can be used.
+
+use anyhow::{bail, Result};
+use rayon::prelude::*;
+
+/// k-medoids partition of the samples of a distance matrix
+pub struct KMedoids {
+    /// Sample index of each cluster's medoid
+    pub medoids: Vec<usize>,
+    /// Cluster of each sample, an index into `medoids`
+    pub assignments: Vec<usize>,
+    /// Sum of the distances of every sample to its medoid
+    pub cost: f64,
+}
+
+/// PAM (BUILD, then SWAP until no swap lowers the cost) on a square distance
+/// matrix stored row-major. Works from the distances alone, so unlike k-means
+/// it is valid for non-Euclidean dissimilarities such as UniFrac.
+pub fn k_medoids(dist_matrix: &[f64], n: usize, k: usize) -> Result<KMedoids> {
+    if k == 0 || k > n {
+        bail!(
+            "k must be between 1 and the number of samples ({}), got {}",
+            n,
+            k
+        );
+    }
+    if dist_matrix.iter().any(|d| !d.is_finite()) {
+        bail!("k-medoids needs finite distances; remove samples with undefined distances first");
+    }
+    let d = |i: usize, j: usize| dist_matrix[i * n + j];
+
+    // BUILD: the most central sample, then greedily whichever lowers the cost most
+    let mut medoids = vec![(0..n)
+        .min_by(|a, b| row_sum(dist_matrix, n, *a).total_cmp(&row_sum(dist_matrix, n, *b)))
+        .unwrap()];
+    let mut nearest: Vec<f64> = (0..n).map(|j| d(medoids[0], j)).collect();
+    while medoids.len() < k {
+        let best = (0..n)
+            .into_par_iter()
+            .filter(|h| !medoids.contains(h))
+            .map(|h| {
+                let gain: f64 = (0..n).map(|j| (nearest[j] - d(h, j)).max(0.0)).sum();
+                (h, gain)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)))
+            .unwrap();
+        medoids.push(best.0);
+        for (j, near) in nearest.iter_mut().enumerate() {
+            *near = near.min(d(best.0, j));
+        }
+    }
+
+    // SWAP: best (medoid, non-medoid) exchange while it lowers the cost
+    loop {
+        let (assignments, near, second) = nearest_two(dist_matrix, n, &medoids);
+        let best = (0..n)
+            .into_par_iter()
+            .filter(|h| !medoids.contains(h))
+            .flat_map_iter(|h| {
+                let (assignments, near, second) = (&assignments, &near, &second);
+                (0..medoids.len()).map(move |m| {
+                    let delta: f64 = (0..n)
+                        .map(|j| {
+                            let kept = if assignments[j] == m {
+                                second[j]
+                            } else {
+                                near[j]
+                            };
+                            kept.min(d(h, j)) - near[j]
+                        })
+                        .sum();
+                    (m, h, delta)
+                })
+            })
+            .min_by(|a, b| a.2.total_cmp(&b.2).then((a.0, a.1).cmp(&(b.0, b.1))));
+        match best {
+            Some((m, h, delta)) if delta < -1e-12 => medoids[m] = h,
+            _ => {
+                let cost = near.iter().sum();
+                return Ok(KMedoids {
+                    medoids,
+                    assignments,
+                    cost,
+                });
+            }
+        }
+    }
+}
+
+fn row_sum(dist_matrix: &[f64], n: usize, i: usize) -> f64 {
+    dist_matrix[i * n..(i + 1) * n].iter().sum()
+}
+
+/// For each sample: its closest medoid (index into `medoids`), the distance
+/// to it, and the distance to the second closest (infinite with one medoid)
+fn nearest_two(
+    dist_matrix: &[f64],
+    n: usize,
+    medoids: &[usize],
+) -> (Vec<usize>, Vec<f64>, Vec<f64>) {
+    let mut assignments = vec![0; n];
+    let mut near = vec![f64::INFINITY; n];
+    let mut second = vec![f64::INFINITY; n];
+    for j in 0..n {
+        for (m, &medoid) in medoids.iter().enumerate() {
+            let dj = dist_matrix[medoid * n + j];
+            if dj < near[j] {
+                second[j] = near[j];
+                near[j] = dj;
+                assignments[j] = m;
+            } else if dj < second[j] {
+                second[j] = dj;
+            }
+        }
+    }
+    (assignments, near, second)
+}
+
+/// DBSCAN on a square distance matrix stored row-major: a sample with at
+/// least `min_samples` samples (itself included) within `eps` is a core
+/// sample, clusters are the core samples connected through such
+/// neighbourhoods plus the samples they reach, and the rest is noise (`None`).
+/// Clusters are numbered in order of their first sample.
+pub fn dbscan(dist_matrix: &[f64], n: usize, eps: f64, min_samples: usize) -> Vec<Option<usize>> {
+    let neighbours =
+        |i: usize| -> Vec<usize> { (0..n).filter(|j| dist_matrix[i * n + j] <= eps).collect() };
+
+    let mut labels: Vec<Option<usize>> = vec![None; n];
+    let mut visited = vec![false; n];
+    let mut n_clusters = 0;
+    for start in 0..n {
+        if visited[start] {
+            continue;
+        }
+        visited[start] = true;
+        let seeds = neighbours(start);
+        if seeds.len() < min_samples {
+            continue;
+        }
+
+        let cluster = n_clusters;
+        n_clusters += 1;
+        labels[start] = Some(cluster);
+        let mut queue = seeds;
+        while let Some(s) = queue.pop() {
+            if labels[s].is_none() {
+                labels[s] = Some(cluster);
+            }
+            if visited[s] {
+                continue;
+            }
+            visited[s] = true;
+            let reach = neighbours(s);
+            if reach.len() >= min_samples {
+                queue.extend(reach.into_iter().filter(|r| !visited[*r]));
+            }
+        }
+    }
+    labels
+}
@@ -1,83 +1,1092 @@
-use anyhow::{Context, Result};
-use ndarray::{Array1, Array2, Zip};
+pub use crate::branches::{
+    branch_lengths, construct_b, construct_b_sparse, leaf_layout, missing_branch_lengths,
+    BranchOptions, MissingLength, SparseB,
+};
+use crate::{kernels, summary::available_memory_bytes};
+use anyhow::{bail, Result};
+use ndarray::{Array1, Array2, Axis, Zip};
 use phylotree::tree::Tree;
 use rayon::prelude::*;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+const GIB: f64 = (1u64 << 30) as f64;
+
+/// Distance of one pair plus how many branches each sample covers
+#[derive(Clone, Copy, Debug)]
+pub struct PairStats {
+    pub i: usize,
+    pub j: usize,
+    pub distance: f64,
+    /// Branches with descendants in both samples
+    pub shared_branches: usize,
+    /// Branches with descendants in sample i
+    pub branches_i: usize,
+    /// Branches with descendants in sample j
+    pub branches_j: usize,
+}
+
+/// Everything about the tree and the table that does not depend on the pair,
+/// built once so that each pair only walks the branches above its two samples'
+/// features (no tree copy, pruning or B construction per call)
+#[derive(Clone, Debug)]
+pub struct UniFracContext {
+    /// Parent of each node (by node id), None for the root
+    parent: Vec<Option<usize>>,
+    /// Length of each node's parent branch, as given by [`branch_lengths`]
+    brlens: Vec<f64>,
+    /// Tip nodes of the features present in each sample (table rows matched
+    /// to tips by name; features not in the tree are left out)
+    sample_tips: Vec<Vec<usize>>,
+    root: usize,
+    include_root_edge: bool,
+}
+
+impl UniFracContext {
+    pub fn new(
+        tree: &Tree,
+        taxa_order: &[String],
+        presence_matrix: &[Vec<f64>],
+        n_samples: usize,
+        opts: &BranchOptions,
+    ) -> Result<UniFracContext> {
+        let root = tree.get_root()?;
+        let mut parent = vec![None; tree.size()];
+        let mut tip_index: HashMap<&str, usize> = HashMap::new();
+        for idx in tree.postorder(&root)? {
+            let node = tree.get(&idx)?;
+            parent[idx] = node.parent;
+            if node.is_tip() {
+                if let Some(name) = node.name.as_deref() {
+                    tip_index.insert(name, idx);
+                }
+            }
+        }
+
+        let mut sample_tips = vec![Vec::new(); n_samples];
+        for (t, taxon) in taxa_order.iter().enumerate() {
+            let Some(&tip) = tip_index.get(taxon.as_str()) else {
+                continue;
+            };
+            for (s, tips) in sample_tips.iter_mut().enumerate() {
+                if presence_matrix[t][s] > 0.0 {
+                    tips.push(tip);
+                }
+            }
+        }
+
+        Ok(UniFracContext {
+            parent,
+            brlens: branch_lengths(tree, opts)?,
+            sample_tips,
+            root,
+            include_root_edge: opts.include_root_edge,
+        })
+    }
+
+    pub fn n_samples(&self) -> usize {
+        self.sample_tips.len()
+    }
+
+    /// Number of features of each sample found in the tree
+    pub fn feature_counts(&self) -> Vec<usize> {
+        self.sample_tips.iter().map(|tips| tips.len()).collect()
+    }
+
+    /// Unweighted UniFrac of samples i and j
+    pub fn distance(&self, i: usize, j: usize) -> f64 {
+        self.pair_stats(i, j).distance
+    }
+
+    /// Distance of samples i and j with the branch counts behind it (the
+    /// root edge is counted only if `opts.include_root_edge`)
+    pub fn pair_stats(&self, i: usize, j: usize) -> PairStats {
+        SCRATCH.with(|scratch| {
+            let mut scratch = scratch.borrow_mut();
+            let (mark, union) = &mut *scratch;
+            self.mark_pair(i, j, mark, union);
+            let stats = self.stats_of(i, j, mark, union);
+            // Leave the marks all zero for the next pair, touching only this pair's nodes
+            for &node in union.iter() {
+                mark[node] = 0;
+            }
+            stats
+        })
+    }
+
+    /// Mark the nodes at or above the features of samples i (bit 1) and j
+    /// (bit 2), listing each marked node once in `union`. `mark` must be all
+    /// zero on entry; it is grown to the tree's size if needed.
+    fn mark_pair(&self, i: usize, j: usize, mark: &mut Vec<u8>, union: &mut Vec<usize>) {
+        if mark.len() < self.parent.len() {
+            mark.resize(self.parent.len(), 0);
+        }
+        union.clear();
+        for (bit, s) in [(1u8, i), (2u8, j)] {
+            for &tip in &self.sample_tips[s] {
+                let mut node = tip;
+                while mark[node] & bit == 0 {
+                    if mark[node] == 0 {
+                        union.push(node);
+                    }
+                    mark[node] |= bit;
+                    match self.parent[node] {
+                        Some(p) => node = p,
+                        None => break,
+                    }
+                }
+            }
+        }
+    }
+
+    /// [`PairStats`] of samples i and j from the marks of [`Self::mark_pair`]
+    fn stats_of(&self, i: usize, j: usize, mark: &[u8], union: &[usize]) -> PairStats {
+        let (mut shared_length, mut union_length) = (0.0, 0.0);
+        let mut stats = PairStats {
+            i,
+            j,
+            distance: 0.0,
+            shared_branches: 0,
+            branches_i: 0,
+            branches_j: 0,
+        };
+        for &b in union {
+            if b == self.root && !self.include_root_edge {
+                continue;
+            }
+            union_length += self.brlens[b];
+            if mark[b] == 3 {
+                shared_length += self.brlens[b];
+                stats.shared_branches += 1;
+            }
+            stats.branches_i += (mark[b] & 1) as usize;
+            stats.branches_j += (mark[b] >> 1) as usize;
+        }
+        stats.distance = 1.0 - shared_length / union_length;
+        stats
+    }
+}
+
+thread_local! {
+    /// Node marks and marked nodes reused by every
+    /// [`UniFracContext::pair_stats`] call on a thread; the marks are all zero
+    /// between calls
+    static SCRATCH: RefCell<(Vec<u8>, Vec<usize>)> = const { RefCell::new((Vec::new(), Vec::new())) };
+}
 
 /// Compute UniFrac for a given pair of samples i,j
-pub fn compute_unifrac_for_pair(
+pub fn compute_unifrac_for_pair(context: &UniFracContext, i: usize, j: usize) -> f64 {
+    context.distance(i, j)
+}
+
+/// ASCII rendering of the branches above the features of samples i and j
+/// (those [`UniFracContext::pair_stats`] walks), marking each branch as
+/// shared by both samples or unique to one of them
+pub fn render_pair_subtree(
     tree: &Tree,
     taxa_order: &[String],
     presence_matrix: &[Vec<f64>],
     i: usize,
     j: usize,
-) -> Result<f64> {
-    // Determine which taxa are present in either sample i or j
-    let mut present_taxa = Vec::new();
-    for (t_idx, taxon) in taxa_order.iter().enumerate() {
-        let val_i = presence_matrix[t_idx][i];
-        let val_j = presence_matrix[t_idx][j];
-        if val_i > 0.0 || val_j > 0.0 {
-            present_taxa.push(taxon.clone());
+    names: [&str; 2],
+    opts: &BranchOptions,
+) -> Result<String> {
+    // The pair's two columns, as samples 0 and 1 of the context
+    let pair: Vec<Vec<f64>> = presence_matrix
+        .iter()
+        .map(|row| vec![row[i], row[j]])
+        .collect();
+    let context = UniFracContext::new(tree, taxa_order, &pair, 2, opts)?;
+    let (mut mark, mut union) = (Vec::new(), Vec::new());
+    context.mark_pair(0, 1, &mut mark, &mut union);
+    let stats = context.stats_of(0, 1, &mark, &union);
+
+    let mut lengths = [0.0; 3]; // shared, only i, only j
+    let mut labels = vec![String::new(); tree.size()];
+    for &idx in &union {
+        let (status, slot) = match mark[idx] {
+            3 => ("shared".to_string(), 0),
+            1 => (format!("only {}", names[0]), 1),
+            _ => (format!("only {}", names[1]), 2),
+        };
+        let length = if idx == context.root && !opts.include_root_edge {
+            0.0
+        } else {
+            context.brlens[idx]
+        };
+        lengths[slot] += length;
+        let name = tree.get(&idx)?.name.clone().unwrap_or_default();
+        labels[idx] = format!("{} [{:.6}] {}", name, length, status);
+    }
+
+    let mut out = String::new();
+    if mark[context.root] != 0 {
+        render_node(tree, context.root, "", None, &labels, &mut out)?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "shared branch length:    {:.6}", lengths[0])?;
+    writeln!(out, "unique to {}: {:.6}", names[0], lengths[1])?;
+    writeln!(out, "unique to {}: {:.6}", names[1], lengths[2])?;
+    writeln!(
+        out,
+        "total branch length:     {:.6}",
+        lengths.iter().sum::<f64>()
+    )?;
+    writeln!(out, "unifrac:                 {:.6}", stats.distance)?;
+
+    Ok(out)
+}
+
+/// Recursively draw `idx` and its labelled descendants; `last` is None for
+/// the root
+fn render_node(
+    tree: &Tree,
+    idx: usize,
+    prefix: &str,
+    last: Option<bool>,
+    labels: &[String],
+    out: &mut String,
+) -> Result<()> {
+    let (connector, extension) = match last {
+        None => ("", ""),
+        Some(true) => ("└── ", "    "),
+        Some(false) => ("├── ", "│   "),
+    };
+    writeln!(out, "{}{}{}", prefix, connector, labels[idx])?;
+
+    let child_prefix = format!("{}{}", prefix, extension);
+    let children: Vec<&usize> = tree
+        .get(&idx)?
+        .children
+        .iter()
+        .filter(|c| !labels[**c].is_empty())
+        .collect();
+    for (k, c) in children.iter().enumerate() {
+        render_node(
+            tree,
+            **c,
+            &child_prefix,
+            Some(k + 1 == children.len()),
+            labels,
+            out,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Compute the full symmetric distance matrix (row-major, n_samples x n_samples)
+pub fn compute_unifrac_matrix(
+    tree: &Tree,
+    taxa_order: &[String],
+    presence_matrix: &[Vec<f64>],
+    n_samples: usize,
+    opts: &BranchOptions,
+) -> Result<Vec<f64>> {
+    let sets = BranchSets::new(tree, taxa_order, presence_matrix, n_samples, opts)?;
+
+    // Upper triangle row by row, heaviest rows first, straight into the
+    // matrix, then mirrored
+    let mut dist_matrix = vec![0.0; n_samples * n_samples];
+    let mut rows: Vec<Option<&mut [f64]>> =
+        dist_matrix.chunks_mut(n_samples.max(1)).map(Some).collect();
+    let scheduled: Vec<(usize, &mut [f64])> = schedule_rows(&sets.branch_counts())
+        .into_iter()
+        .map(|i| (i, rows[i].take().unwrap()))
+        .collect();
+    scheduled
+        .into_par_iter()
+        .with_max_len(1)
+        .for_each(|(i, row)| {
+            for (j, d) in row.iter_mut().enumerate().skip(i + 1) {
+                *d = sets.distance(i, j);
+            }
+        });
+    for i in 0..n_samples {
+        for j in 0..i {
+            dist_matrix[i * n_samples + j] = dist_matrix[j * n_samples + i];
+        }
+    }
+    Ok(dist_matrix)
+}
+
+/// Which UniFrac variant to compute
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Metric {
+    /// Unweighted UniFrac on the rooted tree
+    #[default]
+    Unweighted,
+    /// Unweighted UniFrac over the splits of the unrooted tree
+    Unrooted,
+    /// Normalized weighted UniFrac
+    Weighted,
+    /// Generalized UniFrac with the given alpha
+    Generalized(f64),
+}
+
+impl Metric {
+    /// Parse "unweighted", "unrooted", "weighted" or "generalized[:ALPHA]"
+    /// (alpha defaults to 0.5)
+    pub fn from_name(name: &str) -> Option<Metric> {
+        match name {
+            "unweighted" => Some(Metric::Unweighted),
+            "unrooted" => Some(Metric::Unrooted),
+            "weighted" => Some(Metric::Weighted),
+            "generalized" => Some(Metric::Generalized(0.5)),
+            _ => {
+                let alpha: f64 = name.strip_prefix("generalized:")?.parse().ok()?;
+                (0.0..=1.0)
+                    .contains(&alpha)
+                    .then_some(Metric::Generalized(alpha))
+            }
+        }
+    }
+
+    /// Whether the metric uses abundances rather than presence/absence
+    pub fn is_weighted(self) -> bool {
+        matches!(self, Metric::Weighted | Metric::Generalized(_))
+    }
+
+    /// Generalized UniFrac alpha of a weighted metric (1 for normalized
+    /// weighted UniFrac)
+    pub fn alpha(self) -> Option<f64> {
+        match self {
+            Metric::Weighted => Some(1.0),
+            Metric::Generalized(alpha) => Some(alpha),
+            _ => None,
+        }
+    }
+}
+
+/// Strategy for computing the full matrix; all give the same distances
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Algorithm {
+    /// AND + popcount over bit-packed branch sets
+    #[default]
+    Bitset,
+    /// One pass over the branches, accumulating into all pairs
+    BranchMajor,
+    /// Dense matrix products; fastest for dense, medium-sized problems
+    Gemm,
+    /// Pair loop on an OpenCL device
+    #[cfg(feature = "opencl")]
+    OpenCl,
+}
+
+impl Algorithm {
+    /// Approximate size of the per-branch structure the algorithm builds
+    pub fn branch_matrix_bytes(self, n_branches: usize, n_tips: usize, n_samples: usize) -> usize {
+        let f64_size = std::mem::size_of::<f64>();
+        // Bitsets over branches per sample, plus the per-nibble length table
+        let bitsets =
+            n_samples * n_branches.div_ceil(64) * 8 + n_branches.div_ceil(64) * 16 * 16 * f64_size;
+        match self {
+            Algorithm::Bitset => bitsets,
+            // Node-major coverage bitsets, plus one condensed shared length
+            // and count per pair
+            Algorithm::BranchMajor => {
+                n_branches * n_samples.div_ceil(64) * 8
+                    + n_samples * n_samples.saturating_sub(1) / 2 * (f64_size + 8)
+            }
+            // B (u8) and its f64 copy, the tip presence, X = B·P and its
+            // weighted copy, and the two samples x samples products
+            Algorithm::Gemm => {
+                n_branches * n_tips * (1 + f64_size)
+                    + n_tips * n_samples * f64_size
+                    + 2 * n_branches * n_samples * f64_size
+                    + 2 * n_samples * n_samples * f64_size
+            }
+            #[cfg(feature = "opencl")]
+            Algorithm::OpenCl => bitsets,
+        }
+    }
+
+    /// Names accepted by [`Algorithm::from_name`]
+    pub fn names() -> Vec<&'static str> {
+        let mut names = vec!["bitset", "branch-major", "gemm"];
+        if cfg!(feature = "opencl") {
+            names.push("opencl");
+        }
+        names
+    }
+
+    pub fn from_name(name: &str) -> Option<Algorithm> {
+        match name {
+            "bitset" => Some(Algorithm::Bitset),
+            "branch-major" => Some(Algorithm::BranchMajor),
+            "gemm" => Some(Algorithm::Gemm),
+            #[cfg(feature = "opencl")]
+            "opencl" => Some(Algorithm::OpenCl),
+            _ => None,
+        }
+    }
+}
+
+/// Compute the full distance matrix and the per-pair branch statistics with
+/// the given algorithm
+pub fn compute_unifrac_matrix_using(
+    algorithm: Algorithm,
+    tree: &Tree,
+    taxa_order: &[String],
+    presence_matrix: &[Vec<f64>],
+    n_samples: usize,
+    opts: &BranchOptions,
+) -> Result<(Vec<f64>, Vec<PairStats>)> {
+    match algorithm {
+        Algorithm::Bitset => {
+            compute_unifrac_matrix_stats(tree, taxa_order, presence_matrix, n_samples, opts)
+        }
+        Algorithm::BranchMajor => {
+            compute_unifrac_matrix_branch_major(tree, taxa_order, presence_matrix, n_samples, opts)
+        }
+        Algorithm::Gemm => {
+            compute_unifrac_matrix_gemm(tree, taxa_order, presence_matrix, n_samples, opts)
+        }
+        #[cfg(feature = "opencl")]
+        Algorithm::OpenCl => crate::opencl::compute_unifrac_matrix_opencl(
+            tree,
+            taxa_order,
+            presence_matrix,
+            n_samples,
+            opts,
+        ),
+    }
+}
+
+/// Same result as [`compute_unifrac_matrix_stats`], from dense matrix
+/// products over the full tree. With X = B · P (branches x samples) and
+/// L = diag(brlens), the shared lengths of all pairs are Xᵀ · L · X and the
+/// shared branch counts Xᵀ · X; the diagonals hold the per-sample totals.
+pub fn compute_unifrac_matrix_gemm(
+    tree: &Tree,
+    taxa_order: &[String],
+    presence_matrix: &[Vec<f64>],
+    n_samples: usize,
+    opts: &BranchOptions,
+) -> Result<(Vec<f64>, Vec<PairStats>)> {
+    // Everything here is dense, so check that it fits before allocating any of it
+    let needed = Algorithm::Gemm.branch_matrix_bytes(tree.size(), tree.n_leaves(), n_samples);
+    if let Some(available) = available_memory_bytes() {
+        if needed as u64 > available {
+            bail!(
+                "The gemm algorithm needs about {:.1} GiB for its dense matrices ({} branches x {} tips, {} samples) but only {:.1} GiB are available; use the bitset or branch-major algorithm",
+                needed as f64 / GIB,
+                tree.size(),
+                tree.n_leaves(),
+                n_samples,
+                available as f64 / GIB
+            );
+        }
+    }
+
+    let (leaf_order, leaf_names) = leaf_layout(tree);
+    let (mat_b, brlens) = construct_b(tree, &leaf_order, opts)?;
+    let samples: Vec<usize> = (0..n_samples).collect();
+    let mut x = get_sample_matrix(&mat_b, presence_matrix, taxa_order, &leaf_names, &samples)?;
+
+    // Unless its edge is included, the root is not a branch
+    if !opts.include_root_edge {
+        x.row_mut(tree.get_root()?).fill(0.0);
+    }
+
+    let weighted = &x * &brlens.view().insert_axis(Axis(1));
+    let shared_len = weighted.t().dot(&x);
+    let shared_count = x.t().dot(&x);
+
+    let mut dist_matrix = vec![0.0; n_samples * n_samples];
+    let mut stats = Vec::with_capacity(n_samples * n_samples.saturating_sub(1) / 2);
+    for i in 0..n_samples {
+        for j in i + 1..n_samples {
+            let shared = shared_len[(i, j)];
+            let union = shared_len[(i, i)] + shared_len[(j, j)] - shared;
+            let distance = 1.0 - shared / union;
+            dist_matrix[i * n_samples + j] = distance;
+            dist_matrix[j * n_samples + i] = distance; // symmetric
+            stats.push(PairStats {
+                i,
+                j,
+                distance,
+                shared_branches: shared_count[(i, j)].round() as usize,
+                branches_i: shared_count[(i, i)].round() as usize,
+                branches_j: shared_count[(j, j)].round() as usize,
+            });
+        }
+    }
+
+    Ok((dist_matrix, stats))
+}
+
+/// Compute the full distance matrix and the per-pair branch statistics
+/// (pairs i < j, in row-major order).
+///
+/// Each sample's branches are bit-packed once; every pair is then an AND of
+/// the two bitsets, a popcount for the shared branch count and a lookup in a
+/// per-nibble table of branch-length sums for the shared length.
+pub fn compute_unifrac_matrix_stats(
+    tree: &Tree,
+    taxa_order: &[String],
+    presence_matrix: &[Vec<f64>],
+    n_samples: usize,
+    opts: &BranchOptions,
+) -> Result<(Vec<f64>, Vec<PairStats>)> {
+    compute_unifrac_matrix_progress(tree, taxa_order, presence_matrix, n_samples, opts, None)
+}
+
+/// [`compute_unifrac_matrix_stats`], adding the number of finished pairs to
+/// `progress` as rows complete, for callers reporting on long runs
+pub fn compute_unifrac_matrix_progress(
+    tree: &Tree,
+    taxa_order: &[String],
+    presence_matrix: &[Vec<f64>],
+    n_samples: usize,
+    opts: &BranchOptions,
+    progress: Option<&AtomicUsize>,
+) -> Result<(Vec<f64>, Vec<PairStats>)> {
+    let sets = BranchSets::new(tree, taxa_order, presence_matrix, n_samples, opts)?;
+
+    let rows: Vec<Vec<PairStats>> = schedule_rows(&sets.branch_counts())
+        .into_par_iter()
+        .with_max_len(1)
+        .map(|i| {
+            let row: Vec<PairStats> = (i + 1..n_samples).map(|j| sets.pair_stats(i, j)).collect();
+            if let Some(done) = progress {
+                done.fetch_add(row.len(), Ordering::Relaxed);
+            }
+            row
+        })
+        .collect();
+
+    let mut dist_matrix = vec![0.0; n_samples * n_samples];
+    let mut stats: Vec<PairStats> = rows.into_iter().flatten().collect();
+    stats.sort_unstable_by_key(|pair| (pair.i, pair.j));
+    for pair in &stats {
+        dist_matrix[pair.i * n_samples + pair.j] = pair.distance;
+        dist_matrix[pair.j * n_samples + pair.i] = pair.distance; // symmetric
+    }
+
+    Ok((dist_matrix, stats))
+}
+
+/// Each sample's branches on the full tree, bit-packed, with what is needed
+/// to turn a pair of bitsets into a distance
+pub struct BranchSets {
+    /// One bitset over branches per sample, `n_words` words each
+    bits: Vec<u64>,
+    n_words: usize,
+    /// Per-nibble branch-length sums (see [`length_table`])
+    table: Vec<f64>,
+    /// Covered length and branch count of each sample
+    totals: Vec<(f64, usize)>,
+}
+
+impl BranchSets {
+    pub fn new(
+        tree: &Tree,
+        taxa_order: &[String],
+        presence_matrix: &[Vec<f64>],
+        n_samples: usize,
+        opts: &BranchOptions,
+    ) -> Result<BranchSets> {
+        let lengths = branch_lengths(tree, opts)?;
+        let coverage = sample_coverage(tree, taxa_order, presence_matrix, n_samples)?;
+        let mut bits = sample_branch_bits(&coverage, lengths.len(), n_samples);
+        let n_words = lengths.len().div_ceil(64);
+
+        // Unless its edge is included, the root is not a branch
+        if !opts.include_root_edge {
+            let root = tree.get_root()?;
+            for s in 0..n_samples {
+                bits[s * n_words + root / 64] &= !(1 << (root % 64));
+            }
+        }
+
+        let mut sets = BranchSets {
+            bits,
+            n_words,
+            table: length_table(&lengths),
+            totals: Vec::new(),
+        };
+        sets.totals = (0..n_samples)
+            .map(|s| {
+                let own = sets.sample(s);
+                (
+                    shared_length(own, own, &sets.table),
+                    kernels::popcount_and(own, own) as usize,
+                )
+            })
+            .collect();
+        Ok(sets)
+    }
+
+    pub fn n_samples(&self) -> usize {
+        self.totals.len()
+    }
+
+    /// Number of branches each sample covers
+    pub fn branch_counts(&self) -> Vec<usize> {
+        self.totals.iter().map(|(_, count)| *count).collect()
+    }
+
+    /// Branch bitset of sample `s`
+    pub fn sample(&self, s: usize) -> &[u64] {
+        &self.bits[s * self.n_words..(s + 1) * self.n_words]
+    }
+
+    /// Branches (node ids) of sample `s`, ascending
+    pub fn branches(&self, s: usize) -> Vec<usize> {
+        let mut branches = Vec::new();
+        set_bits(self.sample(s), &mut branches);
+        branches
+    }
+
+    /// Unweighted UniFrac distance of samples i and j
+    pub fn distance(&self, i: usize, j: usize) -> f64 {
+        let shared = shared_length(self.sample(i), self.sample(j), &self.table);
+        let union = self.totals[i].0 + self.totals[j].0 - shared;
+        1.0 - shared / union
+    }
+
+    /// Distance of sample i of these sets and sample j of `other`, which must
+    /// have been built from the same tree and options
+    pub fn cross_distance(&self, i: usize, other: &BranchSets, j: usize) -> f64 {
+        let shared = shared_length(self.sample(i), other.sample(j), &self.table);
+        let union = self.totals[i].0 + other.totals[j].0 - shared;
+        1.0 - shared / union
+    }
+
+    /// Every pair i < j, row by row, with its distance. Each distance is
+    /// computed as the iterator reaches it, so results can be streamed (e.g.
+    /// into a database) without holding the matrix.
+    pub fn pairs(&self) -> impl Iterator<Item = (usize, usize, f64)> + '_ {
+        let n = self.n_samples();
+        (0..n).flat_map(move |i| (i + 1..n).map(move |j| (i, j, self.distance(i, j))))
+    }
+
+    /// [`BranchSets::pairs`] as a rayon parallel iterator, in no particular
+    /// order, for folding results into a concurrent structure
+    pub fn par_pairs(&self) -> impl ParallelIterator<Item = (usize, usize, f64)> + '_ {
+        let n = self.n_samples();
+        (0..n)
+            .into_par_iter()
+            .flat_map_iter(move |i| (i + 1..n).map(move |j| (i, j, self.distance(i, j))))
+    }
+
+    /// Distance and branch counts of samples i and j
+    pub fn pair_stats(&self, i: usize, j: usize) -> PairStats {
+        PairStats {
+            i,
+            j,
+            distance: self.distance(i, j),
+            shared_branches: kernels::popcount_and(self.sample(i), self.sample(j)) as usize,
+            branches_i: self.totals[i].1,
+            branches_j: self.totals[j].1,
         }
     }
+}
+
+/// Time taken by one pair's distance, for profiling
+#[derive(Clone, Copy, Debug)]
+pub struct PairTiming {
+    pub i: usize,
+    pub j: usize,
+    /// Branches with descendants in either sample
+    pub union_branches: usize,
+    pub nanos: u64,
+}
+
+/// Unweighted distance matrix from branch sets, timing every pair
+pub fn compute_unifrac_matrix_timed(sets: &BranchSets) -> (Vec<f64>, Vec<PairTiming>) {
+    let n_samples = sets.n_samples();
+    let rows: Vec<Vec<(f64, PairTiming)>> = (0..n_samples)
+        .into_par_iter()
+        .map(|i| {
+            (i + 1..n_samples)
+                .map(|j| {
+                    let start = Instant::now();
+                    let distance = sets.distance(i, j);
+                    let nanos = start.elapsed().as_nanos() as u64;
+                    let stats = sets.pair_stats(i, j);
+                    let union_branches =
+                        stats.branches_i + stats.branches_j - stats.shared_branches;
+                    let timing = PairTiming {
+                        i,
+                        j,
+                        union_branches,
+                        nanos,
+                    };
+                    (distance, timing)
+                })
+                .collect()
+        })
+        .collect();
+
+    let mut dist_matrix = vec![0.0; n_samples * n_samples];
+    let mut timings = Vec::with_capacity(n_samples * n_samples.saturating_sub(1) / 2);
+    for (distance, timing) in rows.into_iter().flatten() {
+        dist_matrix[timing.i * n_samples + timing.j] = distance;
+        dist_matrix[timing.j * n_samples + timing.i] = distance; // symmetric
+        timings.push(timing);
+    }
+    (dist_matrix, timings)
+}
+
+/// Distances between every sample of `a` (rows) and every sample of `b`
+/// (columns), e.g. two tables over the same tree; n_a x n_b, row-major
+pub fn compute_cross_matrix(a: &BranchSets, b: &BranchSets) -> Vec<f64> {
+    let n_b = b.n_samples();
+    (0..a.n_samples())
+        .into_par_iter()
+        .flat_map_iter(|i| (0..n_b).map(move |j| a.cross_distance(i, b, j)))
+        .collect()
+}
 
-    let mut sub_tree = tree.clone();
-    // prune taxa not in present_taxa
-    {
-        let leaves = sub_tree.get_leaves();
-        let set: std::collections::HashSet<_> = present_taxa.iter().cloned().collect();
-        for l in leaves {
-            let name = sub_tree.get(&l).unwrap().name.clone().unwrap();
-            if !set.contains(&name) {
-                sub_tree.prune(&l).context("Prune failed")?;
-                sub_tree.compress()?; // We also need to compress before pruning other leaves
+/// Pick `m` landmark samples by farthest-point (max-min) selection, starting
+/// from sample 0, and return them with the n x m distances of every sample to
+/// each landmark. Undefined distances (empty samples) never make a landmark.
+pub fn landmark_distances(sets: &BranchSets, m: usize) -> (Vec<usize>, Vec<f64>) {
+    let n = sets.n_samples();
+    let m = m.min(n);
+    let mut landmarks = Vec::with_capacity(m);
+    let mut columns: Vec<Vec<f64>> = Vec::with_capacity(m);
+    let mut nearest = vec![f64::INFINITY; n];
+    let mut chosen = vec![false; n];
+    let mut next = 0;
+    while landmarks.len() < m {
+        let column: Vec<f64> = (0..n)
+            .into_par_iter()
+            .map(|s| sets.cross_distance(s, sets, next))
+            .collect();
+        for (near, d) in nearest.iter_mut().zip(column.iter()) {
+            if !d.is_nan() {
+                *near = near.min(*d);
             }
         }
+        landmarks.push(next);
+        columns.push(column);
+        chosen[next] = true;
+
+        let candidate = (0..n)
+            .filter(|s| !chosen[*s] && nearest[*s].is_finite())
+            .max_by(|a, b| nearest[*a].total_cmp(&nearest[*b]));
+        match candidate {
+            Some(s) => next = s,
+            None => break,
+        }
     }
 
-    let leaves = sub_tree.get_leaves();
-    let mut leaf_order = vec![0; sub_tree.size()];
-    let mut leaf_names = Vec::new();
-    for (l_ord, l_idx) in leaves.into_iter().enumerate() {
-        leaf_order[l_idx] = l_ord;
-        leaf_names.push(sub_tree.get(&l_idx).unwrap().name.clone().unwrap());
+    let m = landmarks.len();
+    let mut dists = vec![0.0; n * m];
+    for (l, column) in columns.iter().enumerate() {
+        for (s, d) in column.iter().enumerate() {
+            dists[s * m + l] = *d;
+        }
     }
+    (landmarks, dists)
+}
 
-    let (mat_b, brlens) = construct_b(&sub_tree, &leaf_order)?;
+/// Transpose node-major sample coverage (see [`sample_coverage`]) into one
+/// bitset over branches per sample, `ceil(n_branches / 64)` words each
+pub fn sample_branch_bits(coverage: &[u64], n_branches: usize, n_samples: usize) -> Vec<u64> {
+    let cov_words = n_samples.div_ceil(64);
+    let n_words = n_branches.div_ceil(64);
+    let mut bits = vec![0u64; n_samples * n_words];
+    let mut members = Vec::with_capacity(n_samples);
+    for b in 0..n_branches {
+        set_bits(&coverage[b * cov_words..(b + 1) * cov_words], &mut members);
+        for s in &members {
+            bits[s * n_words + b / 64] |= 1 << (b % 64);
+        }
+    }
+    bits
+}
 
-    let p_a = get_sample_vec(&mat_b, presence_matrix, taxa_order, &leaf_names, i)?;
-    let p_b = get_sample_vec(&mat_b, presence_matrix, taxa_order, &leaf_names, j)?;
+/// For every 4-bit nibble of a branch bitset, the total length of the
+/// branches set in each of the 16 possible nibble values: 16 entries per
+/// nibble, 2 KB per 64 branches
+pub fn length_table(lengths: &[f64]) -> Vec<f64> {
+    let n_nibbles = lengths.len().div_ceil(64) * 16;
+    let mut table = vec![0.0; n_nibbles * 16];
+    for k in 0..n_nibbles {
+        let base = k * 16;
+        for v in 1..16usize {
+            // Extend the entry without the lowest set bit by that bit's length
+            let low = v.trailing_zeros() as usize;
+            let len = lengths.get(k * 4 + low).copied().unwrap_or_default();
+            table[base + v] = table[base + (v & (v - 1))] + len;
+        }
+    }
+    table
+}
 
-    let sum_shared = parallel_elementwise_sum(&p_a, &p_b, &brlens);
-    let l_total = brlens.sum();
-    let unifrac = 1.0 - (sum_shared / l_total);
+/// Total length of the branches set in both bitsets
+pub fn shared_length(a: &[u64], b: &[u64], table: &[f64]) -> f64 {
+    a.iter()
+        .zip(b.iter())
+        .enumerate()
+        .map(|(w, (x, y))| word_length(w, x & y, table))
+        .sum()
+}
 
-    Ok(unifrac)
+/// Total length of the branches set in word `w` of a branch bitset
+pub fn word_length(w: usize, word: u64, table: &[f64]) -> f64 {
+    if word == 0 {
+        return 0.0;
+    }
+    let base = w * 16 * 16;
+    (0..16)
+        .map(|nibble| table[base + nibble * 16 + ((word >> (4 * nibble)) & 0xf) as usize])
+        .sum()
 }
 
-/// Construct B and brlens
-pub fn construct_b(tree: &Tree, leaf_order: &[usize]) -> Result<(Array2<u8>, Array1<f64>)> {
-    let n_tips = tree.n_leaves();
-    let n_branches = tree.size();
+/// Same result as [`compute_unifrac_matrix_stats`], computed branch-major:
+/// each branch adds its length to every pair of samples that both cover it,
+/// so the tree is walked once instead of once per pair. The union for a pair
+/// follows from the per-sample totals.
+pub fn compute_unifrac_matrix_branch_major(
+    tree: &Tree,
+    taxa_order: &[String],
+    presence_matrix: &[Vec<f64>],
+    n_samples: usize,
+    opts: &BranchOptions,
+) -> Result<(Vec<f64>, Vec<PairStats>)> {
+    let lengths = branch_lengths(tree, opts)?;
+    let n_words = n_samples.div_ceil(64);
+    let coverage = sample_coverage(tree, taxa_order, presence_matrix, n_samples)?;
     let root = tree.get_root()?;
+    let branches: Vec<usize> = (0..lengths.len())
+        .filter(|b| *b != root || opts.include_root_edge)
+        .collect();
+
+    // Per-sample covered length and branch count
+    let mut total_len = vec![0.0; n_samples];
+    let mut total_count = vec![0usize; n_samples];
+    let mut members = Vec::with_capacity(n_samples);
+    for b in &branches {
+        set_bits(&coverage[b * n_words..(b + 1) * n_words], &mut members);
+        for s in &members {
+            total_len[*s] += lengths[*b];
+            total_count[*s] += 1;
+        }
+    }
+
+    // Shared length and count per pair, in one condensed buffer split into
+    // blocks of rows with about the same number of pairs. Each block walks
+    // the branches covering one of its rows and owns its slice outright.
+    let n_pairs = n_samples * n_samples.saturating_sub(1) / 2;
+    let mut shared_len = vec![0.0; n_pairs];
+    let mut shared_count = vec![0usize; n_pairs];
+    let target = n_pairs.div_ceil(4 * rayon::current_num_threads()).max(1);
+    let mut blocks = Vec::new();
+    let (mut len_rest, mut count_rest) = (shared_len.as_mut_slice(), shared_count.as_mut_slice());
+    let mut r0 = 0;
+    while r0 < n_samples {
+        let mut r1 = r0 + 1;
+        while r1 < n_samples
+            && pair_index(r1, r1 + 1, n_samples) - pair_index(r0, r0 + 1, n_samples) < target
+        {
+            r1 += 1;
+        }
+        let size = pair_index(r1, r1 + 1, n_samples) - pair_index(r0, r0 + 1, n_samples);
+        let (len_block, len_tail) = std::mem::take(&mut len_rest).split_at_mut(size);
+        let (count_block, count_tail) = std::mem::take(&mut count_rest).split_at_mut(size);
+        blocks.push((r0, r1, len_block, count_block));
+        (len_rest, count_rest) = (len_tail, count_tail);
+        r0 = r1;
+    }
+    blocks
+        .into_par_iter()
+        .for_each(|(r0, r1, len_block, count_block)| {
+            let base = pair_index(r0, r0 + 1, n_samples);
+            let (w0, w1) = (r0 / 64, (r1 - 1) / 64 + 1);
+            let mut members = Vec::with_capacity(n_samples);
+            for b in &branches {
+                let words = &coverage[b * n_words..(b + 1) * n_words];
+                if words[w0..w1].iter().all(|w| *w == 0) {
+                    continue;
+                }
+                // Samples from this block's first word on, as the pairs' j > i
+                set_bits(&words[w0..], &mut members);
+                for (x, i) in members.iter().map(|m| w0 * 64 + m).enumerate() {
+                    if i < r0 {
+                        continue;
+                    }
+                    if i >= r1 {
+                        break;
+                    }
+                    let row = pair_index(i, i + 1, n_samples) - base;
+                    for j in members[x + 1..].iter().map(|m| w0 * 64 + m) {
+                        let k = row + (j - i - 1);
+                        len_block[k] += lengths[*b];
+                        count_block[k] += 1;
+                    }
+                }
+            }
+        });
+
+    let mut dist_matrix = vec![0.0; n_samples * n_samples];
+    let mut stats = Vec::with_capacity(n_pairs);
+    for i in 0..n_samples {
+        for j in i + 1..n_samples {
+            let k = pair_index(i, j, n_samples);
+            let union = total_len[i] + total_len[j] - shared_len[k];
+            let distance = 1.0 - shared_len[k] / union;
+            dist_matrix[i * n_samples + j] = distance;
+            dist_matrix[j * n_samples + i] = distance; // symmetric
+            stats.push(PairStats {
+                i,
+                j,
+                distance,
+                shared_branches: shared_count[k],
+                branches_i: total_count[i],
+                branches_j: total_count[j],
+            });
+        }
+    }
+
+    Ok((dist_matrix, stats))
+}
+
+/// Position of pair (i, j), i < j, in the row-major list of pairs
+fn pair_index(i: usize, j: usize, n: usize) -> usize {
+    i * n - i * (i + 1) / 2 + (j - i - 1)
+}
+
+/// Indices of the bits set in `words`, written into `out`
+fn set_bits(words: &[u64], out: &mut Vec<usize>) {
+    out.clear();
+    for (w, word) in words.iter().enumerate() {
+        let mut bits = *word;
+        while bits != 0 {
+            out.push(w * 64 + bits.trailing_zeros() as usize);
+            bits &= bits - 1;
+        }
+    }
+}
 
-    let mut mat_b = Array2::<u8>::zeros((n_branches, n_tips));
-    let mut brlens = Array1::zeros(n_branches);
+/// For every node of the tree, the samples with a present tip at or below
+/// it, as bitsets of `ceil(n_samples / 64)` words laid out by node id
+pub fn sample_coverage(
+    tree: &Tree,
+    taxa_order: &[String],
+    presence_matrix: &[Vec<f64>],
+    n_samples: usize,
+) -> Result<Vec<u64>> {
+    let n_words = n_samples.div_ceil(64);
+    let taxon_index: HashMap<&str, usize> = taxa_order
+        .iter()
+        .enumerate()
+        .map(|(t, name)| (name.as_str(), t))
+        .collect();
+
+    let mut coverage = vec![0u64; tree.size() * n_words];
+    let root = tree.get_root()?;
     for idx in tree.postorder(&root)? {
         let node = tree.get(&idx)?;
-        brlens[idx] = node.parent_edge.unwrap_or_default();
         if node.is_tip() {
-            let t_ord = leaf_order[idx];
-            mat_b[(idx, t_ord)] = 1;
+            let name = node.name.as_deref().unwrap_or_default();
+            if let Some(t) = taxon_index.get(name) {
+                for (s, value) in presence_matrix[*t].iter().enumerate().take(n_samples) {
+                    if *value > 0.0 {
+                        coverage[idx * n_words + s / 64] |= 1 << (s % 64);
+                    }
+                }
+            }
         } else {
             for c in node.children.iter() {
-                let merged = &mat_b.row(idx) + &mat_b.row(*c);
-                mat_b.row_mut(idx).assign(&merged);
+                for w in 0..n_words {
+                    coverage[idx * n_words + w] |= coverage[c * n_words + w];
+                }
+            }
+        }
+    }
+
+    Ok(coverage)
+}
+
+/// Compute one distance matrix per group of samples, each holding only the
+/// pairs within that group. All groups are handled in a single pass over the
+/// pairs, sharing the tree and the presence matrix.
+pub fn compute_unifrac_groups(
+    tree: &Tree,
+    taxa_order: &[String],
+    presence_matrix: &[Vec<f64>],
+    groups: &[Vec<usize>],
+    opts: &BranchOptions,
+) -> Result<Vec<Vec<f64>>> {
+    let mut matrices: Vec<Vec<f64>> = groups
+        .iter()
+        .map(|g| vec![0.0; g.len() * g.len()])
+        .collect();
+
+    // (group, a, b) for every pair within a group
+    let mut jobs = Vec::new();
+    for (g, members) in groups.iter().enumerate() {
+        for a in 0..members.len() {
+            for b in a + 1..members.len() {
+                jobs.push((g, a, b));
             }
         }
     }
+    let n_samples = presence_matrix.first().map_or(0, |row| row.len());
+    let context = UniFracContext::new(tree, taxa_order, presence_matrix, n_samples, opts)?;
+    let order = schedule_pairs(
+        &context.feature_counts(),
+        &jobs
+            .iter()
+            .map(|(g, a, b)| (groups[*g][*a], groups[*g][*b]))
+            .collect::<Vec<_>>(),
+    );
+    let distances: Vec<f64> = order
+        .par_iter()
+        .with_max_len(1)
+        .map(|k| {
+            let (g, a, b) = jobs[*k];
+            compute_unifrac_for_pair(&context, groups[g][a], groups[g][b])
+        })
+        .collect();
+
+    for (k, uni) in order.iter().zip(distances) {
+        let (g, a, b) = jobs[*k];
+        let n = groups[g].len();
+        matrices[g][a * n + b] = uni;
+        matrices[g][b * n + a] = uni; // symmetric
+    }
+
+    Ok(matrices)
+}
+
+/// Indices into `pairs`, heaviest first. A pair's cost is estimated from
+/// per-sample sizes (features or branches of each sample) as the sum of the
+/// two, an upper bound on their union; starting the heavy pairs first keeps
+/// one of them from straggling at the end of a parallel run.
+pub fn schedule_pairs(sizes: &[usize], pairs: &[(usize, usize)]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..pairs.len()).collect();
+    order.sort_by_key(|k| {
+        let (i, j) = pairs[*k];
+        std::cmp::Reverse(sizes[i] + sizes[j])
+    });
+    order
+}
 
-    Ok((mat_b, brlens))
+/// Rows of the upper triangle (row i holds the pairs (i, j > i)), heaviest
+/// first, with pair costs estimated as in [`schedule_pairs`]
+pub fn schedule_rows(sizes: &[usize]) -> Vec<usize> {
+    let n = sizes.len();
+    let mut costs = vec![0; n];
+    let mut after = 0; // sizes of the samples past row i
+    for i in (0..n).rev() {
+        costs[i] = (n - 1 - i) * sizes[i] + after;
+        after += sizes[i];
+    }
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by_key(|i| std::cmp::Reverse(costs[*i]));
+    order
 }
 
 /// Construct p_a (or p_b) for a given sample index
@@ -88,19 +1097,42 @@ pub fn get_sample_vec(
     leaf_names: &[String],
     sample_idx: usize,
 ) -> Result<Array1<f64>> {
-    let s = mat.shape();
-    let mut p: Array1<f64> = Array1::zeros(s[0]);
+    let p = get_sample_matrix(mat, presence_matrix, taxa_order, leaf_names, &[sample_idx])?;
+    Ok(p.column(0).to_owned())
+}
 
-    // For each leaf_name, find its taxon index in taxa_order, check presence in sample_idx
+/// Branch presence of several samples at once (branches x samples), as the
+/// single product B · P of B with the leaves x samples presence matrix.
+/// Uses BLAS when built with the `blas` feature.
+pub fn get_sample_matrix(
+    mat: &Array2<u8>,
+    presence_matrix: &[Vec<f64>],
+    taxa_order: &[String],
+    leaf_names: &[String],
+    samples: &[usize],
+) -> Result<Array2<f64>> {
+    let taxon_index: HashMap<&str, usize> = taxa_order
+        .iter()
+        .enumerate()
+        .map(|(t, name)| (name.as_str(), t))
+        .collect();
+
+    // Leaves without a row in the table are absent from every sample; the
+    // binary warns with their count when it loads the inputs
+    let mut leaf_presence = Array2::<f64>::zeros((leaf_names.len(), samples.len()));
     for (col, lname) in leaf_names.iter().enumerate() {
-        let t_idx = taxa_order.iter().position(|x| x == lname).unwrap();
-        let val = presence_matrix[t_idx][sample_idx];
-        if val > 0.0 {
-            // Convert u8 to f64 before addition
-            p = &p + &mat.column(col).mapv(|x| x as f64);
+        let Some(&t_idx) = taxon_index.get(lname.as_str()) else {
+            continue;
+        };
+        for (k, s) in samples.iter().enumerate() {
+            if presence_matrix[t_idx][*s] > 0.0 {
+                leaf_presence[(col, k)] = 1.0;
+            }
         }
     }
 
+    let p = mat.mapv(|x| x as f64).dot(&leaf_presence);
+
     // clamp to 0,1
     Ok(p.mapv(|v: f64| if v > 0.0 { 1.0 } else { 0.0 }))
 }
@@ -130,6 +1162,19 @@ pub fn parallel_elementwise_sum2(
         .sum()
 }
 
+/// Element-wise multiply and sum (p_a * p_b * brlens) with the SIMD kernel
+/// selected for this CPU at runtime
+pub fn dispatched_elementwise_sum(
+    p_a: &Array1<f64>,
+    p_b: &Array1<f64>,
+    brlens: &Array1<f64>,
+) -> f64 {
+    match (p_a.as_slice(), p_b.as_slice(), brlens.as_slice()) {
+        (Some(a), Some(b), Some(l)) => kernels::weighted_shared_sum(a, b, l),
+        _ => vectorized_elementwise_sum(p_a, p_b, brlens),
+    }
+}
+
 pub fn vectorized_elementwise_sum(
     p_a: &Array1<f64>,
     p_b: &Array1<f64>,
@@ -137,3 +1182,205 @@ pub fn vectorized_elementwise_sum(
 ) -> f64 {
     (p_a * p_b * brlens).sum()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::newick::read_newick;
+
+    /// Root edge 0.3; the other branches sum to 9
+    const NEWICK: &str = "((A:1,B:2):0.5,(C:1.5,(D:0.25,E:0.75):1):2):0.3;";
+
+    /// Features x samples: two tips, three tips, an empty sample, a single
+    /// tip, every tip, and raw counts on two tips
+    fn table() -> (Vec<String>, Vec<Vec<f64>>) {
+        let taxa = ["A", "B", "C", "D", "E"]
+            .iter()
+            .map(|t| t.to_string())
+            .collect();
+        let counts = vec![
+            vec![1.0, 1.0, 0.0, 0.0, 1.0, 0.0],
+            vec![1.0, 0.0, 0.0, 0.0, 1.0, 3.0],
+            vec![0.0, 1.0, 0.0, 0.0, 1.0, 0.0],
+            vec![0.0, 1.0, 0.0, 0.0, 1.0, 0.5],
+            vec![0.0, 0.0, 0.0, 1.0, 1.0, 0.0],
+        ];
+        (taxa, counts)
+    }
+
+    fn options(include_root_edge: bool) -> BranchOptions {
+        BranchOptions {
+            include_root_edge,
+            ..Default::default()
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64, what: &str) {
+        assert!(
+            (actual - expected).abs() < 1e-12,
+            "{}: {} instead of {}",
+            what,
+            actual,
+            expected
+        );
+    }
+
+    /// Every algorithm gives the naive per-pair walk's distances and branch counts
+    fn check_algorithms_agree(include_root_edge: bool) {
+        let tree = read_newick(NEWICK.as_bytes()).unwrap();
+        let (taxa, counts) = table();
+        let n = counts[0].len();
+        let opts = options(include_root_edge);
+        let context = UniFracContext::new(&tree, &taxa, &counts, n, &opts).unwrap();
+
+        for algorithm in [Algorithm::Bitset, Algorithm::BranchMajor, Algorithm::Gemm] {
+            let (dist_matrix, stats) =
+                compute_unifrac_matrix_using(algorithm, &tree, &taxa, &counts, n, &opts).unwrap();
+            assert_eq!(stats.len(), n * (n - 1) / 2, "{:?}", algorithm);
+            for pair in &stats {
+                let naive = context.pair_stats(pair.i, pair.j);
+                let what = format!("{:?}, samples {} and {}", algorithm, pair.i, pair.j);
+                assert_close(pair.distance, naive.distance, &what);
+                assert_close(dist_matrix[pair.i * n + pair.j], naive.distance, &what);
+                assert_close(dist_matrix[pair.j * n + pair.i], naive.distance, &what);
+                assert_eq!(
+                    (pair.shared_branches, pair.branches_i, pair.branches_j),
+                    (naive.shared_branches, naive.branches_i, naive.branches_j),
+                    "{}",
+                    what
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn algorithms_agree_without_root_edge() {
+        check_algorithms_agree(false);
+    }
+
+    #[test]
+    fn algorithms_agree_with_root_edge() {
+        check_algorithms_agree(true);
+    }
+
+    /// Distance matrices of every algorithm, with the per-pair walk's last
+    fn all_matrices(
+        newick: &str,
+        taxa: &[String],
+        counts: &[Vec<f64>],
+        opts: &BranchOptions,
+    ) -> Vec<(String, Vec<f64>)> {
+        let tree = read_newick(newick.as_bytes()).unwrap();
+        let n = counts[0].len();
+        let mut matrices: Vec<(String, Vec<f64>)> =
+            [Algorithm::Bitset, Algorithm::BranchMajor, Algorithm::Gemm]
+                .into_iter()
+                .map(|algorithm| {
+                    let (dist_matrix, _) =
+                        compute_unifrac_matrix_using(algorithm, &tree, taxa, counts, n, opts)
+                            .unwrap();
+                    (format!("{:?}", algorithm), dist_matrix)
+                })
+                .collect();
+        let context = UniFracContext::new(&tree, taxa, counts, n, opts).unwrap();
+        let mut naive = vec![0.0; n * n];
+        for i in 0..n {
+            for j in 0..n {
+                if i != j {
+                    naive[i * n + j] = context.distance(i, j);
+                }
+            }
+        }
+        matrices.push(("per-pair walk".to_string(), naive));
+        matrices
+    }
+
+    #[test]
+    fn fixed_distances_on_rooted_tree() {
+        let (taxa, counts) = table();
+        let n = counts[0].len();
+        for (name, m) in all_matrices(NEWICK, &taxa, &counts, &options(false)) {
+            assert_close(m[4], 1.0 - 3.5 / 9.0, &format!("{} 0-4", name));
+            assert_close(m[2], 1.0, &format!("{} 0-2", name));
+            assert_close(m[3 * n + 5], 1.0 - 3.0 / 6.5, &format!("{} 3-5", name));
+            // {A, B} and {A, C, D}: 3.5 and 6.25 long, sharing A and its parent
+            assert_close(m[1], 1.0 - 1.5 / 8.25, &format!("{} 0-1", name));
+        }
+        for (name, m) in all_matrices(NEWICK, &taxa, &counts, &options(true)) {
+            assert_close(
+                m[4],
+                1.0 - 3.8 / 9.3,
+                &format!("{} 0-4 with root edge", name),
+            );
+            assert_close(
+                m[3 * n + 5],
+                1.0 - 3.3 / 6.8,
+                &format!("{} 3-5 with root edge", name),
+            );
+        }
+    }
+
+    /// A root with three children, as unrooted trees are usually written
+    #[test]
+    fn fixed_distances_on_trifurcating_root() {
+        let newick = "(A:1,B:2,(C:3,D:4):5);";
+        let taxa: Vec<String> = ["A", "B", "C", "D"].iter().map(|t| t.to_string()).collect();
+        // Samples {A, C}, {B, D}, {A} and {C, D}
+        let counts = vec![
+            vec![1.0, 0.0, 1.0, 0.0],
+            vec![0.0, 1.0, 0.0, 0.0],
+            vec![1.0, 0.0, 0.0, 1.0],
+            vec![0.0, 1.0, 0.0, 1.0],
+        ];
+        let expected = [
+            (0, 1, 1.0 - 5.0 / 15.0),
+            (0, 2, 1.0 - 1.0 / 9.0),
+            (0, 3, 1.0 - 8.0 / 13.0),
+            (1, 2, 1.0),
+            (1, 3, 1.0 - 9.0 / 14.0),
+            (2, 3, 1.0),
+        ];
+        for (name, m) in all_matrices(newick, &taxa, &counts, &options(false)) {
+            for (i, j, d) in expected {
+                assert_close(m[i * 4 + j], d, &format!("{} {}-{}", name, i, j));
+                assert_close(m[j * 4 + i], d, &format!("{} {}-{}", name, j, i));
+            }
+        }
+    }
+
+    #[test]
+    fn naive_distances_by_hand() {
+        let tree = read_newick(NEWICK.as_bytes()).unwrap();
+        let (taxa, counts) = table();
+        let n = counts[0].len();
+
+        let context = UniFracContext::new(&tree, &taxa, &counts, n, &options(false)).unwrap();
+        // {A, B} inside every tip: 3.5 of 9
+        assert_close(context.distance(0, 4), 1.0 - 3.5 / 9.0, "0-4");
+        // The empty sample shares nothing with anyone
+        assert_close(context.distance(0, 2), 1.0, "0-2");
+        let empty = context.pair_stats(2, 4);
+        assert_eq!((empty.shared_branches, empty.branches_i), (0, 0));
+        // {E} and {B, D}: 3.75 and 5.75 long, sharing the 3 above D and E
+        assert_close(context.distance(3, 5), 1.0 - 3.0 / 6.5, "3-5");
+        let single = context.pair_stats(3, 5);
+        assert_eq!(
+            (single.shared_branches, single.branches_i, single.branches_j),
+            (2, 3, 5)
+        );
+
+        // The root edge is shared by every pair of non-empty samples
+        let context = UniFracContext::new(&tree, &taxa, &counts, n, &options(true)).unwrap();
+        assert_close(
+            context.distance(0, 4),
+            1.0 - 3.8 / 9.3,
+            "0-4 with root edge",
+        );
+        assert_close(context.distance(0, 2), 1.0, "0-2 with root edge");
+        assert_close(
+            context.distance(3, 5),
+            1.0 - 3.3 / 6.8,
+            "3-5 with root edge",
+        );
+    }
+}
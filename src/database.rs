@@ -0,0 +1,271 @@
+//! Feature tables read from, and distances accumulated into, a database
+//! (features `sqlite`, `duckdb`). The file extension picks the engine:
+//! `.duckdb`/`.ddb` open with DuckDB, anything else with SQLite.
+
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+
+/// Whether `path` is opened with DuckDB rather than SQLite
+fn is_duckdb(path: &str) -> bool {
+    let lower = path.to_lowercase();
+    lower.ends_with(".duckdb") || lower.ends_with(".ddb")
+}
+
+/// Run `query` read-only against the database and pivot its rows into a
+/// table (taxa x samples). The query must return three columns: feature ID,
+/// sample ID and count, one row per nonzero count; repeated feature/sample
+/// rows are summed. Features and samples keep the order they first appear in.
+pub fn read_query_table(
+    path: &str,
+    query: &str,
+) -> Result<(Vec<String>, Vec<String>, Vec<Vec<f64>>)> {
+    let rows = if is_duckdb(path) {
+        query_duckdb(path, query)?
+    } else {
+        query_sqlite(path, query)?
+    };
+    if rows.is_empty() {
+        bail!("The query returned no rows");
+    }
+
+    let mut taxa_order = Vec::new();
+    let mut sample_names = Vec::new();
+    let mut taxon_index: HashMap<String, usize> = HashMap::new();
+    let mut sample_index: HashMap<String, usize> = HashMap::new();
+    let mut cells = Vec::with_capacity(rows.len());
+    for (feature, sample, count) in rows {
+        let t = *taxon_index.entry(feature.clone()).or_insert_with(|| {
+            taxa_order.push(feature);
+            taxa_order.len() - 1
+        });
+        let s = *sample_index.entry(sample.clone()).or_insert_with(|| {
+            sample_names.push(sample);
+            sample_names.len() - 1
+        });
+        cells.push((t, s, count));
+    }
+
+    let mut counts = vec![vec![0.0; sample_names.len()]; taxa_order.len()];
+    for (t, s, count) in cells {
+        counts[t][s] += count;
+    }
+    Ok((taxa_order, sample_names, counts))
+}
+
+#[cfg(feature = "sqlite")]
+fn query_sqlite(path: &str, query: &str) -> Result<Vec<(String, String, f64)>> {
+    use rusqlite::{Connection, OpenFlags};
+
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Cannot open SQLite database {}", path))?;
+    let mut stmt = conn.prepare(query).context("Invalid query")?;
+    if stmt.column_count() != 3 {
+        bail!(
+            "The query returns {} columns; expected feature ID, sample ID and count",
+            stmt.column_count()
+        );
+    }
+    let rows = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .context("Cannot read the query's rows as (text, text, number)")?;
+    Ok(rows)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn query_sqlite(path: &str, _query: &str) -> Result<Vec<(String, String, f64)>> {
+    bail!(
+        "{}: SQLite input needs a build with --features sqlite",
+        path
+    )
+}
+
+#[cfg(feature = "duckdb")]
+fn query_duckdb(path: &str, query: &str) -> Result<Vec<(String, String, f64)>> {
+    use duckdb::{AccessMode, Config, Connection};
+
+    let config = Config::default().access_mode(AccessMode::ReadOnly)?;
+    let conn = Connection::open_with_flags(path, config)
+        .with_context(|| format!("Cannot open DuckDB database {}", path))?;
+    let mut stmt = conn.prepare(query).context("Invalid query")?;
+    let rows = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<duckdb::Result<Vec<_>>>()
+        .context("Cannot read the query's rows as (text, text, number)")?;
+    Ok(rows)
+}
+
+#[cfg(not(feature = "duckdb"))]
+fn query_duckdb(path: &str, _query: &str) -> Result<Vec<(String, String, f64)>> {
+    bail!(
+        "{}: DuckDB input needs a build with --features duckdb",
+        path
+    )
+}
+
+/// One run's row in the `runs` table
+#[derive(Clone, Debug)]
+pub struct RunRecord {
+    pub run_id: String,
+    /// Seconds since the Unix epoch
+    pub created: i64,
+    pub version: String,
+    pub metric: String,
+    pub tree: String,
+    pub table: String,
+    /// Command line options as a JSON object
+    pub options: String,
+}
+
+const CREATE_TABLES: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    run_id TEXT PRIMARY KEY,
+    created BIGINT NOT NULL,
+    version TEXT NOT NULL,
+    metric TEXT NOT NULL,
+    tree TEXT NOT NULL,
+    feature_table TEXT NOT NULL,
+    options TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS distances (
+    run_id TEXT NOT NULL,
+    sample_a TEXT NOT NULL,
+    sample_b TEXT NOT NULL,
+    distance DOUBLE
+);
+";
+
+const INSERT_RUN: &str = "INSERT INTO runs VALUES (?, ?, ?, ?, ?, ?, ?)";
+const INSERT_DISTANCE: &str = "INSERT INTO distances VALUES (?, ?, ?, ?)";
+
+/// Append a run and its distances (upper triangle, long format) to the
+/// database, creating the `runs` and `distances` tables on first use; all in
+/// one transaction, so a failed run leaves nothing behind
+pub fn write_distances_db(
+    path: &str,
+    run: &RunRecord,
+    sample_names: &[String],
+    dist_matrix: &[f64],
+) -> Result<()> {
+    let n = sample_names.len();
+    let pairs = (0..n).flat_map(|i| (i + 1..n).map(move |j| (i, j)));
+    if is_duckdb(path) {
+        insert_duckdb(path, run, sample_names, dist_matrix, pairs)
+    } else {
+        insert_sqlite(path, run, sample_names, dist_matrix, pairs)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn insert_sqlite(
+    path: &str,
+    run: &RunRecord,
+    sample_names: &[String],
+    dist_matrix: &[f64],
+    pairs: impl Iterator<Item = (usize, usize)>,
+) -> Result<()> {
+    use rusqlite::{params, Connection};
+
+    let n = sample_names.len();
+    let mut conn =
+        Connection::open(path).with_context(|| format!("Cannot open SQLite database {}", path))?;
+    conn.execute_batch(CREATE_TABLES)?;
+    let tx = conn.transaction()?;
+    tx.execute(
+        INSERT_RUN,
+        params![
+            run.run_id,
+            run.created,
+            run.version,
+            run.metric,
+            run.tree,
+            run.table,
+            run.options
+        ],
+    )?;
+    {
+        let mut insert = tx.prepare(INSERT_DISTANCE)?;
+        for (i, j) in pairs {
+            let d = dist_matrix[i * n + j];
+            insert.execute(params![
+                run.run_id,
+                sample_names[i],
+                sample_names[j],
+                if d.is_nan() { None } else { Some(d) }
+            ])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn insert_sqlite(
+    path: &str,
+    _run: &RunRecord,
+    _sample_names: &[String],
+    _dist_matrix: &[f64],
+    _pairs: impl Iterator<Item = (usize, usize)>,
+) -> Result<()> {
+    bail!(
+        "{}: SQLite output needs a build with --features sqlite",
+        path
+    )
+}
+
+#[cfg(feature = "duckdb")]
+fn insert_duckdb(
+    path: &str,
+    run: &RunRecord,
+    sample_names: &[String],
+    dist_matrix: &[f64],
+    pairs: impl Iterator<Item = (usize, usize)>,
+) -> Result<()> {
+    use duckdb::{params, Connection};
+
+    let n = sample_names.len();
+    let mut conn =
+        Connection::open(path).with_context(|| format!("Cannot open DuckDB database {}", path))?;
+    conn.execute_batch(CREATE_TABLES)?;
+    let tx = conn.transaction()?;
+    tx.execute(
+        INSERT_RUN,
+        params![
+            run.run_id,
+            run.created,
+            run.version,
+            run.metric,
+            run.tree,
+            run.table,
+            run.options
+        ],
+    )?;
+    {
+        let mut appender = tx.appender("distances")?;
+        for (i, j) in pairs {
+            let d = dist_matrix[i * n + j];
+            appender.append_row(params![
+                run.run_id,
+                sample_names[i],
+                sample_names[j],
+                if d.is_nan() { None } else { Some(d) }
+            ])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "duckdb"))]
+fn insert_duckdb(
+    path: &str,
+    _run: &RunRecord,
+    _sample_names: &[String],
+    _dist_matrix: &[f64],
+    _pairs: impl Iterator<Item = (usize, usize)>,
+) -> Result<()> {
+    bail!(
+        "{}: DuckDB output needs a build with --features duckdb",
+        path
+    )
+}
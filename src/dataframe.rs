@@ -0,0 +1,64 @@
+//! Polars integration (feature `polars`): feature tables in from a
+//! `DataFrame`, distance matrices out as one.
+
+use crate::table::FeatureTable;
+use anyhow::{bail, Context, Result};
+use polars::prelude::*;
+
+impl FeatureTable {
+    /// Table from a DataFrame with one row per feature: the IDs in
+    /// `feature_column`, and one numeric column of counts per sample (every
+    /// other column; nulls count as 0)
+    pub fn from_dataframe(df: &DataFrame, feature_column: &str) -> Result<FeatureTable> {
+        let ids = df
+            .column(feature_column)
+            .with_context(|| format!("DataFrame has no column named '{}'", feature_column))?
+            .cast(&DataType::String)?;
+        let taxa_order: Vec<String> = ids
+            .str()?
+            .into_iter()
+            .enumerate()
+            .map(|(row, id)| {
+                id.map(|id| id.to_string())
+                    .with_context(|| format!("Row {} has no feature ID", row))
+            })
+            .collect::<Result<_>>()?;
+
+        let mut sample_names = Vec::new();
+        let mut counts = vec![Vec::with_capacity(df.width()); df.height()];
+        for series in df.get_columns() {
+            if series.name() == feature_column {
+                continue;
+            }
+            if !series.dtype().is_numeric() {
+                bail!(
+                    "Sample column '{}' is {}, not numeric",
+                    series.name(),
+                    series.dtype()
+                );
+            }
+            let values = series.cast(&DataType::Float64)?;
+            for (row, value) in counts.iter_mut().zip(values.f64()?.into_iter()) {
+                row.push(value.unwrap_or(0.0));
+            }
+            sample_names.push(series.name().to_string());
+        }
+        FeatureTable::new(taxa_order, sample_names, counts)
+    }
+}
+
+/// Square distance matrix (row-major, n x n) as a DataFrame: a `sample`
+/// column, then one column per sample, as in the matrix files
+pub fn distance_dataframe(sample_names: &[String], dist_matrix: &[f64]) -> Result<DataFrame> {
+    let n = sample_names.len();
+    if dist_matrix.len() != n * n {
+        bail!("{} distances for {} samples", dist_matrix.len(), n);
+    }
+    let mut columns = Vec::with_capacity(n + 1);
+    columns.push(Series::new("sample", sample_names));
+    for (j, name) in sample_names.iter().enumerate() {
+        let column: Vec<f64> = (0..n).map(|i| dist_matrix[i * n + j]).collect();
+        columns.push(Series::new(name, column));
+    }
+    Ok(DataFrame::new(columns)?)
+}
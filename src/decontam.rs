@@ -0,0 +1,67 @@
+//! Contaminants identified from negative controls by prevalence, as in the
+//! prevalence method of decontam (Davis et al. 2018): a contaminant is found
+//! in a larger fraction of the controls than of the true samples, whereas
+//! real features are, if anything, rarer in the controls.
+//!
+//! Each feature gets the one-sided Fisher exact p-value of its presence in
+//! the controls being that high given its presence overall; features below
+//! the threshold are contaminants.
+
+use crate::expected::ln_choose;
+
+/// Presence of a feature in the controls and in the true samples
+#[derive(Clone, Debug)]
+pub struct ContaminantScore {
+    pub control_prevalence: f64,
+    pub sample_prevalence: f64,
+    /// One-sided Fisher exact p-value for higher prevalence in the controls
+    pub p_value: f64,
+}
+
+/// Score every feature (`counts` features x samples, `is_control` one flag
+/// per sample)
+pub fn prevalence_scores(counts: &[Vec<f64>], is_control: &[bool]) -> Vec<ContaminantScore> {
+    let n = is_control.len();
+    let n_controls = is_control.iter().filter(|c| **c).count();
+    counts
+        .iter()
+        .map(|row| {
+            let mut in_controls = 0;
+            let mut in_samples = 0;
+            for (v, control) in row.iter().zip(is_control) {
+                if *v > 0.0 {
+                    if *control {
+                        in_controls += 1;
+                    } else {
+                        in_samples += 1;
+                    }
+                }
+            }
+            ContaminantScore {
+                control_prevalence: in_controls as f64 / n_controls.max(1) as f64,
+                sample_prevalence: in_samples as f64 / (n - n_controls).max(1) as f64,
+                p_value: fisher_greater(in_controls, in_controls + in_samples, n_controls, n),
+            }
+        })
+        .collect()
+}
+
+/// P(X >= k) for X hypergeometric: `draws` of `total` samples (the
+/// controls), `present` of which carry the feature
+fn fisher_greater(k: usize, present: usize, draws: usize, total: usize) -> f64 {
+    if present == 0 {
+        return 1.0;
+    }
+    let ln_all = ln_choose(total as f64, draws as f64);
+    let upper = present.min(draws);
+    let lower = k.max((present + draws).saturating_sub(total));
+    let p: f64 = (lower..=upper)
+        .map(|x| {
+            (ln_choose(present as f64, x as f64)
+                + ln_choose((total - present) as f64, (draws - x) as f64)
+                - ln_all)
+                .exp()
+        })
+        .sum();
+    p.min(1.0)
+}
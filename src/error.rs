@@ -0,0 +1,67 @@
+use std::{fmt, io};
+
+/// Broad categories of failure, each mapped to its own process exit code so
+/// workflow managers can react without parsing messages
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// An input file does not exist
+    InputNotFound,
+    /// An input file exists but could not be parsed
+    Parse,
+    /// Inputs are individually valid but do not fit together, e.g. no table
+    /// feature matches a tree tip, or a sample is missing from the metadata
+    Mismatch,
+}
+
+impl ErrorKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::InputNotFound => 3,
+            ErrorKind::Parse => 4,
+            ErrorKind::Mismatch => 5,
+        }
+    }
+
+    /// Stable identifier used in structured error output
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::InputNotFound => "input_not_found",
+            ErrorKind::Parse => "parse_failure",
+            ErrorKind::Mismatch => "input_mismatch",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            ErrorKind::InputNotFound => "input not found",
+            ErrorKind::Parse => "failed to parse input",
+            ErrorKind::Mismatch => "inputs do not match",
+        };
+        f.write_str(msg)
+    }
+}
+
+/// Category of an error. A missing file anywhere in the chain takes precedence,
+/// then the innermost [`ErrorKind`] attached as context.
+pub fn classify(err: &anyhow::Error) -> Option<ErrorKind> {
+    let not_found = err.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::NotFound)
+    });
+    if not_found {
+        return Some(ErrorKind::InputNotFound);
+    }
+    err.downcast_ref::<ErrorKind>().copied()
+}
+
+/// Messages of the error's cause chain, outermost first, without the
+/// [`ErrorKind`] markers, which only say which category the error is in
+pub fn messages(err: &anyhow::Error) -> Vec<String> {
+    err.chain()
+        .filter(|cause| cause.downcast_ref::<ErrorKind>().is_none())
+        .map(|cause| cause.to_string())
+        .collect()
+}
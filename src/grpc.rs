@@ -0,0 +1,128 @@
+//! gRPC server (feature `grpc`) answering UniFrac queries against a tree and
+//! table loaded once, so clients skip process startup and parsing costs.
+
+use crate::compute::{
+    compute_unifrac_for_pair, compute_unifrac_matrix, BranchOptions, UniFracContext,
+};
+use anyhow::Result;
+use phylotree::tree::Tree;
+use std::{net::SocketAddr, sync::Arc};
+use tonic::{transport::Server, Request, Response, Status};
+
+pub mod proto {
+    tonic::include_proto!("unifrac");
+}
+
+use proto::{
+    unifrac_service_server::{UnifracService, UnifracServiceServer},
+    MatrixReply, MatrixRequest, PairReply, PairRequest,
+};
+
+/// Inputs kept in memory for the lifetime of the server
+pub struct LoadedInputs {
+    pub tree: Tree,
+    pub taxa_order: Vec<String>,
+    pub sample_names: Vec<String>,
+    pub presence_matrix: Vec<Vec<f64>>,
+    pub opts: BranchOptions,
+}
+
+impl LoadedInputs {
+    fn sample_index(&self, name: &str) -> Result<usize, Status> {
+        self.sample_names
+            .iter()
+            .position(|s| s == name)
+            .ok_or_else(|| Status::not_found(format!("Sample '{}' not found in the table", name)))
+    }
+}
+
+struct Service {
+    inputs: Arc<LoadedInputs>,
+    context: Arc<UniFracContext>,
+}
+
+fn internal(err: anyhow::Error) -> Status {
+    Status::internal(format!("{:#}", err))
+}
+
+#[tonic::async_trait]
+impl UnifracService for Service {
+    async fn compute_pair(
+        &self,
+        request: Request<PairRequest>,
+    ) -> Result<Response<PairReply>, Status> {
+        let request = request.into_inner();
+        let i = self.inputs.sample_index(&request.sample_a)?;
+        let j = self.inputs.sample_index(&request.sample_b)?;
+        let context = self.context.clone();
+
+        // Distances are CPU-bound; keep them off the async workers
+        let distance =
+            tokio::task::spawn_blocking(move || compute_unifrac_for_pair(&context, i, j))
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(PairReply { distance }))
+    }
+
+    async fn compute_matrix(
+        &self,
+        request: Request<MatrixRequest>,
+    ) -> Result<Response<MatrixReply>, Status> {
+        let request = request.into_inner();
+        let inputs = self.inputs.clone();
+        let samples = if request.samples.is_empty() {
+            inputs.sample_names.clone()
+        } else {
+            request.samples
+        };
+        let idx = samples
+            .iter()
+            .map(|s| inputs.sample_index(s))
+            .collect::<Result<Vec<usize>, Status>>()?;
+
+        let distances = tokio::task::spawn_blocking(move || {
+            // Presence restricted to the requested samples, in request order
+            let presence: Vec<Vec<f64>> = inputs
+                .presence_matrix
+                .iter()
+                .map(|row| idx.iter().map(|s| row[*s]).collect())
+                .collect();
+            compute_unifrac_matrix(
+                &inputs.tree,
+                &inputs.taxa_order,
+                &presence,
+                idx.len(),
+                &inputs.opts,
+            )
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(internal)?;
+
+        Ok(Response::new(MatrixReply { samples, distances }))
+    }
+}
+
+/// Serve ComputePair / ComputeMatrix on `addr` until the process is stopped
+pub fn serve(inputs: LoadedInputs, addr: SocketAddr) -> Result<()> {
+    let context = UniFracContext::new(
+        &inputs.tree,
+        &inputs.taxa_order,
+        &inputs.presence_matrix,
+        inputs.sample_names.len(),
+        &inputs.opts,
+    )?;
+    let service = Service {
+        inputs: Arc::new(inputs),
+        context: Arc::new(context),
+    };
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async move {
+        Server::builder()
+            .add_service(UnifracServiceServer::new(service))
+            .serve(addr)
+            .await
+    })?;
+    Ok(())
+}
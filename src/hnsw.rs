@@ -0,0 +1,226 @@
+//! Hierarchical navigable small world graphs (Malkov & Yashunin 2018) for
+//! approximate nearest neighbors under any distance given as a function of
+//! point indices, here UniFrac between samples.
+//!
+//! Every point is linked to close points on layer 0 and, with geometrically
+//! decreasing probability, on higher and sparser layers. A search descends
+//! greedily from the single entry point on the top layer and widens into a
+//! best-first search of `ef` candidates on layer 0. Points are inserted one
+//! after another, but the distances to the neighbors of each visited point
+//! are evaluated in parallel.
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashSet},
+};
+
+/// A point and its distance to the query, ordered by distance
+#[derive(Clone, Copy, Debug)]
+struct Candidate(f64, usize);
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+/// Undefined distances (samples without branches) count as infinitely far
+fn finite(d: f64) -> f64 {
+    if d.is_nan() {
+        f64::INFINITY
+    } else {
+        d
+    }
+}
+
+/// Navigable graph over points 0..len()
+#[derive(Serialize, Deserialize)]
+pub struct Hnsw {
+    /// Links kept per point on the upper layers; twice as many on layer 0
+    m: usize,
+    /// Search width while inserting
+    ef_construction: usize,
+    entry: Option<usize>,
+    /// Neighbors of each point on each of its layers, layer 0 first
+    links: Vec<Vec<Vec<usize>>>,
+}
+
+impl Hnsw {
+    /// Index points 0..n, inserted in order, with `distance` between two of
+    /// them; `seed` draws the layers, so a build is reproducible
+    pub fn build(
+        n: usize,
+        m: usize,
+        ef_construction: usize,
+        seed: u64,
+        distance: impl Fn(usize, usize) -> f64 + Sync,
+    ) -> Hnsw {
+        let mut graph = Hnsw {
+            m: m.max(2),
+            ef_construction: ef_construction.max(1),
+            entry: None,
+            links: Vec::with_capacity(n),
+        };
+        let mut rng = StdRng::seed_from_u64(seed);
+        let level_scale = 1.0 / (graph.m as f64).ln();
+        for q in 0..n {
+            let u: f64 = rng.gen_range(f64::MIN_POSITIVE..1.0);
+            let level = (-u.ln() * level_scale).floor() as usize;
+            graph.insert(q, level, &distance);
+        }
+        graph
+    }
+
+    pub fn len(&self) -> usize {
+        self.links.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.links.is_empty()
+    }
+
+    fn insert(&mut self, q: usize, level: usize, distance: &(impl Fn(usize, usize) -> f64 + Sync)) {
+        self.links.push(vec![Vec::new(); level + 1]);
+        let Some(entry) = self.entry else {
+            self.entry = Some(q);
+            return;
+        };
+        let to_q = |p: usize| finite(distance(q, p));
+        let top = self.links[entry].len() - 1;
+
+        let mut nearest = vec![Candidate(to_q(entry), entry)];
+        for layer in (level + 1..=top).rev() {
+            nearest = self.search_layer(&to_q, &nearest, 1, layer);
+        }
+        for layer in (0..=level.min(top)).rev() {
+            let found = self.search_layer(&to_q, &nearest, self.ef_construction, layer);
+            let max_links = if layer == 0 { 2 * self.m } else { self.m };
+            let neighbors = select_neighbors(&found, self.m, distance);
+            for &nb in &neighbors {
+                self.links[nb][layer].push(q);
+                if self.links[nb][layer].len() > max_links {
+                    let mut linked: Vec<Candidate> = self.links[nb][layer]
+                        .par_iter()
+                        .map(|&p| Candidate(finite(distance(nb, p)), p))
+                        .collect();
+                    linked.sort();
+                    self.links[nb][layer] = select_neighbors(&linked, max_links, distance);
+                }
+            }
+            self.links[q][layer] = neighbors;
+            nearest = found;
+        }
+        if level > top {
+            self.entry = Some(q);
+        }
+    }
+
+    /// Best-first search of one layer from `entries`, keeping the `ef`
+    /// closest points seen, ascending by distance
+    fn search_layer(
+        &self,
+        to_query: &(impl Fn(usize) -> f64 + Sync),
+        entries: &[Candidate],
+        ef: usize,
+        layer: usize,
+    ) -> Vec<Candidate> {
+        let mut visited: HashSet<usize> = entries.iter().map(|c| c.1).collect();
+        let mut candidates: BinaryHeap<Reverse<Candidate>> =
+            entries.iter().map(|c| Reverse(*c)).collect();
+        let mut found: BinaryHeap<Candidate> = entries.iter().copied().collect();
+        while found.len() > ef {
+            found.pop();
+        }
+
+        while let Some(Reverse(closest)) = candidates.pop() {
+            if found.len() >= ef && closest.0 > found.peek().unwrap().0 {
+                break;
+            }
+            let fresh: Vec<usize> = self.links[closest.1][layer]
+                .iter()
+                .copied()
+                .filter(|nb| visited.insert(*nb))
+                .collect();
+            let distances: Vec<f64> = fresh.par_iter().map(|&nb| to_query(nb)).collect();
+            for (nb, d) in fresh.into_iter().zip(distances) {
+                if found.len() < ef || d < found.peek().unwrap().0 {
+                    candidates.push(Reverse(Candidate(d, nb)));
+                    found.push(Candidate(d, nb));
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+        found.into_sorted_vec()
+    }
+
+    /// The (about) `k` indexed points closest to a query given by its
+    /// distance to each point, ascending, searching `ef` candidates wide
+    pub fn search(
+        &self,
+        to_query: impl Fn(usize) -> f64 + Sync,
+        k: usize,
+        ef: usize,
+    ) -> Vec<(usize, f64)> {
+        let Some(entry) = self.entry else {
+            return Vec::new();
+        };
+        let to_query = |p: usize| finite(to_query(p));
+        let mut nearest = vec![Candidate(to_query(entry), entry)];
+        for layer in (1..self.links[entry].len()).rev() {
+            nearest = self.search_layer(&to_query, &nearest, 1, layer);
+        }
+        self.search_layer(&to_query, &nearest, ef.max(k), 0)
+            .into_iter()
+            .take(k)
+            .map(|c| (c.1, c.0))
+            .collect()
+    }
+}
+
+/// Up to `m` of the `candidates` (ascending by distance to the new point),
+/// preferring those closer to it than to any neighbor already chosen, so
+/// that links spread over directions rather than bunch in one cluster
+fn select_neighbors(
+    candidates: &[Candidate],
+    m: usize,
+    distance: &impl Fn(usize, usize) -> f64,
+) -> Vec<usize> {
+    let mut chosen: Vec<usize> = Vec::with_capacity(m);
+    let mut skipped = Vec::new();
+    for c in candidates {
+        if chosen.len() >= m {
+            break;
+        }
+        if chosen.iter().all(|&p| finite(distance(c.1, p)) > c.0) {
+            chosen.push(c.1);
+        } else {
+            skipped.push(c.1);
+        }
+    }
+    for p in skipped {
+        if chosen.len() >= m {
+            break;
+        }
+        chosen.push(p);
+    }
+    chosen
+}
@@ -1,9 +1,84 @@
-use anyhow::{Context, Result};
+use crate::{
+    compute::{PairStats, PairTiming},
+    ordination::Pcoa,
+    stats::{BioenvSubset, DistanceSummary, GroupComparison, LongitudinalPair, PermanovaTest},
+};
+use anyhow::{bail, Context, Result};
+use ndarray::Array2;
 use std::{
-    fs::File,
-    io::{BufRead, BufReader, Write},
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
 };
 
+/// Fail if `path` exists and overwriting was not allowed (`--force`); lets
+/// commands refuse before doing any work rather than after
+pub fn check_output(path: &str, force: bool) -> Result<()> {
+    if !force && Path::new(path).exists() {
+        bail!("{} already exists; pass --force to overwrite it", path);
+    }
+    Ok(())
+}
+
+/// Output written to `<path>.tmp.<pid>` and renamed over `path` by
+/// [`OutputFile::commit`], so readers never see a truncated file. Dropping it
+/// without committing (an error, a panic) removes the temporary file.
+pub struct OutputFile {
+    writer: Option<BufWriter<File>>,
+    tmp_path: PathBuf,
+    path: PathBuf,
+}
+
+impl OutputFile {
+    pub fn create(path: &str, force: bool) -> Result<OutputFile> {
+        check_output(path, force)?;
+        let tmp_path = PathBuf::from(format!("{}.tmp.{}", path, std::process::id()));
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+        Ok(OutputFile {
+            writer: Some(BufWriter::new(file)),
+            tmp_path,
+            path: PathBuf::from(path),
+        })
+    }
+
+    /// Flush to disk and move the file into place
+    pub fn commit(mut self) -> Result<()> {
+        let writer = self.writer.take().unwrap();
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&self.tmp_path, &self.path)
+            .with_context(|| format!("Failed to move output into {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.as_mut().unwrap().flush()
+    }
+}
+
+/// Archives (zip) seek back to patch their headers
+impl Seek for OutputFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.writer.as_mut().unwrap().seek(pos)
+    }
+}
+
+impl Drop for OutputFile {
+    fn drop(&mut self) {
+        if self.writer.take().is_some() {
+            let _ = fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
 /// Read the sample-feature table.
 /// First line: ignore the first element, subsequent elements are sample names
 /// Example:
@@ -12,49 +87,659 @@ use std::{
 /// T2        0        25       0
 /// ...
 ///
-/// Any value > 0 is converted to 1.0, else 0.0.
+/// Any value > 0 is converted to 1.0, else 0.0; see [`read_count_table`]
+/// for the counts themselves.
+///
+/// QIIME classic tables are recognized: leading `# Constructed from biom file`
+/// style comment lines are skipped, the `#OTU ID` header cell is accepted like
+/// any other first header cell, and a final `taxonomy` column is ignored.
+///
+/// Also tolerates what spreadsheet exports produce: CRLF line endings, a UTF-8 BOM,
+/// double-quoted fields (which may contain tabs, with `""` for a literal quote),
+/// trailing empty columns and blank lines. Structural problems are reported
+/// with their line and column.
 pub fn read_sample_table(filename: &str) -> Result<(Vec<String>, Vec<String>, Vec<Vec<f64>>)> {
-    let f = File::open(filename)?;
-    let mut lines = BufReader::new(f).lines();
+    let (taxa_order, sample_names, counts) = read_count_table(filename)?;
+    Ok((taxa_order, sample_names, binarize(counts)))
+}
 
-    // First line: parse sample names
-    let header = lines.next().context("No header in table")??;
-    let mut hdr_split = header.split('\t');
-    hdr_split.next(); // ignore the first element in the header line
-    let sample_names: Vec<String> = hdr_split.map(|s| s.to_string()).collect();
+/// Read the sample-feature table like [`read_sample_table`], keeping the
+/// counts as they are (unparseable values count as 0.0)
+pub fn read_count_table(filename: &str) -> Result<(Vec<String>, Vec<String>, Vec<Vec<f64>>)> {
+    let (taxa_order, sample_names, counts, _) = read_count_table_with(filename, false)?;
+    Ok((taxa_order, sample_names, counts))
+}
+
+/// Read the sample-feature table like [`read_count_table`]. A cell that is
+/// not a number fails the read with its line and column when `strict`, and
+/// otherwise counts as 0.0; the last vector gives the number of such cells
+/// in each sample. Empty cells are absences, never errors.
+pub fn read_count_table_with(
+    filename: &str,
+    strict: bool,
+) -> Result<(Vec<String>, Vec<String>, Vec<Vec<f64>>, Vec<usize>)> {
+    let f = File::open(filename).with_context(|| format!("Cannot open table {}", filename))?;
+    let mut lines = BufReader::new(f).lines().enumerate();
+
+    // Header line: skip leading comments such as QIIME's "# Constructed from biom file"
+    let (header_no, header) = loop {
+        let (idx, line) = lines.next().context("No header in table")?;
+        let line = line?;
+        let line = line.strip_prefix('\u{feff}').unwrap_or(&line).to_string();
+        if !is_comment_line(&line) {
+            break (idx + 1, line);
+        }
+    };
+    let mut hdr_split = split_fields(&header, header_no)?;
+    trim_trailing_empty(&mut hdr_split);
+    // QIIME classic tables may end with a taxonomy annotation column
+    let has_taxonomy = hdr_split.len() > 2
+        && hdr_split
+            .last()
+            .is_some_and(|c| c.eq_ignore_ascii_case("taxonomy"));
+    if has_taxonomy {
+        hdr_split.pop();
+    }
+    if hdr_split.len() < 2 {
+        bail!("{}: line {}: header names no samples", filename, header_no);
+    }
+    let sample_names: Vec<String> = hdr_split.split_off(1); // ignore the first element in the header line
+    for (col, sn) in sample_names.iter().enumerate() {
+        if sn.is_empty() {
+            bail!(
+                "{}: line {}, column {}: empty sample name",
+                filename,
+                header_no,
+                col + 2
+            );
+        }
+    }
 
     let mut taxa_order = Vec::new();
-    let mut presence_matrix = Vec::new();
+    let mut counts = Vec::new();
+    let mut coerced = vec![0; sample_names.len()];
 
-    for line in lines {
+    for (line_idx, line) in lines {
+        let line_no = line_idx + 1;
         let line = line?;
-        let mut parts = line.split('\t');
-        let taxon = parts.next().context("Taxon missing in a line")?.to_string();
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut parts = split_fields(&line, line_no).with_context(|| filename.to_string())?;
+        if has_taxonomy {
+            parts.truncate(sample_names.len() + 1);
+        }
+        trim_trailing_empty(&mut parts);
+        if parts.len() > sample_names.len() + 1 {
+            bail!(
+                "{}: line {}, column {}: {} values for {} samples",
+                filename,
+                line_no,
+                sample_names.len() + 2,
+                parts.len() - 1,
+                sample_names.len()
+            );
+        }
+        // Trailing empty cells may have been dropped above; they count as absent
+        parts.resize(sample_names.len() + 1, String::new());
+
+        let mut parts = parts.into_iter();
+        let taxon = parts.next().unwrap_or_default();
+        if taxon.is_empty() {
+            bail!("{}: line {}, column 1: taxon missing", filename, line_no);
+        }
         taxa_order.push(taxon);
-        let values: Vec<f64> = parts
-            .map(|x| {
-                let val: f64 = x.parse().unwrap_or(0.0);
-                if val > 0.0 {
-                    1.0
+        let mut values = Vec::with_capacity(sample_names.len());
+        for (s, x) in parts.enumerate() {
+            match x.parse::<f64>() {
+                Ok(v) => values.push(v),
+                Err(_) if x.is_empty() => values.push(0.0),
+                Err(_) if strict => bail!(
+                    "{}: line {}, column {}: '{}' is not a number",
+                    filename,
+                    line_no,
+                    s + 2,
+                    x
+                ),
+                Err(_) => {
+                    coerced[s] += 1;
+                    values.push(0.0);
+                }
+            }
+        }
+        counts.push(values);
+    }
+
+    Ok((taxa_order, sample_names, counts, coerced))
+}
+
+/// What to do with samples that share a name in the table header
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Duplicates {
+    /// Refuse the table
+    #[default]
+    Error,
+    /// Rename later occurrences to `<name>_2`, `<name>_3`, ...
+    Suffix,
+    /// Merge them into one sample by summing their counts
+    Sum,
+}
+
+impl Duplicates {
+    pub fn from_name(name: &str) -> Option<Duplicates> {
+        match name {
+            "error" => Some(Duplicates::Error),
+            "suffix" => Some(Duplicates::Suffix),
+            "sum" => Some(Duplicates::Sum),
+            _ => None,
+        }
+    }
+}
+
+/// Apply a [`Duplicates`] policy to the sample names and count columns of a
+/// table (taxa x samples); without duplicates the table is returned as is
+pub fn resolve_duplicates(
+    sample_names: Vec<String>,
+    counts: Vec<Vec<f64>>,
+    policy: Duplicates,
+) -> Result<(Vec<String>, Vec<Vec<f64>>)> {
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    let mut duplicated = Vec::new();
+    for (s, name) in sample_names.iter().enumerate() {
+        if let Some(&first) = seen.get(name.as_str()) {
+            duplicated.push((s, first));
+        } else {
+            seen.insert(name, s);
+        }
+    }
+    if duplicated.is_empty() {
+        return Ok((sample_names, counts));
+    }
+
+    match policy {
+        Duplicates::Error => {
+            let mut names: Vec<&str> = duplicated
+                .iter()
+                .map(|(s, _)| sample_names[*s].as_str())
+                .collect();
+            names.sort_unstable();
+            names.dedup();
+            bail!(
+                "Duplicate sample names in the table header: {}; choose --duplicates suffix or sum",
+                names.join(", ")
+            );
+        }
+        Duplicates::Suffix => {
+            let mut taken: HashSet<String> = sample_names.iter().cloned().collect();
+            let mut renamed = sample_names.clone();
+            for (s, _) in &duplicated {
+                let base = &sample_names[*s];
+                let mut k = 2;
+                while taken.contains(&format!("{}_{}", base, k)) {
+                    k += 1;
+                }
+                renamed[*s] = format!("{}_{}", base, k);
+                taken.insert(renamed[*s].clone());
+            }
+            Ok((renamed, counts))
+        }
+        Duplicates::Sum => {
+            // Column of each original sample in the merged table
+            let mut target = vec![usize::MAX; sample_names.len()];
+            let mut merged_names = Vec::new();
+            for (s, name) in sample_names.iter().enumerate() {
+                let first = seen[name.as_str()];
+                if first == s {
+                    target[s] = merged_names.len();
+                    merged_names.push(name.clone());
                 } else {
-                    0.0
-                } // ensure binary
+                    target[s] = target[first];
+                }
+            }
+            let merged = counts
+                .iter()
+                .map(|row| {
+                    let mut out = vec![0.0; merged_names.len()];
+                    for (s, v) in row.iter().enumerate() {
+                        out[target[s]] += v;
+                    }
+                    out
+                })
+                .collect();
+            Ok((merged_names, merged))
+        }
+    }
+}
+
+/// Presence/absence: any value > 0 becomes 1.0, else 0.0
+pub fn binarize(counts: Vec<Vec<f64>>) -> Vec<Vec<f64>> {
+    counts
+        .into_iter()
+        .map(|row| {
+            row.into_iter()
+                .map(|v| if v > 0.0 { 1.0 } else { 0.0 })
+                .collect()
+        })
+        .collect()
+}
+
+/// Zero every count below `floor` times its sample's total, so that
+/// features too rare in a sample (e.g. cross-talk from other samples of the
+/// run) are absent from it; returns the number of counts zeroed
+pub fn apply_abundance_floor(counts: &mut [Vec<f64>], n_samples: usize, floor: f64) -> usize {
+    let mut totals = vec![0.0; n_samples];
+    for row in counts.iter() {
+        for (total, v) in totals.iter_mut().zip(row) {
+            *total += v.max(0.0);
+        }
+    }
+    let mut zeroed = 0;
+    for row in counts.iter_mut() {
+        for (v, total) in row.iter_mut().zip(&totals) {
+            if *v > 0.0 && *v < floor * total {
+                *v = 0.0;
+                zeroed += 1;
+            }
+        }
+    }
+    zeroed
+}
+
+/// Read a mothur `.shared` file: one row per sample, in the layout
+/// label  Group  numOtus  Otu001  Otu002 ...
+/// 0.03   A      2        10      0
+///
+/// A shared file may hold several OTU definitions (labels, e.g. distance
+/// cutoffs); only the rows of `label` are used, or of the first label in the
+/// file when none is given. Returns the same (taxa, samples, presence) layout
+/// as [`read_sample_table`].
+pub fn read_mothur_shared(
+    filename: &str,
+    label: Option<&str>,
+) -> Result<(Vec<String>, Vec<String>, Vec<Vec<f64>>)> {
+    let (taxa_order, sample_names, counts) = read_mothur_counts(filename, label)?;
+    Ok((taxa_order, sample_names, binarize(counts)))
+}
+
+/// Read a mothur `.shared` file like [`read_mothur_shared`], keeping the counts
+pub fn read_mothur_counts(
+    filename: &str,
+    label: Option<&str>,
+) -> Result<(Vec<String>, Vec<String>, Vec<Vec<f64>>)> {
+    let f = File::open(filename).with_context(|| format!("Cannot open table {}", filename))?;
+    let mut lines = BufReader::new(f).lines();
+
+    let header = lines.next().context("No header in shared file")??;
+    let hdr: Vec<&str> = header.trim_end().split('\t').collect();
+    if hdr.len() < 4
+        || !hdr[0].eq_ignore_ascii_case("label")
+        || !hdr[1].eq_ignore_ascii_case("group")
+        || !hdr[2].eq_ignore_ascii_case("numotus")
+    {
+        bail!(
+            "{}: line 1: expected a 'label, Group, numOtus, OTU...' header",
+            filename
+        );
+    }
+    let taxa_order: Vec<String> = hdr[3..].iter().map(|s| s.to_string()).collect();
+
+    let mut wanted = label.map(|l| l.to_string());
+    let mut sample_names = Vec::new();
+    let mut columns: Vec<Vec<f64>> = Vec::new();
+    for (line_idx, line) in lines.enumerate() {
+        let line_no = line_idx + 2;
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parts: Vec<&str> = line.trim_end().split('\t').collect();
+        if parts.len() != taxa_order.len() + 3 {
+            bail!(
+                "{}: line {}: {} columns, expected {}",
+                filename,
+                line_no,
+                parts.len(),
+                taxa_order.len() + 3
+            );
+        }
+        let wanted_label = wanted.get_or_insert_with(|| parts[0].to_string());
+        if parts[0] != wanted_label.as_str() {
+            continue;
+        }
+        sample_names.push(parts[1].to_string());
+        let values = parts[3..]
+            .iter()
+            .enumerate()
+            .map(|(col, x)| {
+                x.trim().parse::<f64>().with_context(|| {
+                    format!(
+                        "{}: line {}, column {}: invalid count '{}'",
+                        filename,
+                        line_no,
+                        col + 4,
+                        x
+                    )
+                })
             })
-            .collect();
-        presence_matrix.push(values);
+            .collect::<Result<Vec<f64>>>()?;
+        columns.push(values);
+    }
+    if sample_names.is_empty() {
+        bail!(
+            "{}: no rows with label '{}'",
+            filename,
+            wanted.unwrap_or_default()
+        );
+    }
+
+    // Transpose to taxa x samples
+    let counts = (0..taxa_order.len())
+        .map(|t| columns.iter().map(|c| c[t]).collect())
+        .collect();
+
+    Ok((taxa_order, sample_names, counts))
+}
+
+/// Comment lines before the header start with '#' but, unlike headers such as
+/// `#OTU ID<TAB>S1...` or `#<TAB>S1...`, have no tab or a "# " first field
+fn is_comment_line(line: &str) -> bool {
+    line.trim().is_empty()
+        || (line.starts_with('#') && (!line.contains('\t') || line.starts_with("# ")))
+}
+
+/// Split a tab-delimited line into trimmed fields, honouring double quotes
+fn split_fields(line: &str, line_no: usize) -> Result<Vec<String>> {
+    let line = line.trim_end_matches('\r');
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            if c == '"' {
+                if chars.peek() == Some(&'"') {
+                    field.push('"');
+                    chars.next();
+                } else {
+                    in_quotes = false;
+                }
+            } else {
+                field.push(c);
+            }
+        } else if c == '"' && field.trim().is_empty() && !quoted {
+            field.clear();
+            in_quotes = true;
+            quoted = true;
+        } else if c == '\t' {
+            fields.push(finish_field(&mut field, quoted));
+            quoted = false;
+        } else if quoted {
+            if !c.is_whitespace() {
+                bail!(
+                    "line {}, column {}: unexpected text after closing quote",
+                    line_no,
+                    fields.len() + 1
+                );
+            }
+        } else {
+            field.push(c);
+        }
+    }
+    if in_quotes {
+        bail!(
+            "line {}, column {}: unterminated quoted field",
+            line_no,
+            fields.len() + 1
+        );
+    }
+    fields.push(finish_field(&mut field, quoted));
+
+    Ok(fields)
+}
+
+fn finish_field(field: &mut String, quoted: bool) -> String {
+    let f = std::mem::take(field);
+    if quoted {
+        f
+    } else {
+        f.trim().to_string()
+    }
+}
+
+fn trim_trailing_empty(fields: &mut Vec<String>) {
+    while fields.len() > 1 && fields.last().is_some_and(|f| f.is_empty()) {
+        fields.pop();
+    }
+}
+
+/// Write a sample-feature table (taxa x samples) in the layout read by [`read_sample_table`]
+pub fn write_sample_table(
+    taxa_order: &[String],
+    sample_names: &[String],
+    values: &[Vec<f64>],
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let mut file = OutputFile::create(output_file, force)?;
+    write!(file, "#OTU ID")?;
+    for sn in sample_names {
+        write!(file, "\t{}", sn)?;
+    }
+    writeln!(file)?;
+
+    for (taxon, row) in taxa_order.iter().zip(values.iter()) {
+        write!(file, "{}", taxon)?;
+        for v in row {
+            write!(file, "\t{}", v)?;
+        }
+        writeln!(file)?;
+    }
+    file.commit()
+}
+
+/// Concatenate the samples of several tables over the union of their features.
+/// Features missing from a table are absent (0.0) in its samples.
+pub fn merge_sample_tables(
+    tables: Vec<(Vec<String>, Vec<String>, Vec<Vec<f64>>)>,
+) -> Result<(Vec<String>, Vec<String>, Vec<Vec<f64>>)> {
+    let mut taxa_order: Vec<String> = Vec::new();
+    let mut taxon_row = HashMap::new();
+    for (taxa, _, _) in tables.iter() {
+        for t in taxa {
+            if !taxon_row.contains_key(t) {
+                taxon_row.insert(t.clone(), taxa_order.len());
+                taxa_order.push(t.clone());
+            }
+        }
+    }
+
+    let n_samples: usize = tables.iter().map(|(_, samples, _)| samples.len()).sum();
+    let mut sample_names = Vec::with_capacity(n_samples);
+    let mut presence_matrix = vec![vec![0.0; n_samples]; taxa_order.len()];
+    let mut seen = HashSet::new();
+    for (taxa, samples, presence) in tables {
+        let offset = sample_names.len();
+        for s in samples {
+            if !seen.insert(s.clone()) {
+                bail!("Sample '{}' appears in more than one table", s);
+            }
+            sample_names.push(s);
+        }
+        for (t, row) in taxa.iter().zip(presence) {
+            let target = &mut presence_matrix[taxon_row[t]];
+            for (k, v) in row.into_iter().enumerate() {
+                target[offset + k] = v;
+            }
+        }
     }
 
     Ok((taxa_order, sample_names, presence_matrix))
 }
 
+/// Read a branch weights file: one `name<TAB>weight` pair per line, where
+/// name is a tip name or an internal node label. Lines starting with '#' are ignored.
+pub fn read_branch_weights(filename: &str) -> Result<HashMap<String, f64>> {
+    let f = File::open(filename)?;
+    let mut weights = HashMap::new();
+
+    for (line_no, line) in BufReader::new(f).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.split('\t');
+        let name = parts.next().unwrap_or_default().trim().to_string();
+        let weight: f64 = parts
+            .next()
+            .with_context(|| format!("Line {}: missing weight for '{}'", line_no + 1, name))?
+            .trim()
+            .parse()
+            .with_context(|| format!("Line {}: invalid weight for '{}'", line_no + 1, name))?;
+        if weight < 0.0 {
+            bail!("Line {}: negative weight for '{}'", line_no + 1, name);
+        }
+        weights.insert(name, weight);
+    }
+
+    Ok(weights)
+}
+
+/// Read 16S copy numbers: one `feature-id<TAB>copy-number` pair per line
+/// (PICRUSt2's marker prediction table works as is: a non-numeric first line
+/// is taken as its header). Lines starting with '#' are ignored.
+pub fn read_copy_numbers(filename: &str) -> Result<HashMap<String, f64>> {
+    let f = File::open(filename)?;
+    let mut copies = HashMap::new();
+
+    for (line_no, line) in BufReader::new(f).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.split('\t');
+        let name = parts.next().unwrap_or_default().trim().to_string();
+        let field = parts
+            .next()
+            .with_context(|| format!("Line {}: missing copy number for '{}'", line_no + 1, name))?
+            .trim();
+        let copy_number: f64 = match field.parse() {
+            Ok(value) => value,
+            Err(_) if copies.is_empty() && line_no == 0 => continue,
+            Err(_) => bail!("Line {}: invalid copy number for '{}'", line_no + 1, name),
+        };
+        if copy_number <= 0.0 {
+            bail!(
+                "Line {}: copy number of '{}' must be positive",
+                line_no + 1,
+                name
+            );
+        }
+        copies.insert(name, copy_number);
+    }
+
+    Ok(copies)
+}
+
+/// Read an ID map: one `feature-id<TAB>tip-name` pair per line. Several
+/// features may map to the same tip; one feature mapped to two different
+/// tips is an error. Lines starting with '#' are ignored.
+pub fn read_id_map(filename: &str) -> Result<HashMap<String, String>> {
+    let f = File::open(filename)?;
+    let mut map: HashMap<String, String> = HashMap::new();
+
+    for (line_no, line) in BufReader::new(f).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.split('\t');
+        let id = parts.next().unwrap_or_default().trim().to_string();
+        let tip = parts
+            .next()
+            .with_context(|| format!("Line {}: missing tip name for '{}'", line_no + 1, id))?
+            .trim()
+            .to_string();
+        if let Some(previous) = map.get(&id) {
+            if *previous != tip {
+                bail!(
+                    "Line {}: '{}' is already mapped to '{}'",
+                    line_no + 1,
+                    id,
+                    previous
+                );
+            }
+        }
+        map.insert(id, tip);
+    }
+
+    Ok(map)
+}
+
+/// Read (ID, sequence) records from a FASTA file; the ID is the header up
+/// to the first whitespace, and sequences may span several lines
+pub fn read_fasta(filename: &str) -> Result<Vec<(String, String)>> {
+    let f = File::open(filename)?;
+    let mut records: Vec<(String, String)> = Vec::new();
+    for (line_no, line) in BufReader::new(f).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('>') {
+            let id = header.split_whitespace().next().unwrap_or_default();
+            if id.is_empty() {
+                bail!("Line {}: record without an ID", line_no + 1);
+            }
+            records.push((id.to_string(), String::new()));
+        } else {
+            let (_, sequence) = records
+                .last_mut()
+                .with_context(|| format!("Line {}: sequence before any '>' header", line_no + 1))?;
+            sequence.push_str(line);
+        }
+    }
+    Ok(records)
+}
+
+/// Read a list of feature IDs, one per line (only the first tab-separated
+/// field counts, so a two-column feature/count file also works). Reads
+/// stdin when `filename` is "-". Lines starting with '#' are ignored.
+pub fn read_feature_list(filename: &str) -> Result<Vec<String>> {
+    let reader: Box<dyn BufRead> = if filename == "-" {
+        Box::new(BufReader::new(std::io::stdin()))
+    } else {
+        Box::new(BufReader::new(File::open(filename)?))
+    };
+
+    let mut features = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        features.push(
+            line.split('\t')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_string(),
+        );
+    }
+    Ok(features)
+}
+
 /// Write the resulting matrix to a file
 pub fn write_matrix(
     sample_names: &[String],
     dist_matrix: &[f64],
     n: usize,
     output_file: &str,
+    force: bool,
 ) -> Result<()> {
-    let mut file = File::create(output_file)?;
+    let mut file = OutputFile::create(output_file, force)?;
     // Write header
     // The first column header is sample names as well
     write!(file, "Sample")?;
@@ -71,5 +756,812 @@ pub fn write_matrix(
         writeln!(file)?;
     }
 
-    Ok(())
+    file.commit()
+}
+
+/// Write an n_rows x n_cols matrix with row and column sample names, e.g. from
+/// [`crate::compute::compute_cross_matrix`]
+pub fn write_cross_matrix(
+    row_names: &[String],
+    col_names: &[String],
+    dist_matrix: &[f64],
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let mut file = OutputFile::create(output_file, force)?;
+    write!(file, "Sample")?;
+    for sn in col_names {
+        write!(file, "\t{}", sn)?;
+    }
+    writeln!(file)?;
+
+    for (row, sn) in dist_matrix.chunks(col_names.len().max(1)).zip(row_names) {
+        write!(file, "{}", sn)?;
+        for d in row {
+            write!(file, "\t{:.6}", d)?;
+        }
+        writeln!(file)?;
+    }
+
+    file.commit()
+}
+
+/// Write one line of distance statistics per sample
+pub fn write_sample_summaries(
+    sample_names: &[String],
+    summaries: &[DistanceSummary],
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let mut file = OutputFile::create(output_file, force)?;
+    writeln!(file, "sample\tn\tmean\tmin\tq1\tmedian\tq3\tmax")?;
+    for (name, s) in sample_names.iter().zip(summaries.iter()) {
+        writeln!(
+            file,
+            "{}\t{}\t{:.6}\t{:.6}\t{:.6}\t{:.6}\t{:.6}\t{:.6}",
+            name, s.n, s.mean, s.min, s.q1, s.median, s.q3, s.max
+        )?;
+    }
+    file.commit()
+}
+
+/// Write the pairs (i < j) as `sample_a<TAB>sample_b<TAB>distance` lines,
+/// sorted by distance (descending if `descending`), keeping only the first
+/// `top` if given. Undefined distances are listed last.
+pub fn write_sorted_pairs(
+    sample_names: &[String],
+    dist_matrix: &[f64],
+    n: usize,
+    descending: bool,
+    top: Option<usize>,
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let mut pairs: Vec<(usize, usize)> = (0..n)
+        .flat_map(|i| (i + 1..n).map(move |j| (i, j)))
+        .collect();
+    let d = |&(i, j): &(usize, usize)| dist_matrix[i * n + j];
+    pairs.sort_by(|a, b| {
+        let (da, db) = (d(a), d(b));
+        da.is_nan().cmp(&db.is_nan()).then(if descending {
+            db.total_cmp(&da)
+        } else {
+            da.total_cmp(&db)
+        })
+    });
+    pairs.truncate(top.unwrap_or(pairs.len()));
+
+    let mut file = OutputFile::create(output_file, force)?;
+    writeln!(file, "sample_a\tsample_b\tdistance")?;
+    for p in &pairs {
+        writeln!(
+            file,
+            "{}\t{}\t{:.6}",
+            sample_names[p.0],
+            sample_names[p.1],
+            d(p)
+        )?;
+    }
+    file.commit()
+}
+
+/// Write the condensed upper triangle (pairs (0,1), (0,2), ..., (1,2), ...; the
+/// order `scipy.spatial.distance.squareform` expects), one value per line, and
+/// the sample IDs one per line to `<output_file>.ids`
+pub fn write_condensed(
+    sample_names: &[String],
+    dist_matrix: &[f64],
+    n: usize,
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let pairs = (0..n).flat_map(|i| (i + 1..n).map(move |j| dist_matrix[i * n + j]));
+    write_condensed_pairs(sample_names, pairs, output_file, force)
+}
+
+/// [`write_condensed`] with the distances taken from `distances` in
+/// condensed order as they are written, so the matrix is never held
+pub fn write_condensed_pairs(
+    sample_names: &[String],
+    distances: impl Iterator<Item = f64>,
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    // Both files are in place or neither is
+    let mut file = OutputFile::create(output_file, force)?;
+    let mut ids = OutputFile::create(&format!("{}.ids", output_file), force)?;
+    for d in distances {
+        writeln!(file, "{:.6}", d)?;
+    }
+    for sn in sample_names {
+        writeln!(ids, "{}", sn)?;
+    }
+    file.commit()?;
+    ids.commit()
+}
+
+/// Write the samples' branch sets as a Matrix Market pattern matrix, samples
+/// x branches with column k for node id k - 1, with the sample names in
+/// OUTPUT.samples and the branch labels in OUTPUT.branches
+pub fn write_presence_mtx(
+    sample_names: &[String],
+    branch_labels: &[String],
+    branches: &[Vec<usize>],
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let nnz: usize = branches.iter().map(|b| b.len()).sum();
+    let mut file = OutputFile::create(output_file, force)?;
+    let mut samples = OutputFile::create(&format!("{}.samples", output_file), force)?;
+    let mut labels = OutputFile::create(&format!("{}.branches", output_file), force)?;
+    writeln!(file, "%%MatrixMarket matrix coordinate pattern general")?;
+    writeln!(
+        file,
+        "{} {} {}",
+        sample_names.len(),
+        branch_labels.len(),
+        nnz
+    )?;
+    for (s, row) in branches.iter().enumerate() {
+        for b in row {
+            writeln!(file, "{} {}", s + 1, b + 1)?;
+        }
+    }
+    for sn in sample_names {
+        writeln!(samples, "{}", sn)?;
+    }
+    for label in branch_labels {
+        writeln!(labels, "{}", label)?;
+    }
+    file.commit()?;
+    samples.commit()?;
+    labels.commit()
+}
+
+/// Read a square distance matrix: the TSV layout of [`write_matrix`], or
+/// PHYLIP (a sample count line, then one row per sample, square or lower
+/// triangle), told apart by the first line; .h5/.hdf5 files are read as
+/// HDF5 (feature `hdf5`)
+pub fn read_matrix(filename: &str) -> Result<(Vec<String>, Vec<f64>)> {
+    let lower = filename.to_lowercase();
+    if lower.ends_with(".h5") || lower.ends_with(".hdf5") {
+        #[cfg(feature = "hdf5")]
+        return read_hdf5_matrix(filename);
+        #[cfg(not(feature = "hdf5"))]
+        bail!(
+            "{}: HDF5 matrices need a build with --features hdf5; or export the matrix as TSV or PHYLIP",
+            filename
+        );
+    }
+    let mut first = String::new();
+    BufReader::new(File::open(filename)?).read_line(&mut first)?;
+    if first.trim().parse::<usize>().is_ok() {
+        read_phylip_matrix(filename)
+    } else {
+        read_tsv_matrix(filename)
+    }
+}
+
+/// HDF5 distance matrix as written by the C++ unifrac (`ssu --format hdf5`):
+/// the sample IDs in the `order` dataset and the n x n distances in `matrix`
+#[cfg(feature = "hdf5")]
+fn read_hdf5_matrix(filename: &str) -> Result<(Vec<String>, Vec<f64>)> {
+    use hdf5::types::{VarLenAscii, VarLenUnicode};
+
+    let file =
+        hdf5::File::open(filename).with_context(|| format!("Failed to open {}", filename))?;
+    let order = file
+        .dataset("order")
+        .with_context(|| format!("{}: no 'order' dataset of sample IDs", filename))?;
+    let sample_names: Vec<String> = match order.read_raw::<VarLenUnicode>() {
+        Ok(names) => names.iter().map(|n| n.as_str().to_string()).collect(),
+        Err(_) => order
+            .read_raw::<VarLenAscii>()
+            .with_context(|| format!("{}: 'order' does not hold strings", filename))?
+            .iter()
+            .map(|n| n.as_str().to_string())
+            .collect(),
+    };
+    let matrix = file
+        .dataset("matrix")
+        .with_context(|| format!("{}: no 'matrix' dataset", filename))?;
+    let n = sample_names.len();
+    if matrix.shape() != [n, n] {
+        bail!(
+            "{}: 'matrix' is {:?} for {} samples in 'order'",
+            filename,
+            matrix.shape(),
+            n
+        );
+    }
+    let dist_matrix = matrix
+        .read_raw::<f64>()
+        .with_context(|| format!("{}: 'matrix' does not hold numbers", filename))?;
+    Ok((sample_names, dist_matrix))
+}
+
+/// PHYLIP distance matrix; each row's values may be the full row or only
+/// those left of the diagonal
+fn read_phylip_matrix(filename: &str) -> Result<(Vec<String>, Vec<f64>)> {
+    let f = File::open(filename)?;
+    let mut lines = BufReader::new(f)
+        .lines()
+        .filter(|l| l.as_ref().map_or(true, |l| !l.trim().is_empty()));
+
+    let n: usize = lines
+        .next()
+        .context("No sample count in matrix")??
+        .trim()
+        .parse()?;
+    let mut sample_names = Vec::with_capacity(n);
+    let mut dist_matrix = vec![0.0; n * n];
+    for i in 0..n {
+        let line = lines
+            .next()
+            .with_context(|| format!("{}: {} rows for {} samples", filename, i, n))??;
+        let mut parts = line.split_whitespace();
+        let name = parts.next().unwrap_or_default().to_string();
+        let values = parts
+            .map(|x| x.parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("{}: invalid distance in row '{}'", filename, name))?;
+        if values.len() == n {
+            dist_matrix[i * n..(i + 1) * n].copy_from_slice(&values);
+        } else if values.len() == i {
+            for (j, v) in values.into_iter().enumerate() {
+                dist_matrix[i * n + j] = v;
+                dist_matrix[j * n + i] = v;
+            }
+        } else {
+            bail!(
+                "{}: row '{}' has {} values, expected {} (square) or {} (lower triangle)",
+                filename,
+                name,
+                values.len(),
+                n,
+                i
+            );
+        }
+        sample_names.push(name);
+    }
+
+    Ok((sample_names, dist_matrix))
+}
+
+/// Square matrix as written by [`write_matrix`]
+fn read_tsv_matrix(filename: &str) -> Result<(Vec<String>, Vec<f64>)> {
+    let f = File::open(filename)?;
+    let mut lines = BufReader::new(f).lines();
+
+    let header = lines.next().context("No header in matrix")??;
+    let mut hdr_split = header.split('\t');
+    hdr_split.next(); // ignore the corner cell
+    let sample_names: Vec<String> = hdr_split.map(|s| s.to_string()).collect();
+    let n = sample_names.len();
+
+    let mut dist_matrix = Vec::with_capacity(n * n);
+    let mut row = 0;
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut parts = line.split('\t');
+        let name = parts.next().unwrap_or_default();
+        if row >= n || name != sample_names[row] {
+            bail!(
+                "{}: row {} is '{}', expected rows in header order",
+                filename,
+                row + 1,
+                name
+            );
+        }
+        let values = parts
+            .map(|x| x.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("{}: invalid distance in row '{}'", filename, name))?;
+        if values.len() != n {
+            bail!(
+                "{}: row '{}' has {} values, expected {}",
+                filename,
+                name,
+                values.len(),
+                n
+            );
+        }
+        dist_matrix.extend(values);
+        row += 1;
+    }
+    if row != n {
+        bail!("{}: {} rows for {} samples", filename, row, n);
+    }
+
+    Ok((sample_names, dist_matrix))
+}
+
+/// Write PCoA sample coordinates, preceded by eigenvalue and
+/// proportion-explained comment lines
+pub fn write_pcoa(
+    sample_names: &[String],
+    pcoa: &Pcoa,
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let mut file = OutputFile::create(output_file, force)?;
+    let n_axes = pcoa.eigenvalues.len();
+
+    write!(file, "# Eigenvalues")?;
+    for v in &pcoa.eigenvalues {
+        write!(file, "\t{:.6}", v)?;
+    }
+    writeln!(file)?;
+    write!(file, "# Proportion explained")?;
+    for v in &pcoa.proportion_explained {
+        write!(file, "\t{:.6}", v)?;
+    }
+    writeln!(file)?;
+
+    write!(file, "Sample")?;
+    for k in 0..n_axes {
+        write!(file, "\tPC{}", k + 1)?;
+    }
+    writeln!(file)?;
+
+    for (s, sn) in sample_names.iter().enumerate() {
+        write!(file, "{}", sn)?;
+        for k in 0..n_axes {
+            write!(file, "\t{:.6}", pcoa.coordinates[(s, k)])?;
+        }
+        writeln!(file)?;
+    }
+
+    file.commit()
+}
+
+/// Write longitudinal pairs as a tidy TSV table, one pair per line
+pub fn write_longitudinal(
+    pairs: &[LongitudinalPair],
+    sample_names: &[String],
+    subjects: &[String],
+    timepoints: &[String],
+    dist_matrix: &[f64],
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let n = sample_names.len();
+    let mut file = OutputFile::create(output_file, force)?;
+    writeln!(
+        file,
+        "comparison\tsubject_a\tsubject_b\ttimepoint_a\ttimepoint_b\tsample_a\tsample_b\tdistance"
+    )?;
+    for p in pairs {
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.6}",
+            p.comparison,
+            subjects[p.i],
+            subjects[p.j],
+            timepoints[p.i],
+            timepoints[p.j],
+            sample_names[p.i],
+            sample_names[p.j],
+            dist_matrix[p.i * n + p.j]
+        )?;
+    }
+    file.commit()
+}
+
+/// Write PERMANOVA results as a TSV table, one test per line
+pub fn write_permanova(tests: &[PermanovaTest], output_file: &str, force: bool) -> Result<()> {
+    let mut file = OutputFile::create(output_file, force)?;
+    writeln!(file, "group_a\tgroup_b\tn\tpseudo_f\tp_value\tq_value")?;
+    for t in tests {
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{:.6}\t{:.6}\t{:.6}",
+            t.group_a, t.group_b, t.n, t.f_statistic, t.p_value, t.q_value
+        )?;
+    }
+    file.commit()
+}
+
+/// Write the best BIOENV subset of each size, one per line
+pub fn write_bioenv(
+    subsets: &[BioenvSubset],
+    variable_names: &[String],
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let mut file = OutputFile::create(output_file, force)?;
+    writeln!(file, "size\tcorrelation\tvariables")?;
+    for subset in subsets {
+        let names: Vec<&str> = subset
+            .variables
+            .iter()
+            .map(|v| variable_names[*v].as_str())
+            .collect();
+        writeln!(
+            file,
+            "{}\t{:.6}\t{}",
+            names.len(),
+            subset.correlation,
+            names.join(",")
+        )?;
+    }
+    file.commit()
+}
+
+/// Write edge loadings, one branch per line and one column per axis
+pub fn write_loadings(
+    branch_labels: &[String],
+    loadings: &Array2<f64>,
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let mut file = OutputFile::create(output_file, force)?;
+    write!(file, "branch")?;
+    for k in 0..loadings.ncols() {
+        write!(file, "\tPC{}", k + 1)?;
+    }
+    writeln!(file)?;
+    for (label, row) in branch_labels.iter().zip(loadings.rows()) {
+        write!(file, "{}", label)?;
+        for v in row {
+            write!(file, "\t{:.6}", v)?;
+        }
+        writeln!(file)?;
+    }
+    file.commit()
+}
+
+/// Write db-RDA biplot scores, one design column per line and one column
+/// per constrained axis
+pub fn write_biplot(
+    terms: &[String],
+    biplot: &Array2<f64>,
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let mut file = OutputFile::create(output_file, force)?;
+    write!(file, "term")?;
+    for k in 0..biplot.ncols() {
+        write!(file, "\tCAP{}", k + 1)?;
+    }
+    writeln!(file)?;
+    for (term, row) in terms.iter().zip(biplot.rows()) {
+        write!(file, "{}", term)?;
+        for v in row {
+            write!(file, "\t{:.6}", v)?;
+        }
+        writeln!(file)?;
+    }
+    file.commit()
+}
+
+/// Write per-sample balances, one sample per line and one column per balance
+pub fn write_balances(
+    sample_names: &[String],
+    balance_labels: &[String],
+    values: &Array2<f64>,
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let mut file = OutputFile::create(output_file, force)?;
+    write!(file, "Sample")?;
+    for label in balance_labels {
+        write!(file, "\t{}", label)?;
+    }
+    writeln!(file)?;
+    for (sn, row) in sample_names.iter().zip(values.rows()) {
+        write!(file, "{}", sn)?;
+        for v in row {
+            write!(file, "\t{:.6}", v)?;
+        }
+        writeln!(file)?;
+    }
+    file.commit()
+}
+
+/// Write each matrix's DISTATIS weight and its RV coefficients with the
+/// others, one matrix per line
+pub fn write_distatis_weights(
+    labels: &[String],
+    weights: &[f64],
+    rv: &[f64],
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let k = labels.len();
+    let mut file = OutputFile::create(output_file, force)?;
+    write!(file, "matrix\tweight")?;
+    for label in labels {
+        write!(file, "\tRV_{}", label)?;
+    }
+    writeln!(file)?;
+    for (t, label) in labels.iter().enumerate() {
+        write!(file, "{}\t{:.6}", label, weights[t])?;
+        for v in &rv[t * k..(t + 1) * k] {
+            write!(file, "\t{:.6}", v)?;
+        }
+        writeln!(file)?;
+    }
+    file.commit()
+}
+
+/// Write per-matrix (partial) sample scores, one matrix and sample per line
+pub fn write_partial_scores(
+    labels: &[String],
+    sample_names: &[String],
+    partial: &[Array2<f64>],
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let n_axes = partial.first().map(|p| p.ncols()).unwrap_or(0);
+    let mut file = OutputFile::create(output_file, force)?;
+    write!(file, "matrix\tSample")?;
+    for k in 0..n_axes {
+        write!(file, "\tPC{}", k + 1)?;
+    }
+    writeln!(file)?;
+    for (label, scores) in labels.iter().zip(partial) {
+        for (sn, row) in sample_names.iter().zip(scores.rows()) {
+            write!(file, "{}\t{}", label, sn)?;
+            for v in row {
+                write!(file, "\t{:.6}", v)?;
+            }
+            writeln!(file)?;
+        }
+    }
+    file.commit()
+}
+
+/// Write within/between group distance summaries as a TSV table
+pub fn write_group_comparisons(
+    comparisons: &[GroupComparison],
+    pooled: (&DistanceSummary, &DistanceSummary),
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let mut file = OutputFile::create(output_file, force)?;
+    writeln!(
+        file,
+        "comparison\tgroup_a\tgroup_b\tn_pairs\tmean\tsd\tmin\tmedian\tmax"
+    )?;
+
+    let mut write_row = |kind: &str, a: &str, b: &str, s: &DistanceSummary| -> Result<()> {
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{}\t{:.6}\t{:.6}\t{:.6}\t{:.6}\t{:.6}",
+            kind, a, b, s.n, s.mean, s.sd, s.min, s.median, s.max
+        )?;
+        Ok(())
+    };
+    write_row("within", "*", "*", pooled.0)?;
+    write_row("between", "*", "*", pooled.1)?;
+    for c in comparisons {
+        let kind = if c.group_a == c.group_b {
+            "within"
+        } else {
+            "between"
+        };
+        write_row(kind, &c.group_a, &c.group_b, &c.summary)?;
+    }
+
+    file.commit()
+}
+
+/// Write per-pair computation times (nanoseconds) with the pair's union size
+pub fn write_pair_timings(
+    sample_names: &[String],
+    timings: &[PairTiming],
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let mut file = OutputFile::create(output_file, force)?;
+    writeln!(file, "sample_a\tsample_b\tunion_branches\tnanos")?;
+    for t in timings {
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{}",
+            sample_names[t.i], sample_names[t.j], t.union_branches, t.nanos
+        )?;
+    }
+    file.commit()
+}
+
+/// Write per-pair distances with their shared and per-sample branch counts
+pub fn write_pair_stats(
+    sample_names: &[String],
+    stats: &[PairStats],
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let mut file = OutputFile::create(output_file, force)?;
+    writeln!(
+        file,
+        "sample_a\tsample_b\tdistance\tshared_branches\tbranches_a\tbranches_b"
+    )?;
+    for p in stats {
+        writeln!(
+            file,
+            "{}\t{}\t{:.6}\t{}\t{}\t{}",
+            sample_names[p.i],
+            sample_names[p.j],
+            p.distance,
+            p.shared_branches,
+            p.branches_i,
+            p.branches_j
+        )?;
+    }
+    file.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `contents` to a file of its own in the temp directory
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("unifrac-io-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn read(name: &str, contents: &str) -> Result<(Vec<String>, Vec<String>, Vec<Vec<f64>>)> {
+        let path = temp_file(name, contents);
+        let table = read_count_table(&path.to_string_lossy());
+        fs::remove_file(path).unwrap();
+        table
+    }
+
+    fn strings(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn reads_plain_table() {
+        let (taxa, samples, counts) =
+            read("plain.tsv", "ID\tS1\tS2\nA\t1\t0\nB\t0\t2.5\n").unwrap();
+        assert_eq!(taxa, strings(&["A", "B"]));
+        assert_eq!(samples, strings(&["S1", "S2"]));
+        assert_eq!(counts, vec![vec![1.0, 0.0], vec![0.0, 2.5]]);
+    }
+
+    #[test]
+    fn tolerates_spreadsheet_exports() {
+        // BOM, CRLF, quoted fields with a tab and an escaped quote, trailing
+        // empty columns, blank lines and a short row
+        let contents = "\u{feff}ID\t\"S 1\"\t\"S\t2\"\t\t\r\n\
+                        \"A \"\"x\"\"\"\t3\t1\t\t\r\n\
+                        \r\n\
+                        B\t 2 \r\n";
+        let (taxa, samples, counts) = read("spreadsheet.tsv", contents).unwrap();
+        assert_eq!(taxa, strings(&["A \"x\"", "B"]));
+        assert_eq!(samples, strings(&["S 1", "S\t2"]));
+        assert_eq!(counts, vec![vec![3.0, 1.0], vec![2.0, 0.0]]);
+    }
+
+    #[test]
+    fn reports_structural_errors_with_position() {
+        let err = read("extra.tsv", "ID\tS1\nA\t1\t2\n").unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("line 2, column 3"),
+            "{:#}",
+            err
+        );
+        let err = read("unterminated.tsv", "ID\tS1\n\"A\t1\n").unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("unterminated quoted field"),
+            "{:#}",
+            err
+        );
+        let err = read("no-samples.tsv", "ID\n").unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("header names no samples"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn coerces_or_rejects_non_numbers() {
+        let path = temp_file("coerce.tsv", "ID\tS1\tS2\nA\tx\t1\nB\tNA\t\n");
+        let (_, _, counts, coerced) =
+            read_count_table_with(&path.to_string_lossy(), false).unwrap();
+        assert_eq!(counts, vec![vec![0.0, 1.0], vec![0.0, 0.0]]);
+        assert_eq!(coerced, vec![2, 0]);
+        let err = read_count_table_with(&path.to_string_lossy(), true).unwrap_err();
+        fs::remove_file(path).unwrap();
+        assert!(
+            format!("{:#}", err).contains("line 2, column 2: 'x' is not a number"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn reads_qiime_classic_table() {
+        let contents = "# Constructed from biom file\n\
+                        #OTU ID\tS1\tS2\ttaxonomy\n\
+                        A\t5\t0\tk__Bacteria; p__Firmicutes\n\
+                        B\t0\t1\tk__Bacteria\n";
+        let (taxa, samples, counts) = read("classic.tsv", contents).unwrap();
+        assert_eq!(taxa, strings(&["A", "B"]));
+        assert_eq!(samples, strings(&["S1", "S2"]));
+        assert_eq!(counts, vec![vec![5.0, 0.0], vec![0.0, 1.0]]);
+    }
+
+    #[test]
+    fn hash_headers_are_not_comments() {
+        assert!(is_comment_line("# Constructed from biom file"));
+        assert!(is_comment_line("#comment"));
+        assert!(is_comment_line("# a\tb"));
+        assert!(is_comment_line("  "));
+        assert!(!is_comment_line("#OTU ID\tS1"));
+        assert!(!is_comment_line("#\tS1"));
+        assert!(!is_comment_line("ID\tS1"));
+    }
+
+    #[test]
+    fn binarizes_counts() {
+        let counts = vec![vec![0.0, 3.0, -1.0], vec![0.5, 0.0, 0.0]];
+        assert_eq!(
+            binarize(counts),
+            vec![vec![0.0, 1.0, 0.0], vec![1.0, 0.0, 0.0]]
+        );
+    }
+
+    const SHARED: &str = "label\tGroup\tnumOtus\tOtu1\tOtu2\tOtu3\n\
+                          0.03\tA\t3\t10\t0\t1\n\
+                          0.03\tB\t3\t0\t4\t0\n\
+                          0.05\tA\t3\t10\t1\t0\n";
+
+    #[test]
+    fn reads_first_mothur_label() {
+        let path = temp_file("first.shared", SHARED);
+        let (taxa, samples, counts) = read_mothur_counts(&path.to_string_lossy(), None).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(taxa, strings(&["Otu1", "Otu2", "Otu3"]));
+        assert_eq!(samples, strings(&["A", "B"]));
+        assert_eq!(
+            counts,
+            vec![vec![10.0, 0.0], vec![0.0, 4.0], vec![1.0, 0.0]]
+        );
+    }
+
+    #[test]
+    fn reads_chosen_mothur_label() {
+        let path = temp_file("label.shared", SHARED);
+        let filename = path.to_string_lossy().to_string();
+        let (_, samples, presence) = read_mothur_shared(&filename, Some("0.05")).unwrap();
+        let missing = read_mothur_shared(&filename, Some("0.10")).unwrap_err();
+        fs::remove_file(path).unwrap();
+        assert_eq!(samples, strings(&["A"]));
+        assert_eq!(presence, vec![vec![1.0], vec![1.0], vec![0.0]]);
+        assert!(
+            format!("{:#}", missing).contains("no rows with label '0.10'"),
+            "{:#}",
+            missing
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_mothur_files() {
+        let path = temp_file("header.shared", "ID\tS1\nA\t1\n");
+        let err = read_mothur_counts(&path.to_string_lossy(), None).unwrap_err();
+        fs::remove_file(path).unwrap();
+        assert!(format!("{:#}", err).contains("line 1"), "{:#}", err);
+
+        let path = temp_file(
+            "columns.shared",
+            "label\tGroup\tnumOtus\tOtu1\tOtu2\n0.03\tA\t2\t1\n",
+        );
+        let err = read_mothur_counts(&path.to_string_lossy(), None).unwrap_err();
+        fs::remove_file(path).unwrap();
+        assert!(
+            format!("{:#}", err).contains("line 2: 4 columns, expected 5"),
+            "{:#}",
+            err
+        );
+    }
 }
@@ -0,0 +1,208 @@
+//! Hot inner loops with runtime CPU feature dispatch.
+//!
+//! The instruction set is detected once per process, so a single binary built
+//! for a generic target still uses AVX-512, AVX2 or NEON where the node has it.
+
+use std::sync::OnceLock;
+
+/// Instruction set used by the kernels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuLevel {
+    Avx512,
+    Avx2,
+    Neon,
+    Scalar,
+}
+
+impl CpuLevel {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CpuLevel::Avx512 => "avx512",
+            CpuLevel::Avx2 => "avx2",
+            CpuLevel::Neon => "neon",
+            CpuLevel::Scalar => "scalar",
+        }
+    }
+}
+
+static LEVEL: OnceLock<CpuLevel> = OnceLock::new();
+
+/// Best instruction set available on this CPU
+pub fn cpu_level() -> CpuLevel {
+    *LEVEL.get_or_init(detect)
+}
+
+#[allow(unreachable_code)]
+fn detect() -> CpuLevel {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx512f") && is_x86_feature_detected!("popcnt") {
+            return CpuLevel::Avx512;
+        }
+        if is_x86_feature_detected!("avx2")
+            && is_x86_feature_detected!("fma")
+            && is_x86_feature_detected!("popcnt")
+        {
+            return CpuLevel::Avx2;
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            return CpuLevel::Neon;
+        }
+    }
+    CpuLevel::Scalar
+}
+
+/// Sum of p_a * p_b * brlens over all branches
+pub fn weighted_shared_sum(p_a: &[f64], p_b: &[f64], brlens: &[f64]) -> f64 {
+    assert!(p_a.len() == p_b.len() && p_a.len() == brlens.len());
+    match cpu_level() {
+        // Safety: the level is only selected when the CPU supports the features
+        #[cfg(target_arch = "x86_64")]
+        CpuLevel::Avx512 => unsafe { x86::weighted_shared_sum_avx512(p_a, p_b, brlens) },
+        #[cfg(target_arch = "x86_64")]
+        CpuLevel::Avx2 => unsafe { x86::weighted_shared_sum_avx2(p_a, p_b, brlens) },
+        #[cfg(target_arch = "aarch64")]
+        CpuLevel::Neon => unsafe { arm::weighted_shared_sum_neon(p_a, p_b, brlens) },
+        _ => weighted_shared_sum_scalar(p_a, p_b, brlens),
+    }
+}
+
+/// Number of bits set in both `a` and `b`
+pub fn popcount_and(a: &[u64], b: &[u64]) -> u64 {
+    assert_eq!(a.len(), b.len());
+    match cpu_level() {
+        // Safety: the level is only selected when the CPU supports the features
+        #[cfg(target_arch = "x86_64")]
+        CpuLevel::Avx512 | CpuLevel::Avx2 => unsafe { x86::popcount_and_popcnt(a, b) },
+        #[cfg(target_arch = "aarch64")]
+        CpuLevel::Neon => unsafe { arm::popcount_and_neon(a, b) },
+        _ => popcount_and_scalar(a, b),
+    }
+}
+
+pub fn weighted_shared_sum_scalar(p_a: &[f64], p_b: &[f64], brlens: &[f64]) -> f64 {
+    p_a.iter()
+        .zip(p_b.iter())
+        .zip(brlens.iter())
+        .map(|((a, b), l)| a * b * l)
+        .sum()
+}
+
+pub fn popcount_and_scalar(a: &[u64], b: &[u64]) -> u64 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(x, y)| (x & y).count_ones() as u64)
+        .sum()
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "avx512f")]
+    pub unsafe fn weighted_shared_sum_avx512(p_a: &[f64], p_b: &[f64], brlens: &[f64]) -> f64 {
+        let n = p_a.len();
+        let mut acc = _mm512_setzero_pd();
+        let mut k = 0;
+        while k + 8 <= n {
+            let a = _mm512_loadu_pd(p_a.as_ptr().add(k));
+            let b = _mm512_loadu_pd(p_b.as_ptr().add(k));
+            let l = _mm512_loadu_pd(brlens.as_ptr().add(k));
+            acc = _mm512_fmadd_pd(_mm512_mul_pd(a, b), l, acc);
+            k += 8;
+        }
+        let mut sum = _mm512_reduce_add_pd(acc);
+        while k < n {
+            sum += p_a[k] * p_b[k] * brlens[k];
+            k += 1;
+        }
+        sum
+    }
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn weighted_shared_sum_avx2(p_a: &[f64], p_b: &[f64], brlens: &[f64]) -> f64 {
+        let n = p_a.len();
+        let mut acc = _mm256_setzero_pd();
+        let mut k = 0;
+        while k + 4 <= n {
+            let a = _mm256_loadu_pd(p_a.as_ptr().add(k));
+            let b = _mm256_loadu_pd(p_b.as_ptr().add(k));
+            let l = _mm256_loadu_pd(brlens.as_ptr().add(k));
+            acc = _mm256_fmadd_pd(_mm256_mul_pd(a, b), l, acc);
+            k += 4;
+        }
+        let mut lanes = [0.0; 4];
+        _mm256_storeu_pd(lanes.as_mut_ptr(), acc);
+        let mut sum = lanes.iter().sum::<f64>();
+        while k < n {
+            sum += p_a[k] * p_b[k] * brlens[k];
+            k += 1;
+        }
+        sum
+    }
+
+    /// Hardware popcnt, four independent accumulators to keep the unit busy
+    #[target_feature(enable = "popcnt")]
+    pub unsafe fn popcount_and_popcnt(a: &[u64], b: &[u64]) -> u64 {
+        let n = a.len();
+        let mut acc = [0u64; 4];
+        let mut k = 0;
+        while k + 4 <= n {
+            for lane in 0..4 {
+                acc[lane] += _popcnt64((a[k + lane] & b[k + lane]) as i64) as u64;
+            }
+            k += 4;
+        }
+        let mut sum = acc.iter().sum::<u64>();
+        while k < n {
+            sum += _popcnt64((a[k] & b[k]) as i64) as u64;
+            k += 1;
+        }
+        sum
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arm {
+    use std::arch::aarch64::*;
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn weighted_shared_sum_neon(p_a: &[f64], p_b: &[f64], brlens: &[f64]) -> f64 {
+        let n = p_a.len();
+        let mut acc = vdupq_n_f64(0.0);
+        let mut k = 0;
+        while k + 2 <= n {
+            let a = vld1q_f64(p_a.as_ptr().add(k));
+            let b = vld1q_f64(p_b.as_ptr().add(k));
+            let l = vld1q_f64(brlens.as_ptr().add(k));
+            acc = vfmaq_f64(acc, vmulq_f64(a, b), l);
+            k += 2;
+        }
+        let mut sum = vaddvq_f64(acc);
+        while k < n {
+            sum += p_a[k] * p_b[k] * brlens[k];
+            k += 1;
+        }
+        sum
+    }
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn popcount_and_neon(a: &[u64], b: &[u64]) -> u64 {
+        let n = a.len();
+        let mut sum = 0u64;
+        let mut k = 0;
+        while k + 2 <= n {
+            let x = vandq_u64(vld1q_u64(a.as_ptr().add(k)), vld1q_u64(b.as_ptr().add(k)));
+            sum += vaddlvq_u8(vcntq_u8(vreinterpretq_u8_u64(x))) as u64;
+            k += 2;
+        }
+        while k < n {
+            sum += (a[k] & b[k]).count_ones() as u64;
+            k += 1;
+        }
+        sum
+    }
+}
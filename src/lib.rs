@@ -1,2 +1,52 @@
-pub mod io;
+#[cfg(feature = "blas")]
+extern crate blas_src;
+
+pub mod branches;
+pub mod cache;
+pub mod cluster;
 pub mod compute;
+#[cfg(any(feature = "sqlite", feature = "duckdb"))]
+pub mod database;
+#[cfg(feature = "polars")]
+pub mod dataframe;
+pub mod decontam;
+pub mod error;
+pub mod expected;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod hnsw;
+pub mod io;
+pub mod kernels;
+pub mod matrix;
+pub mod metadata;
+pub mod metrics;
+pub mod names;
+pub mod newick;
+pub mod npz;
+#[cfg(all(feature = "numa", target_os = "linux"))]
+pub mod numa;
+#[cfg(feature = "opencl")]
+pub mod opencl;
+pub mod ordination;
+pub mod philr;
+pub mod phylo;
+#[cfg(feature = "plots")]
+pub mod plot;
+pub mod provenance;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod qiime;
+#[cfg(feature = "arrow")]
+pub mod record_batch;
+pub mod reference;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod simulate;
+pub mod sketch;
+pub mod stats;
+pub mod summary;
+pub mod table;
+pub mod unrooted;
+pub mod verify;
+pub mod watch;
+pub mod weighted;
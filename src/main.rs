@@ -1,75 +1,4728 @@
-use anyhow::Result;
-use clap::{Arg, Command};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{parser::ValueSource, value_parser, Arg, ArgAction, ArgMatches, Command};
+use clap_complete::{generate, Shell};
+use log::{debug, error, info, warn, LevelFilter};
+use ndarray::Array2;
 use phylotree::tree::Tree;
-use std::path::Path;
-use itertools::Itertools;
+use rand::{rngs::StdRng, SeedableRng};
+use rayon::prelude::*;
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+#[cfg(any(feature = "sqlite", feature = "duckdb"))]
+use unifrac::database::{read_query_table, write_distances_db, RunRecord};
+#[cfg(feature = "plots")]
+use unifrac::plot::write_pcoa_html;
 use unifrac::{
-    compute::compute_unifrac_for_pair,
-    io::{read_sample_table, write_matrix},
+    branches::{apply_branch_weights, set_unit_lengths},
+    cache::{cache_key, load_cached, store_cached, CachedResult},
+    cluster::{dbscan, k_medoids},
+    compute::{
+        compute_cross_matrix, compute_unifrac_for_pair, compute_unifrac_groups,
+        compute_unifrac_matrix, compute_unifrac_matrix_timed, compute_unifrac_matrix_using,
+        landmark_distances, missing_branch_lengths, render_pair_subtree, Algorithm, BranchOptions,
+        BranchSets, Metric, MissingLength, PairStats, UniFracContext,
+    },
+    decontam::prevalence_scores,
+    error::{classify, messages, ErrorKind},
+    expected::{compute_expected_matrix, read_depths},
+    io::{
+        apply_abundance_floor, binarize, check_output, merge_sample_tables, read_branch_weights,
+        read_copy_numbers, read_count_table_with, read_fasta, read_feature_list, read_id_map,
+        read_matrix, read_mothur_counts, resolve_duplicates, write_balances, write_bioenv,
+        write_biplot, write_condensed, write_condensed_pairs, write_cross_matrix,
+        write_distatis_weights, write_group_comparisons, write_loadings, write_longitudinal,
+        write_matrix, write_pair_stats, write_pair_timings, write_partial_scores, write_pcoa,
+        write_permanova, write_presence_mtx, write_sample_summaries, write_sample_table,
+        write_sorted_pairs, Duplicates, OutputFile,
+    },
+    matrix::{average_matrices, reorder_matrix, submatrix},
+    metadata::{read_metadata, write_metadata, ColumnType, Metadata},
+    metrics::{compute_metrics, PairDistance},
+    names::{rename_features, sequence_hashes, IdRewrite, NameNormalization},
+    newick::read_newick_file,
+    npz::{write_b_npz, write_embeddings_npz, write_presence_npz},
+    ordination::{dbrda, distatis, edge_pca, landmark_pcoa, pcoa},
+    philr::philr,
+    phylo::{
+        branch_label, extract_clade, patristic_matrix, pruned_tree, rename_tips, rerooted_tree,
+        rootedness, subtree_newick, tip_names, Reroot, Rootedness,
+    },
+    provenance::{write_provenance, Provenance},
+    qiime::write_qza,
+    reference::{
+        read_index, read_reference, write_index, write_reference, ReferenceIndex, ReferenceSet,
+    },
+    simulate::{birth_death_tree, simulate_table},
+    sketch::{build_sketches, sketch_pairs},
+    stats::{
+        bioenv, check_grouping, estimate_distribution, group_comparisons, group_indices,
+        group_mean_distances, longitudinal_pairs, mantel, median_distances, pairwise_permanova,
+        permanova, permdisp, random_pairs, robust_z, sample_summaries, silhouette, summarize,
+        within_between, Correlation,
+    },
+    summary::{peak_rss_bytes, tree_stats, write_summary, MemoryReport, RunSummary},
+    unrooted::compute_unrooted_matrix_stats,
+    verify::{reference_distance, verify_pairs},
+    watch::{extend_matrix, plan_update, sample_fingerprints, Delivery, DirWatcher, Update},
+    weighted::{branch_embeddings, branch_proportions, Transform},
 };
 
-fn main() -> Result<()> {
-    // Initialize logger
-    println!("\n ************** initializing logger *****************\n");
-    env_logger::Builder::from_default_env().init();
-    let matches = Command::new("Unweighted_UniFrac")
-        .version("0.1.0")
+fn main() {
+    let matches = cli().get_matches();
+    if let Some(("completions", sub)) = matches.subcommand() {
+        // Completion scripts go to stdout, so nothing else may be printed
+        let shell = *sub.get_one::<Shell>("shell").unwrap();
+        generate(shell, &mut cli(), "unifrac", &mut std::io::stdout());
+        return;
+    }
+
+    // Logs go to stderr, so stdout keeps only results (e.g. the --pair distance)
+    let level = if matches.get_flag("quiet") {
+        Some(LevelFilter::Error)
+    } else {
+        match matches.get_count("verbose") {
+            0 => None,
+            1 => Some(LevelFilter::Debug),
+            _ => Some(LevelFilter::Trace),
+        }
+    };
+    init_logger(matches.get_one::<String>("log_format").unwrap(), level);
+    if let Some(&threads) = matches.get_one::<usize>("threads") {
+        if let Err(err) = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+        {
+            warn!("Could not use {} threads: {}", threads, err);
+        }
+    }
+
+    if let Err(err) = run(&matches) {
+        let kind = classify(&err);
+        let mut messages = messages(&err);
+        let message = if messages.is_empty() {
+            kind.map(|k| k.to_string()).unwrap_or_default()
+        } else {
+            messages.remove(0)
+        };
+        if matches.get_flag("json_errors") {
+            let report = json!({
+                "error": {
+                    "kind": kind.map(|k| k.as_str()).unwrap_or("error"),
+                    "description": kind.map(|k| k.to_string()),
+                    "message": message,
+                    "causes": messages,
+                },
+                "exit_code": kind.map(|k| k.exit_code()).unwrap_or(1),
+            });
+            eprintln!("{}", report);
+        } else if matches.get_one::<String>("log_format").unwrap() == "json" {
+            let mut line = message;
+            for cause in &messages {
+                line.push_str(": ");
+                line.push_str(cause);
+            }
+            match kind {
+                Some(kind) => error!("{} ({})", line, kind),
+                None => error!("{}", line),
+            }
+        } else {
+            match kind {
+                Some(kind) => eprintln!("Error ({}): {}", kind, message),
+                None => eprintln!("Error: {}", message),
+            }
+            if !messages.is_empty() {
+                eprintln!("\nCaused by:");
+                for (k, cause) in messages.iter().enumerate() {
+                    eprintln!("    {}: {}", k, cause);
+                }
+            }
+        }
+        std::process::exit(kind.map(|k| k.exit_code()).unwrap_or(1));
+    }
+}
+
+/// env_logger on stderr at `level` (from -v/-q) or else the UNIFRAC_LOG,
+/// then RUST_LOG, filter (info if neither is set), as text or as JSON lines
+/// with timestamp, level, target and message
+fn init_logger(format: &str, level: Option<LevelFilter>) {
+    let mut builder = match level {
+        Some(level) => {
+            let mut builder = env_logger::Builder::new();
+            builder.filter_level(level);
+            builder
+        }
+        None => {
+            let fallback = std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string());
+            env_logger::Builder::from_env(
+                env_logger::Env::default().filter_or("UNIFRAC_LOG", fallback),
+            )
+        }
+    };
+    if format == "json" {
+        builder.format(|buf, record| {
+            let line = json!({
+                "timestamp": buf.timestamp_millis().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
+/// --version output: everything needed to reproduce a build (set by build.rs)
+const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "\ncommit: ",
+    env!("UNIFRAC_GIT_COMMIT"),
+    "\nfeatures: ",
+    env!("UNIFRAC_FEATURES"),
+    "\ntarget: ",
+    env!("UNIFRAC_TARGET"),
+    "\ntarget CPU features: ",
+    env!("UNIFRAC_TARGET_FEATURES"),
+    "\nBLAS: ",
+    env!("UNIFRAC_BLAS"),
+);
+
+/// Command line definition
+fn cli() -> Command {
+    let cli = Command::new("Unweighted_UniFrac")
+        .version(env!("CARGO_PKG_VERSION"))
+        .long_version(LONG_VERSION)
         .about("Fast Unweighted UniFrac")
+        .subcommand_negates_reqs(true)
+        .arg(
+            Arg::new("json_errors")
+                .long("json-errors")
+                .help("Report errors as JSON on stderr; exit codes: 3 input not found, 4 parse failure, 5 input mismatch")
+                .global(true)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("log_format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .help("Log lines on stderr as plain text or as one JSON object per line")
+                .value_parser(["text", "json"])
+                .default_value("text")
+                .global(true),
+        )
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .help("More log output: -v for debug, -vv for trace; without -v/-q the level comes from UNIFRAC_LOG, then RUST_LOG (env_logger filters), then info")
+                .global(true)
+                .action(ArgAction::Count),
+        )
+        .arg(
+            Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .help("Log errors only")
+                .global(true)
+                .conflicts_with("verbose")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
-            Arg::new("tree")
-                .short('t')
-                .long("tree")
-                .value_name("TREE_FILE")
-                .help("Input newick format tree file")
-                .required(true),
+            Arg::new("threads")
+                .long("threads")
+                .value_name("N")
+                .help("Worker threads [default: one per CPU]")
+                .value_parser(value_parser!(usize).range(1..))
+                .env("UNIFRAC_THREADS")
+                .global(true),
         )
         .arg(
-            Arg::new("table")
-                .short('i')
-                .long("input")
-                .value_name("TABLE_FILE")
-                .help("Input tab-delimited sample-feature table")
-                .required(true),
+            Arg::new("force")
+                .long("force")
+                .help("Overwrite existing output files")
+                .global(true)
+                .action(ArgAction::SetTrue),
         )
+        .args(input_args())
+        .args(table_format_args())
+        .args(compute_args())
         .arg(
             Arg::new("output")
                 .short('o')
                 .long("output")
                 .value_name("OUTPUT_FILE")
                 .help("Output file for distance matrix")
-                .required(true),
+                .required_unless_present_any(["debug_pair", "pair", "estimate"]),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_name("FORMAT")
+                .help("Distance matrix layout: full square matrix, condensed upper triangle plus OUTPUT.ids, a QIIME 2 DistanceMatrix artifact (.qza), or a list of pairs sorted by distance")
+                .value_parser(["square", "condensed", "qza", "pairs"])
+                .default_value("square"),
+        )
+        .arg(
+            Arg::new("sort")
+                .long("sort")
+                .value_name("ORDER")
+                .help("Order of --format pairs: closest pairs first, or most distant first")
+                .value_parser(["ascending", "descending"])
+                .default_value("ascending"),
+        )
+        .arg(
+            Arg::new("top")
+                .long("top")
+                .value_name("N")
+                .help("With --format pairs, list only the first N pairs")
+                .value_parser(value_parser!(usize)),
+        )
+        .args(metric_args())
+        .arg(
+            Arg::new("algorithm")
+                .long("algorithm")
+                .value_name("ALGORITHM")
+                .help("How the matrix is computed: bit-packed branch sets, branch-major accumulation, dense matrix products (gemm), or OpenCL when built with the opencl feature")
+                .value_parser(Algorithm::names())
+                .default_value("bitset"),
+        )
+        .arg(
+            Arg::new("sketch")
+                .long("sketch")
+                .value_name("K")
+                .help("Estimate unweighted distances from K-slot minwise-hash sketches of each sample's branches, error ~0.5/sqrt(K), streamed to the output without holding the matrix (needs --format condensed)")
+                .value_parser(value_parser!(usize).range(1..))
+                .conflicts_with("shared_stats"),
+        )
+        .arg(
+            Arg::new("expected_depth")
+                .long("expected-depth")
+                .value_name("DEPTH")
+                .help("Approximate expected unweighted UniFrac with every sample rarefied to DEPTH reads: expected unique over expected union branch length, from hypergeometric probabilities instead of random subsampling; samples with fewer reads are left out")
+                .value_parser(value_parser!(u64).range(1..))
+                .conflicts_with_all(["sketch", "shared_stats", "profile_pairs", "verify"]),
+        )
+        .arg(
+            Arg::new("profile_pairs")
+                .long("profile-pairs")
+                .value_name("PROFILE_FILE")
+                .help("Time every pair of the unweighted computation (bitset algorithm) and write the times with each pair's union size")
+                .conflicts_with_all(["sketch", "shared_stats"]),
+        )
+        .arg(
+            Arg::new("shared_stats")
+                .long("shared-stats")
+                .value_name("STATS_FILE")
+                .help("Also write, per pair, the distance, shared branch count and each sample's branch count"),
+        )
+        .arg(
+            Arg::new("dump_b")
+                .long("dump-b")
+                .value_name("NPZ_FILE")
+                .help("Also write the branch x tip matrix B (sparse CSR), branch lengths and names as a NumPy .npz"),
+        )
+        .arg(
+            Arg::new("embeddings")
+                .long("embeddings")
+                .value_name("NPZ_FILE")
+                .help("Also write each sample's branch-length-weighted abundance over branches (samples x branches, sparse CSR, after --transform) as a NumPy .npz, for use as features"),
+        )
+        .arg(
+            Arg::new("presence")
+                .long("presence")
+                .value_name("FILE")
+                .help("Also write each sample's branch set (samples x branches, binary): a scipy.sparse .npz, or Matrix Market if FILE ends in .mtx"),
+        )
+        .arg(
+            Arg::new("sample_summary")
+                .long("sample-summary")
+                .help("Also write each sample's distance statistics (min, quartiles, max, mean) to OUTPUT.samples.tsv")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("cache_dir")
+                .long("cache-dir")
+                .value_name("DIR")
+                .help("Cache results in DIR by content hash of the inputs and options, and reuse them on reruns; nothing is cached without it")
+                .env("UNIFRAC_CACHE_DIR"),
+        )
+        .arg(
+            Arg::new("no_cache")
+                .long("no-cache")
+                .help("Always recompute, neither reading nor writing the result cache, even with --cache-dir or UNIFRAC_CACHE_DIR set")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("provenance")
+                .long("provenance")
+                .help("Also write a provenance record (inputs with their SHA-256, version, command line, options, start and end times, output hashes) to OUTPUT.provenance.json, and embed it in .qza output")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("run_summary")
+                .long("run-summary")
+                .help("Also write a JSON run summary (input hashes, counts, timing, options) to OUTPUT.summary.json")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("debug_pair")
+                .long("debug-pair")
+                .value_name("SAMPLE_A,SAMPLE_B")
+                .help("Print the branches above one pair's features with shared and unique branches marked, then exit"),
+        )
+        .arg(
+            Arg::new("pair")
+                .long("pair")
+                .num_args(2)
+                .value_names(["SAMPLE_A", "SAMPLE_B"])
+                .help("Print the distance of one pair to stdout, without computing the matrix")
+                .conflicts_with("debug_pair"),
+        )
+        .arg(
+            Arg::new("estimate")
+                .long("estimate")
+                .value_name("N_PAIRS")
+                .help("Print the mean and quantiles of the distances, with 95% confidence intervals, estimated from N random pairs instead of computing the matrix")
+                .value_parser(value_parser!(usize).range(2..))
+                .conflicts_with_all(["pair", "debug_pair"]),
+        )
+        .arg(
+            Arg::new("verify")
+                .long("verify")
+                .value_name("N_PAIRS")
+                .help("Recompute N random pairs with a slow reference implementation and fail if any differs by more than --verify-tolerance")
+                .value_parser(value_parser!(usize).range(1..))
+                .conflicts_with_all(["sketch", "estimate", "pair", "debug_pair"]),
+        )
+        .arg(
+            Arg::new("verify_tolerance")
+                .long("verify-tolerance")
+                .value_name("TOL")
+                .help("Largest accepted absolute difference for --verify")
+                .value_parser(value_parser!(f64))
+                .default_value("1e-9"),
+        )
+        .arg(
+            Arg::new("metadata")
+                .short('m')
+                .long("metadata")
+                .value_name("METADATA_FILE")
+                .help("Tab-delimited sample metadata file"),
+        )
+        .arg(
+            Arg::new("stratify_by")
+                .long("stratify-by")
+                .value_name("COLUMN")
+                .help("Write one distance matrix per value of this metadata column (OUTPUT.<group>.<ext>)")
+                .requires("metadata"),
+        )
+        .arg(
+            Arg::new("pool_by")
+                .long("pool-by")
+                .value_name("COLUMN")
+                .help("Pool the samples of each value of this metadata column into one and compute distances between the pools")
+                .requires("metadata")
+                .conflicts_with("stratify_by"),
+        )
+        .arg(
+            Arg::new("pool")
+                .long("pool")
+                .value_name("HOW")
+                .help("How --pool-by combines samples: sum their counts, or take the union of their features (each present feature counts 1)")
+                .value_parser(["sum", "union"])
+                .default_value("sum")
+                .requires("pool_by"),
+        )
+        .subcommand(
+            Command::new("pcoa")
+                .about("Principal coordinates analysis of the UniFrac distance matrix")
+                .args(input_args())
+                .args(table_format_args())
+                .args(compute_args())
+                .args(metric_args())
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT_FILE")
+                        .help("Output file for sample coordinates")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("axes")
+                        .long("axes")
+                        .value_name("N")
+                        .help("Number of principal axes to keep")
+                        .value_parser(value_parser!(usize))
+                        .default_value("3"),
+                )
+                .arg(
+                    Arg::new("landmarks")
+                        .long("landmarks")
+                        .value_name("M")
+                        .help("Landmark (Nyström) PCoA from distances to M farthest-point landmark samples only, without the full matrix (unweighted)")
+                        .value_parser(value_parser!(usize).range(2..)),
+                )
+                .arg(
+                    Arg::new("matrix")
+                        .long("matrix")
+                        .value_name("MATRIX_FILE")
+                        .help("Ordinate this existing distance matrix (TSV, PHYLIP, or HDF5 with the hdf5 feature) instead of computing one from --tree and --input")
+                        .conflicts_with_all(["tree", "table", "landmarks"]),
+                )
+                .mut_arg("tree", |a| a.required(false).required_unless_present("matrix"))
+                .mut_arg("table", |a| a.required(false).required_unless_present("matrix"))
+                .args(plot_args()),
+        )
+        .subcommand(
+            Command::new("silhouette")
+                .about("Silhouette widths of metadata groups under a distance matrix")
+                .args(grouping_args())
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT_FILE")
+                        .help("Output file for per-sample silhouette widths"),
+                ),
+        )
+        .subcommand(
+            Command::new("group-distances")
+                .about("Each sample's mean distance to its own group and to every other group, flagging samples closer to another group")
+                .args(grouping_args())
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT_FILE")
+                        .help("Output file for the per-sample mean distances")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("outliers")
+                .about("Flag samples whose median distance to the others is unusually high")
+                .arg(
+                    Arg::new("matrix")
+                        .value_name("MATRIX_FILE")
+                        .help("Distance matrix: TSV as written by the main command, or PHYLIP")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("threshold")
+                        .long("threshold")
+                        .value_name("MADS")
+                        .help("Flag samples whose median distance lies more than this many scaled MADs above the median of all samples")
+                        .value_parser(value_parser!(f64))
+                        .default_value("3"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT_FILE")
+                        .help("Output file ranking every sample, most outlying first"),
+                ),
+        )
+        .subcommand(
+            Command::new("longitudinal")
+                .about("Within-subject distances across timepoints, and between-subject baselines")
+                .arg(
+                    Arg::new("matrix")
+                        .value_name("MATRIX_FILE")
+                        .help("Distance matrix: TSV as written by the main command, or PHYLIP")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("metadata")
+                        .short('m')
+                        .long("metadata")
+                        .value_name("METADATA_FILE")
+                        .help("Tab-delimited sample metadata file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("subject")
+                        .long("subject")
+                        .value_name("COLUMN")
+                        .help("Metadata column identifying the subject")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("timepoint")
+                        .long("timepoint")
+                        .value_name("COLUMN")
+                        .help("Metadata column with the timepoint, ordered numerically if every value is a number")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT_FILE")
+                        .help("Output file for the tidy table of pairs")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("permanova")
+                .about("PERMANOVA test of differences between metadata groups")
+                .args(grouping_args())
+                .args(permutation_args())
+                .arg(
+                    Arg::new("pairwise")
+                        .long("pairwise")
+                        .help("Also test every pair of groups, with Benjamini-Hochberg adjusted p-values")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT_FILE")
+                        .help("Output file for the results table [default: stdout]"),
+                ),
+        )
+        .subcommand(
+            Command::new("mantel")
+                .about("Mantel test of the correlation between two distance matrices")
+                .arg(
+                    Arg::new("matrix")
+                        .value_name("MATRIX_FILE")
+                        .help("First distance matrix (TSV or PHYLIP)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("other")
+                        .value_name("OTHER_MATRIX_FILE")
+                        .help("Second distance matrix; only samples in both matrices are used")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("method")
+                        .long("method")
+                        .value_name("METHOD")
+                        .help("Correlation of the distances")
+                        .value_parser(["pearson", "spearman"])
+                        .default_value("pearson"),
+                )
+                .args(permutation_args()),
+        )
+        .subcommand(
+            Command::new("bioenv")
+                .about("BIOENV: the numeric metadata variables whose Euclidean distances best match the distance matrix (Spearman)")
+                .arg(
+                    Arg::new("matrix")
+                        .value_name("MATRIX_FILE")
+                        .help("Distance matrix (TSV or PHYLIP)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("metadata")
+                        .short('m')
+                        .long("metadata")
+                        .value_name("METADATA_FILE")
+                        .help("Tab-delimited sample metadata file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("columns")
+                        .short('c')
+                        .long("columns")
+                        .value_name("COLUMN,...")
+                        .help("Numeric metadata columns to consider [default: every numeric column]")
+                        .value_delimiter(','),
+                )
+                .arg(
+                    Arg::new("max_size")
+                        .long("max-size")
+                        .value_name("N")
+                        .help("Largest subset of variables tried [default: all of them]")
+                        .value_parser(value_parser!(usize).range(1..)),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT_FILE")
+                        .help("Output file: the best subset of each size with its correlation")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("dbrda")
+                .about("Distance-based redundancy analysis: ordination of the part of the matrix explained by metadata variables, with a permutation test")
+                .arg(
+                    Arg::new("matrix")
+                        .value_name("MATRIX_FILE")
+                        .help("Distance matrix (TSV or PHYLIP)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("metadata")
+                        .short('m')
+                        .long("metadata")
+                        .value_name("METADATA_FILE")
+                        .help("Tab-delimited sample metadata file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("columns")
+                        .short('c')
+                        .long("columns")
+                        .value_name("COLUMN,...")
+                        .help("Metadata columns of the design; numeric columns enter as they are, categorical ones as one indicator per level after the first")
+                        .value_delimiter(',')
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT_FILE")
+                        .help("Output file for the sample scores on the constrained axes")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("axes")
+                        .long("axes")
+                        .value_name("N")
+                        .help("Number of constrained axes to keep")
+                        .value_parser(value_parser!(usize))
+                        .default_value("3"),
+                )
+                .arg(
+                    Arg::new("biplot")
+                        .long("biplot")
+                        .value_name("BIPLOT_FILE")
+                        .help("Also write the correlation of each design column with each constrained axis"),
+                )
+                .args(permutation_args()),
+        )
+        .subcommand(
+            Command::new("permdisp")
+                .about("PERMDISP test for homogeneity of group dispersions")
+                .args(grouping_args())
+                .args(permutation_args())
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT_FILE")
+                        .help("Output file for each sample's distance to its group centroid"),
+                ),
+        )
+        .subcommand(
+            Command::new("cluster")
+                .about("Cluster samples directly from a distance matrix")
+                .arg(
+                    Arg::new("matrix")
+                        .value_name("MATRIX_FILE")
+                        .help("Distance matrix: TSV as written by the main command, or PHYLIP")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("method")
+                        .long("method")
+                        .value_name("METHOD")
+                        .help("Clustering method")
+                        .value_parser(["k-medoids", "dbscan"])
+                        .default_value("k-medoids"),
+                )
+                .arg(
+                    Arg::new("k")
+                        .short('k')
+                        .value_name("N")
+                        .help("Number of clusters (k-medoids)")
+                        .value_parser(value_parser!(usize).range(1..)),
+                )
+                .arg(
+                    Arg::new("eps")
+                        .long("eps")
+                        .value_name("DISTANCE")
+                        .help("Neighbourhood radius (dbscan)")
+                        .value_parser(value_parser!(f64)),
+                )
+                .arg(
+                    Arg::new("min_samples")
+                        .long("min-samples")
+                        .value_name("N")
+                        .help("Samples within --eps, itself included, that make a core sample (dbscan)")
+                        .value_parser(value_parser!(usize).range(1..))
+                        .default_value("5"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT_FILE")
+                        .help("Output file for cluster assignments, in the metadata layout")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("edge-pca")
+                .about("Edge PCA: which branches drive the differences between samples")
+                .args(input_args())
+                .args(table_format_args())
+                .arg(clade_arg())
+                .arg(transform_arg())
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT_FILE")
+                        .help("Output file for sample scores")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("loadings")
+                        .long("loadings")
+                        .value_name("LOADINGS_FILE")
+                        .help("Output file for the branch loadings of each axis"),
+                )
+                .arg(
+                    Arg::new("axes")
+                        .long("axes")
+                        .value_name("N")
+                        .help("Number of principal axes to keep")
+                        .value_parser(value_parser!(usize))
+                        .default_value("3"),
+                ),
+        )
+        .subcommand(
+            Command::new("philr")
+                .about("PhILR: each sample's isometric log-ratio balances over the tree's internal nodes")
+                .args(input_args())
+                .args(table_format_args())
+                .arg(clade_arg())
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT_FILE")
+                        .help("Output file for the samples x balances table")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("pseudocount")
+                        .long("pseudocount")
+                        .value_name("X")
+                        .help("Added to every count before taking logs")
+                        .value_parser(value_parser!(f64))
+                        .default_value("1"),
+                ),
+        )
+        .subcommand(
+            Command::new("cross")
+                .about("Distances between the samples of two tables over the same tree")
+                .args(input_args())
+                .arg(
+                    Arg::new("against")
+                        .short('j')
+                        .long("against")
+                        .value_name("TABLE_FILE")
+                        .help("Second sample-feature table; its samples become the columns")
+                        .required(true),
+                )
+                .args(table_format_args())
+                .args(compute_args())
+                .args(metric_args())
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT_FILE")
+                        .help("Output file for the rectangular distance matrix")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("tree")
+                .about("Reroot or prune a tree and write it back as newick")
+                .arg(
+                    Arg::new("tree")
+                        .short('t')
+                        .long("tree")
+                        .value_name("TREE_FILE")
+                        .help("Input newick format tree file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("midpoint")
+                        .long("midpoint")
+                        .help("Root halfway along the longest tip-to-tip path")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("outgroup"),
+                )
+                .arg(
+                    Arg::new("outgroup")
+                        .long("outgroup")
+                        .value_name("NAME|TIP1,TIP2,...")
+                        .help("Root halfway along the edge above this clade: an internal node label, or tips whose MRCA defines it"),
+                )
+                .arg(
+                    Arg::new("keep_tips")
+                        .long("keep-tips")
+                        .value_name("TIPS_FILE")
+                        .help("Keep only these tips, one per line ('-' for stdin); applied before rerooting"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT_FILE")
+                        .help("Output newick file")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("tree-stats")
+                .about("Tip and node counts, branch lengths and depths of a tree")
+                .arg(
+                    Arg::new("tree")
+                        .short('t')
+                        .long("tree")
+                        .value_name("TREE_FILE")
+                        .help("Input newick format tree file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .help("Print the statistics as JSON")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("patristic")
+                .about("Tip-to-tip branch-length distance matrix of the tree")
+                .arg(
+                    Arg::new("tree")
+                        .short('t')
+                        .long("tree")
+                        .value_name("TREE_FILE")
+                        .help("Input newick format tree file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("table")
+                        .short('i')
+                        .long("input")
+                        .value_name("TABLE_FILE")
+                        .help("Only keep tips that are features of this sample-feature table"),
+                )
+                .args(table_format_args())
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT_FILE")
+                        .help("Output file for the tip distance matrix")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("matrix")
+                .about("Operations on existing distance matrices")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("average")
+                        .about("Element-wise average of matrices over the same samples")
+                        .arg(
+                            Arg::new("matrices")
+                                .value_name("MATRIX_FILE")
+                                .help("Distance matrices to average")
+                                .num_args(1..)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("output")
+                                .short('o')
+                                .long("output")
+                                .value_name("OUTPUT_FILE")
+                                .help("Output file for the averaged matrix")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("variance")
+                                .long("variance")
+                                .value_name("VARIANCE_FILE")
+                                .help("Also write the element-wise sample variance"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("distatis")
+                        .about("DISTATIS: consensus ordination of several matrices over the same samples (e.g. one per metric), each weighted by its agreement with the others")
+                        .arg(
+                            Arg::new("matrices")
+                                .value_name("MATRIX_FILE")
+                                .help("Distance matrices to combine, at least two")
+                                .num_args(2..)
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("output")
+                                .short('o')
+                                .long("output")
+                                .value_name("OUTPUT_FILE")
+                                .help("Output file for the compromise coordinates")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("axes")
+                                .long("axes")
+                                .value_name("N")
+                                .help("Number of principal axes to keep")
+                                .value_parser(value_parser!(usize))
+                                .default_value("3"),
+                        )
+                        .arg(
+                            Arg::new("weights")
+                                .long("weights")
+                                .value_name("WEIGHTS_FILE")
+                                .help("Also write each matrix's weight and its RV coefficients with the others"),
+                        )
+                        .arg(
+                            Arg::new("partial")
+                                .long("partial")
+                                .value_name("PARTIAL_FILE")
+                                .help("Also write each matrix's samples projected onto the compromise axes"),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("reference")
+                .about("Compare new samples against a prepared set of reference samples")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("build")
+                        .about("Prepare the tree and the reference samples' branch sets")
+                        .args(input_args())
+                        .args(table_format_args())
+                        .args(compute_args())
+                        .arg(
+                            Arg::new("output")
+                                .short('o')
+                                .long("output")
+                                .value_name("REFERENCE_FILE")
+                                .help("Output file for the reference set")
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    Command::new("index")
+                        .about("Prepare the reference samples with an HNSW nearest-neighbor graph over their UniFrac distances")
+                        .args(input_args())
+                        .args(table_format_args())
+                        .args(compute_args())
+                        .arg(
+                            Arg::new("output")
+                                .short('o')
+                                .long("output")
+                                .value_name("INDEX_FILE")
+                                .help("Output file for the indexed reference set")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("neighbors")
+                                .long("neighbors")
+                                .value_name("M")
+                                .help("Links per sample on the upper layers (twice as many on the bottom one); more gives better recall and a larger index")
+                                .value_parser(value_parser!(usize).range(2..))
+                                .default_value("16"),
+                        )
+                        .arg(
+                            Arg::new("ef_construction")
+                                .long("ef-construction")
+                                .value_name("EF")
+                                .help("Candidates considered when linking each sample; more gives a better graph and a slower build")
+                                .value_parser(value_parser!(usize).range(1..))
+                                .default_value("200"),
+                        )
+                        .arg(
+                            Arg::new("seed")
+                                .long("seed")
+                                .value_name("SEED")
+                                .help("Random seed for the graph layers")
+                                .value_parser(value_parser!(u64))
+                                .default_value("1"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("search")
+                        .about("The k nearest references of every sample of a query table, through the index's graph")
+                        .arg(
+                            Arg::new("index")
+                                .short('x')
+                                .long("index")
+                                .value_name("INDEX_FILE")
+                                .help("Indexed reference set written by 'reference index'")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("table")
+                                .short('i')
+                                .long("input")
+                                .value_name("TABLE_FILE")
+                                .help("Sample-feature table of the query samples")
+                                .required(true),
+                        )
+                        .args(table_format_args())
+                        .arg(
+                            Arg::new("output")
+                                .short('o')
+                                .long("output")
+                                .value_name("OUTPUT_FILE")
+                                .help("Output file: query, rank, reference and distance per line")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("k")
+                                .short('k')
+                                .long("top")
+                                .value_name("K")
+                                .help("Number of nearest references per query sample")
+                                .value_parser(value_parser!(usize).range(1..))
+                                .default_value("10"),
+                        )
+                        .arg(
+                            Arg::new("ef")
+                                .long("ef")
+                                .value_name("EF")
+                                .help("Candidates kept during the search (at least K); more gives better recall and slower queries")
+                                .value_parser(value_parser!(usize).range(1..))
+                                .default_value("64"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("query")
+                        .about("Stream the distances from one new sample to every reference")
+                        .arg(
+                            Arg::new("reference")
+                                .short('r')
+                                .long("reference")
+                                .value_name("REFERENCE_FILE")
+                                .help("Reference set written by 'reference build'")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("features")
+                                .short('f')
+                                .long("features")
+                                .value_name("FEATURE_FILE")
+                                .help("Features present in the new sample, one per line ('-' for stdin)")
+                                .required(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("meta")
+                .about("Combined matrix over several studies sharing one reference tree")
+                .arg(
+                    Arg::new("tree")
+                        .short('t')
+                        .long("tree")
+                        .value_name("TREE_FILE")
+                        .help("Input newick format tree file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("tables")
+                        .short('i')
+                        .long("input")
+                        .value_name("TABLE_FILE")
+                        .help("Sample-feature tables, one per study")
+                        .num_args(1..)
+                        .action(ArgAction::Append)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("metadata")
+                        .short('m')
+                        .long("metadata")
+                        .value_name("METADATA_FILE")
+                        .help("Sample metadata files, in the same order as the tables")
+                        .num_args(1..)
+                        .action(ArgAction::Append)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("labels")
+                        .long("labels")
+                        .value_name("LABEL")
+                        .help("Study labels, in the same order as the tables [default: table file names]")
+                        .num_args(1..)
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("label_column")
+                        .long("label-column")
+                        .value_name("COLUMN")
+                        .help("Take each study's label from this metadata column, which must hold one value across the study's samples")
+                        .conflicts_with("labels"),
+                )
+                .arg(
+                    Arg::new("study_column")
+                        .long("study-column")
+                        .value_name("COLUMN")
+                        .help("Name of the study label column added to the combined metadata")
+                        .default_value("study"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT_FILE")
+                        .help("Output file for the combined distance matrix")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("combined_metadata")
+                        .long("combined-metadata")
+                        .value_name("METADATA_FILE")
+                        .help("Output file for the combined metadata with the study column")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("summary")
+                        .long("summary")
+                        .value_name("SUMMARY_FILE")
+                        .help("Output file for within-study and between-study distance summaries")
+                        .required(true),
+                )
+                .args(table_format_args())
+                .args(compute_args())
+                .args(metric_args()),
+        )
+        .subcommand(
+            Command::new("simulate")
+                .about("Simulate a birth-death tree and a phylogenetically correlated sample-feature table")
+                .arg(
+                    Arg::new("tips")
+                        .long("tips")
+                        .value_name("N")
+                        .help("Number of tips (features)")
+                        .value_parser(value_parser!(usize))
+                        .default_value("100"),
+                )
+                .arg(
+                    Arg::new("samples")
+                        .long("samples")
+                        .value_name("N")
+                        .help("Number of samples")
+                        .value_parser(value_parser!(usize))
+                        .default_value("20"),
+                )
+                .arg(
+                    Arg::new("birth")
+                        .long("birth")
+                        .value_name("RATE")
+                        .help("Speciation rate")
+                        .value_parser(value_parser!(f64))
+                        .default_value("1.0"),
+                )
+                .arg(
+                    Arg::new("death")
+                        .long("death")
+                        .value_name("RATE")
+                        .help("Extinction rate")
+                        .value_parser(value_parser!(f64))
+                        .default_value("0.5"),
+                )
+                .arg(
+                    Arg::new("environments")
+                        .long("environments")
+                        .value_name("N")
+                        .help("Number of environments; samples of one environment share a presence pattern")
+                        .value_parser(value_parser!(usize))
+                        .default_value("3"),
+                )
+                .arg(
+                    Arg::new("prevalence")
+                        .long("prevalence")
+                        .value_name("FRACTION")
+                        .help("Expected fraction of features present in an environment")
+                        .value_parser(value_parser!(f64))
+                        .default_value("0.3"),
+                )
+                .arg(
+                    Arg::new("switch_rate")
+                        .long("switch-rate")
+                        .value_name("RATE")
+                        .help("Rate of presence gain/loss along branches; lower means stronger phylogenetic signal")
+                        .value_parser(value_parser!(f64))
+                        .default_value("1.0"),
+                )
+                .arg(
+                    Arg::new("noise")
+                        .long("noise")
+                        .value_name("PROBABILITY")
+                        .help("Probability of flipping each feature's presence in a sample")
+                        .value_parser(value_parser!(f64))
+                        .default_value("0.05"),
+                )
+                .arg(
+                    Arg::new("seed")
+                        .long("seed")
+                        .value_name("SEED")
+                        .help("Random seed")
+                        .value_parser(value_parser!(u64))
+                        .default_value("42"),
+                )
+                .arg(
+                    Arg::new("tree_out")
+                        .long("tree-out")
+                        .value_name("TREE_FILE")
+                        .help("Output newick tree")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("table_out")
+                        .long("table-out")
+                        .value_name("TABLE_FILE")
+                        .help("Output sample-feature table")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("metadata_out")
+                        .long("metadata-out")
+                        .value_name("METADATA_FILE")
+                        .help("Also write sample metadata with each sample's environment"),
+                ),
+        )
+        .subcommand(
+            Command::new("watch")
+                .about("Watch a directory for delivered tables and write each one's distance matrix, extending it when a delivery only adds samples")
+                .arg(
+                    Arg::new("dir")
+                        .value_name("DIR")
+                        .help("Directory receiving sample-feature tables (.tsv, .txt, .shared)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("tree")
+                        .short('t')
+                        .long("tree")
+                        .value_name("TREE_FILE")
+                        .help("Input newick format tree file")
+                        .required(true),
+                )
+                .args(table_format_args())
+                .args(compute_args())
+                .args(metric_args())
+                .arg(
+                    Arg::new("out_dir")
+                        .short('o')
+                        .long("out-dir")
+                        .value_name("DIR")
+                        .help("Directory for the matrices, one TABLE_NAME.txt per table, rewritten on each delivery")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("interval")
+                        .long("interval")
+                        .value_name("SECONDS")
+                        .help("Seconds between looks at the directory; a table is read once it is unchanged between two looks")
+                        .value_parser(value_parser!(u64).range(1..))
+                        .default_value("30"),
+                )
+                .arg(
+                    Arg::new("once")
+                        .long("once")
+                        .help("Process the tables present now and exit")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("completions")
+                .about("Generate shell completion scripts")
+                .hide(true)
+                .arg(
+                    Arg::new("shell")
+                        .value_name("SHELL")
+                        .help("Shell to generate completions for")
+                        .value_parser(value_parser!(Shell))
+                        .required(true),
+                ),
+        );
+
+    #[cfg(all(feature = "numa", target_os = "linux"))]
+    let cli = cli.arg(
+        Arg::new("numa")
+            .long("numa")
+            .help("Pin one thread pool per NUMA node, each with its own copy of the branch bitsets (bitset algorithm, unweighted)")
+            .action(ArgAction::SetTrue),
+    );
+
+    #[cfg(any(feature = "sqlite", feature = "duckdb"))]
+    let cli = cli
+        .arg(
+            Arg::new("db")
+                .long("db")
+                .value_name("DATABASE_FILE")
+                .help("Read the table from this SQLite (or .duckdb/.ddb DuckDB) database with --query, instead of --input")
+                .conflicts_with("table")
+                .requires("query"),
+        )
+        .arg(
+            Arg::new("query")
+                .long("query")
+                .value_name("SQL")
+                .help("Query returning feature ID, sample ID and count, one row per nonzero count")
+                .requires("db"),
+        )
+        .arg(
+            Arg::new("to_db")
+                .long("to-db")
+                .value_name("DATABASE_FILE")
+                .help("Also append the distances (long format) and a record of the run to this SQLite (or .duckdb/.ddb DuckDB) database"),
+        )
+        .mut_arg("table", |a| a.required(false).required_unless_present("db"));
+
+    #[cfg(feature = "grpc")]
+    let cli = cli.subcommand(
+        Command::new("serve-grpc")
+            .about("Serve ComputePair/ComputeMatrix RPCs over a tree and table loaded once")
+            .args(input_args())
+            .args(table_format_args())
+            .args(compute_args())
+            .arg(
+                Arg::new("addr")
+                    .long("addr")
+                    .value_name("HOST:PORT")
+                    .help("Address to listen on")
+                    .value_parser(value_parser!(std::net::SocketAddr))
+                    .default_value("127.0.0.1:50051"),
+            ),
+    );
+
+    #[cfg(feature = "server")]
+    let cli = cli.subcommand(
+        Command::new("serve")
+            .about("REST API: upload tables, start matrix jobs, poll progress, download results")
+            .arg(
+                Arg::new("tree")
+                    .short('t')
+                    .long("tree")
+                    .value_name("TREE_FILE")
+                    .help("Input newick format tree file")
+                    .required(true),
+            )
+            .args(table_format_args())
+            .args(compute_args())
+            .args(metric_args())
+            .arg(
+                Arg::new("addr")
+                    .long("addr")
+                    .value_name("HOST:PORT")
+                    .help("Address to listen on")
+                    .value_parser(value_parser!(std::net::SocketAddr))
+                    .default_value("127.0.0.1:8080"),
+            )
+            .arg(
+                Arg::new("workdir")
+                    .long("workdir")
+                    .value_name("DIR")
+                    .help("Directory for uploaded tables and finished matrices; POST /tables?path=FILE only reads files under it")
+                    .default_value("unifrac-server"),
+            ),
+    );
+
+    cli
+}
+
+fn run(matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        Some(("pcoa", sub)) => run_pcoa(sub),
+        Some(("cluster", sub)) => run_cluster(sub),
+        Some(("silhouette", sub)) => run_silhouette(sub),
+        Some(("group-distances", sub)) => run_group_distances(sub),
+        Some(("outliers", sub)) => run_outliers(sub),
+        Some(("longitudinal", sub)) => run_longitudinal(sub),
+        Some(("permanova", sub)) => run_permanova(sub),
+        Some(("mantel", sub)) => run_mantel(sub),
+        Some(("bioenv", sub)) => run_bioenv(sub),
+        Some(("dbrda", sub)) => run_dbrda(sub),
+        Some(("permdisp", sub)) => run_permdisp(sub),
+        Some(("cross", sub)) => run_cross(sub),
+        Some(("edge-pca", sub)) => run_edge_pca(sub),
+        Some(("philr", sub)) => run_philr(sub),
+        Some(("patristic", sub)) => run_patristic(sub),
+        Some(("tree", sub)) => run_tree(sub),
+        Some(("tree-stats", sub)) => run_tree_stats(sub),
+        Some(("meta", sub)) => run_meta(sub),
+        Some(("simulate", sub)) => run_simulate(sub),
+        Some(("watch", sub)) => run_watch(sub),
+        Some(("matrix", sub)) => match sub.subcommand() {
+            Some(("average", avg)) => run_matrix_average(avg),
+            Some(("distatis", sub)) => run_matrix_distatis(sub),
+            _ => unreachable!("subcommand is required"),
+        },
+        Some(("reference", sub)) => match sub.subcommand() {
+            Some(("build", build)) => run_reference_build(build),
+            Some(("index", index)) => run_reference_index(index),
+            Some(("search", search)) => run_reference_search(search),
+            Some(("query", query)) => run_reference_query(query),
+            _ => unreachable!("subcommand is required"),
+        },
+        #[cfg(feature = "grpc")]
+        Some(("serve-grpc", sub)) => run_serve_grpc(sub),
+        #[cfg(feature = "server")]
+        Some(("serve", sub)) => run_serve(sub),
+        _ => run_unifrac(matches),
+    }
+}
+
+/// Distance matrix and metadata grouping, for the analyses of existing matrices
+fn grouping_args() -> [Arg; 3] {
+    [
+        Arg::new("matrix")
+            .value_name("MATRIX_FILE")
+            .help("Distance matrix: TSV as written by the main command, or PHYLIP")
+            .required(true),
+        Arg::new("metadata")
+            .short('m')
+            .long("metadata")
+            .value_name("METADATA_FILE")
+            .help("Tab-delimited sample metadata file")
+            .required(true),
+        Arg::new("column")
+            .short('c')
+            .long("column")
+            .value_name("COLUMN")
+            .help("Metadata column defining the groups")
+            .required(true),
+    ]
+}
+
+/// Number of label permutations and their random seed, for permutation tests
+fn permutation_args() -> [Arg; 2] {
+    [
+        Arg::new("permutations")
+            .long("permutations")
+            .value_name("N")
+            .help("Number of permutations for the p-value")
+            .value_parser(value_parser!(usize))
+            .default_value("999"),
+        Arg::new("seed")
+            .long("seed")
+            .value_name("SEED")
+            .help("Random seed for the permutations")
+            .value_parser(value_parser!(u64))
+            .default_value("1"),
+    ]
+}
+
+/// Read the matrix and each of its samples' group from the metadata
+fn load_grouping(matches: &ArgMatches) -> Result<(Vec<String>, Vec<f64>, Vec<String>)> {
+    let (sample_names, dist_matrix) = load_matrix(matches.get_one::<String>("matrix").unwrap())?;
+    let metadata = load_metadata(matches.get_one::<String>("metadata").unwrap())?;
+    let labels = metadata
+        .column_for(&sample_names, matches.get_one::<String>("column").unwrap())
+        .context(ErrorKind::Mismatch)?;
+    check_grouping(&labels).context(ErrorKind::Mismatch)?;
+    Ok((sample_names, dist_matrix, labels))
+}
+
+/// Tree and table arguments shared by every command that computes distances
+fn input_args() -> [Arg; 2] {
+    [
+        Arg::new("tree")
+            .short('t')
+            .long("tree")
+            .value_name("TREE_FILE")
+            .help("Input newick format tree file")
+            .required(true),
+        Arg::new("table")
+            .short('i')
+            .long("input")
+            .value_name("TABLE_FILE")
+            .help("Input tab-delimited sample-feature table")
+            .required(true),
+    ]
+}
+
+/// How to read the sample-feature table
+fn table_format_args() -> [Arg; 10] {
+    [
+        Arg::new("input_format")
+            .long("input-format")
+            .value_name("FORMAT")
+            .help("Table format; auto picks mothur for .shared files and tsv otherwise")
+            .value_parser(["auto", "tsv", "mothur"])
+            .default_value("auto"),
+        Arg::new("shared_label")
+            .long("shared-label")
+            .value_name("LABEL")
+            .help(
+                "OTU definition (label column) to use from a mothur .shared file [default: first]",
+            ),
+        Arg::new("duplicates")
+            .long("duplicates")
+            .value_name("POLICY")
+            .help("Samples sharing a name: fail, rename later ones NAME_2, NAME_3, ..., or sum their counts")
+            .value_parser(["error", "suffix", "sum"])
+            .default_value("error"),
+        Arg::new("strict_parse")
+            .long("strict-parse")
+            .help("Fail on a table cell that is not a number, naming its line and column, instead of counting it as 0 with a warning")
+            .action(ArgAction::SetTrue),
+        Arg::new("id_map")
+            .long("id-map")
+            .value_name("MAP_FILE")
+            .help("Tab-delimited feature ID / tip name pairs translating the table's feature IDs to the tree's tip names"),
+        Arg::new("sequences")
+            .long("sequences")
+            .value_name("FASTA_FILE")
+            .help("Representative sequences of the features; feature IDs and tip names that are IDs in the file are replaced by the MD5 of their sequence (QIIME 2's feature IDs), so hashes on one side match IDs on the other"),
+        Arg::new("id_regex")
+            .long("id-regex")
+            .value_name("PATTERN")
+            .help("Regular expression replaced in feature IDs and/or tip names before matching, e.g. '^ASV_' or ';size=[0-9]+$'"),
+        Arg::new("id_replace")
+            .long("id-replace")
+            .value_name("REPLACEMENT")
+            .help("Replacement for --id-regex matches; $1, $name, ... refer to capture groups")
+            .default_value(""),
+        Arg::new("id_regex_on")
+            .long("id-regex-on")
+            .value_name("NAMES")
+            .help("Which names --id-regex rewrites")
+            .value_parser(["features", "tips", "both"])
+            .default_value("both"),
+        Arg::new("normalize_names")
+            .long("normalize-names")
+            .value_name("RULES")
+            .help("Ignore cosmetic differences between feature IDs and tip names: underscores (same as spaces), quotes, case, or all; comma-separated")
+            .value_parser(["underscores", "quotes", "case", "all"])
+            .value_delimiter(','),
+    ]
+}
+
+/// Rules given with --normalize-names, if the command has the option
+fn name_normalization(matches: &ArgMatches) -> Option<NameNormalization> {
+    let rules = matches.try_get_many::<String>("normalize_names").ok()??;
+    NameNormalization::from_names(rules.map(|r| r.as_str()))
+}
+
+/// Cleanup of `target` ("features" or "tips") names: the --id-regex
+/// substitution if it applies to them, then --normalize-names
+fn name_cleaner(matches: &ArgMatches, target: &str) -> Result<Option<impl Fn(&str) -> String>> {
+    let applies = matches
+        .try_get_one::<String>("id_regex_on")
+        .ok()
+        .flatten()
+        .is_some_and(|on| on == "both" || on == target);
+    let rewrite = match matches.try_get_one::<String>("id_regex").ok().flatten() {
+        Some(pattern) if applies => {
+            let replacement = matches.get_one::<String>("id_replace").unwrap();
+            Some(IdRewrite::new(pattern, replacement).context(ErrorKind::Parse)?)
+        }
+        _ => None,
+    };
+    let norm = name_normalization(matches);
+    if rewrite.is_none() && norm.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(move |name: &str| {
+        let name = match &rewrite {
+            Some(rewrite) => rewrite.apply(name),
+            None => name.to_string(),
+        };
+        match &norm {
+            Some(norm) => norm.apply(&name),
+            None => name,
+        }
+    }))
+}
+
+/// --sequences: the MD5 of each record's sequence, by record ID
+fn sequence_hashes_of(matches: &ArgMatches) -> Result<Option<HashMap<String, String>>> {
+    let Some(fasta_file) = matches.try_get_one::<String>("sequences").ok().flatten() else {
+        return Ok(None);
+    };
+    require_file(fasta_file)?;
+    let records = read_fasta(fasta_file)
+        .with_context(|| fasta_file.to_string())
+        .context(ErrorKind::Parse)?;
+    if records.is_empty() {
+        return Err(anyhow!("{} holds no sequences", fasta_file).context(ErrorKind::Parse));
+    }
+    Ok(Some(sequence_hashes(&records)))
+}
+
+/// Apply --sequences, --id-regex and --normalize-names to the tip names of
+/// the tree
+fn normalize_tips(matches: &ArgMatches, tree: &mut Tree) -> Result<()> {
+    if let Some(hashes) = sequence_hashes_of(matches)? {
+        rename_tips(tree, |n| {
+            hashes.get(n).cloned().unwrap_or_else(|| n.to_string())
+        })
+        .context("Tips have the same sequence in --sequences")
+        .context(ErrorKind::Mismatch)?;
+    }
+    if let Some(clean) = name_cleaner(matches, "tips")? {
+        rename_tips(tree, clean)
+            .context("Tip names collide after --id-regex/--normalize-names")
+            .context(ErrorKind::Mismatch)?;
+    }
+    Ok(())
+}
+
+/// Read a sample-feature table in the format selected with --input-format.
+/// Counts are kept; the unweighted computations treat any value > 0 as present.
+fn read_table(
+    matches: &ArgMatches,
+    table_file: &str,
+) -> Result<(Vec<String>, Vec<String>, Vec<Vec<f64>>)> {
+    require_file(table_file)?;
+    let format = matches.get_one::<String>("input_format").unwrap().as_str();
+    let is_shared = match format {
+        "mothur" => true,
+        "tsv" => false,
+        _ => Path::new(table_file)
+            .extension()
+            .is_some_and(|e| e == "shared"),
+    };
+    let queried = query_table(matches, table_file)?;
+    let (taxa_order, sample_names, counts) = if let Some(table) = queried {
+        table
+    } else if is_shared {
+        let label = matches
+            .get_one::<String>("shared_label")
+            .map(|l| l.as_str());
+        read_mothur_counts(table_file, label).context(ErrorKind::Parse)?
+    } else {
+        let (taxa_order, sample_names, counts, coerced) =
+            read_count_table_with(table_file, matches.get_flag("strict_parse"))
+                .context(ErrorKind::Parse)?;
+        report_coerced(table_file, &sample_names, &coerced);
+        (taxa_order, sample_names, counts)
+    };
+    let policy = Duplicates::from_name(matches.get_one::<String>("duplicates").unwrap()).unwrap();
+    let (sample_names, counts) = resolve_duplicates(sample_names, counts, policy)
+        .with_context(|| table_file.to_string())
+        .context(ErrorKind::Parse)?;
+    let (taxa_order, counts) = match matches.try_get_one::<String>("id_map").ok().flatten() {
+        Some(map_file) => {
+            require_file(map_file)?;
+            let map = read_id_map(map_file)
+                .with_context(|| map_file.to_string())
+                .context(ErrorKind::Parse)?;
+            let mut unmapped: Vec<&str> = taxa_order
+                .iter()
+                .filter(|t| !map.contains_key(*t))
+                .map(|t| t.as_str())
+                .collect();
+            if !unmapped.is_empty() {
+                let total = unmapped.len();
+                unmapped.truncate(5);
+                let more = if total > 5 {
+                    format!(" and {} more", total - 5)
+                } else {
+                    String::new()
+                };
+                warn!(
+                    "{} of {} features of {} are not in {} and keep their IDs: {}{}",
+                    total,
+                    taxa_order.len(),
+                    table_file,
+                    map_file,
+                    unmapped.join(", "),
+                    more
+                );
+            }
+            let (taxa_order, counts, _) = rename_features(taxa_order, counts, |n| {
+                map.get(n).cloned().unwrap_or_else(|| n.to_string())
+            });
+            (taxa_order, counts)
+        }
+        None => (taxa_order, counts),
+    };
+    let (taxa_order, counts) = match sequence_hashes_of(matches)? {
+        Some(hashes) => {
+            let hashed = taxa_order
+                .iter()
+                .filter(|t| hashes.contains_key(*t))
+                .count();
+            let (taxa_order, counts, merged) = rename_features(taxa_order, counts, |n| {
+                hashes.get(n).cloned().unwrap_or_else(|| n.to_string())
+            });
+            info!(
+                "{} features of {} replaced by the MD5 of their sequence",
+                hashed, table_file
+            );
+            if merged > 0 {
+                warn!(
+                    "{} features of {} have the same sequence as another and were summed into it",
+                    merged, table_file
+                );
+            }
+            (taxa_order, counts)
+        }
+        None => (taxa_order, counts),
+    };
+    let (taxa_order, counts) = match name_cleaner(matches, "features")? {
+        Some(clean) => {
+            let (taxa_order, counts, merged) = rename_features(taxa_order, counts, clean);
+            if merged > 0 {
+                warn!(
+                    "{} features of {} have the same cleaned-up name as another and were summed into it",
+                    merged, table_file
+                );
+            }
+            (taxa_order, counts)
+        }
+        None => (taxa_order, counts),
+    };
+    Ok((taxa_order, sample_names, counts))
+}
+
+/// Warn about the table cells that were not numbers and counted as 0, by
+/// sample, the most affected first
+fn report_coerced(table_file: &str, sample_names: &[String], coerced: &[usize]) {
+    let total: usize = coerced.iter().sum();
+    if total == 0 {
+        return;
+    }
+    let mut affected: Vec<(usize, &str)> = coerced
+        .iter()
+        .zip(sample_names)
+        .filter(|(c, _)| **c > 0)
+        .map(|(c, s)| (*c, s.as_str()))
+        .collect();
+    affected.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+    let shown: Vec<String> = affected
+        .iter()
+        .take(5)
+        .map(|(c, s)| format!("{} ({})", s, c))
+        .collect();
+    let more = if affected.len() > 5 {
+        format!(" and {} more samples", affected.len() - 5)
+    } else {
+        String::new()
+    };
+    warn!(
+        "{} cells of {} are not numbers and count as 0 (--strict-parse refuses them): {}{}",
+        total,
+        table_file,
+        shown.join(", "),
+        more
+    );
+}
+
+/// With --db, the table as returned by --query on `db_file` (features
+/// `sqlite`, `duckdb`)
+#[cfg(any(feature = "sqlite", feature = "duckdb"))]
+fn query_table(
+    matches: &ArgMatches,
+    db_file: &str,
+) -> Result<Option<(Vec<String>, Vec<String>, Vec<Vec<f64>>)>> {
+    let Some(query) = matches.try_get_one::<String>("query").ok().flatten() else {
+        return Ok(None);
+    };
+    let table = read_query_table(db_file, query)
+        .with_context(|| db_file.to_string())
+        .context(ErrorKind::Parse)?;
+    Ok(Some(table))
+}
+
+#[cfg(not(any(feature = "sqlite", feature = "duckdb")))]
+fn query_table(
+    _matches: &ArgMatches,
+    _db_file: &str,
+) -> Result<Option<(Vec<String>, Vec<String>, Vec<Vec<f64>>)>> {
+    Ok(None)
+}
+
+/// --to-db: append the distances and a record of the run to a database
+/// (features `sqlite`, `duckdb`)
+#[cfg(any(feature = "sqlite", feature = "duckdb"))]
+fn record_in_db(
+    matches: &ArgMatches,
+    metric: &str,
+    sample_names: &[String],
+    dist_matrix: &[f64],
+) -> Result<()> {
+    let Some(db_file) = matches.try_get_one::<String>("to_db").ok().flatten() else {
+        return Ok(());
+    };
+    let run = RunRecord {
+        run_id: format!("{:016x}", rand::random::<u64>()),
+        created: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        metric: metric.to_string(),
+        tree: matches.get_one::<String>("tree").unwrap().clone(),
+        table: table_source(matches).clone(),
+        options: serde_json::to_string(&collect_options(matches))?,
+    };
+    write_distances_db(db_file, &run, sample_names, dist_matrix)
+        .with_context(|| db_file.to_string())?;
+    info!("Added run {} to {}", run.run_id, db_file);
+    Ok(())
+}
+
+#[cfg(not(any(feature = "sqlite", feature = "duckdb")))]
+fn record_in_db(
+    _matches: &ArgMatches,
+    _metric: &str,
+    _sample_names: &[String],
+    _dist_matrix: &[f64],
+) -> Result<()> {
+    Ok(())
+}
+
+/// The table file, or with --db the database it is queried from
+fn table_source(matches: &ArgMatches) -> &String {
+    matches
+        .get_one::<String>("table")
+        .or_else(|| matches.try_get_one::<String>("db").ok().flatten())
+        .unwrap()
+}
+
+/// --plot and the metadata coloring it (feature `plots`)
+#[cfg(feature = "plots")]
+fn plot_args() -> Vec<Arg> {
+    vec![
+        Arg::new("plot")
+            .long("plot")
+            .value_name("HTML_FILE")
+            .help("Also write a self-contained interactive HTML scatter plot"),
+        Arg::new("metadata")
+            .short('m')
+            .long("metadata")
+            .value_name("METADATA_FILE")
+            .help("Tab-delimited sample metadata file"),
+        Arg::new("color_by")
+            .long("color-by")
+            .value_name("COLUMN")
+            .help("Metadata column used to color the plot")
+            .requires("metadata"),
+    ]
+}
+
+#[cfg(not(feature = "plots"))]
+fn plot_args() -> Vec<Arg> {
+    Vec::new()
+}
+
+fn clade_arg() -> Arg {
+    Arg::new("clade")
+        .long("clade")
+        .value_name("NAME|TIP1,TIP2,...")
+        .help("Restrict the computation to one clade: an internal node label, or tips whose MRCA defines it")
+}
+
+/// Options controlling how distances are computed, shared by every computing command
+fn compute_args() -> Vec<Arg> {
+    vec![
+        Arg::new("branch_weights")
+            .long("branch-weights")
+            .value_name("WEIGHTS_FILE")
+            .help("Tab-delimited node name / weight pairs multiplying the branch lengths of each named clade of the whole tree; every name must be a node of the tree"),
+        clade_arg(),
+        Arg::new("missing_brlen")
+            .long("missing-brlen")
+            .value_name("POLICY")
+            .help("Branches without a length in the tree: fail, or treat them as length 0 or 1")
+            .value_parser(["error", "zero", "one"])
+            .default_value("zero"),
+        Arg::new("root_edge")
+            .long("root-edge")
+            .value_name("include|exclude")
+            .help("Whether the root's own edge (if the tree gives it a length) counts as a branch")
+            .value_parser(["include", "exclude"])
+            .default_value("exclude"),
+        Arg::new("min_rel_abund")
+            .long("min-rel-abund-per-sample")
+            .value_name("F")
+            .help("Treat features making up less than this fraction of a sample's counts as absent from it, against cross-talk and low-level contamination (all metrics)")
+            .value_parser(|s: &str| match s.parse::<f64>() {
+                Ok(f) if (0.0..1.0).contains(&f) => Ok(f),
+                _ => Err("expected a fraction in [0, 1)".to_string()),
+            }),
+        Arg::new("negative_controls")
+            .long("negative-controls")
+            .value_name("SAMPLE_LIST")
+            .help("File naming the negative-control samples, one per line; features more prevalent in them than in the other samples (one-sided Fisher test) are treated as contaminants, and the controls are left out of the result. Each table is scored against its own controls, so every table must contain some"),
+        Arg::new("contaminant_threshold")
+            .long("contaminant-threshold")
+            .value_name("P")
+            .help("p-value below which a feature is a contaminant")
+            .value_parser(value_parser!(f64))
+            .default_value("0.1"),
+        Arg::new("contaminants")
+            .long("contaminants")
+            .value_name("ACTION")
+            .help("Remove contaminants from every sample, or down-weight their counts by the fraction of controls without them")
+            .value_parser(["remove", "downweight"])
+            .default_value("remove"),
+        Arg::new("copy_numbers")
+            .long("copy-numbers")
+            .value_name("COPY_NUMBER_FILE")
+            .help("Tab-delimited feature ID / 16S copy number pairs; counts are divided by them before weighted metrics (features not listed keep their counts)"),
+        Arg::new("rooting")
+            .long("rooting")
+            .value_name("STRATEGY")
+            .help("Root placement: 'none' computes on the root as given; 'auto' midpoint-roots a tree whose root has three or more children (an unrooted tree) and keeps rooted trees; 'midpoint' or 'outgroup:CLADE' reroot any tree")
+            .value_parser(|s: &str| match s {
+                "none" | "auto" | "midpoint" => Ok(s.to_string()),
+                _ if s.strip_prefix("outgroup:").is_some_and(|c| !c.is_empty()) => Ok(s.to_string()),
+                _ => Err("expected none, auto, midpoint or outgroup:CLADE".to_string()),
+            })
+            .default_value("none"),
+        Arg::new("unit_branch_lengths")
+            .long("unit-branch-lengths")
+            .help("Treat every branch as length 1, for trees with unreliable lengths (e.g. taxonomy-derived)")
+            .action(ArgAction::SetTrue),
+    ]
+}
+
+/// Which UniFrac variant to compute, and how abundances enter the weighted ones
+fn metric_args() -> [Arg; 2] {
+    [
+        Arg::new("metric")
+            .long("metric")
+            .value_name("METRIC")
+            .help("UniFrac variant: unweighted, unrooted (unweighted over the unrooted tree's splits), weighted (normalized), or generalized[:ALPHA] (default alpha 0.5). Several, comma-separated, are computed in one pass, one matrix each (OUTPUT.<metric>.<ext>)")
+            .value_parser(|s: &str| {
+                Metric::from_name(s).ok_or_else(|| {
+                    "expected unweighted, unrooted, weighted or generalized[:ALPHA] with ALPHA in [0, 1]"
+                        .to_string()
+                })
+            })
+            .value_delimiter(',')
+            .action(ArgAction::Append)
+            .default_value("unweighted"),
+        transform_arg(),
+    ]
+}
+
+fn transform_arg() -> Arg {
+    Arg::new("transform")
+        .long("transform")
+        .value_name("TRANSFORM")
+        .help("Transform applied to counts before weighted/generalized UniFrac; css (cumulative-sum scaling) normalizes each sample's library")
+        .value_parser(|s: &str| match s {
+            "none" | "sqrt" | "log1p" | "css" => Ok(s.to_string()),
+            "clr" => Err("clr is not supported: centered log-ratios are negative below a sample's geometric mean, so they are not abundances UniFrac can spread over the tree".to_string()),
+            _ => Err("expected none, sqrt, log1p or css".to_string()),
+        })
+        .default_value("none")
+}
+
+/// The metrics given with --metric, with their names as typed
+fn selected_metrics(matches: &ArgMatches) -> Vec<(Metric, String)> {
+    let metrics = matches.get_many::<Metric>("metric").unwrap().copied();
+    let names = matches.get_raw("metric").unwrap().flat_map(|v| {
+        v.to_string_lossy()
+            .split(',')
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>()
+    });
+    metrics.zip(names).collect()
+}
+
+/// The one metric given with --metric, for commands that produce a single
+/// result
+fn single_metric(matches: &ArgMatches, what: &str) -> Result<Metric> {
+    let metrics = selected_metrics(matches);
+    if metrics.len() > 1 {
+        bail!("{} takes a single --metric", what);
+    }
+    Ok(metrics[0].0)
+}
+
+/// Whether outputs may replace existing files (`--force`)
+fn force(matches: &ArgMatches) -> bool {
+    matches.get_flag("force")
+}
+
+/// Whether `id` was set on the command line (or from the environment)
+/// rather than left at its default
+fn explicitly_given(matches: &ArgMatches, id: &str) -> bool {
+    matches
+        .value_source(id)
+        .is_some_and(|source| source != ValueSource::DefaultValue)
+}
+
+/// Refuse a --transform that none of the selected metrics would use
+fn check_transform(matches: &ArgMatches) -> Result<()> {
+    let weighted = selected_metrics(matches)
+        .iter()
+        .any(|(m, _)| matches!(m, Metric::Weighted | Metric::Generalized(_)));
+    if explicitly_given(matches, "transform") && !weighted {
+        bail!("--transform only applies to the weighted and generalized metrics");
+    }
+    Ok(())
+}
+
+/// The columns of `counts` for the samples in `keep`, in that order
+fn select_samples(counts: &[Vec<f64>], keep: &[usize]) -> Vec<Vec<f64>> {
+    counts
+        .iter()
+        .map(|row| keep.iter().map(|&s| row[s]).collect())
+        .collect()
+}
+
+/// Compute the matrix for one metric selected with --metric. Per-pair branch
+/// statistics only exist for the unweighted metrics.
+fn compute_distances(
+    matches: &ArgMatches,
+    metric: Metric,
+    algorithm: Algorithm,
+    tree: &Tree,
+    taxa_order: &[String],
+    counts: &[Vec<f64>],
+    n_samples: usize,
+    opts: &BranchOptions,
+) -> Result<(Vec<f64>, Option<Vec<PairStats>>)> {
+    let transform = Transform::from_name(matches.get_one::<String>("transform").unwrap()).unwrap();
+    debug!(
+        "Computing {:?} UniFrac ({:?} algorithm, {:?} transform) for {} samples",
+        metric, algorithm, transform, n_samples
+    );
+    let result = match metric {
+        Metric::Unweighted => {
+            #[cfg(all(feature = "numa", target_os = "linux"))]
+            if matches!(algorithm, Algorithm::Bitset)
+                && matches.try_get_one::<bool>("numa").ok().flatten() == Some(&true)
+            {
+                let (dist_matrix, stats) = unifrac::numa::compute_unifrac_matrix_numa(
+                    tree, taxa_order, counts, n_samples, opts,
+                )?;
+                return Ok((dist_matrix, Some(stats)));
+            }
+            // Per-pair statistics are only kept for --shared-stats
+            let want_stats = matches
+                .try_get_one::<String>("shared_stats")
+                .ok()
+                .flatten()
+                .is_some();
+            if algorithm == Algorithm::Bitset && !want_stats {
+                let dist_matrix =
+                    compute_unifrac_matrix(tree, taxa_order, counts, n_samples, opts)?;
+                return Ok((dist_matrix, None));
+            }
+            let (dist_matrix, stats) =
+                compute_unifrac_matrix_using(algorithm, tree, taxa_order, counts, n_samples, opts)?;
+            (dist_matrix, Some(stats))
+        }
+        Metric::Unrooted => {
+            let (dist_matrix, stats) =
+                compute_unrooted_matrix_stats(tree, taxa_order, counts, n_samples, opts)?;
+            (dist_matrix, Some(stats))
+        }
+        Metric::Weighted | Metric::Generalized(_) => {
+            let distance =
+                PairDistance::new(metric, tree, taxa_order, counts, n_samples, opts, transform)?;
+            (distance.matrix(n_samples), None)
+        }
+    };
+    Ok(result)
+}
+
+/// --verify: check random pairs of the computed matrix against the reference
+/// implementation, before anything is written
+fn verify_distances(
+    matches: &ArgMatches,
+    metric: Metric,
+    tree: &Tree,
+    taxa_order: &[String],
+    sample_names: &[String],
+    counts: &[Vec<f64>],
+    dist_matrix: &[f64],
+    opts: &BranchOptions,
+) -> Result<()> {
+    let Some(&n_pairs) = matches.get_one::<usize>("verify") else {
+        return Ok(());
+    };
+    if metric == Metric::Unrooted {
+        bail!("--verify has no reference implementation for the unrooted metric");
+    }
+    let n = sample_names.len();
+    if n < 2 {
+        return Ok(());
+    }
+    let transform = Transform::from_name(matches.get_one::<String>("transform").unwrap()).unwrap();
+    let tolerance = *matches.get_one::<f64>("verify_tolerance").unwrap();
+    let pairs = random_pairs(n, n_pairs, &mut StdRng::seed_from_u64(1));
+
+    let mismatches = verify_pairs(dist_matrix, n, &pairs, tolerance, |i, j| {
+        reference_distance(tree, taxa_order, counts, i, j, metric, transform, opts)
+    })?;
+    if let Some(first) = mismatches.first() {
+        bail!(
+            "--verify: {} of {} {:?} distances differ from the reference by more than {}; first: {} vs {}, computed {}, reference {}",
+            mismatches.len(),
+            pairs.len(),
+            metric,
+            tolerance,
+            sample_names[first.i],
+            sample_names[first.j],
+            first.computed,
+            first.reference
+        );
+    }
+    info!(
+        "Verified {} {:?} distances against the reference implementation",
+        pairs.len(),
+        metric
+    );
+    Ok(())
+}
+
+/// Branch options for trees read by [`load_tree`], which has already applied
+/// --branch-weights (and with it --unit-branch-lengths) to the lengths
+fn branch_options(matches: &ArgMatches) -> Result<BranchOptions> {
+    let weighted = matches.contains_id("branch_weights");
+    Ok(BranchOptions {
+        weights: None,
+        unit_lengths: matches.get_flag("unit_branch_lengths") && !weighted,
+        missing_length: MissingLength::from_name(
+            matches.get_one::<String>("missing_brlen").unwrap(),
         )
-        .get_matches();
+        .unwrap(),
+        include_root_edge: matches.get_one::<String>("root_edge").unwrap() == "include",
+    })
+}
 
+/// Read the tree, restricted to the requested clade if any, with tip names
+/// normalized like the table's features
+fn load_tree(matches: &ArgMatches) -> Result<Tree> {
     let tree_file = matches.get_one::<String>("tree").unwrap();
-    let table_file = matches.get_one::<String>("table").unwrap();
-    let output_file = matches.get_one::<String>("output").unwrap();
+    let mut tree = read_tree(tree_file)?;
+    // Weights name clades of the whole tree, so they go on before --clade or
+    // rerooting can remove the named nodes
+    if let Some(file) = matches.get_one::<String>("branch_weights") {
+        let weights = read_branch_weights(file).context(ErrorKind::Parse)?;
+        if matches.get_flag("unit_branch_lengths") {
+            set_unit_lengths(&mut tree)?;
+        }
+        apply_branch_weights(&mut tree, &weights)
+            .with_context(|| format!("Invalid branch weights {}", file))
+            .context(ErrorKind::Mismatch)?;
+    }
+    if let Some(spec) = matches.get_one::<String>("clade") {
+        tree = extract_clade(&tree, spec).context(ErrorKind::Mismatch)?;
+    }
+    normalize_tips(matches, &mut tree)?;
+    apply_rooting(matches, tree)
+}
+
+/// --rooting: warn about a tree whose root has three or more children and
+/// place the root as configured
+fn apply_rooting(matches: &ArgMatches, tree: Tree) -> Result<Tree> {
+    let Some(strategy) = matches.try_get_one::<String>("rooting").ok().flatten() else {
+        return Ok(tree);
+    };
+    // The unrooted metric does not depend on the root
+    let only_unrooted = matches
+        .try_get_many::<Metric>("metric")
+        .ok()
+        .flatten()
+        .is_some_and(|mut metrics| metrics.all(|m| *m == Metric::Unrooted));
+
+    let (kind, n_children) = rootedness(&tree)?;
+    let reroot = match strategy.as_str() {
+        "none" => None,
+        "auto" if kind == Rootedness::Rooted || only_unrooted => None,
+        "auto" | "midpoint" => Some(Reroot::Midpoint),
+        spec => Some(Reroot::Outgroup(
+            spec.trim_start_matches("outgroup:").to_string(),
+        )),
+    };
+    if kind == Rootedness::Unrooted && !only_unrooted {
+        match &reroot {
+            None => warn!(
+                "The tree's root has {} children, so the tree is unrooted; computing on the root as written, which affects rooted metrics; --rooting auto midpoint-roots it",
+                n_children
+            ),
+            Some(Reroot::Midpoint) => warn!(
+                "The tree's root has {} children, so the tree is unrooted; midpoint-rooting it (--rooting)",
+                n_children
+            ),
+            Some(Reroot::Outgroup(clade)) => warn!(
+                "The tree's root has {} children, so the tree is unrooted; rooting it on {} (--rooting)",
+                n_children, clade
+            ),
+        }
+    }
+
+    let Some(how) = reroot else {
+        return Ok(tree);
+    };
+    debug!("Rerooting the tree: {:?}", how);
+    rerooted_tree(&tree, &how).context(ErrorKind::Mismatch)
+}
+
+/// Report branches without a length: an error under `--missing-brlen error`,
+/// otherwise a warning saying how they are treated
+fn check_branch_lengths(matches: &ArgMatches, tree: &Tree) -> Result<()> {
+    let missing = missing_branch_lengths(tree)?;
+    if missing == 0 || matches.get_flag("unit_branch_lengths") {
+        return Ok(());
+    }
+    let policy = matches.get_one::<String>("missing_brlen").unwrap();
+    if policy == "error" {
+        return Err(anyhow!(
+            "{} branches of the tree have no length; use --missing-brlen zero|one to compute anyway",
+            missing
+        )
+        .context(ErrorKind::Parse));
+    }
+    let length = if policy == "one" { 1 } else { 0 };
+    warn!(
+        "{} branches have no length and are treated as length {}",
+        missing, length
+    );
+    Ok(())
+}
+
+/// Fail with [`ErrorKind::InputNotFound`] unless the file exists
+fn require_file(path: &str) -> Result<()> {
+    if !Path::new(path).exists() {
+        return Err(anyhow!("{} does not exist", path).context(ErrorKind::InputNotFound));
+    }
+    Ok(())
+}
+
+fn read_tree(tree_file: &str) -> Result<Tree> {
+    require_file(tree_file)?;
+    read_newick_file(Path::new(tree_file))
+        .with_context(|| format!("Invalid newick tree {}", tree_file))
+        .context(ErrorKind::Parse)
+}
+
+fn load_matrix(matrix_file: &str) -> Result<(Vec<String>, Vec<f64>)> {
+    require_file(matrix_file)?;
+    read_matrix(matrix_file).context(ErrorKind::Parse)
+}
+
+fn load_metadata(metadata_file: &str) -> Result<Metadata> {
+    require_file(metadata_file)?;
+    read_metadata(metadata_file).context(ErrorKind::Parse)
+}
+
+/// Read a sample-feature table and apply the per-table options of
+/// compute_args (--min-rel-abund-per-sample, --negative-controls,
+/// --copy-numbers), for every command that computes distances from tables
+fn load_table(
+    matches: &ArgMatches,
+    table_file: &str,
+) -> Result<(Vec<String>, Vec<String>, Vec<Vec<f64>>)> {
+    let (taxa_order, sample_names, mut counts) = read_table(matches, table_file)?;
+
+    if let Some(&floor) = matches.try_get_one::<f64>("min_rel_abund").ok().flatten() {
+        let zeroed = apply_abundance_floor(&mut counts, sample_names.len(), floor);
+        info!(
+            "{}: {} counts below {} of their sample's total treated as absent",
+            table_file, zeroed, floor
+        );
+    }
+
+    let (sample_names, counts) = match matches
+        .try_get_one::<String>("negative_controls")
+        .ok()
+        .flatten()
+    {
+        Some(controls_file) => remove_contaminants(
+            matches,
+            controls_file,
+            table_file,
+            &taxa_order,
+            sample_names,
+            counts,
+        )?,
+        None => (sample_names, counts),
+    };
+
+    let counts = match matches.try_get_one::<String>("copy_numbers").ok().flatten() {
+        Some(copy_file) => correct_copy_numbers(copy_file, &taxa_order, counts)?,
+        None => counts,
+    };
+    Ok((taxa_order, sample_names, counts))
+}
+
+/// Read the tree and the sample-feature table
+fn load_inputs(matches: &ArgMatches) -> Result<(Tree, Vec<String>, Vec<String>, Vec<Vec<f64>>)> {
+    let table_file = table_source(matches);
 
     // Read the tree
-    let tree = Tree::from_file(Path::new(tree_file))?;
+    let tree = load_tree(matches)?;
+    check_branch_lengths(matches, &tree)?;
 
     // Read the sample-feature table
-    let (taxa_order, sample_names, presence_matrix) = read_sample_table(table_file)?;
-    assert!(
-        presence_matrix.iter().map(|row| row.len()).all_equal(),
-        "rows of the presence matrix are not all the same size..."
-    );
-    let n_samples = sample_names.len();
+    let (taxa_order, sample_names, presence_matrix) = load_table(matches, table_file)?;
+    let tips = tip_names(&tree);
+    if !taxa_order.iter().any(|t| tips.contains(t)) {
+        return Err(anyhow!(
+            "None of the {} table features match a tip of the tree",
+            taxa_order.len()
+        )
+        .context(ErrorKind::Mismatch));
+    }
+    // Either side may hold names the other lacks; say how many are left out
+    let features: HashSet<&str> = taxa_order.iter().map(|t| t.as_str()).collect();
+    let tips_without_row = tips
+        .iter()
+        .filter(|t| !features.contains(t.as_str()))
+        .count();
+    if tips_without_row > 0 {
+        warn!(
+            "{} of {} tree tips have no row in the table and count as absent from every sample",
+            tips_without_row,
+            tips.len()
+        );
+    }
+    let features_without_tip = taxa_order.iter().filter(|t| !tips.contains(*t)).count();
+    if features_without_tip > 0 {
+        warn!(
+            "{} of {} table features match no tip of the tree and are left out",
+            features_without_tip,
+            taxa_order.len()
+        );
+    }
 
-    // Compute distance matrix: n_samples x n_samples
-    let mut dist_matrix = vec![0.0; n_samples * n_samples];
+    let (sample_names, presence_matrix) =
+        match matches.try_get_one::<String>("pool_by").ok().flatten() {
+            Some(column) => pool_samples(matches, column, &sample_names, presence_matrix)?,
+            None => (sample_names, presence_matrix),
+        };
+    if let Some(&depth) = matches.try_get_one::<u64>("expected_depth").ok().flatten() {
+        let (sample_names, presence_matrix) =
+            drop_shallow_samples(depth, sample_names, presence_matrix)?;
+        return Ok((tree, taxa_order, sample_names, presence_matrix));
+    }
+    Ok((tree, taxa_order, sample_names, presence_matrix))
+}
 
-    for i in 0..n_samples {
-        dist_matrix[i * n_samples + i] = 0.0; // distance to itself = 0
-        for j in i + 1..n_samples {
-            let uni = compute_unifrac_for_pair(&tree, &taxa_order, &presence_matrix, i, j)?;
-            dist_matrix[i * n_samples + j] = uni;
-            dist_matrix[j * n_samples + i] = uni; // symmetric
-        }
+/// --expected-depth: leave out samples with fewer reads than the depth, as
+/// rarefying would
+fn drop_shallow_samples(
+    depth: u64,
+    sample_names: Vec<String>,
+    counts: Vec<Vec<f64>>,
+) -> Result<(Vec<String>, Vec<Vec<f64>>)> {
+    let depths = read_depths(&counts, sample_names.len());
+    let keep: Vec<usize> = (0..sample_names.len())
+        .filter(|&s| depths[s] >= depth)
+        .collect();
+    if keep.len() == sample_names.len() {
+        return Ok((sample_names, counts));
+    }
+    if keep.is_empty() {
+        bail!("No sample has {} reads for --expected-depth", depth);
     }
+    warn!(
+        "Leaving out {} of {} samples with fewer than {} reads",
+        sample_names.len() - keep.len(),
+        sample_names.len(),
+        depth
+    );
+    let sample_names = keep.iter().map(|&s| sample_names[s].clone()).collect();
+    let counts = select_samples(&counts, &keep);
+    Ok((sample_names, counts))
+}
 
-    // Write output matrix
-    write_matrix(&sample_names, &dist_matrix, n_samples, output_file)?;
+/// --negative-controls: score the features by their prevalence in the
+/// controls, remove or down-weight the contaminants in every sample, and
+/// leave the controls out
+fn remove_contaminants(
+    matches: &ArgMatches,
+    controls_file: &str,
+    table_file: &str,
+    taxa_order: &[String],
+    sample_names: Vec<String>,
+    mut counts: Vec<Vec<f64>>,
+) -> Result<(Vec<String>, Vec<Vec<f64>>)> {
+    require_file(controls_file)?;
+    let controls: HashSet<String> = read_feature_list(controls_file)
+        .with_context(|| controls_file.to_string())
+        .context(ErrorKind::Parse)?
+        .into_iter()
+        .collect();
+    let is_control: Vec<bool> = sample_names.iter().map(|s| controls.contains(s)).collect();
+    let n_controls = is_control.iter().filter(|c| **c).count();
+    if n_controls == 0 {
+        return Err(anyhow!(
+            "None of the {} samples in {} is in {}",
+            controls.len(),
+            controls_file,
+            table_file
+        )
+        .context(ErrorKind::Mismatch));
+    }
+    if n_controls < controls.len() {
+        warn!(
+            "{} of the samples in {} are not in {}",
+            controls.len() - n_controls,
+            controls_file,
+            table_file
+        );
+    }
+    if n_controls == sample_names.len() {
+        bail!("Every sample of {} is a negative control", table_file);
+    }
+
+    let threshold = *matches.get_one::<f64>("contaminant_threshold").unwrap();
+    let downweight = matches.get_one::<String>("contaminants").unwrap() == "downweight";
+    let scores = prevalence_scores(&counts, &is_control);
+    let mut contaminants = Vec::new();
+    for ((taxon, row), score) in taxa_order.iter().zip(counts.iter_mut()).zip(&scores) {
+        if score.p_value >= threshold {
+            continue;
+        }
+        debug!(
+            "Contaminant {}: in {:.3} of controls and {:.3} of samples, p = {:.4}",
+            taxon, score.control_prevalence, score.sample_prevalence, score.p_value
+        );
+        let factor = if downweight {
+            1.0 - score.control_prevalence
+        } else {
+            0.0
+        };
+        row.iter_mut().for_each(|v| *v *= factor);
+        contaminants.push(taxon.as_str());
+    }
+    let action = if downweight {
+        "down-weighted"
+    } else {
+        "removed"
+    };
+    info!(
+        "{} of {} features are contaminants by prevalence in {} negative controls and were {}",
+        contaminants.len(),
+        taxa_order.len(),
+        n_controls,
+        action
+    );
+    if !contaminants.is_empty() {
+        let shown = contaminants.len().min(10);
+        let more = if contaminants.len() > shown {
+            ", ..."
+        } else {
+            ""
+        };
+        info!("Contaminants: {}{}", contaminants[..shown].join(", "), more);
+    }
+
+    let keep: Vec<usize> = (0..sample_names.len())
+        .filter(|&s| !is_control[s])
+        .collect();
+    let sample_names = keep.iter().map(|&s| sample_names[s].clone()).collect();
+    let counts = select_samples(&counts, &keep);
+    Ok((sample_names, counts))
+}
+
+/// --copy-numbers: divide each feature's counts by its copy number
+fn correct_copy_numbers(
+    copy_file: &str,
+    taxa_order: &[String],
+    mut counts: Vec<Vec<f64>>,
+) -> Result<Vec<Vec<f64>>> {
+    require_file(copy_file)?;
+    let copies = read_copy_numbers(copy_file).context(ErrorKind::Parse)?;
+    let mut unlisted = 0;
+    for (taxon, row) in taxa_order.iter().zip(counts.iter_mut()) {
+        match copies.get(taxon) {
+            Some(copy_number) => row.iter_mut().for_each(|v| *v /= copy_number),
+            None => unlisted += 1,
+        }
+    }
+    if unlisted > 0 {
+        warn!(
+            "{} of {} features have no copy number in {} and keep their counts",
+            unlisted,
+            taxa_order.len(),
+            copy_file
+        );
+    }
+    Ok(counts)
+}
+
+/// --pool-by: one column per value of the metadata column, summing its
+/// samples' counts, or (--pool union) marking each feature present in any
+fn pool_samples(
+    matches: &ArgMatches,
+    column: &str,
+    sample_names: &[String],
+    counts: Vec<Vec<f64>>,
+) -> Result<(Vec<String>, Vec<Vec<f64>>)> {
+    let metadata = load_metadata(matches.get_one::<String>("metadata").unwrap())?;
+    let labels = metadata
+        .column_for(sample_names, column)
+        .context(ErrorKind::Mismatch)?;
+    let (pools, pooled) = if matches.get_one::<String>("pool").unwrap() == "union" {
+        let (pools, pooled) = resolve_duplicates(labels, binarize(counts), Duplicates::Sum)?;
+        (pools, binarize(pooled))
+    } else {
+        resolve_duplicates(labels, counts, Duplicates::Sum)?
+    };
+    info!(
+        "Pooled {} samples into {} groups of '{}'",
+        sample_names.len(),
+        pools.len(),
+        column
+    );
+    Ok((pools, pooled))
+}
+
+/// Read the inputs, then compute the distance matrix
+fn load_and_compute(matches: &ArgMatches) -> Result<(Vec<String>, Vec<f64>)> {
+    let (tree, taxa_order, sample_names, counts) = load_inputs(matches)?;
+    let opts = branch_options(matches)?;
+
+    // Compute distance matrix: n_samples x n_samples
+    let metrics = selected_metrics(matches);
+    if metrics.len() > 1 {
+        bail!("This command takes a single --metric");
+    }
+    let (dist_matrix, _) = compute_distances(
+        matches,
+        metrics[0].0,
+        Algorithm::default(),
+        &tree,
+        &taxa_order,
+        &counts,
+        sample_names.len(),
+        &opts,
+    )?;
+
+    Ok((sample_names, dist_matrix))
+}
+
+/// Write a distance matrix in the layout selected with --format
+fn write_distances(
+    matches: &ArgMatches,
+    sample_names: &[String],
+    dist_matrix: &[f64],
+    provenance: Option<&Provenance>,
+    output_file: &str,
+) -> Result<()> {
+    let n = sample_names.len();
+    if matches.get_flag("sample_summary") {
+        write_sample_summaries(
+            sample_names,
+            &sample_summaries(dist_matrix, n),
+            &format!("{}.samples.tsv", output_file),
+            force(matches),
+        )?;
+    }
+    match matches.get_one::<String>("format").map(|f| f.as_str()) {
+        Some("condensed") => {
+            write_condensed(sample_names, dist_matrix, n, output_file, force(matches))
+        }
+        Some("qza") => write_qza(
+            sample_names,
+            dist_matrix,
+            n,
+            provenance,
+            output_file,
+            force(matches),
+        ),
+        Some("pairs") => write_sorted_pairs(
+            sample_names,
+            dist_matrix,
+            n,
+            matches.get_one::<String>("sort").unwrap() == "descending",
+            matches.get_one::<usize>("top").copied(),
+            output_file,
+            force(matches),
+        ),
+        _ => write_matrix(sample_names, dist_matrix, n, output_file, force(matches)),
+    }
+}
+
+/// Index of each requested sample in the table header
+fn sample_indices(sample_names: &[String], wanted: &[&str]) -> Result<Vec<usize>> {
+    wanted
+        .iter()
+        .map(|w| {
+            sample_names
+                .iter()
+                .position(|s| s == w)
+                .with_context(|| format!("Sample '{}' not found in the table", w))
+                .context(ErrorKind::Mismatch)
+        })
+        .collect()
+}
+
+fn run_unifrac(matches: &ArgMatches) -> Result<()> {
+    let start = Instant::now();
+    if let Some(pair) = matches.get_one::<String>("debug_pair") {
+        return run_debug_pair(matches, pair);
+    }
+    if let Some(pair) = matches.get_many::<String>("pair") {
+        let names: Vec<&str> = pair.map(|s| s.as_str()).collect();
+        return run_single_pair(matches, &names);
+    }
+    if let Some(&n_pairs) = matches.get_one::<usize>("estimate") {
+        return run_estimate(matches, n_pairs);
+    }
+    let output_file = matches.get_one::<String>("output").unwrap();
+    if let Some(column) = matches.get_one::<String>("stratify_by") {
+        return run_stratified(matches, column, output_file);
+    }
+
+    // Refuse to clobber before the computation rather than after it
+    let metrics = selected_metrics(matches);
+    if matches.contains_id("expected_depth")
+        && (metrics.len() > 1 || metrics[0].0 != Metric::Unweighted)
+    {
+        bail!("--expected-depth only applies to a single unweighted --metric");
+    }
+    let single_unweighted = metrics.len() == 1 && metrics[0].0 == Metric::Unweighted;
+    if !single_unweighted {
+        for (id, flag) in [("sketch", "--sketch"), ("profile_pairs", "--profile-pairs")] {
+            if matches.contains_id(id) {
+                bail!("{} only applies to a single unweighted --metric", flag);
+            }
+        }
+        if explicitly_given(matches, "algorithm") {
+            bail!("--algorithm only applies to a single unweighted --metric");
+        }
+        #[cfg(all(feature = "numa", target_os = "linux"))]
+        if matches.get_flag("numa") {
+            bail!("--numa only applies to a single unweighted --metric");
+        }
+    }
+    #[cfg(all(feature = "numa", target_os = "linux"))]
+    if matches.get_flag("numa") && matches.get_one::<String>("algorithm").unwrap() != "bitset" {
+        bail!(
+            "--numa splits the bitset algorithm's rows over the nodes; leave --algorithm at bitset"
+        );
+    }
+    // Pairs are timed one by one, which only the bitset algorithm does
+    if matches.contains_id("profile_pairs")
+        && matches.get_one::<String>("algorithm").unwrap() != "bitset"
+    {
+        bail!("--profile-pairs times the bitset algorithm's pairs; leave --algorithm at bitset");
+    }
+    // Sketched distances go straight to the output, without a matrix
+    if matches.contains_id("sketch") {
+        if matches.get_one::<String>("format").unwrap() != "condensed" {
+            bail!("--sketch streams the distances to the output without holding the matrix; add --format condensed");
+        }
+        let to_db = matches.try_get_one::<String>("to_db").ok().flatten();
+        if matches.get_flag("sample_summary") || to_db.is_some() {
+            bail!(
+                "--sample-summary and --to-db need the full matrix, which --sketch does not keep"
+            );
+        }
+    }
+    check_transform(matches)?;
+    if metrics.len() > 1 {
+        for (_, name) in &metrics {
+            check_output(&labelled_output_name(output_file, name), force(matches))?;
+        }
+    } else {
+        check_output(output_file, force(matches))?;
+    }
+
+    let mut provenance = start_provenance(matches)?;
+    let cache = result_cache(matches)?;
+    if let Some((dir, key)) = &cache {
+        if let Some(cached) = load_cached(dir, key) {
+            info!("Reusing cached result {} (--no-cache to recompute)", key);
+            let mut written = Vec::new();
+            for (_, name) in &metrics {
+                let metric_file = if metrics.len() > 1 {
+                    labelled_output_name(output_file, name)
+                } else {
+                    output_file.clone()
+                };
+                let dist_matrix = cached
+                    .matrix(name)
+                    .with_context(|| format!("Cache entry {} has no {} matrix", key, name))?;
+                write_distances(
+                    matches,
+                    &cached.sample_names,
+                    &dist_matrix,
+                    provenance.as_ref(),
+                    &metric_file,
+                )?;
+                record_in_db(matches, name, &cached.sample_names, &dist_matrix)?;
+                written.push(metric_file);
+            }
+            return finish_provenance(provenance.as_mut(), &written, output_file, force(matches));
+        }
+    }
+
+    let (tree, taxa_order, sample_names, counts) = load_inputs(matches)?;
+    let opts = branch_options(matches)?;
+    let n_samples = sample_names.len();
+    info!(
+        "Loaded {} samples x {} features and a tree of {} nodes",
+        n_samples,
+        taxa_order.len(),
+        tree.size()
+    );
+
+    if let Some(dump_file) = matches.get_one::<String>("dump_b") {
+        check_output(dump_file, force(matches))?;
+        write_b_npz(&tree, &opts, dump_file, force(matches))?;
+        info!("Wrote B and branch lengths to {}", dump_file);
+    }
+    if let Some(embedding_file) = matches.get_one::<String>("embeddings") {
+        check_output(embedding_file, force(matches))?;
+        let transform =
+            Transform::from_name(matches.get_one::<String>("transform").unwrap()).unwrap();
+        let embeddings =
+            branch_embeddings(&tree, &taxa_order, &counts, n_samples, &opts, transform)?;
+        write_embeddings_npz(
+            &tree,
+            &sample_names,
+            &embeddings,
+            embedding_file,
+            force(matches),
+        )?;
+        info!("Wrote the samples' branch embeddings to {}", embedding_file);
+    }
+    if let Some(presence_file) = matches.get_one::<String>("presence") {
+        check_output(presence_file, force(matches))?;
+        let sets = BranchSets::new(&tree, &taxa_order, &counts, n_samples, &opts)?;
+        let branches: Vec<Vec<usize>> = (0..n_samples).map(|s| sets.branches(s)).collect();
+        if presence_file.ends_with(".mtx") {
+            let labels = (0..tree.size())
+                .map(|b| branch_label(&tree, b))
+                .collect::<Result<Vec<_>>>()?;
+            write_presence_mtx(
+                &sample_names,
+                &labels,
+                &branches,
+                presence_file,
+                force(matches),
+            )?;
+        } else {
+            write_presence_npz(
+                &tree,
+                &sample_names,
+                &branches,
+                presence_file,
+                force(matches),
+            )?;
+        }
+        info!("Wrote the samples' branch sets to {}", presence_file);
+    }
+
+    let algorithm = Algorithm::from_name(matches.get_one::<String>("algorithm").unwrap()).unwrap();
+
+    let mut written = Vec::new();
+    if metrics.len() > 1 {
+        if matches.contains_id("shared_stats") {
+            bail!("--shared-stats needs a single unweighted metric");
+        }
+        let transform =
+            Transform::from_name(matches.get_one::<String>("transform").unwrap()).unwrap();
+        let kinds: Vec<Metric> = metrics.iter().map(|(m, _)| *m).collect();
+        let matrices = compute_metrics(
+            &tree,
+            &taxa_order,
+            &counts,
+            n_samples,
+            &opts,
+            &kinds,
+            transform,
+        )?;
+        for ((metric, name), dist_matrix) in metrics.iter().zip(matrices.iter()) {
+            verify_distances(
+                matches,
+                *metric,
+                &tree,
+                &taxa_order,
+                &sample_names,
+                &counts,
+                dist_matrix,
+                &opts,
+            )?;
+            let metric_file = labelled_output_name(output_file, name);
+            write_distances(
+                matches,
+                &sample_names,
+                dist_matrix,
+                provenance.as_ref(),
+                &metric_file,
+            )?;
+            record_in_db(matches, name, &sample_names, dist_matrix)?;
+            info!("Wrote the {} matrix to {}", name, metric_file);
+            written.push(metric_file);
+        }
+        let named: Vec<(String, &[f64])> = metrics
+            .iter()
+            .zip(matrices.iter())
+            .map(|((_, name), m)| (name.clone(), m.as_slice()))
+            .collect();
+        cache_result(&cache, &sample_names, &named);
+    } else if let Some(&k) = matches.get_one::<usize>("sketch") {
+        let sketches = build_sketches(&tree, &taxa_order, &counts, n_samples, &opts, k, 0)?;
+        write_condensed_pairs(
+            &sample_names,
+            sketch_pairs(&sketches, k),
+            output_file,
+            force(matches),
+        )?;
+        info!(
+            "Wrote {} sketched distances to {} in {:.2}s",
+            n_samples * n_samples.saturating_sub(1) / 2,
+            output_file,
+            start.elapsed().as_secs_f64()
+        );
+        written.push(output_file.clone());
+    } else {
+        // Compute distance matrix: n_samples x n_samples
+        let (dist_matrix, pair_stats) =
+            if let Some(&depth) = matches.get_one::<u64>("expected_depth") {
+                let dist_matrix =
+                    compute_expected_matrix(&tree, &taxa_order, &counts, n_samples, &opts, depth)?;
+                (dist_matrix, None)
+            } else if let Some(profile_file) = matches.get_one::<String>("profile_pairs") {
+                check_output(profile_file, force(matches))?;
+                let sets = BranchSets::new(&tree, &taxa_order, &counts, n_samples, &opts)?;
+                let (dist_matrix, timings) = compute_unifrac_matrix_timed(&sets);
+                let nanos: Vec<f64> = timings.iter().map(|t| t.nanos as f64).collect();
+                let summary = summarize(&nanos);
+                info!(
+                    "Per-pair time (ns): min {:.0}, q1 {:.0}, median {:.0}, q3 {:.0}, max {:.0}",
+                    summary.min, summary.q1, summary.median, summary.q3, summary.max
+                );
+                write_pair_timings(&sample_names, &timings, profile_file, force(matches))?;
+                (dist_matrix, None)
+            } else {
+                compute_distances(
+                    matches,
+                    metrics[0].0,
+                    algorithm,
+                    &tree,
+                    &taxa_order,
+                    &counts,
+                    n_samples,
+                    &opts,
+                )?
+            };
+
+        info!(
+            "Computed {} distances in {:.2}s",
+            n_samples * n_samples.saturating_sub(1) / 2,
+            start.elapsed().as_secs_f64()
+        );
+
+        verify_distances(
+            matches,
+            metrics[0].0,
+            &tree,
+            &taxa_order,
+            &sample_names,
+            &counts,
+            &dist_matrix,
+            &opts,
+        )?;
+
+        // Write output matrix
+        write_distances(
+            matches,
+            &sample_names,
+            &dist_matrix,
+            provenance.as_ref(),
+            output_file,
+        )?;
+        info!("Wrote the distance matrix to {}", output_file);
+        written.push(output_file.clone());
+        record_in_db(matches, &metrics[0].1, &sample_names, &dist_matrix)?;
+        cache_result(
+            &cache,
+            &sample_names,
+            &[(metrics[0].1.clone(), dist_matrix.as_slice())],
+        );
+        if let Some(stats_file) = matches.get_one::<String>("shared_stats") {
+            let pair_stats = pair_stats.context("--shared-stats needs an unweighted metric")?;
+            write_pair_stats(&sample_names, &pair_stats, stats_file, force(matches))?;
+        }
+    }
+
+    if matches.get_flag("run_summary") {
+        let mut summary = RunSummary {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            options: collect_options(matches),
+            ..Default::default()
+        };
+        summary.add_input(matches.get_one::<String>("tree").unwrap())?;
+        summary.add_input(table_source(matches))?;
+        if let Some(weights_file) = matches.get_one::<String>("branch_weights") {
+            summary.add_input(weights_file)?;
+        }
+        summary.describe_inputs(&tree, &taxa_order, n_samples)?;
+        summary.wall_time_secs = start.elapsed().as_secs_f64();
+        let mut branch_matrix_bytes = 0;
+        if metrics.iter().any(|(m, _)| !m.is_weighted()) {
+            branch_matrix_bytes +=
+                algorithm.branch_matrix_bytes(summary.n_branches, summary.n_tips, n_samples);
+        }
+        if metrics.iter().any(|(m, _)| m.is_weighted()) {
+            // Per-branch abundance proportions, samples x branches
+            branch_matrix_bytes += summary.n_branches * n_samples * std::mem::size_of::<f64>();
+        }
+        summary.memory = MemoryReport {
+            peak_rss_bytes: peak_rss_bytes(),
+            presence_matrix_bytes: taxa_order.len() * n_samples * std::mem::size_of::<f64>(),
+            branch_matrix_bytes,
+            distance_matrix_bytes: if matches.contains_id("sketch") {
+                0
+            } else {
+                metrics.len() * n_samples * n_samples * std::mem::size_of::<f64>()
+            },
+        };
+        write_summary(
+            &summary,
+            &format!("{}.summary.json", output_file),
+            force(matches),
+        )?;
+    }
+
+    finish_provenance(provenance.as_mut(), &written, output_file, force(matches))
+}
+
+/// With --provenance, a record of this run started now, hashing the input
+/// files before they are read
+fn start_provenance(matches: &ArgMatches) -> Result<Option<Provenance>> {
+    if !matches.get_flag("provenance") {
+        return Ok(None);
+    }
+    let inputs: Vec<&str> = INPUT_FILE_OPTIONS
+        .iter()
+        .filter_map(|id| matches.try_get_one::<String>(id).ok().flatten())
+        .map(|f| f.as_str())
+        .collect();
+    let provenance = Provenance::start(&inputs, collect_options(matches))
+        .context("Failed to hash the inputs for --provenance")?;
+    Ok(Some(provenance))
+}
+
+/// Hash the `written` outputs into the record and write it beside the output
+fn finish_provenance(
+    provenance: Option<&mut Provenance>,
+    written: &[String],
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let Some(provenance) = provenance else {
+        return Ok(());
+    };
+    provenance.finish(written)?;
+    let provenance_file = format!("{}.provenance.json", output_file);
+    write_provenance(provenance, &provenance_file, force)?;
+    info!("Wrote the provenance record to {}", provenance_file);
+    Ok(())
+}
+
+/// Options that only change what is written or logged, or how fast, not the
+/// distances
+const OUTPUT_ONLY_OPTIONS: &[&str] = &[
+    "output",
+    "format",
+    "sort",
+    "top",
+    "sample_summary",
+    "to_db",
+    "force",
+    "log_format",
+    "verbose",
+    "quiet",
+    "json_errors",
+    "cache_dir",
+    "no_cache",
+    "threads",
+    "provenance",
+];
+
+/// Options naming input files, which enter the cache key by content
+const INPUT_FILE_OPTIONS: &[&str] = &[
+    "tree",
+    "table",
+    "db",
+    "branch_weights",
+    "id_map",
+    "sequences",
+    "copy_numbers",
+    "negative_controls",
+    "metadata",
+];
+
+/// Cache directory and key of this run; None with --no-cache, without a
+/// cache directory, or when the run also writes per-pair outputs that the
+/// cache does not keep
+fn result_cache(matches: &ArgMatches) -> Result<Option<(PathBuf, String)>> {
+    let side_outputs = [
+        "shared_stats",
+        "profile_pairs",
+        "dump_b",
+        "embeddings",
+        "presence",
+        "verify",
+    ];
+    if matches.get_flag("no_cache")
+        || matches.get_flag("run_summary")
+        || side_outputs.iter().any(|id| matches.contains_id(id))
+    {
+        return Ok(None);
+    }
+    let Some(dir) = matches.get_one::<String>("cache_dir").map(PathBuf::from) else {
+        return Ok(None);
+    };
+
+    let files: Vec<(&str, &str)> = INPUT_FILE_OPTIONS
+        .iter()
+        .filter_map(|id| {
+            let file = matches.try_get_one::<String>(id).ok().flatten()?;
+            Some((*id, file.as_str()))
+        })
+        .collect();
+    let mut options = collect_options(matches);
+    options.retain(|id, _| {
+        !OUTPUT_ONLY_OPTIONS.contains(&id.as_str()) && !INPUT_FILE_OPTIONS.contains(&id.as_str())
+    });
+    let key = cache_key(&files, &options)?;
+    debug!("Result cache key {} in {}", key, dir.display());
+    Ok(Some((dir, key)))
+}
+
+/// Store freshly computed matrices in the cache; failing to is only a warning
+fn cache_result(
+    cache: &Option<(PathBuf, String)>,
+    sample_names: &[String],
+    matrices: &[(String, &[f64])],
+) {
+    let Some((dir, key)) = cache else {
+        return;
+    };
+    if let Err(err) = store_cached(dir, key, &CachedResult::new(sample_names, matrices)) {
+        warn!("Could not cache the result in {}: {:#}", dir.display(), err);
+    }
+}
+
+/// Every option present on the command line (or defaulted), as raw strings
+fn collect_options(matches: &ArgMatches) -> BTreeMap<String, String> {
+    matches
+        .ids()
+        .filter_map(|id| {
+            let values = matches.get_raw(id.as_str())?;
+            let joined = values
+                .map(|v| v.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+                .join(",");
+            Some((id.to_string(), joined))
+        })
+        .collect()
+}
+
+fn run_stratified(matches: &ArgMatches, column: &str, output_file: &str) -> Result<()> {
+    let mut provenance = start_provenance(matches)?;
+    let (tree, taxa_order, sample_names, presence_matrix) = load_inputs(matches)?;
+    let metadata = load_metadata(matches.get_one::<String>("metadata").unwrap())?;
+    let labels = metadata
+        .column_for(&sample_names, column)
+        .context(ErrorKind::Mismatch)?;
+
+    let mut by_group: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (s, label) in labels.iter().enumerate() {
+        by_group.entry(label.as_str()).or_default().push(s);
+    }
+    let groups: Vec<Vec<usize>> = by_group.values().cloned().collect();
+
+    // Labels that only differ in characters made filename-safe would share a file
+    let mut group_files: HashMap<String, &str> = HashMap::new();
+    for label in by_group.keys() {
+        let group_file = labelled_output_name(output_file, label);
+        if let Some(other) = group_files.insert(group_file.clone(), label) {
+            bail!(
+                "Groups '{}' and '{}' of {} would both be written to {}",
+                other,
+                label,
+                column,
+                group_file
+            );
+        }
+        check_output(&group_file, force(matches))?;
+    }
+
+    let opts = branch_options(matches)?;
+    let metric = single_metric(matches, "--stratify-by")?;
+    let matrices = if metric == Metric::Unweighted {
+        compute_unifrac_groups(&tree, &taxa_order, &presence_matrix, &groups, &opts)?
+    } else {
+        groups
+            .iter()
+            .map(|members| {
+                let counts = select_samples(&presence_matrix, members);
+                let (dist_matrix, _) = compute_distances(
+                    matches,
+                    metric,
+                    Algorithm::default(),
+                    &tree,
+                    &taxa_order,
+                    &counts,
+                    members.len(),
+                    &opts,
+                )?;
+                Ok(dist_matrix)
+            })
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    let mut written = Vec::new();
+    for ((label, members), dist_matrix) in by_group.iter().zip(matrices.iter()) {
+        let names: Vec<String> = members.iter().map(|s| sample_names[*s].clone()).collect();
+        let group_file = labelled_output_name(output_file, label);
+        write_distances(
+            matches,
+            &names,
+            dist_matrix,
+            provenance.as_ref(),
+            &group_file,
+        )?;
+        written.push(group_file);
+    }
+
+    finish_provenance(provenance.as_mut(), &written, output_file, force(matches))
+}
+
+/// `out.txt` + `gut` -> `out.gut.txt`, with the label (a group or a metric) made filename-safe
+fn labelled_output_name(output_file: &str, label: &str) -> String {
+    let safe: String = label
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let path = Path::new(output_file);
+    match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(ext)) => path
+            .with_file_name(format!(
+                "{}.{}.{}",
+                stem.to_string_lossy(),
+                safe,
+                ext.to_string_lossy()
+            ))
+            .to_string_lossy()
+            .into_owned(),
+        _ => format!("{}.{}", output_file, safe),
+    }
+}
+
+fn run_debug_pair(matches: &ArgMatches, pair: &str) -> Result<()> {
+    let names: Vec<&str> = pair.split(',').map(|s| s.trim()).collect();
+    if names.len() != 2 {
+        bail!(
+            "--debug-pair expects two sample names separated by a comma, got '{}'",
+            pair
+        );
+    }
+    if single_metric(matches, "--debug-pair")? != Metric::Unweighted {
+        bail!("--debug-pair shows the shared and unique branches of unweighted UniFrac only");
+    }
+    let (tree, taxa_order, sample_names, presence_matrix) = load_inputs(matches)?;
+    let idx = sample_indices(&sample_names, &names)?;
+    let opts = branch_options(matches)?;
+
+    let rendering = render_pair_subtree(
+        &tree,
+        &taxa_order,
+        &presence_matrix,
+        idx[0],
+        idx[1],
+        [names[0], names[1]],
+        &opts,
+    )?;
+    print!("{}", rendering);
+
+    Ok(())
+}
+
+fn run_single_pair(matches: &ArgMatches, names: &[&str]) -> Result<()> {
+    let metric = single_metric(matches, "--pair")?;
+    let (tree, taxa_order, sample_names, presence_matrix) = load_inputs(matches)?;
+    let idx = sample_indices(&sample_names, names)?;
+    let opts = branch_options(matches)?;
+
+    let distance = if metric == Metric::Unweighted {
+        let context = UniFracContext::new(
+            &tree,
+            &taxa_order,
+            &presence_matrix,
+            sample_names.len(),
+            &opts,
+        )?;
+        compute_unifrac_for_pair(&context, idx[0], idx[1])
+    } else {
+        let counts = select_samples(&presence_matrix, &idx);
+        let (dist_matrix, _) = compute_distances(
+            matches,
+            metric,
+            Algorithm::default(),
+            &tree,
+            &taxa_order,
+            &counts,
+            2,
+            &opts,
+        )?;
+        dist_matrix[1]
+    };
+    println!("{}", distance);
+
+    Ok(())
+}
+
+/// Distances of random pairs, summarized; pairs are drawn with a fixed seed
+/// so that reruns agree
+fn run_estimate(matches: &ArgMatches, n_pairs: usize) -> Result<()> {
+    let metrics = selected_metrics(matches);
+    if metrics.len() > 1 {
+        bail!("--estimate takes a single --metric");
+    }
+    let (tree, taxa_order, sample_names, counts) = load_inputs(matches)?;
+    let opts = branch_options(matches)?;
+    let n = sample_names.len();
+    if n < 2 {
+        bail!("--estimate needs at least two samples");
+    }
+    let pairs = random_pairs(n, n_pairs, &mut StdRng::seed_from_u64(1));
+
+    let transform = Transform::from_name(matches.get_one::<String>("transform").unwrap()).unwrap();
+    let metric = PairDistance::new(
+        metrics[0].0,
+        &tree,
+        &taxa_order,
+        &counts,
+        n,
+        &opts,
+        transform,
+    )?;
+    let distances: Vec<f64> = pairs
+        .par_iter()
+        .map(|&(i, j)| metric.distance(i, j))
+        .collect();
+    let defined: Vec<f64> = distances.into_iter().filter(|d| !d.is_nan()).collect();
+    if defined.len() < n_pairs {
+        warn!(
+            "{} sampled pairs have an undefined distance (empty samples) and were left out",
+            n_pairs - defined.len()
+        );
+    }
+
+    println!("pairs\t{} of {}", defined.len(), n * (n - 1) / 2);
+    println!("statistic\testimate\tci95_low\tci95_high");
+    for e in estimate_distribution(&defined, &[0.05, 0.25, 0.5, 0.75, 0.95]) {
+        println!(
+            "{}\t{:.6}\t{:.6}\t{:.6}",
+            e.statistic, e.value, e.ci_low, e.ci_high
+        );
+    }
+    Ok(())
+}
+
+fn run_reference_build(matches: &ArgMatches) -> Result<()> {
+    let output_file = matches.get_one::<String>("output").unwrap();
+    check_output(output_file, force(matches))?;
+    let (tree, taxa_order, sample_names, presence_matrix) = load_inputs(matches)?;
+    let opts = branch_options(matches)?;
+    let reference =
+        ReferenceSet::build(&tree, &taxa_order, &sample_names, &presence_matrix, &opts)?;
+    write_reference(&reference, output_file, force(matches))
+}
+
+fn run_reference_index(matches: &ArgMatches) -> Result<()> {
+    let output_file = matches.get_one::<String>("output").unwrap();
+    check_output(output_file, force(matches))?;
+    let (tree, taxa_order, sample_names, presence_matrix) = load_inputs(matches)?;
+    let opts = branch_options(matches)?;
+    let reference =
+        ReferenceSet::build(&tree, &taxa_order, &sample_names, &presence_matrix, &opts)?;
+
+    let start = Instant::now();
+    let index = ReferenceIndex::build(
+        reference,
+        *matches.get_one::<usize>("neighbors").unwrap(),
+        *matches.get_one::<usize>("ef_construction").unwrap(),
+        *matches.get_one::<u64>("seed").unwrap(),
+    );
+    info!(
+        "Indexed {} reference samples in {:.2}s",
+        index.graph.len(),
+        start.elapsed().as_secs_f64()
+    );
+    write_index(&index, output_file, force(matches))
+}
+
+fn run_reference_search(matches: &ArgMatches) -> Result<()> {
+    let output_file = matches.get_one::<String>("output").unwrap();
+    check_output(output_file, force(matches))?;
+    let index_file = matches.get_one::<String>("index").unwrap();
+    require_file(index_file)?;
+    let index = read_index(index_file).context(ErrorKind::Parse)?;
+    let table_file = matches.get_one::<String>("table").unwrap();
+    let (taxa_order, sample_names, counts) = read_table(matches, table_file)?;
+    let k = *matches.get_one::<usize>("k").unwrap();
+    let ef = *matches.get_one::<usize>("ef").unwrap();
+
+    let start = Instant::now();
+    let results: Vec<Option<Vec<(&str, f64)>>> = (0..sample_names.len())
+        .into_par_iter()
+        .map(|s| {
+            let features: Vec<String> = taxa_order
+                .iter()
+                .zip(counts.iter())
+                .filter(|(_, row)| row[s] > 0.0)
+                .map(|(t, _)| t.clone())
+                .collect();
+            let (query, matched) = index.reference.query_bits(&features);
+            (matched > 0).then(|| index.nearest(&query, k, ef))
+        })
+        .collect();
+    info!(
+        "Searched {} query samples in {:.2}s",
+        sample_names.len(),
+        start.elapsed().as_secs_f64()
+    );
+
+    let mut file = OutputFile::create(output_file, force(matches))?;
+    writeln!(file, "query\trank\treference\tdistance")?;
+    let mut unmatched = 0;
+    for (name, nearest) in sample_names.iter().zip(results) {
+        let Some(nearest) = nearest else {
+            unmatched += 1;
+            continue;
+        };
+        for (rank, (reference, distance)) in nearest.iter().enumerate() {
+            writeln!(
+                file,
+                "{}\t{}\t{}\t{:.6}",
+                name,
+                rank + 1,
+                reference,
+                distance
+            )?;
+        }
+    }
+    file.commit()?;
+    if unmatched > 0 {
+        warn!(
+            "{} query samples have no feature on the reference tree and were skipped",
+            unmatched
+        );
+    }
+    Ok(())
+}
+
+fn run_reference_query(matches: &ArgMatches) -> Result<()> {
+    let reference_file = matches.get_one::<String>("reference").unwrap();
+    require_file(reference_file)?;
+    let reference = read_reference(reference_file).context(ErrorKind::Parse)?;
+    let features_file = matches.get_one::<String>("features").unwrap();
+    if features_file != "-" {
+        require_file(features_file)?;
+    }
+    let features = read_feature_list(features_file).context(ErrorKind::Parse)?;
+
+    let (query, matched) = reference.query_bits(&features);
+    if matched == 0 {
+        return Err(anyhow!(
+            "None of the {} query features match a tip of the reference tree",
+            features.len()
+        )
+        .context(ErrorKind::Mismatch));
+    }
+
+    let mut out = std::io::stdout().lock();
+    for (name, distance) in reference.distances(&query) {
+        writeln!(out, "{}\t{}", name, distance)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "grpc")]
+fn run_serve_grpc(matches: &ArgMatches) -> Result<()> {
+    let (tree, taxa_order, sample_names, presence_matrix) = load_inputs(matches)?;
+    let inputs = unifrac::grpc::LoadedInputs {
+        tree,
+        taxa_order,
+        sample_names,
+        presence_matrix,
+        opts: branch_options(matches)?,
+    };
+    let addr = *matches.get_one::<std::net::SocketAddr>("addr").unwrap();
+    info!("Serving {} samples on {}", inputs.sample_names.len(), addr);
+    unifrac::grpc::serve(inputs, addr)
+}
+
+#[cfg(feature = "server")]
+fn run_serve(matches: &ArgMatches) -> Result<()> {
+    let metric = single_metric(matches, "serve")?;
+    check_transform(matches)?;
+    let transform = Transform::from_name(matches.get_one::<String>("transform").unwrap()).unwrap();
+    let tree = load_tree(matches)?;
+    check_branch_lengths(matches, &tree)?;
+    let opts = branch_options(matches)?;
+    let addr = *matches.get_one::<std::net::SocketAddr>("addr").unwrap();
+    let workdir = std::path::PathBuf::from(matches.get_one::<String>("workdir").unwrap());
+    // Uploaded tables get the same parsing, renaming and filtering as tables given with -i
+    let table_options = matches.clone();
+    let read_table: unifrac::server::TableReader =
+        Box::new(move |table_file| load_table(&table_options, table_file));
+    info!("Serving on http://{}", addr);
+    unifrac::server::serve(tree, opts, metric, transform, read_table, workdir, addr)
+}
+
+fn run_pcoa(matches: &ArgMatches) -> Result<()> {
+    let output_file = matches.get_one::<String>("output").unwrap();
+    let n_axes = *matches.get_one::<usize>("axes").unwrap();
+
+    let (sample_names, ordination) = match matches.get_one::<usize>("landmarks") {
+        Some(&m) => {
+            if selected_metrics(matches)[0].0 != Metric::Unweighted {
+                bail!("--landmarks only supports the unweighted metric");
+            }
+            let (tree, taxa_order, sample_names, counts) = load_inputs(matches)?;
+            let opts = branch_options(matches)?;
+            let sets = BranchSets::new(&tree, &taxa_order, &counts, sample_names.len(), &opts)?;
+            let (landmarks, dists) = landmark_distances(&sets, m);
+            let ordination = landmark_pcoa(&dists, &landmarks, sample_names.len(), n_axes)?;
+            (sample_names, ordination)
+        }
+        None => {
+            let (sample_names, dist_matrix) = match matches.get_one::<String>("matrix") {
+                Some(matrix_file) => load_matrix(matrix_file)?,
+                None => load_and_compute(matches)?,
+            };
+            let ordination = pcoa(&dist_matrix, sample_names.len(), n_axes)?;
+            (sample_names, ordination)
+        }
+    };
+    write_pcoa(&sample_names, &ordination, output_file, force(matches))?;
+
+    #[cfg(feature = "plots")]
+    if let Some(plot_file) = matches.get_one::<String>("plot") {
+        let groups = match matches.get_one::<String>("color_by") {
+            Some(column) => {
+                let metadata = load_metadata(matches.get_one::<String>("metadata").unwrap())?;
+                let groups = metadata
+                    .column_for(&sample_names, column)
+                    .context(ErrorKind::Mismatch)?;
+                Some((column.as_str(), groups))
+            }
+            None => None,
+        };
+        write_pcoa_html(
+            &sample_names,
+            &ordination,
+            groups.as_ref().map(|(c, g)| (*c, g.as_slice())),
+            plot_file,
+            force(matches),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn run_silhouette(matches: &ArgMatches) -> Result<()> {
+    let (sample_names, dist_matrix, labels) = load_grouping(matches)?;
+    let widths = silhouette(&dist_matrix, sample_names.len(), &labels);
+
+    let mut by_group: BTreeMap<&str, Vec<f64>> = BTreeMap::new();
+    for (label, w) in labels.iter().zip(widths.iter()) {
+        by_group.entry(label.as_str()).or_default().push(*w);
+    }
+    println!("group\tn\tmean_silhouette");
+    for (group, values) in &by_group {
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        println!("{}\t{}\t{:.6}", group, values.len(), mean);
+    }
+    let overall = widths.iter().sum::<f64>() / widths.len() as f64;
+    println!("overall\t{}\t{:.6}", widths.len(), overall);
+
+    if let Some(output_file) = matches.get_one::<String>("output") {
+        let rows = sample_names
+            .iter()
+            .zip(labels.iter().zip(widths.iter()))
+            .map(|(s, (label, w))| (s.clone(), vec![label.clone(), format!("{:.6}", w)]))
+            .collect();
+        let widths = Metadata::from_rows(vec!["group".into(), "silhouette".into()], rows)?;
+        write_metadata(&widths, output_file, force(matches))?;
+    }
+    Ok(())
+}
+
+fn run_group_distances(matches: &ArgMatches) -> Result<()> {
+    let output_file = matches.get_one::<String>("output").unwrap();
+    check_output(output_file, force(matches))?;
+    let (sample_names, dist_matrix, labels) = load_grouping(matches)?;
+    let n = sample_names.len();
+    let (groups, group_of) = group_indices(&labels);
+    let means = group_mean_distances(&dist_matrix, n, &group_of, groups.len());
+
+    let mut columns = vec!["group".to_string(), "nearest_group".to_string()];
+    columns.extend(groups.iter().map(|g| format!("mean_to_{}", g)));
+    let mut closer_elsewhere = Vec::new();
+    let mut rows = Vec::with_capacity(n);
+    for (i, s) in sample_names.iter().enumerate() {
+        let row = &means[i * groups.len()..(i + 1) * groups.len()];
+        let nearest = (0..groups.len())
+            .filter(|g| !row[*g].is_nan())
+            .min_by(|a, b| row[*a].total_cmp(&row[*b]));
+        let nearest_name = nearest.map(|g| groups[g].clone()).unwrap_or_default();
+        if nearest.is_some_and(|g| g != group_of[i]) {
+            closer_elsewhere.push((s, &labels[i], nearest_name.clone()));
+        }
+        let mut values = vec![labels[i].clone(), nearest_name];
+        values.extend(row.iter().map(|m| format!("{:.6}", m)));
+        rows.push((s.clone(), values));
+    }
+
+    println!(
+        "{} of {} samples are closer on average to another group than to their own",
+        closer_elsewhere.len(),
+        n
+    );
+    for (s, own, nearest) in &closer_elsewhere {
+        println!("{}\t{} -> {}", s, own, nearest);
+    }
+    write_metadata(
+        &Metadata::from_rows(columns, rows)?,
+        output_file,
+        force(matches),
+    )
+}
+
+fn run_outliers(matches: &ArgMatches) -> Result<()> {
+    let (sample_names, dist_matrix) = load_matrix(matches.get_one::<String>("matrix").unwrap())?;
+    let threshold = *matches.get_one::<f64>("threshold").unwrap();
+    let medians = median_distances(&dist_matrix, sample_names.len());
+    let scores = robust_z(&medians);
+
+    let mut order: Vec<usize> = (0..sample_names.len()).collect();
+    order.sort_by(|a, b| scores[*b].total_cmp(&scores[*a]));
+    let flagged: Vec<usize> = order
+        .iter()
+        .copied()
+        .filter(|s| scores[*s] > threshold)
+        .collect();
+    println!(
+        "{} of {} samples are outliers (more than {} MADs above the median)",
+        flagged.len(),
+        sample_names.len(),
+        threshold
+    );
+    for s in &flagged {
+        println!(
+            "{}\t{:.6}\t{:.2}",
+            sample_names[*s], medians[*s], scores[*s]
+        );
+    }
+
+    if let Some(output_file) = matches.get_one::<String>("output") {
+        let rows = order
+            .iter()
+            .map(|s| {
+                let outlier = if scores[*s] > threshold { "yes" } else { "no" };
+                (
+                    sample_names[*s].clone(),
+                    vec![
+                        format!("{:.6}", medians[*s]),
+                        format!("{:.4}", scores[*s]),
+                        outlier.to_string(),
+                    ],
+                )
+            })
+            .collect();
+        let columns = vec![
+            "median_distance".into(),
+            "robust_z".into(),
+            "outlier".into(),
+        ];
+        write_metadata(
+            &Metadata::from_rows(columns, rows)?,
+            output_file,
+            force(matches),
+        )?;
+    }
+    Ok(())
+}
+
+fn run_longitudinal(matches: &ArgMatches) -> Result<()> {
+    let (sample_names, dist_matrix) = load_matrix(matches.get_one::<String>("matrix").unwrap())?;
+    let metadata = load_metadata(matches.get_one::<String>("metadata").unwrap())?;
+    let column = |id: &str| {
+        metadata
+            .column_for(&sample_names, matches.get_one::<String>(id).unwrap())
+            .context(ErrorKind::Mismatch)
+    };
+    let subjects = column("subject")?;
+    let timepoints = column("timepoint")?;
+
+    let pairs = longitudinal_pairs(&subjects, &timepoints);
+    write_longitudinal(
+        &pairs,
+        &sample_names,
+        &subjects,
+        &timepoints,
+        &dist_matrix,
+        matches.get_one::<String>("output").unwrap(),
+        force(matches),
+    )
+}
+
+fn run_permanova(matches: &ArgMatches) -> Result<()> {
+    let (sample_names, dist_matrix, labels) = load_grouping(matches)?;
+    let n = sample_names.len();
+    let permutations = *matches.get_one::<usize>("permutations").unwrap();
+    let mut rng = StdRng::seed_from_u64(*matches.get_one::<u64>("seed").unwrap());
+
+    let mut tests = vec![permanova(&dist_matrix, n, &labels, permutations, &mut rng)?];
+    if matches.get_flag("pairwise") {
+        tests.extend(pairwise_permanova(
+            &dist_matrix,
+            n,
+            &labels,
+            permutations,
+            &mut rng,
+        ));
+    }
+
+    match matches.get_one::<String>("output") {
+        Some(output_file) => write_permanova(&tests, output_file, force(matches)),
+        None => {
+            println!("group_a\tgroup_b\tn\tpseudo_f\tp_value\tq_value");
+            for t in &tests {
+                println!(
+                    "{}\t{}\t{}\t{:.6}\t{:.6}\t{:.6}",
+                    t.group_a, t.group_b, t.n, t.f_statistic, t.p_value, t.q_value
+                );
+            }
+            Ok(())
+        }
+    }
+}
+
+/// More variables than this make enumerating their subsets impractical
+const BIOENV_MAX_VARIABLES: usize = 20;
+
+fn run_bioenv(matches: &ArgMatches) -> Result<()> {
+    let output_file = matches.get_one::<String>("output").unwrap();
+    check_output(output_file, force(matches))?;
+    let (sample_names, dist_matrix) = load_matrix(matches.get_one::<String>("matrix").unwrap())?;
+    let metadata = load_metadata(matches.get_one::<String>("metadata").unwrap())?;
+    let columns: Vec<String> = match matches.get_many::<String>("columns") {
+        Some(columns) => columns.cloned().collect(),
+        None => metadata
+            .columns
+            .iter()
+            .filter(|c| metadata.column_type(c) == Some(ColumnType::Numeric))
+            .cloned()
+            .collect(),
+    };
+    if columns.is_empty() {
+        bail!("The metadata has no numeric column");
+    }
+    if columns.len() > BIOENV_MAX_VARIABLES {
+        bail!(
+            "{} variables give too many subsets; choose at most {} with --columns",
+            columns.len(),
+            BIOENV_MAX_VARIABLES
+        );
+    }
+    let values = columns
+        .iter()
+        .map(|c| metadata.numeric_column_for(&sample_names, c))
+        .collect::<Result<Vec<_>>>()
+        .context(ErrorKind::Mismatch)?;
+
+    // Samples missing a value are left out
+    let complete: Vec<String> = sample_names
+        .iter()
+        .enumerate()
+        .filter(|(s, _)| values.iter().all(|v| !v[*s].is_nan()))
+        .map(|(_, name)| name.clone())
+        .collect();
+    if complete.len() < 3 {
+        bail!(
+            "{} samples have a value in every variable; BIOENV needs at least 3",
+            complete.len()
+        );
+    }
+    if complete.len() < sample_names.len() {
+        warn!(
+            "{} samples miss a value of some variable and are left out",
+            sample_names.len() - complete.len()
+        );
+    }
+    let keep: HashSet<&str> = complete.iter().map(|s| s.as_str()).collect();
+    let variables: Vec<Vec<f64>> = values
+        .iter()
+        .map(|v| {
+            sample_names
+                .iter()
+                .zip(v)
+                .filter(|(name, _)| keep.contains(name.as_str()))
+                .map(|(_, x)| *x)
+                .collect()
+        })
+        .collect();
+    let dist_matrix = submatrix(&sample_names, &dist_matrix, &complete)?;
+
+    let max_size = matches
+        .get_one::<usize>("max_size")
+        .copied()
+        .unwrap_or(columns.len());
+    let subsets = bioenv(&dist_matrix, complete.len(), &variables, max_size);
+    if let Some(best) = subsets
+        .iter()
+        .max_by(|a, b| a.correlation.total_cmp(&b.correlation))
+    {
+        let names: Vec<&str> = best
+            .variables
+            .iter()
+            .map(|v| columns[*v].as_str())
+            .collect();
+        info!(
+            "Best subset: {} (rho = {:.4})",
+            names.join(", "),
+            best.correlation
+        );
+    }
+    write_bioenv(&subsets, &columns, output_file, force(matches))
+}
+
+/// Design columns of the metadata `columns` over `samples`: numeric columns
+/// as they are, categorical ones as indicators of every level but the first
+/// (in sorted order). Returns the column names and values (NaN where a
+/// sample has no value).
+fn design_matrix(
+    metadata: &Metadata,
+    samples: &[String],
+    columns: &[String],
+) -> Result<(Vec<String>, Vec<Vec<f64>>)> {
+    let mut terms = Vec::new();
+    let mut design = Vec::new();
+    for column in columns {
+        if metadata.column_type(column) == Some(ColumnType::Numeric) {
+            design.push(metadata.numeric_column_for(samples, column)?);
+            terms.push(column.clone());
+            continue;
+        }
+        let values = metadata.column_for(samples, column)?;
+        let levels: BTreeSet<&str> = values
+            .iter()
+            .filter(|v| !v.is_empty())
+            .map(|v| v.as_str())
+            .collect();
+        for level in levels.iter().skip(1) {
+            design.push(
+                values
+                    .iter()
+                    .map(|v| match v.as_str() {
+                        "" => f64::NAN,
+                        v if v == *level => 1.0,
+                        _ => 0.0,
+                    })
+                    .collect(),
+            );
+            terms.push(format!("{}[{}]", column, level));
+        }
+    }
+    Ok((terms, design))
+}
+
+fn run_dbrda(matches: &ArgMatches) -> Result<()> {
+    let output_file = matches.get_one::<String>("output").unwrap();
+    check_output(output_file, force(matches))?;
+    let (sample_names, dist_matrix) = load_matrix(matches.get_one::<String>("matrix").unwrap())?;
+    let metadata = load_metadata(matches.get_one::<String>("metadata").unwrap())?;
+    let columns: Vec<String> = matches
+        .get_many::<String>("columns")
+        .unwrap()
+        .cloned()
+        .collect();
+    let (terms, design) =
+        design_matrix(&metadata, &sample_names, &columns).context(ErrorKind::Mismatch)?;
+
+    // Samples missing a value are left out
+    let complete: Vec<usize> = (0..sample_names.len())
+        .filter(|&s| design.iter().all(|column| !column[s].is_nan()))
+        .collect();
+    if complete.len() < sample_names.len() {
+        warn!(
+            "{} samples miss a value of the design and are left out",
+            sample_names.len() - complete.len()
+        );
+    }
+    let kept_names: Vec<String> = complete.iter().map(|&s| sample_names[s].clone()).collect();
+    let design: Vec<Vec<f64>> = design
+        .iter()
+        .map(|column| complete.iter().map(|&s| column[s]).collect())
+        .collect();
+    let dist_matrix = submatrix(&sample_names, &dist_matrix, &kept_names)?;
+
+    let mut rng = StdRng::seed_from_u64(*matches.get_one::<u64>("seed").unwrap());
+    let result = dbrda(
+        &dist_matrix,
+        kept_names.len(),
+        &design,
+        *matches.get_one::<usize>("axes").unwrap(),
+        *matches.get_one::<usize>("permutations").unwrap(),
+        &mut rng,
+    )?;
+
+    println!("n\tdf\ttotal_inertia\tconstrained_inertia\tproportion\tpseudo_f\tp_value");
+    println!(
+        "{}\t{}\t{:.6}\t{:.6}\t{:.6}\t{:.6}\t{:.6}",
+        kept_names.len(),
+        result.rank,
+        result.total_inertia,
+        result.constrained_inertia,
+        result.constrained_inertia / result.total_inertia,
+        result.f_statistic,
+        result.p_value
+    );
+    write_pcoa(
+        &kept_names,
+        &result.constrained,
+        output_file,
+        force(matches),
+    )?;
+    if let Some(biplot_file) = matches.get_one::<String>("biplot") {
+        write_biplot(&terms, &result.biplot, biplot_file, force(matches))?;
+    }
+    Ok(())
+}
+
+fn run_mantel(matches: &ArgMatches) -> Result<()> {
+    let (names_a, matrix_a) = load_matrix(matches.get_one::<String>("matrix").unwrap())?;
+    let (names_b, matrix_b) = load_matrix(matches.get_one::<String>("other").unwrap())?;
+    let in_b: HashSet<&str> = names_b.iter().map(|s| s.as_str()).collect();
+    let shared: Vec<String> = names_a
+        .iter()
+        .filter(|s| in_b.contains(s.as_str()))
+        .cloned()
+        .collect();
+    if shared.len() < 3 {
+        return Err(anyhow!(
+            "The matrices share {} samples; the Mantel test needs at least 3",
+            shared.len()
+        )
+        .context(ErrorKind::Mismatch));
+    }
+    let dropped = names_a.len() + names_b.len() - 2 * shared.len();
+    if dropped > 0 {
+        warn!(
+            "{} samples are in only one of the matrices and are left out",
+            dropped
+        );
+    }
+
+    let a = submatrix(&names_a, &matrix_a, &shared)?;
+    let b = submatrix(&names_b, &matrix_b, &shared)?;
+    let method = match matches.get_one::<String>("method").unwrap().as_str() {
+        "spearman" => Correlation::Spearman,
+        _ => Correlation::Pearson,
+    };
+    let mut rng = StdRng::seed_from_u64(*matches.get_one::<u64>("seed").unwrap());
+    let test = mantel(
+        &a,
+        &b,
+        shared.len(),
+        method,
+        *matches.get_one::<usize>("permutations").unwrap(),
+        &mut rng,
+    );
+
+    println!("n\tstatistic\tp_value");
+    println!("{}\t{:.6}\t{:.6}", test.n, test.statistic, test.p_value);
+    Ok(())
+}
+
+fn run_permdisp(matches: &ArgMatches) -> Result<()> {
+    let (sample_names, dist_matrix, labels) = load_grouping(matches)?;
+    let mut rng = StdRng::seed_from_u64(*matches.get_one::<u64>("seed").unwrap());
+    let result = permdisp(
+        &dist_matrix,
+        sample_names.len(),
+        &labels,
+        *matches.get_one::<usize>("permutations").unwrap(),
+        &mut rng,
+    )?;
+
+    println!("group\tn\tmean_distance_to_centroid");
+    for ((group, size), mean) in result
+        .groups
+        .iter()
+        .zip(result.group_sizes.iter())
+        .zip(result.mean_dispersions.iter())
+    {
+        println!("{}\t{}\t{:.6}", group, size, mean);
+    }
+    println!(
+        "F = {:.6}, p = {:.6} ({} permutations)",
+        result.f_statistic, result.p_value, result.permutations
+    );
+
+    if let Some(output_file) = matches.get_one::<String>("output") {
+        let rows = sample_names
+            .iter()
+            .zip(labels.iter().zip(result.distances.iter()))
+            .map(|(s, (label, d))| (s.clone(), vec![label.clone(), format!("{:.6}", d)]))
+            .collect();
+        let distances =
+            Metadata::from_rows(vec!["group".into(), "distance_to_centroid".into()], rows)?;
+        write_metadata(&distances, output_file, force(matches))?;
+    }
+    Ok(())
+}
+
+fn run_cluster(matches: &ArgMatches) -> Result<()> {
+    let output_file = matches.get_one::<String>("output").unwrap();
+    check_output(output_file, force(matches))?;
+    let (sample_names, dist_matrix) = load_matrix(matches.get_one::<String>("matrix").unwrap())?;
+    let n = sample_names.len();
+
+    if matches.get_one::<String>("method").unwrap() == "dbscan" {
+        let eps = *matches
+            .get_one::<f64>("eps")
+            .context("dbscan needs the neighbourhood radius, --eps")?;
+        let min_samples = *matches.get_one::<usize>("min_samples").unwrap();
+        let labels = dbscan(&dist_matrix, n, eps, min_samples);
+        let n_clusters = labels.iter().flatten().max().map_or(0, |c| c + 1);
+        let n_noise = labels.iter().filter(|l| l.is_none()).count();
+        println!("{} clusters, {} noise samples", n_clusters, n_noise);
+
+        let rows = sample_names
+            .iter()
+            .zip(labels.iter())
+            .map(|(s, label)| {
+                let values = match label {
+                    Some(c) => vec![(c + 1).to_string(), "false".to_string()],
+                    None => vec!["NA".to_string(), "true".to_string()],
+                };
+                (s.clone(), values)
+            })
+            .collect();
+        let assignments = Metadata::from_rows(vec!["cluster".into(), "noise".into()], rows)?;
+        return write_metadata(&assignments, output_file, force(matches));
+    }
+
+    let k = *matches
+        .get_one::<usize>("k")
+        .context("k-medoids needs the number of clusters, -k")?;
+    let clusters = k_medoids(&dist_matrix, n, k)?;
+    println!(
+        "{} clusters, total distance to medoids {:.6}",
+        clusters.medoids.len(),
+        clusters.cost
+    );
+
+    let rows = sample_names
+        .iter()
+        .zip(clusters.assignments.iter())
+        .map(|(s, c)| {
+            let medoid = sample_names[clusters.medoids[*c]].clone();
+            (s.clone(), vec![(c + 1).to_string(), medoid])
+        })
+        .collect();
+    let assignments = Metadata::from_rows(vec!["cluster".into(), "medoid".into()], rows)?;
+    write_metadata(&assignments, output_file, force(matches))
+}
+
+fn run_philr(matches: &ArgMatches) -> Result<()> {
+    let output_file = matches.get_one::<String>("output").unwrap();
+    check_output(output_file, force(matches))?;
+    let pseudocount = *matches.get_one::<f64>("pseudocount").unwrap();
+    if pseudocount <= 0.0 {
+        bail!("--pseudocount must be positive, as zero counts have no logarithm");
+    }
+    let tree = load_tree(matches)?;
+    let table_file = matches.get_one::<String>("table").unwrap();
+    let (taxa_order, sample_names, counts) = read_table(matches, table_file)?;
+
+    let tips = tip_names(&tree);
+    let unplaced = taxa_order.iter().filter(|t| !tips.contains(*t)).count();
+    if unplaced > 0 {
+        warn!(
+            "{} of {} features are not tips of the tree and are left out",
+            unplaced,
+            taxa_order.len()
+        );
+    }
+    let balances = philr(&tree, &taxa_order, &counts, sample_names.len(), pseudocount)
+        .context(ErrorKind::Mismatch)?;
+    if balances.nodes.is_empty() {
+        bail!("Fewer than two features of the table are tips of the tree");
+    }
+    let labels = balances
+        .nodes
+        .iter()
+        .map(|n| branch_label(&tree, *n))
+        .collect::<Result<Vec<_>>>()?;
+    write_balances(
+        &sample_names,
+        &labels,
+        &balances.values,
+        output_file,
+        force(matches),
+    )?;
+    info!(
+        "Wrote {} balances of {} samples to {}",
+        labels.len(),
+        sample_names.len(),
+        output_file
+    );
+    Ok(())
+}
+
+fn run_edge_pca(matches: &ArgMatches) -> Result<()> {
+    let tree = load_tree(matches)?;
+    let table_file = matches.get_one::<String>("table").unwrap();
+    let (taxa_order, sample_names, counts) = read_table(matches, table_file)?;
+    let n = sample_names.len();
+    let transform = Transform::from_name(matches.get_one::<String>("transform").unwrap()).unwrap();
+    let n_axes = *matches.get_one::<usize>("axes").unwrap();
+
+    // Mass below minus mass above each edge, which with proportions is 2p - 1
+    let props = branch_proportions(&tree, &taxa_order, &counts, n, transform)?;
+    let root = tree.get_root()?;
+    let edges: Vec<usize> = tree
+        .postorder(&root)?
+        .into_iter()
+        .filter(|e| *e != root)
+        .collect();
+    let mut masses = Array2::<f64>::zeros((n, edges.len()));
+    for (k, &e) in edges.iter().enumerate() {
+        for s in 0..n {
+            masses[(s, k)] = 2.0 * props[(s, e)] - 1.0;
+        }
+    }
+
+    let (scores, loadings) = edge_pca(&masses, n_axes)?;
+    write_pcoa(
+        &sample_names,
+        &scores,
+        matches.get_one::<String>("output").unwrap(),
+        force(matches),
+    )?;
+
+    // The five branches with the largest loadings on each axis
+    let labels = edges
+        .iter()
+        .map(|e| branch_label(&tree, *e))
+        .collect::<Result<Vec<_>>>()?;
+    for axis in 0..loadings.ncols() {
+        let mut order: Vec<usize> = (0..edges.len()).collect();
+        order.sort_by(|a, b| {
+            loadings[(*b, axis)]
+                .abs()
+                .total_cmp(&loadings[(*a, axis)].abs())
+        });
+        let top: Vec<String> = order
+            .iter()
+            .take(5)
+            .map(|e| format!("{} ({:+.3})", labels[*e], loadings[(*e, axis)]))
+            .collect();
+        println!("PC{}: {}", axis + 1, top.join("; "));
+    }
+    if let Some(loadings_file) = matches.get_one::<String>("loadings") {
+        write_loadings(&labels, &loadings, loadings_file, force(matches))?;
+    }
+    Ok(())
+}
+
+fn run_cross(matches: &ArgMatches) -> Result<()> {
+    let output_file = matches.get_one::<String>("output").unwrap();
+    check_output(output_file, force(matches))?;
+
+    let metric = single_metric(matches, "cross")?;
+    check_transform(matches)?;
+    let (tree, taxa_a, samples_a, counts_a) = load_inputs(matches)?;
+    let (taxa_b, samples_b, counts_b) =
+        load_table(matches, matches.get_one::<String>("against").unwrap())?;
+    let tips = tip_names(&tree);
+    if !taxa_b.iter().any(|t| tips.contains(t)) {
+        return Err(anyhow!(
+            "None of the {} features of the second table match a tip of the tree",
+            taxa_b.len()
+        )
+        .context(ErrorKind::Mismatch));
+    }
+    let opts = branch_options(matches)?;
+
+    let (n_a, n_b) = (samples_a.len(), samples_b.len());
+    let dist_matrix = if metric == Metric::Unweighted {
+        let sets_a = BranchSets::new(&tree, &taxa_a, &counts_a, n_a, &opts)?;
+        let sets_b = BranchSets::new(&tree, &taxa_b, &counts_b, n_b, &opts)?;
+        compute_cross_matrix(&sets_a, &sets_b)
+    } else {
+        // The full matrix of both tables side by side, cut down to the A x B
+        // block; the same name may be in both tables, so columns are numbered
+        let numbered = |prefix: &str, n: usize| -> Vec<String> {
+            (0..n).map(|s| format!("{}{}", prefix, s)).collect()
+        };
+        let (taxa_order, _, counts) = merge_sample_tables(vec![
+            (taxa_a, numbered("a", n_a), counts_a),
+            (taxa_b, numbered("b", n_b), counts_b),
+        ])?;
+        let n = n_a + n_b;
+        let (full, _) = compute_distances(
+            matches,
+            metric,
+            Algorithm::default(),
+            &tree,
+            &taxa_order,
+            &counts,
+            n,
+            &opts,
+        )?;
+        (0..n_a)
+            .flat_map(|i| full[i * n + n_a..(i + 1) * n].iter().copied())
+            .collect()
+    };
+
+    write_cross_matrix(
+        &samples_a,
+        &samples_b,
+        &dist_matrix,
+        output_file,
+        force(matches),
+    )
+}
+
+fn run_watch(matches: &ArgMatches) -> Result<()> {
+    let dir = PathBuf::from(matches.get_one::<String>("dir").unwrap());
+    let out_dir = PathBuf::from(matches.get_one::<String>("out_dir").unwrap());
+    if !dir.is_dir() {
+        return Err(
+            anyhow!("{} is not a directory", dir.display()).context(ErrorKind::InputNotFound)
+        );
+    }
+    std::fs::create_dir_all(&out_dir)
+        .with_context(|| format!("Failed to create {}", out_dir.display()))?;
+    if dir.canonicalize()? == out_dir.canonicalize()? {
+        bail!(
+            "--out-dir must differ from the watched directory, whose .txt files are read as tables"
+        );
+    }
+    let metrics = selected_metrics(matches);
+    if metrics.len() > 1 {
+        bail!("This command takes a single --metric");
+    }
+    let metric = metrics[0].0;
+    let tree = load_tree(matches)?;
+    check_branch_lengths(matches, &tree)?;
+    let opts = branch_options(matches)?;
+    let once = matches.get_flag("once");
+    let interval = Duration::from_secs(*matches.get_one::<u64>("interval").unwrap());
+
+    // The matrices in --out-dir belong to the watcher, including those of earlier runs
+    let mut watcher = DirWatcher::new(&dir, !once);
+    let mut deliveries: HashMap<PathBuf, Delivery> = HashMap::new();
+    info!(
+        "Watching {} for tables every {}s",
+        dir.display(),
+        interval.as_secs()
+    );
+    loop {
+        for path in watcher.poll()? {
+            let stem = path.file_stem().unwrap().to_string_lossy();
+            let output_file = out_dir.join(format!("{}.txt", stem));
+            let delivery = match update_delivery(
+                matches,
+                metric,
+                &tree,
+                &opts,
+                &path,
+                deliveries.get(&path),
+            ) {
+                Ok(Some(delivery)) => delivery,
+                Ok(None) => {
+                    info!("{}: no new samples", path.display());
+                    continue;
+                }
+                Err(err) => {
+                    warn!("Skipping {}: {:#}", path.display(), err);
+                    continue;
+                }
+            };
+            let n = delivery.sample_names.len();
+            write_matrix(
+                &delivery.sample_names,
+                &delivery.dist_matrix,
+                n,
+                &output_file.to_string_lossy(),
+                true,
+            )?;
+            info!(
+                "Wrote the matrix of {} samples of {} to {}",
+                n,
+                path.display(),
+                output_file.display()
+            );
+            deliveries.insert(path, delivery);
+        }
+        if once {
+            return Ok(());
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+/// Read a delivered table and compute its matrix; when the delivery only
+/// added samples, the unweighted matrix is extended with their distances
+/// instead of recomputed. None if nothing changed.
+fn update_delivery(
+    matches: &ArgMatches,
+    metric: Metric,
+    tree: &Tree,
+    opts: &BranchOptions,
+    path: &Path,
+    previous: Option<&Delivery>,
+) -> Result<Option<Delivery>> {
+    let table_file = path.to_string_lossy();
+    let (taxa_order, sample_names, counts) = load_table(matches, &table_file)?;
+    let n = sample_names.len();
+    let fingerprints = sample_fingerprints(&taxa_order, &counts, n);
+    let update = match previous {
+        Some(previous) => plan_update(previous, &sample_names, &fingerprints),
+        None => Update::Recompute,
+    };
+
+    let dist_matrix = match (update, previous) {
+        (Update::Unchanged, _) => return Ok(None),
+        (Update::Extend(added), Some(previous)) if metric == Metric::Unweighted => {
+            info!(
+                "{}: {} new samples next to {}",
+                table_file,
+                added.len(),
+                n - added.len()
+            );
+            let added_counts: Vec<Vec<f64>> = counts
+                .iter()
+                .map(|row| added.iter().map(|&s| row[s]).collect())
+                .collect();
+            let added_sets = BranchSets::new(tree, &taxa_order, &added_counts, added.len(), opts)?;
+            let sets = BranchSets::new(tree, &taxa_order, &counts, n, opts)?;
+            let added_rows = compute_cross_matrix(&added_sets, &sets);
+            extend_matrix(previous, &sample_names, &added, &added_rows)
+        }
+        _ => {
+            let (dist_matrix, _) = compute_distances(
+                matches,
+                metric,
+                Algorithm::default(),
+                tree,
+                &taxa_order,
+                &counts,
+                n,
+                opts,
+            )?;
+            dist_matrix
+        }
+    };
+    Ok(Some(Delivery {
+        sample_names,
+        fingerprints,
+        dist_matrix,
+    }))
+}
+
+fn run_tree(matches: &ArgMatches) -> Result<()> {
+    let output_file = matches.get_one::<String>("output").unwrap();
+    check_output(output_file, force(matches))?;
+    let mut tree = read_tree(matches.get_one::<String>("tree").unwrap())?;
+
+    let mut changed = false;
+    if let Some(tips_file) = matches.get_one::<String>("keep_tips") {
+        if tips_file != "-" {
+            require_file(tips_file)?;
+        }
+        let keep: HashSet<String> = read_feature_list(tips_file)
+            .context(ErrorKind::Parse)?
+            .into_iter()
+            .collect();
+        let unknown = keep.difference(&tip_names(&tree)).count();
+        if unknown > 0 {
+            warn!("{} of the tips to keep are not in the tree", unknown);
+        }
+        tree = pruned_tree(&tree, &keep).context(ErrorKind::Mismatch)?;
+        changed = true;
+    }
+
+    let reroot = match matches.get_one::<String>("outgroup") {
+        Some(spec) => Some(Reroot::Outgroup(spec.clone())),
+        None if matches.get_flag("midpoint") => Some(Reroot::Midpoint),
+        None => None,
+    };
+    if let Some(how) = reroot {
+        tree = rerooted_tree(&tree, &how).context(ErrorKind::Mismatch)?;
+        changed = true;
+    }
+
+    if !changed {
+        bail!("Nothing to do: give --midpoint, --outgroup and/or --keep-tips");
+    }
+    let mut file = OutputFile::create(output_file, force(matches))?;
+    writeln!(file, "{}", subtree_newick(&tree, tree.get_root()?)?)?;
+    file.commit()
+}
+
+fn run_tree_stats(matches: &ArgMatches) -> Result<()> {
+    let tree = read_tree(matches.get_one::<String>("tree").unwrap())?;
+    let stats = tree_stats(&tree)?;
+    if matches.get_flag("json") {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+    println!("tips\t{}", stats.n_tips);
+    println!("internal_nodes\t{}", stats.n_internal);
+    println!("total_branch_length\t{:.6}", stats.total_branch_length);
+    println!(
+        "root_to_tip_depth\tmin {:.6}, q1 {:.6}, median {:.6}, q3 {:.6}, max {:.6}",
+        stats.depth_min, stats.depth_q1, stats.depth_median, stats.depth_q3, stats.depth_max
+    );
+    println!("polytomies\t{}", stats.polytomies);
+    println!("unary_nodes\t{}", stats.unary_nodes);
+    println!("zero_length_branches\t{}", stats.zero_length_branches);
+    println!("branches_missing_length\t{}", stats.branches_missing_length);
+    Ok(())
+}
+
+fn run_patristic(matches: &ArgMatches) -> Result<()> {
+    let tree_file = matches.get_one::<String>("tree").unwrap();
+    let output_file = matches.get_one::<String>("output").unwrap();
+
+    let mut tree = read_tree(tree_file)?;
+    normalize_tips(matches, &mut tree)?;
+    let mut tips = tree.get_leaves();
+    if let Some(table_file) = matches.get_one::<String>("table") {
+        let (taxa_order, _, _) = read_table(matches, table_file)?;
+        let features: HashSet<&str> = taxa_order.iter().map(|t| t.as_str()).collect();
+        tips.retain(|t| {
+            let name = tree.get(t).unwrap().name.as_deref().unwrap_or_default();
+            features.contains(name)
+        });
+    }
+    let tip_names: Vec<String> = tips
+        .iter()
+        .map(|t| tree.get(t).unwrap().name.clone().unwrap_or_default())
+        .collect();
+
+    let dist_matrix = patristic_matrix(&tree, &tips)?;
+    write_matrix(
+        &tip_names,
+        &dist_matrix,
+        tip_names.len(),
+        output_file,
+        force(matches),
+    )?;
+
+    Ok(())
+}
+
+fn run_matrix_average(matches: &ArgMatches) -> Result<()> {
+    let output_file = matches.get_one::<String>("output").unwrap();
+    let files: Vec<&String> = matches.get_many::<String>("matrices").unwrap().collect();
+
+    let (sample_names, first) = load_matrix(files[0])?;
+    let mut matrices = vec![first];
+    for file in &files[1..] {
+        let (names, dist_matrix) = load_matrix(file)?;
+        let aligned = reorder_matrix(&names, &dist_matrix, &sample_names)
+            .with_context(|| format!("{} does not match {}", file, files[0]))
+            .context(ErrorKind::Mismatch)?;
+        matrices.push(aligned);
+    }
+
+    let (mean, variance) = average_matrices(&matrices)?;
+    let n = sample_names.len();
+    write_matrix(&sample_names, &mean, n, output_file, force(matches))?;
+    if let Some(variance_file) = matches.get_one::<String>("variance") {
+        write_matrix(&sample_names, &variance, n, variance_file, force(matches))?;
+    }
+
+    Ok(())
+}
+
+fn run_matrix_distatis(matches: &ArgMatches) -> Result<()> {
+    let output_file = matches.get_one::<String>("output").unwrap();
+    check_output(output_file, force(matches))?;
+    let files: Vec<&String> = matches.get_many::<String>("matrices").unwrap().collect();
+
+    let (sample_names, first) = load_matrix(files[0])?;
+    let mut matrices = vec![first];
+    for file in &files[1..] {
+        let (names, dist_matrix) = load_matrix(file)?;
+        let aligned = reorder_matrix(&names, &dist_matrix, &sample_names)
+            .with_context(|| format!("{} does not match {}", file, files[0]))
+            .context(ErrorKind::Mismatch)?;
+        matrices.push(aligned);
+    }
+    let labels: Vec<String> = files
+        .iter()
+        .map(|f| {
+            Path::new(f)
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_else(|| f.to_string())
+        })
+        .collect();
+
+    let result = distatis(
+        &matrices,
+        sample_names.len(),
+        *matches.get_one::<usize>("axes").unwrap(),
+    )?;
+    for (label, weight) in labels.iter().zip(&result.weights) {
+        info!("{}: weight {:.4}", label, weight);
+    }
+    write_pcoa(
+        &sample_names,
+        &result.compromise,
+        output_file,
+        force(matches),
+    )?;
+    if let Some(weights_file) = matches.get_one::<String>("weights") {
+        write_distatis_weights(
+            &labels,
+            &result.weights,
+            &result.rv,
+            weights_file,
+            force(matches),
+        )?;
+    }
+    if let Some(partial_file) = matches.get_one::<String>("partial") {
+        write_partial_scores(
+            &labels,
+            &sample_names,
+            &result.partial,
+            partial_file,
+            force(matches),
+        )?;
+    }
+    Ok(())
+}
+
+/// Label of each study of `meta`: --labels, the value of --label-column
+/// shared by the study's samples, or the table's file name
+fn study_labels_of(
+    matches: &ArgMatches,
+    table_files: &[&String],
+    tables: &[(Vec<String>, Vec<String>, Vec<Vec<f64>>)],
+    studies: &[Metadata],
+) -> Result<Vec<String>> {
+    let mut labels: Vec<String> = Vec::with_capacity(table_files.len());
+    for (k, ((table_file, (_, samples, _)), metadata)) in
+        table_files.iter().zip(tables).zip(studies).enumerate()
+    {
+        let label = match (
+            matches.get_many::<String>("labels"),
+            matches.get_one::<String>("label_column"),
+        ) {
+            (Some(given), _) => given.cloned().nth(k).unwrap(),
+            (None, Some(column)) => {
+                if !metadata.columns.contains(column) {
+                    bail!(
+                        "Metadata of {} has no column named '{}'",
+                        table_file,
+                        column
+                    );
+                }
+                let values: BTreeSet<&str> = samples
+                    .iter()
+                    .filter_map(|s| metadata.get(s, column))
+                    .filter(|v| !v.is_empty())
+                    .collect();
+                if values.len() != 1 {
+                    bail!(
+                        "Samples of {} have {} different values in --label-column {}",
+                        table_file,
+                        values.len(),
+                        column
+                    );
+                }
+                values.into_iter().next().unwrap().to_string()
+            }
+            (None, None) => Path::new(table_file.as_str())
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_else(|| table_file.to_string()),
+        };
+        labels.push(label);
+    }
+    for (k, label) in labels.iter().enumerate() {
+        if let Some(other) = labels[..k].iter().position(|l| l == label) {
+            bail!(
+                "{} and {} both get the study label '{}'; give --labels or --label-column",
+                table_files[other],
+                table_files[k],
+                label
+            );
+        }
+    }
+    Ok(labels)
+}
+
+fn run_meta(matches: &ArgMatches) -> Result<()> {
+    let table_files: Vec<&String> = matches.get_many::<String>("tables").unwrap().collect();
+    let metadata_files: Vec<&String> = matches.get_many::<String>("metadata").unwrap().collect();
+    let study_column = matches.get_one::<String>("study_column").unwrap();
+    if table_files.len() != metadata_files.len() {
+        bail!(
+            "{} tables but {} metadata files; give one metadata file per table",
+            table_files.len(),
+            metadata_files.len()
+        );
+    }
+    if let Some(labels) = matches.get_many::<String>("labels") {
+        if labels.len() != table_files.len() {
+            bail!("{} labels for {} tables", labels.len(), table_files.len());
+        }
+    }
+
+    let metric = single_metric(matches, "meta")?;
+    check_transform(matches)?;
+    let tree = load_tree(matches)?;
+
+    // Combined metadata: study label first, then the union of every study's columns
+    let mut tables = Vec::new();
+    let mut studies = Vec::new();
+    let mut columns = vec![study_column.clone()];
+    for (table_file, metadata_file) in table_files.iter().zip(metadata_files.iter()) {
+        let table = load_table(matches, table_file)?;
+        let metadata = load_metadata(metadata_file)?;
+        let missing = table.1.iter().filter(|s| !metadata.has_sample(s)).count();
+        if missing > 0 {
+            warn!(
+                "{} of {} samples of {} are missing from {}; their metadata is left empty",
+                missing,
+                table.1.len(),
+                table_file,
+                metadata_file
+            );
+        }
+        for c in &metadata.columns {
+            if !columns.contains(c) {
+                columns.push(c.clone());
+            }
+        }
+        tables.push(table);
+        studies.push(metadata);
+    }
+    let labels = study_labels_of(matches, &table_files, &tables, &studies)?;
+
+    let mut rows = Vec::new();
+    let mut study_labels = Vec::new();
+    for ((label, (_, samples, _)), metadata) in labels.iter().zip(tables.iter()).zip(studies.iter())
+    {
+        for s in samples {
+            let mut row = vec![label.clone()];
+            for c in &columns[1..] {
+                row.push(metadata.get(s, c).unwrap_or_default().to_string());
+            }
+            rows.push((s.clone(), row));
+            study_labels.push(label.clone());
+        }
+    }
+    let combined = Metadata::from_rows(columns, rows)?;
+
+    let (taxa_order, sample_names, presence_matrix) = merge_sample_tables(tables)?;
+    let opts = branch_options(matches)?;
+    let n = sample_names.len();
+    let (dist_matrix, _) = compute_distances(
+        matches,
+        metric,
+        Algorithm::default(),
+        &tree,
+        &taxa_order,
+        &presence_matrix,
+        n,
+        &opts,
+    )?;
+
+    write_matrix(
+        &sample_names,
+        &dist_matrix,
+        n,
+        matches.get_one::<String>("output").unwrap(),
+        force(matches),
+    )?;
+    write_metadata(
+        &combined,
+        matches.get_one::<String>("combined_metadata").unwrap(),
+        force(matches),
+    )?;
+
+    let comparisons = group_comparisons(&dist_matrix, n, &study_labels);
+    let (within, between) = within_between(&dist_matrix, n, &study_labels);
+    write_group_comparisons(
+        &comparisons,
+        (&within, &between),
+        matches.get_one::<String>("summary").unwrap(),
+        force(matches),
+    )?;
+
+    Ok(())
+}
+
+fn run_simulate(matches: &ArgMatches) -> Result<()> {
+    let get_usize = |id: &str| *matches.get_one::<usize>(id).unwrap();
+    let get_f64 = |id: &str| *matches.get_one::<f64>(id).unwrap();
+    let mut rng = StdRng::seed_from_u64(*matches.get_one::<u64>("seed").unwrap());
+
+    let tree = birth_death_tree(
+        get_usize("tips"),
+        get_f64("birth"),
+        get_f64("death"),
+        &mut rng,
+    )?;
+    let table = simulate_table(
+        &tree,
+        get_usize("samples"),
+        get_usize("environments"),
+        get_f64("prevalence"),
+        get_f64("switch_rate"),
+        get_f64("noise"),
+        &mut rng,
+    )?;
+
+    let mut tree_file = OutputFile::create(
+        matches.get_one::<String>("tree_out").unwrap(),
+        force(matches),
+    )?;
+    writeln!(tree_file, "{}", tree.to_newick())?;
+    tree_file.commit()?;
+    write_sample_table(
+        &table.taxa_order,
+        &table.sample_names,
+        &table.counts,
+        matches.get_one::<String>("table_out").unwrap(),
+        force(matches),
+    )?;
+    if let Some(metadata_file) = matches.get_one::<String>("metadata_out") {
+        let rows = table
+            .sample_names
+            .iter()
+            .zip(table.environments.iter())
+            .map(|(s, env)| (s.clone(), vec![format!("env{}", env + 1)]))
+            .collect();
+        let metadata = Metadata::from_rows(vec!["environment".to_string()], rows)?;
+        write_metadata(&metadata, metadata_file, force(matches))?;
+    }
 
     Ok(())
 }
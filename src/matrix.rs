@@ -0,0 +1,98 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+
+/// Reorder a square matrix so that its samples follow `target` order.
+/// Fails unless both name the same set of samples.
+pub fn reorder_matrix(
+    sample_names: &[String],
+    dist_matrix: &[f64],
+    target: &[String],
+) -> Result<Vec<f64>> {
+    let n = target.len();
+    if sample_names.len() != n {
+        bail!("Matrices have {} and {} samples", sample_names.len(), n);
+    }
+    let position: HashMap<&str, usize> = sample_names
+        .iter()
+        .enumerate()
+        .map(|(k, s)| (s.as_str(), k))
+        .collect();
+    let map = target
+        .iter()
+        .map(|s| {
+            position
+                .get(s.as_str())
+                .copied()
+                .with_context(|| format!("Sample '{}' is missing from a matrix", s))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut reordered = vec![0.0; n * n];
+    for i in 0..n {
+        for j in 0..n {
+            reordered[i * n + j] = dist_matrix[map[i] * n + map[j]];
+        }
+    }
+    Ok(reordered)
+}
+
+/// The rows and columns of `target` samples, in that order, out of a larger
+/// square matrix
+pub fn submatrix(
+    sample_names: &[String],
+    dist_matrix: &[f64],
+    target: &[String],
+) -> Result<Vec<f64>> {
+    let m = sample_names.len();
+    let position: HashMap<&str, usize> = sample_names
+        .iter()
+        .enumerate()
+        .map(|(k, s)| (s.as_str(), k))
+        .collect();
+    let map = target
+        .iter()
+        .map(|s| {
+            position
+                .get(s.as_str())
+                .copied()
+                .with_context(|| format!("Sample '{}' is missing from a matrix", s))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let n = target.len();
+    let mut sub = vec![0.0; n * n];
+    for i in 0..n {
+        for j in 0..n {
+            sub[i * n + j] = dist_matrix[map[i] * m + map[j]];
+        }
+    }
+    Ok(sub)
+}
+
+/// Element-wise mean and sample variance (n - 1 denominator; zero for a
+/// single matrix) of equally-sized matrices
+pub fn average_matrices(matrices: &[Vec<f64>]) -> Result<(Vec<f64>, Vec<f64>)> {
+    let first = matrices.first().context("No matrices to average")?;
+    if matrices.iter().any(|m| m.len() != first.len()) {
+        bail!("Matrices differ in size");
+    }
+    let k = matrices.len() as f64;
+
+    let mut mean = vec![0.0; first.len()];
+    for m in matrices {
+        for (acc, v) in mean.iter_mut().zip(m.iter()) {
+            *acc += v / k;
+        }
+    }
+
+    let mut variance = vec![0.0; first.len()];
+    if matrices.len() > 1 {
+        for m in matrices {
+            for ((acc, v), mu) in variance.iter_mut().zip(m.iter()).zip(mean.iter()) {
+                *acc += (v - mu) * (v - mu) / (k - 1.0);
+            }
+        }
+    }
+
+    Ok((mean, variance))
+}
@@ -0,0 +1,374 @@
+use crate::io::OutputFile;
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+};
+
+/// Kind of values a metadata column holds, as in QIIME 2's `#q2:types` line
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColumnType {
+    Categorical,
+    /// Every non-empty value is a number
+    Numeric,
+}
+
+impl ColumnType {
+    pub fn from_name(name: &str) -> Option<ColumnType> {
+        match name.to_ascii_lowercase().as_str() {
+            "categorical" => Some(ColumnType::Categorical),
+            "numeric" => Some(ColumnType::Numeric),
+            _ => None,
+        }
+    }
+}
+
+/// Tab-delimited sample metadata
+pub struct Metadata {
+    pub sample_ids: Vec<String>,
+    pub columns: Vec<String>,
+    types: Vec<ColumnType>,
+    values: Vec<Vec<String>>,
+    index: HashMap<String, usize>,
+}
+
+impl Metadata {
+    /// Build metadata from (sample ID, values) rows. Columns whose non-empty
+    /// values all parse as numbers are numeric, the others categorical.
+    pub fn from_rows(columns: Vec<String>, rows: Vec<(String, Vec<String>)>) -> Result<Metadata> {
+        let mut sample_ids = Vec::with_capacity(rows.len());
+        let mut values = Vec::with_capacity(rows.len());
+        let mut index = HashMap::new();
+        for (id, mut row) in rows {
+            if row.len() > columns.len() {
+                bail!(
+                    "Sample '{}' has more values than there are metadata columns",
+                    id
+                );
+            }
+            row.resize(columns.len(), String::new());
+            if index.insert(id.clone(), sample_ids.len()).is_some() {
+                bail!("Sample '{}' appears twice in the metadata", id);
+            }
+            sample_ids.push(id);
+            values.push(row);
+        }
+        let types = (0..columns.len())
+            .map(|col| {
+                let numeric = values
+                    .iter()
+                    .map(|row: &Vec<String>| row[col].as_str())
+                    .filter(|v| !v.is_empty())
+                    .all(|v| v.parse::<f64>().is_ok());
+                if numeric && values.iter().any(|row| !row[col].is_empty()) {
+                    ColumnType::Numeric
+                } else {
+                    ColumnType::Categorical
+                }
+            })
+            .collect();
+        Ok(Metadata {
+            sample_ids,
+            columns,
+            types,
+            values,
+            index,
+        })
+    }
+
+    /// Declare the types of the columns, e.g. from a `#q2:types` line;
+    /// fails if a numeric column holds a value that is not a number
+    pub fn set_types(&mut self, types: Vec<ColumnType>) -> Result<()> {
+        if types.len() != self.columns.len() {
+            bail!(
+                "{} column types for {} columns",
+                types.len(),
+                self.columns.len()
+            );
+        }
+        for (col, ty) in types.iter().enumerate() {
+            if *ty != ColumnType::Numeric {
+                continue;
+            }
+            for (id, row) in self.sample_ids.iter().zip(self.values.iter()) {
+                let v = &row[col];
+                if !v.is_empty() && v.parse::<f64>().is_err() {
+                    bail!(
+                        "Column '{}' is numeric but sample '{}' has '{}'",
+                        self.columns[col],
+                        id,
+                        v
+                    );
+                }
+            }
+        }
+        self.types = types;
+        Ok(())
+    }
+
+    /// Type of `column`, if it exists
+    pub fn column_type(&self, column: &str) -> Option<ColumnType> {
+        let col = self.columns.iter().position(|c| c == column)?;
+        Some(self.types[col])
+    }
+
+    /// Fail unless every sample has a metadata row, naming those that do not
+    pub fn check_samples(&self, samples: &[String]) -> Result<()> {
+        let missing: Vec<&str> = samples
+            .iter()
+            .filter(|s| !self.index.contains_key(*s))
+            .map(|s| s.as_str())
+            .collect();
+        if !missing.is_empty() {
+            let shown = missing.len().min(5);
+            bail!(
+                "{} of {} samples are missing from the metadata: {}{}",
+                missing.len(),
+                samples.len(),
+                missing[..shown].join(", "),
+                if missing.len() > shown { ", ..." } else { "" }
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether `sample` has a metadata row
+    pub fn has_sample(&self, sample: &str) -> bool {
+        self.index.contains_key(sample)
+    }
+
+    /// Value of `column` for `sample`, if both exist
+    pub fn get(&self, sample: &str, column: &str) -> Option<&str> {
+        let row = *self.index.get(sample)?;
+        let col = self.columns.iter().position(|c| c == column)?;
+        Some(self.values[row][col].as_str())
+    }
+
+    /// Values of `column` for the given samples, in that order.
+    /// Fails if the column is unknown or a sample has no metadata row.
+    pub fn column_for(&self, samples: &[String], column: &str) -> Result<Vec<String>> {
+        let col = self
+            .columns
+            .iter()
+            .position(|c| c == column)
+            .with_context(|| format!("Metadata has no column named '{}'", column))?;
+        self.check_samples(samples)?;
+        Ok(samples
+            .iter()
+            .map(|s| self.values[self.index[s]][col].clone())
+            .collect())
+    }
+
+    /// Values of a numeric `column` for the given samples, NaN where empty
+    pub fn numeric_column_for(&self, samples: &[String], column: &str) -> Result<Vec<f64>> {
+        let values = self.column_for(samples, column)?;
+        if self.column_type(column) != Some(ColumnType::Numeric) {
+            bail!("Metadata column '{}' is not numeric", column);
+        }
+        Ok(values
+            .iter()
+            .map(|v| v.parse().unwrap_or(f64::NAN))
+            .collect())
+    }
+}
+
+/// Read a sample metadata file.
+/// First line: ID header followed by column names, then one line per sample
+/// Example:
+/// sample-id  body-site  subject
+/// SampleA    gut        S1
+/// SampleB    tongue     S1
+///
+/// Lines starting with '#' after the header are treated as comments, except
+/// a QIIME 2 `#q2:types` line declaring each column categorical or numeric;
+/// without one, types are inferred (see [`Metadata::from_rows`]).
+pub fn read_metadata(filename: &str) -> Result<Metadata> {
+    let f = File::open(filename)?;
+    let mut lines = BufReader::new(f).lines();
+
+    let header = lines.next().context("No header in metadata")??;
+    let mut hdr_split = header.split('\t');
+    hdr_split.next(); // the ID column
+    let columns: Vec<String> = hdr_split.map(|s| s.trim().to_string()).collect();
+
+    let mut rows = Vec::new();
+    let mut types = None;
+    for (line_no, line) in lines.enumerate() {
+        let line = line?;
+        if line.starts_with("#q2:types") {
+            let declared = line
+                .split('\t')
+                .skip(1)
+                .map(|t| {
+                    ColumnType::from_name(t.trim()).with_context(|| {
+                        format!("Metadata line {}: unknown column type '{}'", line_no + 2, t)
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            types = Some(declared);
+            continue;
+        }
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.split('\t');
+        let id = parts.next().unwrap_or_default().trim().to_string();
+        let row: Vec<String> = parts.map(|s| s.trim().to_string()).collect();
+        if row.len() > columns.len() {
+            bail!(
+                "Metadata line {} has {} values but the header names {} columns",
+                line_no + 2,
+                row.len(),
+                columns.len()
+            );
+        }
+        rows.push((id, row));
+    }
+
+    let mut metadata = Metadata::from_rows(columns, rows)?;
+    if let Some(mut types) = types {
+        // Trailing columns left out of the types line are categorical
+        if types.len() < metadata.columns.len() {
+            types.resize(metadata.columns.len(), ColumnType::Categorical);
+        }
+        metadata.set_types(types)?;
+    }
+    Ok(metadata)
+}
+
+/// Write metadata in the same layout [`read_metadata`] reads
+pub fn write_metadata(metadata: &Metadata, filename: &str, force: bool) -> Result<()> {
+    let mut file = OutputFile::create(filename, force)?;
+    write!(file, "sample-id")?;
+    for c in &metadata.columns {
+        write!(file, "\t{}", c)?;
+    }
+    writeln!(file)?;
+    for (id, row) in metadata.sample_ids.iter().zip(metadata.values.iter()) {
+        write!(file, "{}", id)?;
+        for v in row {
+            write!(file, "\t{}", v)?;
+        }
+        writeln!(file)?;
+    }
+    file.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Write `contents` to a file of its own in the temp directory
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("unifrac-metadata-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn metadata() -> Metadata {
+        Metadata::from_rows(
+            strings(&["site", "ph", "empty"]),
+            vec![
+                ("S1".into(), strings(&["gut", "6.5"])),
+                ("S2".into(), strings(&["tongue", "", ""])),
+                ("S3".into(), strings(&["gut", "7"])),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn infers_column_types() {
+        let metadata = metadata();
+        assert_eq!(metadata.column_type("site"), Some(ColumnType::Categorical));
+        assert_eq!(metadata.column_type("ph"), Some(ColumnType::Numeric));
+        assert_eq!(metadata.column_type("empty"), Some(ColumnType::Categorical));
+        assert_eq!(metadata.column_type("depth"), None);
+    }
+
+    #[test]
+    fn looks_up_columns_in_sample_order() {
+        let metadata = metadata();
+        let samples = strings(&["S3", "S1"]);
+        assert_eq!(
+            metadata.column_for(&samples, "site").unwrap(),
+            strings(&["gut", "gut"])
+        );
+        let ph = metadata
+            .numeric_column_for(&strings(&["S1", "S2"]), "ph")
+            .unwrap();
+        assert_eq!(ph[0], 6.5);
+        assert!(ph[1].is_nan());
+        assert!(metadata.numeric_column_for(&samples, "site").is_err());
+        assert!(metadata.column_for(&samples, "depth").is_err());
+        assert_eq!(metadata.get("S2", "site"), Some("tongue"));
+        assert_eq!(metadata.get("S4", "site"), None);
+    }
+
+    #[test]
+    fn names_missing_samples() {
+        let err = metadata()
+            .check_samples(&strings(&["S1", "S4", "S5"]))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "2 of 3 samples are missing from the metadata: S4, S5"
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_and_overlong_rows() {
+        let columns = strings(&["site"]);
+        let duplicate = vec![
+            ("S1".into(), strings(&["gut"])),
+            ("S1".into(), strings(&["tongue"])),
+        ];
+        assert!(Metadata::from_rows(columns.clone(), duplicate).is_err());
+        let overlong = vec![("S1".into(), strings(&["gut", "extra"]))];
+        assert!(Metadata::from_rows(columns, overlong).is_err());
+    }
+
+    #[test]
+    fn reads_declared_types() {
+        let path = temp_file(
+            "types.tsv",
+            "sample-id\tsubject\tph\tsite\n\
+             #q2:types\tcategorical\tnumeric\n\
+             # a comment\n\
+             S1\t1\t6.5\tgut\n\
+             \n\
+             S2\t2\t\ttongue\n",
+        );
+        let metadata = read_metadata(&path.to_string_lossy()).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(metadata.sample_ids, strings(&["S1", "S2"]));
+        // Numbers, but declared categorical
+        assert_eq!(
+            metadata.column_type("subject"),
+            Some(ColumnType::Categorical)
+        );
+        assert_eq!(metadata.column_type("ph"), Some(ColumnType::Numeric));
+        assert_eq!(metadata.column_type("site"), Some(ColumnType::Categorical));
+    }
+
+    #[test]
+    fn rejects_text_in_declared_numeric_column() {
+        let path = temp_file(
+            "bad-types.tsv",
+            "sample-id\tph\n#q2:types\tnumeric\nS1\tacidic\n",
+        );
+        let err = read_metadata(&path.to_string_lossy()).unwrap_err();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Column 'ph' is numeric but sample 'S1' has 'acidic'"
+        );
+    }
+}
@@ -0,0 +1,169 @@
+//! Several UniFrac metrics in one pass: the tree is prepared once, and every
+//! pair is visited once for all of the requested metrics.
+
+use crate::{
+    compute::{branch_lengths, BranchOptions, BranchSets, Metric},
+    unrooted::UnrootedSets,
+    weighted::{branch_proportions, generalized_distance, generalized_distances_into, Transform},
+};
+use anyhow::Result;
+use ndarray::Array2;
+use phylotree::tree::Tree;
+use rayon::prelude::*;
+
+/// One metric's per-sample structures, built once, for the distance of any
+/// pair of samples
+pub enum PairDistance {
+    Unweighted(BranchSets),
+    Unrooted(UnrootedSets),
+    Generalized {
+        props: Array2<f64>,
+        lengths: Vec<f64>,
+        alpha: f64,
+    },
+}
+
+impl PairDistance {
+    pub fn new(
+        metric: Metric,
+        tree: &Tree,
+        taxa_order: &[String],
+        counts: &[Vec<f64>],
+        n_samples: usize,
+        opts: &BranchOptions,
+        transform: Transform,
+    ) -> Result<PairDistance> {
+        Ok(match metric {
+            Metric::Unweighted => PairDistance::Unweighted(BranchSets::new(
+                tree, taxa_order, counts, n_samples, opts,
+            )?),
+            Metric::Unrooted => PairDistance::Unrooted(UnrootedSets::new(
+                tree, taxa_order, counts, n_samples, opts,
+            )?),
+            Metric::Weighted | Metric::Generalized(_) => PairDistance::Generalized {
+                props: branch_proportions(tree, taxa_order, counts, n_samples, transform)?,
+                lengths: branch_lengths(tree, opts)?,
+                alpha: metric.alpha().unwrap(),
+            },
+        })
+    }
+
+    pub fn distance(&self, i: usize, j: usize) -> f64 {
+        match self {
+            PairDistance::Unweighted(sets) => sets.distance(i, j),
+            PairDistance::Unrooted(sets) => sets.distance(i, j),
+            PairDistance::Generalized {
+                props,
+                lengths,
+                alpha,
+            } => generalized_distance(
+                props.row(i).to_slice().unwrap(),
+                props.row(j).to_slice().unwrap(),
+                lengths,
+                *alpha,
+            ),
+        }
+    }
+
+    /// Full matrix (row-major, n_samples x n_samples) of the distances
+    pub fn matrix(&self, n_samples: usize) -> Vec<f64> {
+        let mut dist_matrix = vec![0.0; n_samples * n_samples];
+        dist_matrix
+            .par_chunks_mut(n_samples.max(1))
+            .enumerate()
+            .for_each(|(i, row)| {
+                for (j, d) in row.iter_mut().enumerate().skip(i + 1) {
+                    *d = self.distance(i, j);
+                }
+            });
+        for i in 0..n_samples {
+            for j in i + 1..n_samples {
+                dist_matrix[j * n_samples + i] = dist_matrix[i * n_samples + j];
+                // symmetric
+            }
+        }
+        dist_matrix
+    }
+}
+
+/// One distance matrix (row-major, n_samples x n_samples) per metric, in the
+/// order of `metrics`. `counts` may hold raw counts: the unweighted metrics
+/// treat any value > 0 as present, the weighted ones use `transform`.
+pub fn compute_metrics(
+    tree: &Tree,
+    taxa_order: &[String],
+    counts: &[Vec<f64>],
+    n_samples: usize,
+    opts: &BranchOptions,
+    metrics: &[Metric],
+    transform: Transform,
+) -> Result<Vec<Vec<f64>>> {
+    // Alpha of each weighted metric (1 for normalized weighted UniFrac)
+    let alphas: Vec<f64> = metrics.iter().filter_map(|m| m.alpha()).collect();
+
+    let sets = if metrics.contains(&Metric::Unweighted) {
+        Some(BranchSets::new(tree, taxa_order, counts, n_samples, opts)?)
+    } else {
+        None
+    };
+    let unrooted = if metrics.contains(&Metric::Unrooted) {
+        Some(UnrootedSets::new(
+            tree, taxa_order, counts, n_samples, opts,
+        )?)
+    } else {
+        None
+    };
+    let (lengths, props) = if alphas.is_empty() {
+        (Vec::new(), None)
+    } else {
+        let props = branch_proportions(tree, taxa_order, counts, n_samples, transform)?;
+        (branch_lengths(tree, opts)?, Some(props))
+    };
+
+    // Each row of each matrix is filled right of the diagonal by one task,
+    // then mirrored
+    let n = n_samples;
+    let mut matrices = vec![vec![0.0; n * n]; metrics.len()];
+    let mut rows: Vec<Vec<&mut [f64]>> = (0..n).map(|_| Vec::new()).collect();
+    for matrix in matrices.iter_mut() {
+        for (i, row) in matrix.chunks_mut(n.max(1)).enumerate() {
+            rows[i].push(row);
+        }
+    }
+    rows.into_par_iter().enumerate().for_each(|(i, mut row)| {
+        let mut weighted = vec![0.0; alphas.len()];
+        let mut den = vec![0.0; alphas.len()];
+        for j in i + 1..n {
+            if let Some(props) = &props {
+                generalized_distances_into(
+                    props.row(i).to_slice().unwrap(),
+                    props.row(j).to_slice().unwrap(),
+                    &lengths,
+                    &alphas,
+                    &mut weighted,
+                    &mut den,
+                );
+            }
+            let mut next_alpha = 0;
+            for (metric, out) in metrics.iter().zip(row.iter_mut()) {
+                out[j] = match metric {
+                    Metric::Unweighted => sets.as_ref().unwrap().distance(i, j),
+                    Metric::Unrooted => unrooted.as_ref().unwrap().distance(i, j),
+                    Metric::Weighted | Metric::Generalized(_) => {
+                        next_alpha += 1;
+                        weighted[next_alpha - 1]
+                    }
+                };
+            }
+        }
+    });
+    for matrix in matrices.iter_mut() {
+        for i in 0..n {
+            for j in i + 1..n {
+                matrix[j * n + i] = matrix[i * n + j]; // symmetric
+            }
+        }
+    }
+
+    Ok(matrices)
+}
@@ -0,0 +1,139 @@
+//! Reconciling table feature IDs with tree tip names that are written
+//! differently.
+
+use anyhow::{Context, Result};
+use md5::{Digest, Md5};
+use regex::Regex;
+use std::collections::HashMap;
+
+/// Cosmetic differences to ignore when matching feature IDs to tip names
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NameNormalization {
+    /// Spaces and underscores are the same character (newick writes spaces
+    /// in unquoted labels as underscores)
+    pub underscores: bool,
+    /// Surrounding single or double quotes are dropped
+    pub quotes: bool,
+    /// Letter case is ignored
+    pub case: bool,
+}
+
+impl NameNormalization {
+    /// Parse option names: `underscores`, `quotes`, `case`, or `all`
+    pub fn from_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Option<NameNormalization> {
+        let mut norm = NameNormalization::default();
+        for name in names {
+            match name {
+                "underscores" => norm.underscores = true,
+                "quotes" => norm.quotes = true,
+                "case" => norm.case = true,
+                "all" => {
+                    norm = NameNormalization {
+                        underscores: true,
+                        quotes: true,
+                        case: true,
+                    }
+                }
+                _ => return None,
+            }
+        }
+        Some(norm)
+    }
+
+    /// Canonical form of a name
+    pub fn apply(&self, name: &str) -> String {
+        let mut name = name.trim();
+        if self.quotes {
+            for q in ['\'', '"'] {
+                if name.len() >= 2 && name.starts_with(q) && name.ends_with(q) {
+                    name = name[1..name.len() - 1].trim();
+                }
+            }
+        }
+        let mut name = name.to_string();
+        if self.underscores {
+            name = name.replace(' ', "_");
+        }
+        if self.case {
+            name = name.to_lowercase();
+        }
+        name
+    }
+}
+
+/// Regular expression substitution on names, for IDs carrying pipeline
+/// decorations such as an `ASV_` prefix or a `;size=123` suffix
+#[derive(Clone, Debug)]
+pub struct IdRewrite {
+    pattern: Regex,
+    replacement: String,
+}
+
+impl IdRewrite {
+    /// `replacement` may refer to capture groups as `$1` or `$name`
+    pub fn new(pattern: &str, replacement: &str) -> Result<IdRewrite> {
+        let pattern =
+            Regex::new(pattern).with_context(|| format!("Invalid ID regex '{}'", pattern))?;
+        Ok(IdRewrite {
+            pattern,
+            replacement: replacement.to_string(),
+        })
+    }
+
+    /// Every match in `name` replaced
+    pub fn apply(&self, name: &str) -> String {
+        self.pattern
+            .replace_all(name, self.replacement.as_str())
+            .into_owned()
+    }
+}
+
+/// Hex MD5 digest of a sequence, as QIIME 2 names features: of the
+/// uppercase sequence without whitespace
+pub fn sequence_md5(sequence: &str) -> String {
+    let canonical: String = sequence
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    format!("{:x}", Md5::digest(canonical.as_bytes()))
+}
+
+/// MD5 of each (ID, sequence) record's sequence, by ID, so that names given
+/// as IDs and names given as hashes can be brought to the hashes
+pub fn sequence_hashes(records: &[(String, String)]) -> HashMap<String, String> {
+    records
+        .iter()
+        .map(|(id, sequence)| (id.clone(), sequence_md5(sequence)))
+        .collect()
+}
+
+/// Rename the features (rows) of a table (taxa x samples). Features that end
+/// up with the same name are merged by summing their rows, keeping the
+/// position of the first; also returns how many were merged away.
+pub fn rename_features(
+    taxa_order: Vec<String>,
+    counts: Vec<Vec<f64>>,
+    rename: impl Fn(&str) -> String,
+) -> (Vec<String>, Vec<Vec<f64>>, usize) {
+    let mut index: HashMap<String, usize> = HashMap::new();
+    let mut names = Vec::with_capacity(taxa_order.len());
+    let mut merged: Vec<Vec<f64>> = Vec::with_capacity(counts.len());
+    for (taxon, row) in taxa_order.iter().zip(counts) {
+        let name = rename(taxon);
+        match index.get(&name) {
+            Some(&k) => {
+                for (total, c) in merged[k].iter_mut().zip(row) {
+                    *total += c;
+                }
+            }
+            None => {
+                index.insert(name.clone(), names.len());
+                names.push(name);
+                merged.push(row);
+            }
+        }
+    }
+    let n_merged = taxa_order.len() - names.len();
+    (names, merged, n_merged)
+}
@@ -0,0 +1,339 @@
+//! Newick read incrementally from a buffered reader.
+//!
+//! The tree is never held as one string: nodes go into flat arrays (parent,
+//! name, branch length) as their tokens stream past, in preorder, and the
+//! [`Tree`] is built from those. Quoted labels ('' for a quote), [comments],
+//! internal node labels and whitespace between tokens are understood; a
+//! token out of place (a second label, a subtree after a closed one) is an
+//! error, and anything after the first ';' is ignored.
+
+use anyhow::{bail, Context, Result};
+use phylotree::tree::{Node, Tree};
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{BufRead, BufReader, ErrorKind, Read},
+    path::Path,
+};
+
+/// A parsed tree as flat arrays indexed by node, parents before children
+#[derive(Default)]
+pub struct FlatTree {
+    /// Parent of each node, None for the root (node 0)
+    pub parent: Vec<Option<usize>>,
+    pub name: Vec<Option<String>>,
+    /// Length of the branch above each node, if the newick gives one
+    pub length: Vec<Option<f64>>,
+}
+
+impl FlatTree {
+    pub fn len(&self) -> usize {
+        self.parent.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parent.is_empty()
+    }
+
+    fn push(&mut self, parent: Option<usize>) -> usize {
+        self.parent.push(parent);
+        self.name.push(None);
+        self.length.push(None);
+        self.parent.len() - 1
+    }
+
+    /// Build the tree, consuming the arrays as it goes
+    pub fn into_tree(self) -> Result<Tree> {
+        let mut tree = Tree::new();
+        let mut ids = Vec::with_capacity(self.len());
+        for ((parent, name), length) in self.parent.into_iter().zip(self.name).zip(self.length) {
+            let mut node = Node::new();
+            node.name = name;
+            let id = match parent {
+                None => {
+                    node.parent_edge = length;
+                    tree.add(node)
+                }
+                Some(p) => tree.add_child(node, ids[p], length)?,
+            };
+            ids.push(id);
+        }
+        Ok(tree)
+    }
+}
+
+/// Bytes of the input with the line and column of the last one read
+struct Cursor<R> {
+    reader: R,
+    peeked: Option<u8>,
+    line: usize,
+    column: usize,
+}
+
+impl<R: BufRead> Cursor<R> {
+    fn peek(&mut self) -> Result<Option<u8>> {
+        if self.peeked.is_none() {
+            let buf = loop {
+                match self.reader.fill_buf() {
+                    Ok(buf) => break buf,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e.into()),
+                }
+            };
+            if let Some(&byte) = buf.first() {
+                self.reader.consume(1);
+                self.peeked = Some(byte);
+            }
+        }
+        Ok(self.peeked)
+    }
+
+    fn next(&mut self) -> Result<Option<u8>> {
+        let byte = self.peek()?;
+        self.peeked = None;
+        if byte == Some(b'\n') {
+            self.line += 1;
+            self.column = 0;
+        } else if byte.is_some() {
+            self.column += 1;
+        }
+        Ok(byte)
+    }
+
+    /// Skip whitespace and comments
+    fn skip_blank(&mut self) -> Result<()> {
+        while let Some(byte) = self.peek()? {
+            match byte {
+                b'[' => {
+                    self.next()?;
+                    self.comment()?;
+                }
+                byte if byte.is_ascii_whitespace() => {
+                    self.next()?;
+                }
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn position(&self) -> String {
+        format!("line {}, column {}", self.line, self.column)
+    }
+
+    /// `first` and the characters after it up to the next delimiter,
+    /// whitespace or comment
+    fn unquoted(&mut self, first: Option<u8>) -> Result<String> {
+        let mut text: Vec<u8> = first.into_iter().collect();
+        while let Some(byte) = self.peek()? {
+            if b"(),:;[".contains(&byte) || byte.is_ascii_whitespace() {
+                break;
+            }
+            text.push(byte);
+            self.next()?;
+        }
+        String::from_utf8(text).with_context(|| format!("Invalid UTF-8 before {}", self.position()))
+    }
+
+    /// A label in single quotes, the opening one already read
+    fn quoted(&mut self) -> Result<String> {
+        let start = self.position();
+        let mut text = Vec::new();
+        loop {
+            match self.next()? {
+                Some(b'\'') if self.peek()? == Some(b'\'') => {
+                    self.next()?;
+                    text.push(b'\'');
+                }
+                Some(b'\'') => break,
+                Some(byte) => text.push(byte),
+                None => bail!("Quoted label opened at {} is never closed", start),
+            }
+        }
+        String::from_utf8(text).with_context(|| format!("Invalid UTF-8 in the label at {}", start))
+    }
+
+    /// Skip a [comment], the opening bracket already read
+    fn comment(&mut self) -> Result<()> {
+        let start = self.position();
+        loop {
+            match self.next()? {
+                Some(b']') => return Ok(()),
+                Some(_) => {}
+                None => bail!("Comment opened at {} is never closed", start),
+            }
+        }
+    }
+}
+
+/// Parse one newick tree from `reader` into flat arrays
+pub fn parse_newick<R: BufRead>(reader: R) -> Result<FlatTree> {
+    let mut input = Cursor {
+        reader,
+        peeked: None,
+        line: 1,
+        column: 0,
+    };
+    let mut flat = FlatTree::default();
+    let mut current = flat.push(None);
+    // Internal nodes whose children are being read
+    let mut open: Vec<usize> = Vec::new();
+    // Whether `current` has children already (so cannot get a second set)
+    let mut closed = false;
+    loop {
+        let Some(byte) = input.next()? else {
+            bail!(
+                "The tree ends at {} without a closing ';'",
+                input.position()
+            );
+        };
+        match byte {
+            b'(' => {
+                if closed || flat.name[current].is_some() || flat.length[current].is_some() {
+                    bail!("Unexpected '(' at {}", input.position());
+                }
+                open.push(current);
+                current = flat.push(Some(current));
+            }
+            b',' => {
+                let Some(&parent) = open.last() else {
+                    bail!("',' outside any parentheses at {}", input.position());
+                };
+                current = flat.push(Some(parent));
+                closed = false;
+            }
+            b')' => {
+                current = open
+                    .pop()
+                    .with_context(|| format!("Unbalanced ')' at {}", input.position()))?;
+                closed = true;
+            }
+            b':' => {
+                if flat.length[current].is_some() {
+                    bail!("Second branch length at {}", input.position());
+                }
+                input.skip_blank()?;
+                let text = input.unquoted(None)?;
+                let length = text.parse::<f64>().with_context(|| {
+                    format!("Invalid branch length '{}' at {}", text, input.position())
+                })?;
+                flat.length[current] = Some(length);
+            }
+            b'[' => input.comment()?,
+            b';' => break,
+            byte if byte.is_ascii_whitespace() => {}
+            _ => {
+                if flat.name[current].is_some() || flat.length[current].is_some() {
+                    bail!("Unexpected label at {}", input.position());
+                }
+                flat.name[current] = Some(if byte == b'\'' {
+                    input.quoted()?
+                } else {
+                    input.unquoted(Some(byte))?
+                });
+            }
+        }
+    }
+    if !open.is_empty() {
+        bail!("{} '(' are never closed", open.len());
+    }
+    Ok(flat)
+}
+
+/// `name` as a newick label: in single quotes (with '' for a quote) when it
+/// contains whitespace or a character newick gives a meaning to
+pub fn quote_label(name: &str) -> Cow<'_, str> {
+    let plain = !name.is_empty()
+        && !name
+            .bytes()
+            .any(|b| b"()[]',:;".contains(&b) || b.is_ascii_whitespace());
+    if plain {
+        Cow::Borrowed(name)
+    } else {
+        Cow::Owned(format!("'{}'", name.replace('\'', "''")))
+    }
+}
+
+/// Read a newick tree from a file without loading the file into memory
+pub fn read_newick_file(path: &Path) -> Result<Tree> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    parse_newick(BufReader::with_capacity(1 << 20, file))?.into_tree()
+}
+
+/// Read a newick tree from any reader
+pub fn read_newick<R: Read>(reader: R) -> Result<Tree> {
+    parse_newick(BufReader::new(reader))?.into_tree()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(newick: &str) -> Result<FlatTree> {
+        parse_newick(newick.as_bytes())
+    }
+
+    #[test]
+    fn parses_labels_lengths_and_structure() {
+        let flat = parse("((A:1,'B c':2)inner:0.5,C:1.5)root;").unwrap();
+        assert_eq!(flat.parent, vec![None, Some(0), Some(1), Some(1), Some(0)]);
+        let names: Vec<Option<&str>> = flat.name.iter().map(|n| n.as_deref()).collect();
+        assert_eq!(
+            names,
+            vec![
+                Some("root"),
+                Some("inner"),
+                Some("A"),
+                Some("B c"),
+                Some("C")
+            ]
+        );
+        assert_eq!(
+            flat.length,
+            vec![None, Some(0.5), Some(1.0), Some(2.0), Some(1.5)]
+        );
+    }
+
+    #[test]
+    fn accepts_whitespace_comments_and_quotes() {
+        let flat = parse("( A : 1 [note] ,\n 'it''s' :[x] 2 ) ;").unwrap();
+        assert_eq!(flat.name[1].as_deref(), Some("A"));
+        assert_eq!(flat.name[2].as_deref(), Some("it's"));
+        assert_eq!(flat.length[1], Some(1.0));
+        assert_eq!(flat.length[2], Some(2.0));
+    }
+
+    #[test]
+    fn ignores_text_after_the_semicolon() {
+        assert_eq!(parse("(A,B);\n(C,D);").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn rejects_malformed_trees() {
+        for newick in [
+            "(A,B)(C,D);",
+            "(A,B)C(D);",
+            "(A,B",
+            "(A,B;",
+            "(A,B));",
+            "A,B;",
+            "(A B,C);",
+            "(A:1:2,B);",
+            "(A:1 B,C);",
+            "(A:x,B);",
+            "('A,B);",
+            "(A[,B);",
+        ] {
+            assert!(parse(newick).is_err(), "{} was accepted", newick);
+        }
+    }
+
+    #[test]
+    fn quoted_labels_read_back() {
+        for name in ["plain", "two words", "a:b", "it's", "(x)", ""] {
+            let newick = format!("({},B);", quote_label(name));
+            let flat = parse(&newick).unwrap();
+            assert_eq!(flat.name[1].as_deref(), Some(name), "{}", newick);
+        }
+    }
+}
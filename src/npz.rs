@@ -0,0 +1,233 @@
+//! NumPy .npz archives (a zip of .npy arrays), for handing the internal
+//! branch structure and per-sample branch embeddings to Python:
+//! `scipy.sparse.load_npz` reads the sparse matrices, `numpy.load` reads
+//! everything.
+
+use crate::{
+    branches::{construct_b_sparse, leaf_layout, BranchOptions},
+    io::OutputFile,
+};
+use anyhow::Result;
+use ndarray::Array2;
+use phylotree::tree::Tree;
+use std::io::Write;
+use zip::{write::SimpleFileOptions, ZipWriter};
+
+/// Write B (branches x tips, CSR) with its branch lengths:
+/// `format`, `shape`, `data`, `indices`, `indptr` (the scipy.sparse layout),
+/// `brlens` (per row of B), `parent` (node id of each row's parent, -1 for
+/// the root), `node_names` (per row, empty if unlabelled) and `tip_names`
+/// (per column)
+pub fn write_b_npz(
+    tree: &Tree,
+    opts: &BranchOptions,
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let (leaf_order, tip_names) = leaf_layout(tree);
+    let (mat_b, brlens) = construct_b_sparse(tree, &leaf_order, opts)?;
+    let n_branches = tree.size();
+
+    let mut parent = Vec::with_capacity(n_branches);
+    let mut node_names = Vec::with_capacity(n_branches);
+    for idx in 0..n_branches {
+        let node = tree.get(&idx)?;
+        parent.push(node.parent.map(|p| p as i64).unwrap_or(-1));
+        node_names.push(node.name.clone().unwrap_or_default());
+    }
+
+    let as_i64 = |v: &[usize]| v.iter().map(|x| *x as i64).collect::<Vec<i64>>();
+    let arrays = [
+        ("format", npy_bytes_scalar(b"csr")),
+        (
+            "shape",
+            npy_i64(&[n_branches as i64, tip_names.len() as i64]),
+        ),
+        ("data", npy_u8(&vec![1; mat_b.nnz()])),
+        ("indices", npy_i64(&as_i64(&mat_b.indices))),
+        ("indptr", npy_i64(&as_i64(&mat_b.indptr))),
+        ("brlens", npy_f64(brlens.as_slice().unwrap())),
+        ("parent", npy_i64(&parent)),
+        ("node_names", npy_str(&node_names)),
+        ("tip_names", npy_str(&tip_names)),
+    ];
+
+    write_npz(&arrays, output_file, force)
+}
+
+/// Write per-sample embeddings (samples x branches, CSR over the nonzero
+/// entries) as a scipy.sparse archive, with `sample_names` (per row),
+/// `parent` and `node_names` (per column, as in [`write_b_npz`])
+pub fn write_embeddings_npz(
+    tree: &Tree,
+    sample_names: &[String],
+    embeddings: &Array2<f64>,
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let mut data = Vec::new();
+    let mut indices = Vec::new();
+    let mut indptr = vec![0i64];
+    for row in embeddings.rows() {
+        for (b, v) in row.iter().enumerate() {
+            if *v != 0.0 {
+                data.push(*v);
+                indices.push(b as i64);
+            }
+        }
+        indptr.push(data.len() as i64);
+    }
+
+    write_sample_csr(
+        tree,
+        sample_names,
+        npy_f64(&data),
+        &indices,
+        &indptr,
+        output_file,
+        force,
+    )
+}
+
+/// Write the samples' branch sets (node ids) as a binary samples x branches
+/// scipy.sparse archive, laid out as in [`write_embeddings_npz`]
+pub fn write_presence_npz(
+    tree: &Tree,
+    sample_names: &[String],
+    branches: &[Vec<usize>],
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let indices: Vec<i64> = branches.iter().flatten().map(|b| *b as i64).collect();
+    let mut indptr = vec![0i64];
+    for row in branches {
+        indptr.push(indptr.last().unwrap() + row.len() as i64);
+    }
+    write_sample_csr(
+        tree,
+        sample_names,
+        npy_u8(&vec![1; indices.len()]),
+        &indices,
+        &indptr,
+        output_file,
+        force,
+    )
+}
+
+/// A samples x branches CSR matrix with its sample and branch names
+fn write_sample_csr(
+    tree: &Tree,
+    sample_names: &[String],
+    data: Vec<u8>,
+    indices: &[i64],
+    indptr: &[i64],
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let n_branches = tree.size();
+    let mut parent = Vec::with_capacity(n_branches);
+    let mut node_names = Vec::with_capacity(n_branches);
+    for idx in 0..n_branches {
+        let node = tree.get(&idx)?;
+        parent.push(node.parent.map(|p| p as i64).unwrap_or(-1));
+        node_names.push(node.name.clone().unwrap_or_default());
+    }
+
+    let arrays = [
+        ("format", npy_bytes_scalar(b"csr")),
+        (
+            "shape",
+            npy_i64(&[sample_names.len() as i64, n_branches as i64]),
+        ),
+        ("data", data),
+        ("indices", npy_i64(indices)),
+        ("indptr", npy_i64(indptr)),
+        ("sample_names", npy_str(sample_names)),
+        ("parent", npy_i64(&parent)),
+        ("node_names", npy_str(&node_names)),
+    ];
+    write_npz(&arrays, output_file, force)
+}
+
+/// Zip the named .npy arrays into `output_file`, streamed to disk; arrays
+/// over 4 GiB get zip64 entries
+fn write_npz(arrays: &[(&str, Vec<u8>)], output_file: &str, force: bool) -> Result<()> {
+    let mut zip = ZipWriter::new(OutputFile::create(output_file, force)?);
+    for (name, array) in arrays {
+        let options = SimpleFileOptions::default().large_file(array.len() as u64 > u32::MAX as u64);
+        zip.start_file(format!("{}.npy", name), options)?;
+        zip.write_all(array)?;
+    }
+    zip.finish()?.commit()
+}
+
+/// .npy version 1.0 header for a C-ordered array; the header is padded so
+/// the data starts on a 64-byte boundary
+fn npy_header(descr: &str, shape: &[usize]) -> Vec<u8> {
+    let shape = match shape {
+        [] => "()".to_string(),
+        [n] => format!("({},)", n),
+        dims => format!(
+            "({})",
+            dims.iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut dict = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+        descr, shape
+    );
+    let unpadded = 10 + dict.len() + 1;
+    dict.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    dict.push('\n');
+
+    let mut out = b"\x93NUMPY\x01\x00".to_vec();
+    out.extend((dict.len() as u16).to_le_bytes());
+    out.extend(dict.as_bytes());
+    out
+}
+
+fn npy_f64(values: &[f64]) -> Vec<u8> {
+    let mut out = npy_header("<f8", &[values.len()]);
+    values.iter().for_each(|v| out.extend(v.to_le_bytes()));
+    out
+}
+
+fn npy_i64(values: &[i64]) -> Vec<u8> {
+    let mut out = npy_header("<i8", &[values.len()]);
+    values.iter().for_each(|v| out.extend(v.to_le_bytes()));
+    out
+}
+
+fn npy_u8(values: &[u8]) -> Vec<u8> {
+    let mut out = npy_header("|u1", &[values.len()]);
+    out.extend(values);
+    out
+}
+
+/// 0-d byte string, as scipy.sparse.save_npz stores the format name
+fn npy_bytes_scalar(value: &[u8]) -> Vec<u8> {
+    let mut out = npy_header(&format!("|S{}", value.len()), &[]);
+    out.extend(value);
+    out
+}
+
+/// Fixed-width unicode (UTF-32) array, as numpy stores str arrays
+fn npy_str(values: &[String]) -> Vec<u8> {
+    let width = values
+        .iter()
+        .map(|v| v.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let mut out = npy_header(&format!("<U{}", width), &[values.len()]);
+    for v in values {
+        let n = v.chars().count();
+        v.chars()
+            .chain(std::iter::repeat('\0').take(width - n))
+            .for_each(|c| out.extend((c as u32).to_le_bytes()));
+    }
+    out
+}
@@ -0,0 +1,153 @@
+//! NUMA-aware pair loop (feature `numa`, Linux only).
+//!
+//! On multi-socket nodes the pair loop is memory bound, and threads reading
+//! branch bitsets that live on the other socket pay for every access. Here
+//! each NUMA node gets its own thread pool pinned to its CPUs and its own copy
+//! of the read-only branch bitsets, built from inside that pool so the pages
+//! are first touched (and so placed) locally. Rows of the matrix are dealt out
+//! to the nodes round-robin, which keeps the triangular workload balanced.
+
+use crate::compute::{compute_unifrac_matrix_stats, BranchOptions, BranchSets, PairStats};
+use anyhow::{anyhow, bail, Context, Result};
+use phylotree::tree::Tree;
+use rayon::prelude::*;
+use std::fs;
+
+/// CPUs of each online NUMA node, from /sys/devices/system/node
+pub fn numa_nodes() -> Vec<Vec<usize>> {
+    let Ok(entries) = fs::read_dir("/sys/devices/system/node") else {
+        return Vec::new();
+    };
+    let mut nodes: Vec<(usize, Vec<usize>)> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().to_string_lossy().into_owned();
+            let id: usize = name.strip_prefix("node")?.parse().ok()?;
+            let cpulist = fs::read_to_string(e.path().join("cpulist")).ok()?;
+            let cpus = parse_cpulist(cpulist.trim());
+            (!cpus.is_empty()).then_some((id, cpus))
+        })
+        .collect();
+    nodes.sort();
+    nodes.into_iter().map(|(_, cpus)| cpus).collect()
+}
+
+/// Parse a kernel CPU list such as "0-3,8-11"
+fn parse_cpulist(list: &str) -> Vec<usize> {
+    let mut cpus = Vec::new();
+    for part in list.split(',').filter(|p| !p.is_empty()) {
+        match part.split_once('-') {
+            Some((lo, hi)) => {
+                if let (Ok(lo), Ok(hi)) = (lo.parse::<usize>(), hi.parse::<usize>()) {
+                    cpus.extend(lo..=hi);
+                }
+            }
+            None => cpus.extend(part.parse::<usize>().ok()),
+        }
+    }
+    cpus
+}
+
+/// Restrict the calling thread to `cpus`
+fn pin_to(cpus: &[usize]) -> Result<()> {
+    let setsize = libc::CPU_SETSIZE as usize;
+    if let Some(cpu) = cpus.iter().find(|cpu| **cpu >= setsize) {
+        bail!(
+            "CPU {} is beyond the {} CPUs a cpu_set_t can hold",
+            cpu,
+            setsize
+        );
+    }
+    // Safety: cpu_set_t is plain data, and CPU_SET is only given ids below CPU_SETSIZE
+    let status = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        for cpu in cpus {
+            libc::CPU_SET(*cpu, &mut set);
+        }
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if status != 0 {
+        return Err(std::io::Error::last_os_error())
+            .context("Failed to pin a thread to its NUMA node's CPUs");
+    }
+    Ok(())
+}
+
+/// Threads for each node: the current pool's thread count (e.g. from
+/// `--threads`) shared out in proportion to the nodes' CPUs, at least one
+/// and at most as many as the node has CPUs
+fn threads_per_node(nodes: &[Vec<usize>], total: usize) -> Vec<usize> {
+    let n_cpus: usize = nodes.iter().map(|cpus| cpus.len()).sum();
+    nodes
+        .iter()
+        .map(|cpus| (total * cpus.len()).div_ceil(n_cpus).clamp(1, cpus.len()))
+        .collect()
+}
+
+/// Same result as [`compute_unifrac_matrix_stats`], with one pinned thread
+/// pool and one copy of the branch bitsets per NUMA node. The pools together
+/// use as many threads as the calling rayon pool has. Falls back to the
+/// plain computation on single-node machines.
+pub fn compute_unifrac_matrix_numa(
+    tree: &Tree,
+    taxa_order: &[String],
+    presence_matrix: &[Vec<f64>],
+    n_samples: usize,
+    opts: &BranchOptions,
+) -> Result<(Vec<f64>, Vec<PairStats>)> {
+    let nodes = numa_nodes();
+    if nodes.len() < 2 {
+        return compute_unifrac_matrix_stats(tree, taxa_order, presence_matrix, n_samples, opts);
+    }
+
+    let threads = threads_per_node(&nodes, rayon::current_num_threads());
+    let parts: Vec<Result<Vec<PairStats>>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = nodes
+            .iter()
+            .zip(threads)
+            .enumerate()
+            .map(|(k, (cpus, n_threads))| {
+                let n_nodes = nodes.len();
+                scope.spawn(move || -> Result<Vec<PairStats>> {
+                    let pool = rayon::ThreadPoolBuilder::new()
+                        .num_threads(n_threads)
+                        .build()?;
+                    for pinned in pool.broadcast(|_| pin_to(cpus)) {
+                        pinned.with_context(|| format!("NUMA node {}", k))?;
+                    }
+                    pool.install(|| {
+                        let sets =
+                            BranchSets::new(tree, taxa_order, presence_matrix, n_samples, opts)?;
+                        let rows: Vec<usize> = (k..n_samples).step_by(n_nodes).collect();
+                        Ok(rows
+                            .par_iter()
+                            .flat_map_iter(|i| (i + 1..n_samples).map(|j| sets.pair_stats(*i, j)))
+                            .collect())
+                    })
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| {
+                h.join()
+                    .unwrap_or_else(|_| Err(anyhow!("NUMA worker panicked")))
+            })
+            .collect()
+    });
+
+    let mut stats = Vec::with_capacity(n_samples * n_samples.saturating_sub(1) / 2);
+    for part in parts {
+        stats.extend(part?);
+    }
+    stats.sort_by_key(|p| (p.i, p.j));
+
+    let mut dist_matrix = vec![0.0; n_samples * n_samples];
+    for pair in &stats {
+        dist_matrix[pair.i * n_samples + pair.j] = pair.distance;
+        dist_matrix[pair.j * n_samples + pair.i] = pair.distance; // symmetric
+    }
+
+    Ok((dist_matrix, stats))
+}
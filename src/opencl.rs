@@ -0,0 +1,134 @@
+//! OpenCL backend (feature `opencl`), for GPUs where wgpu/Vulkan drivers
+//! are unavailable. Uses the same bit-packed branch sets as the CPU bitset
+//! algorithm; one work item per pair.
+
+use crate::compute::{
+    branch_lengths, sample_branch_bits, sample_coverage, BranchOptions, PairStats,
+};
+use anyhow::{anyhow, Result};
+use ocl::{flags::MemFlags, Buffer, ProQue};
+use phylotree::tree::Tree;
+
+const KERNEL_SRC: &str = r#"
+#pragma OPENCL EXTENSION cl_khr_fp64 : enable
+
+__kernel void shared_branches(
+    __global const ulong* bits,
+    __global const double* lengths,
+    const uint n_words,
+    const uint n_samples,
+    __global double* shared_len,
+    __global uint* shared_count)
+{
+    uint i = get_global_id(0);
+    uint j = get_global_id(1);
+    if (i > j) {
+        return;
+    }
+    __global const ulong* a = bits + (ulong)i * n_words;
+    __global const ulong* b = bits + (ulong)j * n_words;
+    double len = 0.0;
+    uint count = 0;
+    for (uint w = 0; w < n_words; w++) {
+        ulong both = a[w] & b[w];
+        count += popcount(both);
+        while (both != 0) {
+            ulong low = both & (~both + 1);
+            len += lengths[w * 64 + (63 - clz(low))];
+            both ^= low;
+        }
+    }
+    shared_len[(ulong)i * n_samples + j] = len;
+    shared_count[(ulong)i * n_samples + j] = count;
+}
+"#;
+
+fn cl_err(err: ocl::Error) -> anyhow::Error {
+    anyhow!("OpenCL: {}", err)
+}
+
+/// Same result as [`crate::compute::compute_unifrac_matrix_stats`], with the
+/// pair loop running on the default OpenCL device
+pub fn compute_unifrac_matrix_opencl(
+    tree: &Tree,
+    taxa_order: &[String],
+    presence_matrix: &[Vec<f64>],
+    n_samples: usize,
+    opts: &BranchOptions,
+) -> Result<(Vec<f64>, Vec<PairStats>)> {
+    let mut lengths = branch_lengths(tree, opts)?;
+    let coverage = sample_coverage(tree, taxa_order, presence_matrix, n_samples)?;
+    let mut bits = sample_branch_bits(&coverage, lengths.len(), n_samples);
+    let n_words = lengths.len().div_ceil(64);
+
+    // Unless its edge is included, the root is not a branch; padding bits are never set
+    if !opts.include_root_edge {
+        let root = tree.get_root()?;
+        for s in 0..n_samples {
+            bits[s * n_words + root / 64] &= !(1 << (root % 64));
+        }
+    }
+    lengths.resize(n_words * 64, 0.0);
+
+    let pro_que = ProQue::builder()
+        .src(KERNEL_SRC)
+        .dims((n_samples, n_samples))
+        .build()
+        .map_err(cl_err)?;
+    let bits_buf = Buffer::<u64>::builder()
+        .queue(pro_que.queue().clone())
+        .flags(MemFlags::new().read_only())
+        .len(bits.len().max(1))
+        .copy_host_slice(&bits)
+        .build()
+        .map_err(cl_err)?;
+    let lengths_buf = Buffer::<f64>::builder()
+        .queue(pro_que.queue().clone())
+        .flags(MemFlags::new().read_only())
+        .len(lengths.len().max(1))
+        .copy_host_slice(&lengths)
+        .build()
+        .map_err(cl_err)?;
+    let len_buf = pro_que.create_buffer::<f64>().map_err(cl_err)?;
+    let count_buf = pro_que.create_buffer::<u32>().map_err(cl_err)?;
+
+    let kernel = pro_que
+        .kernel_builder("shared_branches")
+        .arg(&bits_buf)
+        .arg(&lengths_buf)
+        .arg(n_words as u32)
+        .arg(n_samples as u32)
+        .arg(&len_buf)
+        .arg(&count_buf)
+        .build()
+        .map_err(cl_err)?;
+    // Safety: the kernel only touches the buffers passed above, within bounds
+    unsafe { kernel.enq().map_err(cl_err)? };
+
+    let mut shared_len = vec![0.0; n_samples * n_samples];
+    let mut shared_count = vec![0u32; n_samples * n_samples];
+    len_buf.read(&mut shared_len).enq().map_err(cl_err)?;
+    count_buf.read(&mut shared_count).enq().map_err(cl_err)?;
+
+    let mut dist_matrix = vec![0.0; n_samples * n_samples];
+    let mut stats = Vec::with_capacity(n_samples * n_samples.saturating_sub(1) / 2);
+    for i in 0..n_samples {
+        for j in i + 1..n_samples {
+            let shared = shared_len[i * n_samples + j];
+            let union = shared_len[i * n_samples + i] + shared_len[j * n_samples + j] - shared;
+            let distance = 1.0 - shared / union;
+            dist_matrix[i * n_samples + j] = distance;
+            dist_matrix[j * n_samples + i] = distance; // symmetric
+            stats.push(PairStats {
+                i,
+                j,
+                distance,
+                shared_branches: shared_count[i * n_samples + j] as usize,
+                branches_i: shared_count[i * n_samples + i] as usize,
+                branches_j: shared_count[j * n_samples + j] as usize,
+            });
+        }
+    }
+
+    Ok((dist_matrix, stats))
+}
@@ -0,0 +1,729 @@
+use crate::stats::pearson;
+use anyhow::{bail, Result};
+use ndarray::{Array2, Axis};
+use rand::{seq::SliceRandom, Rng};
+
+/// Principal coordinates of a distance matrix
+pub struct Pcoa {
+    /// Eigenvalues of the retained axes, in decreasing order
+    pub eigenvalues: Vec<f64>,
+    /// Fraction of the total positive inertia captured by each axis
+    pub proportion_explained: Vec<f64>,
+    /// Sample coordinates, samples x axes
+    pub coordinates: Array2<f64>,
+}
+
+/// Classical (Gower) principal coordinates analysis of a square distance matrix
+/// stored row-major in `dist_matrix`. At most `n_axes` axes with positive
+/// eigenvalues are kept.
+pub fn pcoa(dist_matrix: &[f64], n: usize, n_axes: usize) -> Result<Pcoa> {
+    if n < 2 {
+        bail!("PCoA needs at least two samples, got {}", n);
+    }
+    if dist_matrix.len() != n * n {
+        bail!(
+            "Distance matrix has {} entries, expected {}",
+            dist_matrix.len(),
+            n * n
+        );
+    }
+
+    let mut centered = gower_center(dist_matrix, n);
+    let (values, vectors) = symmetric_eigen(&mut centered, n);
+
+    let total: f64 = values.iter().filter(|v| **v > 0.0).sum();
+    let kept: Vec<usize> = (0..n).filter(|k| values[*k] > 0.0).take(n_axes).collect();
+
+    let mut coordinates = Array2::<f64>::zeros((n, kept.len()));
+    for (axis, &k) in kept.iter().enumerate() {
+        let scale = values[k].sqrt();
+        for s in 0..n {
+            coordinates[(s, axis)] = vectors[s * n + k] * scale;
+        }
+    }
+    let eigenvalues: Vec<f64> = kept.iter().map(|k| values[*k]).collect();
+    let proportion_explained = eigenvalues.iter().map(|v| v / total).collect();
+
+    Ok(Pcoa {
+        eigenvalues,
+        proportion_explained,
+        coordinates,
+    })
+}
+
+/// Landmark (Nyström) PCoA: classical PCoA of the landmarks alone, with every
+/// sample placed from its distances to the landmarks (de Silva & Tenenbaum's
+/// landmark MDS), so only n x m distances are ever needed.
+///
+/// `landmark_dists` is n x m row-major, the distance of each sample to each
+/// landmark; `landmarks` gives the sample index of each landmark. Eigenvalues
+/// are scaled by n / m to estimate those of the full matrix.
+pub fn landmark_pcoa(
+    landmark_dists: &[f64],
+    landmarks: &[usize],
+    n: usize,
+    n_axes: usize,
+) -> Result<Pcoa> {
+    let m = landmarks.len();
+    if m < 2 {
+        bail!("Landmark PCoA needs at least two landmarks, got {}", m);
+    }
+    if landmark_dists.len() != n * m {
+        bail!(
+            "Landmark distances have {} entries, expected {}",
+            landmark_dists.len(),
+            n * m
+        );
+    }
+
+    let mut among = vec![0.0; m * m];
+    for (a, &s) in landmarks.iter().enumerate() {
+        among[a * m..(a + 1) * m].copy_from_slice(&landmark_dists[s * m..(s + 1) * m]);
+    }
+    let mean_sq: Vec<f64> = (0..m)
+        .map(|b| (0..m).map(|a| among[a * m + b].powi(2)).sum::<f64>() / m as f64)
+        .collect();
+    let mut centered = gower_center(&among, m);
+    let (values, vectors) = symmetric_eigen(&mut centered, m);
+
+    let total: f64 = values.iter().filter(|v| **v > 0.0).sum();
+    let kept: Vec<usize> = (0..m).filter(|k| values[*k] > 0.0).take(n_axes).collect();
+
+    let mut coordinates = Array2::<f64>::zeros((n, kept.len()));
+    for (axis, &k) in kept.iter().enumerate() {
+        let scale = values[k].sqrt();
+        for s in 0..n {
+            let row = &landmark_dists[s * m..(s + 1) * m];
+            let projection: f64 = (0..m)
+                .map(|b| vectors[b * m + k] * (row[b] * row[b] - mean_sq[b]))
+                .sum();
+            coordinates[(s, axis)] = -0.5 * projection / scale;
+        }
+    }
+    let eigenvalues: Vec<f64> = kept
+        .iter()
+        .map(|k| values[*k] * n as f64 / m as f64)
+        .collect();
+    let proportion_explained = kept.iter().map(|k| values[*k] / total).collect();
+
+    Ok(Pcoa {
+        eigenvalues,
+        proportion_explained,
+        coordinates,
+    })
+}
+
+/// Edge PCA (Matsen & Evans 2013): PCA of the samples' edge mass differences,
+/// given as samples x edges. Solved through the n x n Gram matrix of the
+/// column-centred data, so the cost does not grow with the square of the
+/// number of edges. Returns the sample scores (eigenvalues are variances) and
+/// the unit-length edge loadings of each axis, edges x axes.
+pub fn edge_pca(masses: &Array2<f64>, n_axes: usize) -> Result<(Pcoa, Array2<f64>)> {
+    let (n, n_edges) = masses.dim();
+    if n < 2 {
+        bail!("Edge PCA needs at least two samples, got {}", n);
+    }
+    let means = masses.mean_axis(Axis(0)).unwrap();
+    let centered = masses - &means;
+    let mut gram: Vec<f64> = centered.dot(&centered.t()).iter().copied().collect();
+    let (values, vectors) = symmetric_eigen(&mut gram, n);
+
+    let total: f64 = values.iter().filter(|v| **v > 0.0).sum();
+    let kept: Vec<usize> = (0..n).filter(|k| values[*k] > 0.0).take(n_axes).collect();
+
+    let mut coordinates = Array2::<f64>::zeros((n, kept.len()));
+    let mut loadings = Array2::<f64>::zeros((n_edges, kept.len()));
+    for (axis, &k) in kept.iter().enumerate() {
+        let scale = values[k].sqrt();
+        for s in 0..n {
+            coordinates[(s, axis)] = vectors[s * n + k] * scale;
+        }
+        for e in 0..n_edges {
+            let dot: f64 = (0..n).map(|s| centered[(s, e)] * vectors[s * n + k]).sum();
+            loadings[(e, axis)] = dot / scale;
+        }
+    }
+    let eigenvalues: Vec<f64> = kept.iter().map(|k| values[*k] / (n - 1) as f64).collect();
+    let proportion_explained = kept.iter().map(|k| values[*k] / total).collect();
+
+    Ok((
+        Pcoa {
+            eigenvalues,
+            proportion_explained,
+            coordinates,
+        },
+        loadings,
+    ))
+}
+
+/// DISTATIS consensus of several distance matrices over the same samples
+pub struct Distatis {
+    /// Ordination of the compromise (weighted mean) cross-product matrix
+    pub compromise: Pcoa,
+    /// Weight of each matrix in the compromise, summing to 1
+    pub weights: Vec<f64>,
+    /// RV coefficients between the matrices, k x k row-major
+    pub rv: Vec<f64>,
+    /// Each matrix's samples projected onto the compromise axes (partial
+    /// factor scores, whose mean is the compromise), samples x axes
+    pub partial: Vec<Array2<f64>>,
+}
+
+/// DISTATIS (Abdi et al. 2005): every matrix is double-centred into a
+/// cross-product matrix and scaled to a first eigenvalue of 1; their RV
+/// coefficients give the weights (the first eigenvector of the RV matrix,
+/// so matrices that agree with the others weigh more), and the weighted sum
+/// is ordinated like PCoA. At most `n_axes` axes with positive eigenvalues
+/// are kept.
+pub fn distatis(matrices: &[Vec<f64>], n: usize, n_axes: usize) -> Result<Distatis> {
+    let k = matrices.len();
+    if k < 2 {
+        bail!("DISTATIS needs at least two matrices, got {}", k);
+    }
+    if n < 2 {
+        bail!("DISTATIS needs at least two samples, got {}", n);
+    }
+    if let Some(m) = matrices.iter().find(|m| m.len() != n * n) {
+        bail!(
+            "Distance matrix has {} entries, expected {}",
+            m.len(),
+            n * n
+        );
+    }
+
+    let cross: Vec<Vec<f64>> = matrices
+        .iter()
+        .map(|d| {
+            let mut c = gower_center(d, n);
+            let (values, _) = symmetric_eigen(&mut c.clone(), n);
+            if values[0] > 0.0 {
+                c.iter_mut().for_each(|v| *v /= values[0]);
+            }
+            c
+        })
+        .collect();
+
+    let dot = |a: &[f64], b: &[f64]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f64>();
+    let mut rv = vec![0.0; k * k];
+    for i in 0..k {
+        for j in i..k {
+            let r = dot(&cross[i], &cross[j])
+                / (dot(&cross[i], &cross[i]) * dot(&cross[j], &cross[j])).sqrt();
+            rv[i * k + j] = r;
+            rv[j * k + i] = r;
+        }
+    }
+    let (_, rv_vectors) = symmetric_eigen(&mut rv.clone(), k);
+    let first: Vec<f64> = (0..k).map(|t| rv_vectors[t * k]).collect();
+    let sum: f64 = first.iter().sum();
+    if sum == 0.0 || first.iter().any(|v| v.is_nan()) {
+        bail!("The matrices' RV coefficients give no usable weights");
+    }
+    let weights: Vec<f64> = first.iter().map(|v| v / sum).collect();
+
+    let mut compromise = vec![0.0; n * n];
+    for (c, w) in cross.iter().zip(&weights) {
+        compromise.iter_mut().zip(c).for_each(|(s, v)| *s += w * v);
+    }
+    let (values, vectors) = symmetric_eigen(&mut compromise, n);
+    let total: f64 = values.iter().filter(|v| **v > 0.0).sum();
+    let kept: Vec<usize> = (0..n).filter(|a| values[*a] > 0.0).take(n_axes).collect();
+
+    let mut coordinates = Array2::<f64>::zeros((n, kept.len()));
+    for (axis, &a) in kept.iter().enumerate() {
+        let scale = values[a].sqrt();
+        for s in 0..n {
+            coordinates[(s, axis)] = vectors[s * n + a] * scale;
+        }
+    }
+
+    // F_t = k w_t S_t U Λ^(-1/2)
+    let partial = cross
+        .iter()
+        .zip(&weights)
+        .map(|(c, w)| {
+            let mut scores = Array2::<f64>::zeros((n, kept.len()));
+            for (axis, &a) in kept.iter().enumerate() {
+                let scale = k as f64 * w / values[a].sqrt();
+                for s in 0..n {
+                    let projected: f64 = (0..n).map(|j| c[s * n + j] * vectors[j * n + a]).sum();
+                    scores[(s, axis)] = scale * projected;
+                }
+            }
+            scores
+        })
+        .collect();
+
+    let eigenvalues: Vec<f64> = kept.iter().map(|a| values[*a]).collect();
+    let proportion_explained = eigenvalues.iter().map(|v| v / total).collect();
+    Ok(Distatis {
+        compromise: Pcoa {
+            eigenvalues,
+            proportion_explained,
+            coordinates,
+        },
+        weights,
+        rv,
+        partial,
+    })
+}
+
+/// Distance-based redundancy analysis of a matrix against a design
+pub struct Dbrda {
+    /// Ordination of the fitted part, the constrained (CAP) axes
+    pub constrained: Pcoa,
+    /// Correlation of each design column with each constrained axis,
+    /// columns x axes
+    pub biplot: Array2<f64>,
+    /// Rank of the design once centred, the constrained degrees of freedom
+    pub rank: usize,
+    /// Trace of the Gower-centred matrix
+    pub total_inertia: f64,
+    /// Part of it fitted by the design
+    pub constrained_inertia: f64,
+    pub f_statistic: f64,
+    pub p_value: f64,
+    pub permutations: usize,
+}
+
+/// db-RDA (Legendre & Anderson 1999; McArdle & Anderson 2001, as vegan's
+/// capscale and dbrda): the Gower-centred matrix G is projected onto the
+/// span of the centred design columns (one per variable, `design` holding
+/// each over the n samples), H G H is ordinated like PCoA, and the pseudo-F
+/// (tr(HGH) / q) / (tr((I - H) G (I - H)) / (n - q - 1)) is tested by
+/// permuting the samples. Proportions explained are of the total inertia.
+pub fn dbrda<R: Rng>(
+    dist_matrix: &[f64],
+    n: usize,
+    design: &[Vec<f64>],
+    n_axes: usize,
+    permutations: usize,
+    rng: &mut R,
+) -> Result<Dbrda> {
+    if dist_matrix.len() != n * n {
+        bail!(
+            "Distance matrix has {} entries, expected {}",
+            dist_matrix.len(),
+            n * n
+        );
+    }
+    let centered: Vec<Vec<f64>> = design
+        .iter()
+        .map(|column| {
+            let mean = column.iter().sum::<f64>() / n as f64;
+            column.iter().map(|v| v - mean).collect()
+        })
+        .collect();
+
+    // Orthonormal basis Q of the design (Gram-Schmidt), dropping aliased columns
+    let norm = |v: &[f64]| v.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let mut basis: Vec<Vec<f64>> = Vec::new();
+    for column in &centered {
+        let mut v = column.clone();
+        for q in &basis {
+            let dot: f64 = v.iter().zip(q).map(|(a, b)| a * b).sum();
+            v.iter_mut().zip(q).for_each(|(a, b)| *a -= dot * b);
+        }
+        let length = norm(&v);
+        if length > 1e-9 * norm(column).max(f64::MIN_POSITIVE) {
+            v.iter_mut().for_each(|a| *a /= length);
+            basis.push(v);
+        }
+    }
+    let q = basis.len();
+    if q == 0 {
+        bail!("The design does not vary between the samples");
+    }
+    if q + 1 >= n {
+        bail!(
+            "The design has rank {} for {} samples; nothing is left for the residual",
+            q,
+            n
+        );
+    }
+
+    let g = gower_center(dist_matrix, n);
+    let total_inertia: f64 = (0..n).map(|i| g[i * n + i]).sum();
+    // tr(HGH) = tr(Q'GQ), with the rows of Q taken in `order`
+    let fitted_trace = |order: &[usize]| -> f64 {
+        basis
+            .iter()
+            .map(|b| {
+                (0..n)
+                    .map(|i| {
+                        let row = &g[i * n..(i + 1) * n];
+                        b[order[i]] * (0..n).map(|j| row[j] * b[order[j]]).sum::<f64>()
+                    })
+                    .sum::<f64>()
+            })
+            .sum()
+    };
+    let pseudo_f =
+        |fitted: f64| (fitted / q as f64) / ((total_inertia - fitted) / (n - q - 1) as f64);
+
+    let identity: Vec<usize> = (0..n).collect();
+    let constrained_inertia = fitted_trace(&identity);
+    let f_statistic = pseudo_f(constrained_inertia);
+    let mut shuffled = identity.clone();
+    let mut as_extreme = 0;
+    for _ in 0..permutations {
+        shuffled.shuffle(rng);
+        if pseudo_f(fitted_trace(&shuffled)) >= f_statistic {
+            as_extreme += 1;
+        }
+    }
+
+    // H G H = Q (Q'GQ) Q', so its axes are Q times those of the q x q Q'GQ
+    let gq: Vec<Vec<f64>> = basis
+        .iter()
+        .map(|b| {
+            (0..n)
+                .map(|i| (0..n).map(|j| g[i * n + j] * b[j]).sum())
+                .collect()
+        })
+        .collect();
+    let mut small = vec![0.0; q * q];
+    for a in 0..q {
+        for b in 0..q {
+            small[a * q + b] = basis[a].iter().zip(&gq[b]).map(|(x, y)| x * y).sum();
+        }
+    }
+    let (values, vectors) = symmetric_eigen(&mut small, q);
+    let kept: Vec<usize> = (0..q).filter(|a| values[*a] > 0.0).take(n_axes).collect();
+    let mut coordinates = Array2::<f64>::zeros((n, kept.len()));
+    for (axis, &a) in kept.iter().enumerate() {
+        let scale = values[a].sqrt();
+        for s in 0..n {
+            let along: f64 = (0..q).map(|b| basis[b][s] * vectors[b * q + a]).sum();
+            coordinates[(s, axis)] = along * scale;
+        }
+    }
+
+    let mut biplot = Array2::<f64>::zeros((design.len(), kept.len()));
+    for (c, column) in centered.iter().enumerate() {
+        for axis in 0..kept.len() {
+            let scores = coordinates.column(axis).to_vec();
+            biplot[(c, axis)] = pearson(column, &scores);
+        }
+    }
+
+    let eigenvalues: Vec<f64> = kept.iter().map(|a| values[*a]).collect();
+    let proportion_explained = eigenvalues.iter().map(|v| v / total_inertia).collect();
+    Ok(Dbrda {
+        constrained: Pcoa {
+            eigenvalues,
+            proportion_explained,
+            coordinates,
+        },
+        biplot,
+        rank: q,
+        total_inertia,
+        constrained_inertia,
+        f_statistic,
+        p_value: (as_extreme + 1) as f64 / (permutations + 1) as f64,
+        permutations,
+    })
+}
+
+/// Double-center -0.5 * D^2, i.e. compute J A J with J = I - 11'/n
+pub fn gower_center(dist_matrix: &[f64], n: usize) -> Vec<f64> {
+    let mut a: Vec<f64> = dist_matrix.iter().map(|d| -0.5 * d * d).collect();
+    let row_means: Vec<f64> = (0..n)
+        .map(|i| a[i * n..(i + 1) * n].iter().sum::<f64>() / n as f64)
+        .collect();
+    let grand_mean = row_means.iter().sum::<f64>() / n as f64;
+    for i in 0..n {
+        for j in 0..n {
+            // A is symmetric, so column means equal row means
+            a[i * n + j] += grand_mean - row_means[i] - row_means[j];
+        }
+    }
+    a
+}
+
+/// Eigen-decomposition of a symmetric n x n matrix (row-major, overwritten).
+/// Returns eigenvalues sorted in decreasing order and the matching eigenvectors
+/// as the columns of a row-major n x n matrix.
+///
+/// Householder tridiagonalisation followed by the implicit QL algorithm
+/// (tred2/tql2, as in EISPACK and JAMA).
+pub fn symmetric_eigen(a: &mut [f64], n: usize) -> (Vec<f64>, Vec<f64>) {
+    let mut d = vec![0.0; n];
+    let mut e = vec![0.0; n];
+    tred2(a, n, &mut d, &mut e);
+    tql2(a, n, &mut d, &mut e);
+
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|x, y| d[*y].total_cmp(&d[*x]));
+    let values = order.iter().map(|k| d[*k]).collect();
+    let mut vectors = vec![0.0; n * n];
+    for (col, &k) in order.iter().enumerate() {
+        for row in 0..n {
+            vectors[row * n + col] = a[row * n + k];
+        }
+    }
+    (values, vectors)
+}
+
+fn tred2(v: &mut [f64], n: usize, d: &mut [f64], e: &mut [f64]) {
+    for j in 0..n {
+        d[j] = v[(n - 1) * n + j];
+    }
+
+    for i in (1..n).rev() {
+        let mut scale = 0.0;
+        let mut h = 0.0;
+        for k in 0..i {
+            scale += d[k].abs();
+        }
+        if scale == 0.0 {
+            e[i] = d[i - 1];
+            for j in 0..i {
+                d[j] = v[(i - 1) * n + j];
+                v[i * n + j] = 0.0;
+                v[j * n + i] = 0.0;
+            }
+        } else {
+            for k in 0..i {
+                d[k] /= scale;
+                h += d[k] * d[k];
+            }
+            let mut f = d[i - 1];
+            let mut g = h.sqrt();
+            if f > 0.0 {
+                g = -g;
+            }
+            e[i] = scale * g;
+            h -= f * g;
+            d[i - 1] = f - g;
+            for j in 0..i {
+                e[j] = 0.0;
+            }
+
+            for j in 0..i {
+                f = d[j];
+                v[j * n + i] = f;
+                g = e[j] + v[j * n + j] * f;
+                for k in j + 1..i {
+                    g += v[k * n + j] * d[k];
+                    e[k] += v[k * n + j] * f;
+                }
+                e[j] = g;
+            }
+            f = 0.0;
+            for j in 0..i {
+                e[j] /= h;
+                f += e[j] * d[j];
+            }
+            let hh = f / (h + h);
+            for j in 0..i {
+                e[j] -= hh * d[j];
+            }
+            for j in 0..i {
+                f = d[j];
+                g = e[j];
+                for k in j..i {
+                    v[k * n + j] -= f * e[k] + g * d[k];
+                }
+                d[j] = v[(i - 1) * n + j];
+                v[i * n + j] = 0.0;
+            }
+        }
+        d[i] = h;
+    }
+
+    for i in 0..n.saturating_sub(1) {
+        v[(n - 1) * n + i] = v[i * n + i];
+        v[i * n + i] = 1.0;
+        let h = d[i + 1];
+        if h != 0.0 {
+            for k in 0..=i {
+                d[k] = v[k * n + i + 1] / h;
+            }
+            for j in 0..=i {
+                let mut g = 0.0;
+                for k in 0..=i {
+                    g += v[k * n + i + 1] * v[k * n + j];
+                }
+                for k in 0..=i {
+                    v[k * n + j] -= g * d[k];
+                }
+            }
+        }
+        for k in 0..=i {
+            v[k * n + i + 1] = 0.0;
+        }
+    }
+    for j in 0..n {
+        d[j] = v[(n - 1) * n + j];
+        v[(n - 1) * n + j] = 0.0;
+    }
+    if n > 0 {
+        v[(n - 1) * n + n - 1] = 1.0;
+    }
+    e[0] = 0.0;
+}
+
+fn tql2(v: &mut [f64], n: usize, d: &mut [f64], e: &mut [f64]) {
+    for i in 1..n {
+        e[i - 1] = e[i];
+    }
+    if n > 0 {
+        e[n - 1] = 0.0;
+    }
+
+    let mut f = 0.0;
+    let mut tst1: f64 = 0.0;
+    let eps = f64::EPSILON;
+    for l in 0..n {
+        tst1 = tst1.max(d[l].abs() + e[l].abs());
+        let mut m = l;
+        while m < n {
+            if e[m].abs() <= eps * tst1 {
+                break;
+            }
+            m += 1;
+        }
+        if m == n {
+            m = n - 1;
+        }
+
+        if m > l {
+            loop {
+                let mut g = d[l];
+                let mut p = (d[l + 1] - g) / (2.0 * e[l]);
+                let mut r = p.hypot(1.0);
+                if p < 0.0 {
+                    r = -r;
+                }
+                d[l] = e[l] / (p + r);
+                d[l + 1] = e[l] * (p + r);
+                let dl1 = d[l + 1];
+                let mut h = g - d[l];
+                for i in l + 2..n {
+                    d[i] -= h;
+                }
+                f += h;
+
+                p = d[m];
+                let mut c = 1.0;
+                let mut c2 = c;
+                let mut c3 = c;
+                let el1 = e[l + 1];
+                let mut s = 0.0;
+                let mut s2 = 0.0;
+                for i in (l..m).rev() {
+                    c3 = c2;
+                    c2 = c;
+                    s2 = s;
+                    g = c * e[i];
+                    h = c * p;
+                    r = p.hypot(e[i]);
+                    e[i + 1] = s * r;
+                    s = e[i] / r;
+                    c = p / r;
+                    p = c * d[i] - s * g;
+                    d[i + 1] = h + s * (c * g + s * d[i]);
+                    for k in 0..n {
+                        h = v[k * n + i + 1];
+                        v[k * n + i + 1] = s * v[k * n + i] + c * h;
+                        v[k * n + i] = c * v[k * n + i] - s * h;
+                    }
+                }
+                p = -s * s2 * c3 * el1 * e[l] / dl1;
+                e[l] = s * p;
+                d[l] = c * p;
+
+                if e[l].abs() <= eps * tst1 {
+                    break;
+                }
+            }
+        }
+        d[l] += f;
+        e[l] = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Corners of a 3 x 4 rectangle: (0,0), (3,0), (0,4), (3,4)
+    const RECTANGLE: [f64; 16] = [
+        0.0, 3.0, 4.0, 5.0, //
+        3.0, 0.0, 5.0, 4.0, //
+        4.0, 5.0, 0.0, 3.0, //
+        5.0, 4.0, 3.0, 0.0,
+    ];
+
+    fn assert_close(actual: f64, expected: f64, what: &str) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{}: {} instead of {}",
+            what,
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn pcoa_recovers_euclidean_points() {
+        let result = pcoa(&RECTANGLE, 4, 2).unwrap();
+        assert_close(result.eigenvalues[0], 16.0, "first eigenvalue");
+        assert_close(result.eigenvalues[1], 9.0, "second eigenvalue");
+        assert_close(result.proportion_explained[0], 0.64, "first proportion");
+        assert_close(result.proportion_explained[1], 0.36, "second proportion");
+
+        let c = &result.coordinates;
+        for i in 0..4 {
+            for j in 0..4 {
+                let d = ((c[(i, 0)] - c[(j, 0)]).powi(2) + (c[(i, 1)] - c[(j, 1)]).powi(2)).sqrt();
+                assert_close(d, RECTANGLE[i * 4 + j], &format!("distance {}-{}", i, j));
+            }
+        }
+    }
+
+    #[test]
+    fn pcoa_keeps_at_most_n_axes() {
+        let result = pcoa(&RECTANGLE, 4, 1).unwrap();
+        assert_eq!(result.eigenvalues.len(), 1);
+        assert_eq!(result.coordinates.dim(), (4, 1));
+        assert_close(result.proportion_explained[0], 0.64, "proportion");
+    }
+
+    #[test]
+    fn pcoa_rejects_bad_input() {
+        assert!(pcoa(&[0.0], 1, 2).is_err());
+        assert!(pcoa(&RECTANGLE[..9], 4, 2).is_err());
+    }
+
+    #[test]
+    fn gower_centering_zeroes_row_sums() {
+        let centered = gower_center(&RECTANGLE, 4);
+        for i in 0..4 {
+            let sum: f64 = centered[i * 4..(i + 1) * 4].iter().sum();
+            assert_close(sum, 0.0, &format!("row {}", i));
+        }
+    }
+
+    #[test]
+    fn eigen_decomposition_is_sorted() {
+        let mut a = [
+            2.0, 1.0, 0.0, //
+            1.0, 2.0, 0.0, //
+            0.0, 0.0, 5.0,
+        ];
+        let (values, vectors) = symmetric_eigen(&mut a, 3);
+        assert_close(values[0], 5.0, "first eigenvalue");
+        assert_close(values[1], 3.0, "second eigenvalue");
+        assert_close(values[2], 1.0, "third eigenvalue");
+        // Eigenvectors are the columns, each determined up to its sign
+        let v = |row: usize, col: usize| vectors[row * 3 + col];
+        assert_close(v(0, 0), 0.0, "first vector, x");
+        assert_close(v(2, 0).abs(), 1.0, "first vector, z");
+        assert_close(v(0, 1) * v(1, 1), 0.5, "second vector, x * y");
+        assert_close(v(2, 1), 0.0, "second vector, z");
+        assert_close(v(0, 2) * v(1, 2), -0.5, "third vector, x * y");
+        assert_close(v(2, 2), 0.0, "third vector, z");
+    }
+}
@@ -0,0 +1,101 @@
+//! Phylogenetic isometric log-ratio coordinates (PhILR, Silverman et al.
+//! 2017).
+//!
+//! Every internal node of a bifurcating tree splits the tips below it into
+//! its two children's, and the balance of that split,
+//! sqrt(r s / (r + s)) ln(g(left) / g(right)) with r and s tips on each side
+//! and g the geometric mean of their (pseudocounted) counts, is one
+//! coordinate. Only tips that are features of the table take part, so a tree
+//! with n such tips gives n - 1 balances.
+
+use anyhow::{bail, Result};
+use ndarray::Array2;
+use phylotree::tree::Tree;
+use std::collections::HashMap;
+
+/// Balances of each sample, samples x balances, with the node of each balance
+/// (parents before children)
+pub struct Balances {
+    pub nodes: Vec<usize>,
+    pub values: Array2<f64>,
+}
+
+/// PhILR balances of every sample; `pseudocount` is added to each count
+/// before taking logs
+pub fn philr(
+    tree: &Tree,
+    taxa_order: &[String],
+    counts: &[Vec<f64>],
+    n_samples: usize,
+    pseudocount: f64,
+) -> Result<Balances> {
+    let taxon_index: HashMap<&str, usize> = taxa_order
+        .iter()
+        .enumerate()
+        .map(|(t, name)| (name.as_str(), t))
+        .collect();
+
+    // Table tips below each node and the sum of their log counts
+    let mut n_below = vec![0usize; tree.size()];
+    let mut ln_sum = Array2::<f64>::zeros((n_samples, tree.size()));
+    let root = tree.get_root()?;
+    let order = tree.postorder(&root)?;
+    for &idx in &order {
+        let node = tree.get(&idx)?;
+        if node.is_tip() {
+            let name = node.name.as_deref().unwrap_or_default();
+            if let Some(t) = taxon_index.get(name) {
+                n_below[idx] = 1;
+                for s in 0..n_samples {
+                    ln_sum[(s, idx)] = (counts[*t][s].max(0.0) + pseudocount).ln();
+                }
+            }
+            continue;
+        }
+        let occupied = node.children.iter().filter(|c| n_below[**c] > 0).count();
+        if occupied > 2 {
+            bail!(
+                "PhILR needs a bifurcating tree, but node {} has {} children with table features; resolve its polytomy first",
+                idx,
+                occupied
+            );
+        }
+        for c in node.children.iter() {
+            n_below[idx] += n_below[*c];
+            for s in 0..n_samples {
+                ln_sum[(s, idx)] += ln_sum[(s, *c)];
+            }
+        }
+    }
+
+    let mut nodes = Vec::new();
+    let mut columns = Vec::new();
+    for &idx in order.iter().rev() {
+        let sides: Vec<usize> = tree
+            .get(&idx)?
+            .children
+            .iter()
+            .copied()
+            .filter(|c| n_below[*c] > 0)
+            .collect();
+        let [left, right] = sides[..] else {
+            continue;
+        };
+        let (r, s) = (n_below[left] as f64, n_below[right] as f64);
+        let scale = (r * s / (r + s)).sqrt();
+        nodes.push(idx);
+        columns.push(
+            (0..n_samples)
+                .map(|k| scale * (ln_sum[(k, left)] / r - ln_sum[(k, right)] / s))
+                .collect::<Vec<f64>>(),
+        );
+    }
+
+    let mut values = Array2::<f64>::zeros((n_samples, nodes.len()));
+    for (b, column) in columns.into_iter().enumerate() {
+        for (s, v) in column.into_iter().enumerate() {
+            values[(s, b)] = v;
+        }
+    }
+    Ok(Balances { nodes, values })
+}
@@ -0,0 +1,599 @@
+use crate::newick::{quote_label, read_newick, FlatTree};
+use anyhow::{bail, Context, Result};
+use phylotree::tree::Tree;
+use std::collections::{HashMap, HashSet};
+
+/// Names of all tips of the tree
+pub fn tip_names(tree: &Tree) -> HashSet<String> {
+    tree.get_leaves()
+        .iter()
+        .filter_map(|l| tree.get(l).ok().and_then(|n| n.name.clone()))
+        .collect()
+}
+
+/// Id of the node carrying `name` (tip name or internal node label)
+pub fn find_node(tree: &Tree, name: &str) -> Result<usize> {
+    let root = tree.get_root()?;
+    for idx in tree.postorder(&root)? {
+        if tree.get(&idx)?.name.as_deref() == Some(name) {
+            return Ok(idx);
+        }
+    }
+    bail!("No node named '{}' in the tree", name)
+}
+
+/// Most recent common ancestor of a set of nodes
+pub fn mrca(tree: &Tree, nodes: &[usize]) -> Result<usize> {
+    let (first, rest) = nodes.split_first().context("MRCA of an empty node set")?;
+
+    // Ancestors of the first node, from itself up to the root
+    let mut path = vec![*first];
+    while let Some(parent) = tree.get(path.last().unwrap())?.parent {
+        path.push(parent);
+    }
+
+    // Walk the path upwards until an ancestor covers every other node
+    let mut lowest = 0;
+    for node in rest {
+        let mut ancestors = HashSet::new();
+        let mut cur = Some(*node);
+        while let Some(idx) = cur {
+            ancestors.insert(idx);
+            cur = tree.get(&idx)?.parent;
+        }
+        while !ancestors.contains(&path[lowest]) {
+            lowest += 1;
+        }
+    }
+
+    Ok(path[lowest])
+}
+
+/// Resolve a clade specification: either a node name, or a comma-separated
+/// list of tip names whose MRCA defines the clade
+pub fn resolve_clade(tree: &Tree, spec: &str) -> Result<usize> {
+    if spec.contains(',') {
+        let tips = spec
+            .split(',')
+            .map(|name| find_node(tree, name.trim()))
+            .collect::<Result<Vec<_>>>()?;
+        mrca(tree, &tips)
+    } else {
+        find_node(tree, spec.trim())
+    }
+}
+
+/// Name of the branch above `node`: the node's own name, or for an unnamed
+/// internal node its first and last descendant tips, "T1,T9", which
+/// [`resolve_clade`] maps back to the node
+pub fn branch_label(tree: &Tree, node: usize) -> Result<String> {
+    let n = tree.get(&node)?;
+    if let Some(name) = n.name.as_deref().filter(|s| !s.is_empty()) {
+        return Ok(name.to_string());
+    }
+    if n.is_tip() {
+        return Ok(format!("node{}", node));
+    }
+    let mut first = node;
+    while let Some(c) = tree.get(&first)?.children.first() {
+        first = *c;
+    }
+    let mut last = node;
+    while let Some(c) = tree.get(&last)?.children.last() {
+        last = *c;
+    }
+    let name =
+        |idx: usize| -> Result<String> { Ok(tree.get(&idx)?.name.clone().unwrap_or_default()) };
+    Ok(format!("{},{}", name(first)?, name(last)?))
+}
+
+/// Newick string of the subtree rooted at `node`. The node's own parent edge
+/// is left out, so the subtree root becomes the root of the new tree.
+pub fn subtree_newick(tree: &Tree, node: usize) -> Result<String> {
+    let order = tree.postorder(&node)?;
+    let mut rendered: Vec<Option<String>> = vec![None; tree.size()];
+
+    for idx in order {
+        let n = tree.get(&idx)?;
+        let mut s = String::new();
+        if !n.is_tip() {
+            let children = n
+                .children
+                .iter()
+                .map(|c| rendered[*c].take().context("Child rendered out of order"))
+                .collect::<Result<Vec<_>>>()?;
+            s.push('(');
+            s.push_str(&children.join(","));
+            s.push(')');
+        }
+        if let Some(name) = &n.name {
+            s.push_str(&quote_label(name));
+        }
+        if idx != node {
+            if let Some(len) = n.parent_edge {
+                s.push_str(&format!(":{}", len));
+            }
+        }
+        rendered[idx] = Some(s);
+    }
+
+    let mut newick = rendered[node].take().unwrap_or_default();
+    newick.push(';');
+    Ok(newick)
+}
+
+/// New tree made of the clade selected by `spec` (see [`resolve_clade`])
+pub fn extract_clade(tree: &Tree, spec: &str) -> Result<Tree> {
+    let node = resolve_clade(tree, spec)?;
+    if tree.get(&node)?.is_tip() {
+        bail!("Clade '{}' is a single tip", spec);
+    }
+    let newick = subtree_newick(tree, node)?;
+    read_newick(newick.as_bytes()).context("Failed to build the clade subtree")
+}
+
+/// Tip-to-tip path length matrix (row-major) over the given tips, in that order.
+/// Each pair is handled once, at the node where the two tips' lineages merge.
+pub fn patristic_matrix(tree: &Tree, tips: &[usize]) -> Result<Vec<f64>> {
+    let n = tips.len();
+    let root = tree.get_root()?;
+    let order = tree.postorder(&root)?;
+
+    // Root-to-node path lengths; reverse postorder visits parents first
+    let mut depth = vec![0.0; tree.size()];
+    for idx in order.iter().rev() {
+        let node = tree.get(idx)?;
+        if let Some(parent) = node.parent {
+            depth[*idx] = depth[parent] + node.parent_edge.unwrap_or_default();
+        }
+    }
+
+    let mut column = vec![usize::MAX; tree.size()];
+    for (k, t) in tips.iter().enumerate() {
+        column[*t] = k;
+    }
+
+    let mut dist_matrix = vec![0.0; n * n];
+    let mut below: Vec<Vec<usize>> = vec![Vec::new(); tree.size()];
+    for idx in order {
+        let node = tree.get(&idx)?;
+        let mut merged = Vec::new();
+        if column[idx] != usize::MAX {
+            merged.push(column[idx]);
+        }
+        for c in node.children.iter() {
+            let child_tips = std::mem::take(&mut below[*c]);
+            for &a in merged.iter() {
+                for &b in child_tips.iter() {
+                    let d = depth[tips[a]] + depth[tips[b]] - 2.0 * depth[idx];
+                    dist_matrix[a * n + b] = d;
+                    dist_matrix[b * n + a] = d;
+                }
+            }
+            merged.extend(child_tips);
+        }
+        below[idx] = merged;
+    }
+
+    Ok(dist_matrix)
+}
+
+/// Whether a tree's root is a real root (two children) or just where an
+/// unrooted tree was written down (three or more)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rootedness {
+    Rooted,
+    Unrooted,
+}
+
+/// [`Rootedness`] of the tree and the number of children of its root
+pub fn rootedness(tree: &Tree) -> Result<(Rootedness, usize)> {
+    let n_children = tree.get(&tree.get_root()?)?.children.len();
+    let rootedness = if n_children >= 3 {
+        Rootedness::Unrooted
+    } else {
+        Rootedness::Rooted
+    };
+    Ok((rootedness, n_children))
+}
+
+/// Where to put the root of a tree
+#[derive(Clone, Debug)]
+pub enum Reroot {
+    /// Halfway along the longest tip-to-tip path
+    Midpoint,
+    /// Halfway along the edge above this clade (see [`resolve_clade`])
+    Outgroup(String),
+}
+
+/// The tree with the root moved as requested. A root left with a single
+/// child is dissolved into its neighbours' edges.
+pub fn rerooted_tree(tree: &Tree, how: &Reroot) -> Result<Tree> {
+    let mut layout = Layout::from_tree(tree)?;
+    let (node, offset) = match how {
+        Reroot::Midpoint => layout.midpoint()?,
+        Reroot::Outgroup(spec) => {
+            let node = resolve_clade(tree, spec)?;
+            if node == layout.root {
+                bail!("Outgroup '{}' spans the current root", spec);
+            }
+            (node, layout.length[node].unwrap_or_default() / 2.0)
+        }
+    };
+    layout.reroot_above(node, offset);
+    layout.collapse_unary();
+    layout.into_tree()
+}
+
+/// The tree restricted to the given tips; internal nodes left with a single
+/// child are removed and their edges merged
+pub fn pruned_tree(tree: &Tree, keep: &HashSet<String>) -> Result<Tree> {
+    let mut layout = Layout::from_tree(tree)?;
+    let mut kept = vec![false; layout.parent.len()];
+    for v in layout.postorder() {
+        kept[v] = if layout.children[v].is_empty() {
+            layout.name[v].as_ref().is_some_and(|n| keep.contains(n))
+        } else {
+            layout.children[v].retain(|c| kept[*c]);
+            !layout.children[v].is_empty()
+        };
+    }
+    if !kept[layout.root] {
+        bail!("None of the {} tips to keep are in the tree", keep.len());
+    }
+    layout.collapse_unary();
+    layout.into_tree()
+}
+
+/// A rooted tree as plain arrays, for restructuring before writing it out
+struct Layout {
+    parent: Vec<Option<usize>>,
+    children: Vec<Vec<usize>>,
+    /// Length of the edge above each node
+    length: Vec<Option<f64>>,
+    name: Vec<Option<String>>,
+    root: usize,
+}
+
+impl Layout {
+    fn from_tree(tree: &Tree) -> Result<Layout> {
+        let root = tree.get_root()?;
+        let size = tree.size();
+        let mut layout = Layout {
+            parent: vec![None; size],
+            children: vec![Vec::new(); size],
+            length: vec![None; size],
+            name: vec![None; size],
+            root,
+        };
+        for idx in tree.postorder(&root)? {
+            let node = tree.get(&idx)?;
+            layout.parent[idx] = node.parent;
+            layout.children[idx] = node.children.clone();
+            layout.length[idx] = node.parent_edge;
+            layout.name[idx] = node.name.clone();
+        }
+        Ok(layout)
+    }
+
+    /// Nodes reachable from the root, children before parents
+    fn postorder(&self) -> Vec<usize> {
+        let mut order = Vec::with_capacity(self.parent.len());
+        let mut stack = vec![self.root];
+        while let Some(v) = stack.pop() {
+            order.push(v);
+            stack.extend(self.children[v].iter().copied());
+        }
+        order.reverse();
+        order
+    }
+
+    /// Path length from `from` to every node, and the neighbour each is
+    /// reached through
+    fn distances_from(&self, from: usize) -> (Vec<f64>, Vec<Option<usize>>) {
+        let mut dist = vec![f64::NAN; self.parent.len()];
+        let mut via = vec![None; self.parent.len()];
+        dist[from] = 0.0;
+        let mut stack = vec![from];
+        while let Some(v) = stack.pop() {
+            let up = self.parent[v].map(|p| (p, self.length[v]));
+            let down = self.children[v].iter().map(|c| (*c, self.length[*c]));
+            for (w, len) in down.chain(up) {
+                if dist[w].is_nan() {
+                    dist[w] = dist[v] + len.unwrap_or_default();
+                    via[w] = Some(v);
+                    stack.push(w);
+                }
+            }
+        }
+        (dist, via)
+    }
+
+    /// Edge (as the node below it) and distance up from that node of the
+    /// midpoint of the longest tip-to-tip path
+    fn midpoint(&self) -> Result<(usize, f64)> {
+        let tips: Vec<usize> = self
+            .postorder()
+            .into_iter()
+            .filter(|v| self.children[*v].is_empty())
+            .collect();
+        if tips.len() < 2 {
+            bail!("Midpoint rooting needs at least two tips");
+        }
+        let farthest = |dist: &[f64]| {
+            *tips
+                .iter()
+                .max_by(|a, b| dist[**a].total_cmp(&dist[**b]))
+                .unwrap()
+        };
+        let (dist, _) = self.distances_from(tips[0]);
+        let end = farthest(&dist);
+        let (dist, via) = self.distances_from(end);
+        let start = farthest(&dist);
+        let half = dist[start] / 2.0;
+
+        // Walk from `start` towards `end` until the next step passes halfway
+        let mut v = start;
+        let mut walked = 0.0;
+        while let Some(w) = via[v] {
+            let step = if self.parent[v] == Some(w) {
+                self.length[v]
+            } else {
+                self.length[w]
+            }
+            .unwrap_or_default();
+            if walked + step >= half {
+                let into = half - walked;
+                return Ok(if self.parent[v] == Some(w) {
+                    (v, into)
+                } else {
+                    (w, step - into)
+                });
+            }
+            walked += step;
+            v = w;
+        }
+        bail!("Failed to find the midpoint of the tree")
+    }
+
+    /// Put a new root on the edge above `node`, `offset` up from `node`,
+    /// turning the path from there to the old root around
+    fn reroot_above(&mut self, node: usize, offset: f64) {
+        let edge = self.length[node].unwrap_or_default();
+        let offset = offset.clamp(0.0, edge);
+        let root = self.parent.len();
+        let above = self.parent[node].unwrap();
+        self.parent.push(None);
+        self.children.push(vec![node, above]);
+        self.length.push(None);
+        self.name.push(None);
+        self.parent[node] = Some(root);
+        self.length[node] = Some(offset);
+
+        let mut child = node;
+        let mut v = above;
+        let mut new_parent = root;
+        let mut new_length = Some(edge - offset);
+        loop {
+            let old_parent = self.parent[v];
+            let old_length = self.length[v];
+            self.children[v].retain(|c| *c != child);
+            self.parent[v] = Some(new_parent);
+            self.length[v] = new_length;
+            match old_parent {
+                Some(p) => {
+                    self.children[v].push(p);
+                    child = v;
+                    new_parent = v;
+                    new_length = old_length;
+                    v = p;
+                }
+                None => break,
+            }
+        }
+        self.root = root;
+    }
+
+    /// Remove internal nodes with a single child, merging their edges, and
+    /// a root with a single child
+    fn collapse_unary(&mut self) {
+        for v in self.postorder() {
+            if self.children[v].len() != 1 {
+                continue;
+            }
+            let c = self.children[v][0];
+            match self.parent[v] {
+                Some(p) => {
+                    self.length[c] = match (self.length[c], self.length[v]) {
+                        (None, None) => None,
+                        (a, b) => Some(a.unwrap_or_default() + b.unwrap_or_default()),
+                    };
+                    for sibling in self.children[p].iter_mut() {
+                        if *sibling == v {
+                            *sibling = c;
+                        }
+                    }
+                    self.parent[c] = Some(p);
+                }
+                None => {
+                    self.parent[c] = None;
+                    self.length[c] = None;
+                    self.root = c;
+                }
+            }
+            self.children[v].clear();
+        }
+    }
+
+    /// Build the tree from the nodes reachable from the root, in preorder
+    fn into_tree(self) -> Result<Tree> {
+        let mut flat = FlatTree::default();
+        let mut stack = vec![(self.root, None)];
+        while let Some((v, parent)) = stack.pop() {
+            let id = flat.parent.len();
+            flat.parent.push(parent);
+            flat.name.push(self.name[v].clone());
+            flat.length.push(parent.and(self.length[v]));
+            stack.extend(self.children[v].iter().rev().map(|c| (*c, Some(id))));
+        }
+        flat.into_tree()
+    }
+}
+
+/// Rename every named tip; fails if two tips would end up with the same name
+pub fn rename_tips(tree: &mut Tree, rename: impl Fn(&str) -> String) -> Result<()> {
+    let mut seen: HashMap<String, String> = HashMap::new();
+    for leaf in tree.get_leaves() {
+        let node = tree.get_mut(&leaf)?;
+        let Some(name) = node.name.as_ref() else {
+            continue;
+        };
+        let new_name = rename(name);
+        if let Some(other) = seen.insert(new_name.clone(), name.clone()) {
+            bail!("Tips '{}' and '{}' both become '{}'", other, name, new_name);
+        }
+        node.name = Some(new_name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A, B and C, D form the two clades below the root
+    const NEWICK: &str = "((A:1,B:2):0.5,(C:1.5,D:0.25):1);";
+
+    fn tree() -> Tree {
+        read_newick(NEWICK.as_bytes()).unwrap()
+    }
+
+    fn newick(tree: &Tree) -> String {
+        subtree_newick(tree, tree.get_root().unwrap()).unwrap()
+    }
+
+    fn patristic(tree: &Tree, names: &[&str]) -> Vec<f64> {
+        let tips: Vec<usize> = names.iter().map(|n| find_node(tree, n).unwrap()).collect();
+        patristic_matrix(tree, &tips).unwrap()
+    }
+
+    /// Path length from the root down to the node named `name`
+    fn depth(tree: &Tree, name: &str) -> f64 {
+        let mut node = tree.get(&find_node(tree, name).unwrap()).unwrap();
+        let mut depth = 0.0;
+        while let Some(parent) = node.parent {
+            depth += node.parent_edge.unwrap_or_default();
+            node = tree.get(&parent).unwrap();
+        }
+        depth
+    }
+
+    fn assert_close(actual: f64, expected: f64, what: &str) {
+        assert!(
+            (actual - expected).abs() < 1e-12,
+            "{}: {} instead of {}",
+            what,
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn resolves_clades() {
+        let tree = tree();
+        let names: HashSet<String> = ["A", "B", "C", "D"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(tip_names(&tree), names);
+
+        let ab = resolve_clade(&tree, "A, B").unwrap();
+        assert_eq!(subtree_newick(&tree, ab).unwrap(), "(A:1,B:2);");
+        assert_eq!(branch_label(&tree, ab).unwrap(), "A,B");
+        assert_eq!(
+            resolve_clade(&tree, &branch_label(&tree, ab).unwrap()).unwrap(),
+            ab
+        );
+        assert_eq!(
+            resolve_clade(&tree, "A,C").unwrap(),
+            tree.get_root().unwrap()
+        );
+        assert!(resolve_clade(&tree, "A,E").is_err());
+    }
+
+    #[test]
+    fn extracts_clades() {
+        let tree = tree();
+        assert_eq!(
+            newick(&extract_clade(&tree, "C,D").unwrap()),
+            "(C:1.5,D:0.25);"
+        );
+        assert!(extract_clade(&tree, "A").is_err());
+    }
+
+    #[test]
+    fn patristic_distances() {
+        let dist_matrix = patristic(&tree(), &["A", "B", "C", "D"]);
+        let expected = [
+            0.0, 3.0, 4.0, 2.75, //
+            3.0, 0.0, 5.0, 3.75, //
+            4.0, 5.0, 0.0, 1.75, //
+            2.75, 3.75, 1.75, 0.0,
+        ];
+        for (k, (d, e)) in dist_matrix.iter().zip(expected.iter()).enumerate() {
+            assert_close(*d, *e, &format!("entry {}", k));
+        }
+    }
+
+    #[test]
+    fn tells_rooted_from_unrooted() {
+        assert_eq!(rootedness(&tree()).unwrap(), (Rootedness::Rooted, 2));
+        let unrooted = read_newick("(A:1,B:1,(C:1,D:1):1);".as_bytes()).unwrap();
+        assert_eq!(rootedness(&unrooted).unwrap(), (Rootedness::Unrooted, 3));
+    }
+
+    #[test]
+    fn reroots_on_outgroup() {
+        let tree = tree();
+        let rerooted = rerooted_tree(&tree, &Reroot::Outgroup("A".into())).unwrap();
+        assert_eq!(newick(&rerooted), "(A:0.5,(B:2,(C:1.5,D:0.25):1.5):0.5);");
+        let names = ["A", "B", "C", "D"];
+        assert_eq!(patristic(&rerooted, &names), patristic(&tree, &names));
+        assert!(rerooted_tree(&tree, &Reroot::Outgroup("A,C".into())).is_err());
+    }
+
+    #[test]
+    fn reroots_on_midpoint() {
+        let tree = tree();
+        let rerooted = rerooted_tree(&tree, &Reroot::Midpoint).unwrap();
+        // B and C are the farthest apart, 5 along the tree
+        assert_close(depth(&rerooted, "B"), 2.5, "depth of B");
+        assert_close(depth(&rerooted, "C"), 2.5, "depth of C");
+        let names = ["A", "B", "C", "D"];
+        for (d, e) in patristic(&rerooted, &names)
+            .iter()
+            .zip(patristic(&tree, &names).iter())
+        {
+            assert_close(*d, *e, "patristic distance");
+        }
+    }
+
+    #[test]
+    fn prunes_tips() {
+        let tree = tree();
+        let keep =
+            |names: &[&str]| -> HashSet<String> { names.iter().map(|s| s.to_string()).collect() };
+        let pruned = pruned_tree(&tree, &keep(&["A", "C", "D"])).unwrap();
+        assert_eq!(newick(&pruned), "(A:1.5,(C:1.5,D:0.25):1);");
+        // The root is left with a single child, which becomes the root
+        let pruned = pruned_tree(&tree, &keep(&["A", "B"])).unwrap();
+        assert_eq!(newick(&pruned), "(A:1,B:2);");
+        assert!(pruned_tree(&tree, &keep(&["E"])).is_err());
+    }
+
+    #[test]
+    fn renames_tips() {
+        let mut tree = tree();
+        rename_tips(&mut tree, |name| name.to_lowercase()).unwrap();
+        assert_eq!(newick(&tree), "((a:1,b:2):0.5,(c:1.5,d:0.25):1);");
+        let err = rename_tips(&mut tree, |_| "x".to_string()).unwrap_err();
+        assert!(err.to_string().contains("both become 'x'"), "{}", err);
+    }
+}
@@ -0,0 +1,155 @@
+use crate::{io::OutputFile, ordination::Pcoa};
+use anyhow::Result;
+use serde_json::json;
+use std::io::Write;
+
+/// Write a self-contained interactive HTML scatter plot of PCoA coordinates.
+/// `groups` optionally gives a metadata column name and one value per sample,
+/// used to color the points.
+pub fn write_pcoa_html(
+    sample_names: &[String],
+    pcoa: &Pcoa,
+    groups: Option<(&str, &[String])>,
+    output_file: &str,
+    force: bool,
+) -> Result<()> {
+    let points: Vec<_> = sample_names
+        .iter()
+        .enumerate()
+        .map(|(s, name)| {
+            json!({
+                "id": name,
+                "group": groups.map(|(_, g)| g[s].as_str()),
+                "coords": pcoa.coordinates.row(s).to_vec(),
+            })
+        })
+        .collect();
+    let data = json!({
+        "colorBy": groups.map(|(column, _)| column),
+        "explained": pcoa.proportion_explained,
+        "points": points,
+    });
+    // Keep sample names from closing the <script> element early
+    let data = data.to_string().replace("</", "<\\/");
+
+    let mut file = OutputFile::create(output_file, force)?;
+    write!(file, "{}", PCOA_TEMPLATE.replace("__DATA__", &data))?;
+
+    file.commit()
+}
+
+const PCOA_TEMPLATE: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>UniFrac PCoA</title>
+<style>
+body { font-family: sans-serif; margin: 20px; color: #222; }
+#controls { margin-bottom: 10px; }
+#controls label { margin-right: 16px; }
+#wrap { display: flex; align-items: flex-start; }
+#legend { margin-left: 20px; font-size: 13px; }
+#legend div { cursor: pointer; margin: 3px 0; user-select: none; }
+#legend div.off { opacity: 0.3; }
+#legend span { display: inline-block; width: 12px; height: 12px; margin-right: 6px; border-radius: 6px; }
+#tip { position: absolute; pointer-events: none; background: #fff; border: 1px solid #999;
+       padding: 4px 8px; font-size: 12px; display: none; }
+circle { stroke: #333; stroke-width: 0.5; }
+circle:hover { stroke-width: 2; }
+</style>
+</head>
+<body>
+<div id="controls">
+<label>X axis <select id="xaxis"></select></label>
+<label>Y axis <select id="yaxis"></select></label>
+</div>
+<div id="wrap"><svg id="plot" width="700" height="600"></svg><div id="legend"></div></div>
+<div id="tip"></div>
+<script>
+const data = __DATA__;
+const palette = ["#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd", "#8c564b",
+                 "#e377c2", "#7f7f7f", "#bcbd22", "#17becf"];
+const ns = "http://www.w3.org/2000/svg";
+const svg = document.getElementById("plot");
+const tip = document.getElementById("tip");
+const hidden = new Set();
+const groups = [...new Set(data.points.map(p => p.group === null ? "all samples" : p.group))];
+const color = g => palette[groups.indexOf(g) % palette.length];
+const groupOf = p => p.group === null ? "all samples" : p.group;
+
+for (const id of ["xaxis", "yaxis"]) {
+  const sel = document.getElementById(id);
+  data.explained.forEach((e, k) => {
+    const opt = document.createElement("option");
+    opt.value = k;
+    opt.text = "PC" + (k + 1) + " (" + (100 * e).toFixed(1) + "%)";
+    sel.appendChild(opt);
+  });
+  sel.onchange = draw;
+}
+if (data.explained.length > 1) document.getElementById("yaxis").value = 1;
+
+function el(name, attrs) {
+  const e = document.createElementNS(ns, name);
+  for (const k in attrs) e.setAttribute(k, attrs[k]);
+  return e;
+}
+
+function draw() {
+  const x = +document.getElementById("xaxis").value;
+  const y = +document.getElementById("yaxis").value;
+  const w = +svg.getAttribute("width"), h = +svg.getAttribute("height"), m = 50;
+  svg.innerHTML = "";
+  const xs = data.points.map(p => p.coords[x]), ys = data.points.map(p => p.coords[y]);
+  const pad = v => (Math.max(...v) - Math.min(...v)) * 0.05 || 1;
+  const x0 = Math.min(...xs) - pad(xs), x1 = Math.max(...xs) + pad(xs);
+  const y0 = Math.min(...ys) - pad(ys), y1 = Math.max(...ys) + pad(ys);
+  const sx = v => m + (v - x0) / (x1 - x0) * (w - 2 * m);
+  const sy = v => h - m - (v - y0) / (y1 - y0) * (h - 2 * m);
+  svg.appendChild(el("rect", {x: m, y: m, width: w - 2 * m, height: h - 2 * m, fill: "none", stroke: "#999"}));
+  svg.appendChild(el("line", {x1: sx(0), x2: sx(0), y1: m, y2: h - m, stroke: "#ddd"}));
+  svg.appendChild(el("line", {x1: m, x2: w - m, y1: sy(0), y2: sy(0), stroke: "#ddd"}));
+  const xl = el("text", {x: w / 2, y: h - 15, "text-anchor": "middle"});
+  xl.textContent = document.getElementById("xaxis").selectedOptions[0].text;
+  svg.appendChild(xl);
+  const yl = el("text", {x: 15, y: h / 2, "text-anchor": "middle", transform: "rotate(-90 15 " + h / 2 + ")"});
+  yl.textContent = document.getElementById("yaxis").selectedOptions[0].text;
+  svg.appendChild(yl);
+  for (const p of data.points) {
+    if (hidden.has(groupOf(p))) continue;
+    const c = el("circle", {cx: sx(p.coords[x]), cy: sy(p.coords[y]), r: 5, fill: color(groupOf(p))});
+    c.onmousemove = ev => {
+      tip.style.display = "block";
+      tip.style.left = ev.pageX + 12 + "px";
+      tip.style.top = ev.pageY + 12 + "px";
+      tip.textContent = p.id + (p.group === null ? "" : " (" + data.colorBy + ": " + p.group + ")");
+    };
+    c.onmouseout = () => { tip.style.display = "none"; };
+    svg.appendChild(c);
+  }
+}
+
+const legend = document.getElementById("legend");
+if (data.colorBy !== null) {
+  const title = document.createElement("b");
+  title.textContent = data.colorBy;
+  legend.appendChild(title);
+}
+for (const g of groups) {
+  const d = document.createElement("div");
+  const sw = document.createElement("span");
+  sw.style.background = color(g);
+  d.appendChild(sw);
+  d.appendChild(document.createTextNode(g));
+  d.onclick = () => {
+    if (hidden.has(g)) hidden.delete(g); else hidden.add(g);
+    d.classList.toggle("off");
+    draw();
+  };
+  legend.appendChild(d);
+}
+draw();
+</script>
+</body>
+</html>
+"##;
@@ -0,0 +1,111 @@
+//! Provenance of a run: what went in (with content hashes), which options
+//! and version produced the result, when, and what came out, so that a
+//! matrix can be traced back to its inputs long after the run.
+
+use crate::{
+    io::OutputFile,
+    summary::{sha256_file, InputFile},
+};
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Provenance record, written as JSON
+#[derive(Clone, Debug, Serialize)]
+pub struct Provenance {
+    pub tool: String,
+    pub version: String,
+    /// Arguments of the process, as invoked
+    pub command_line: Vec<String>,
+    /// Options as given (or defaulted)
+    pub options: BTreeMap<String, String>,
+    pub inputs: Vec<InputFile>,
+    pub outputs: Vec<InputFile>,
+    pub started: String,
+    #[serde(skip)]
+    pub started_at: SystemTime,
+    /// Empty until [`Provenance::finish`]
+    pub finished: String,
+}
+
+impl Provenance {
+    /// Start a record now, hashing the input files before they are read
+    pub fn start(inputs: &[&str], options: BTreeMap<String, String>) -> Result<Provenance> {
+        let inputs = inputs
+            .iter()
+            .map(|path| {
+                Ok(InputFile {
+                    path: path.to_string(),
+                    sha256: sha256_file(path)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Provenance {
+            tool: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            command_line: std::env::args().collect(),
+            options,
+            inputs,
+            outputs: Vec::new(),
+            started: timestamp(),
+            started_at: SystemTime::now(),
+            finished: String::new(),
+        })
+    }
+
+    /// Hash the written `outputs` and close the record
+    pub fn finish(&mut self, outputs: &[String]) -> Result<()> {
+        for path in outputs {
+            self.outputs.push(InputFile {
+                path: path.clone(),
+                sha256: sha256_file(path)?,
+            });
+        }
+        self.finished = timestamp();
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// Write the record as pretty-printed JSON
+pub fn write_provenance(provenance: &Provenance, output_file: &str, force: bool) -> Result<()> {
+    let mut file = OutputFile::create(output_file, force)?;
+    serde_json::to_writer_pretty(&mut file, provenance)?;
+    file.commit()
+}
+
+/// Current UTC time as `YYYY-MM-DDTHH:MM:SS+00:00`
+pub fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}+00:00",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
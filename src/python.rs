@@ -0,0 +1,110 @@
+//! Python bindings (feature `python`, built with maturin) for use from
+//! QIIME2 / scikit-bio code: a `biom.Table` in, a `skbio.DistanceMatrix` out.
+
+use crate::{
+    compute::{BranchOptions, Metric},
+    newick::{read_newick, read_newick_file},
+    table::FeatureTable,
+    weighted::Transform,
+};
+use phylotree::tree::Tree;
+use pyo3::{exceptions::PyValueError, prelude::*, types::PyDict};
+use std::path::Path;
+
+fn value_error(err: anyhow::Error) -> PyErr {
+    PyValueError::new_err(format!("{:#}", err))
+}
+
+/// The tree as a newick file path, a newick string, or any object whose
+/// `str()` is newick (such as an `skbio.TreeNode`)
+fn read_tree(tree: &Bound<'_, PyAny>) -> PyResult<Tree> {
+    let text: String = match tree.extract::<String>() {
+        Ok(text) => text,
+        Err(_) => tree.str()?.extract()?,
+    };
+    let parsed = if Path::new(&text).exists() {
+        read_newick_file(Path::new(&text))
+    } else {
+        read_newick(text.trim().as_bytes())
+    };
+    parsed.map_err(|e| PyValueError::new_err(format!("Invalid newick tree: {:#}", e)))
+}
+
+/// Features (observations) x samples counts of a `biom.Table`
+fn read_biom(table: &Bound<'_, PyAny>) -> PyResult<FeatureTable> {
+    let py = table.py();
+    let ids = |axis: &str| -> PyResult<Vec<String>> {
+        let kwargs = PyDict::new_bound(py);
+        kwargs.set_item("axis", axis)?;
+        table
+            .call_method("ids", (), Some(&kwargs))?
+            .call_method0("tolist")?
+            .extract()
+    };
+    let (features, samples) = (ids("observation")?, ids("sample")?);
+
+    // The scipy.sparse arrays, read without densifying the table in Python:
+    // row `f` of the CSR matrix holds the values `data[indptr[f]..indptr[f + 1]]`
+    // in the columns at the same positions of `indices`
+    let csr = table.getattr("matrix_data")?.call_method0("tocsr")?;
+    let array = |name: &str| csr.getattr(name)?.call_method0("tolist");
+    let indptr: Vec<usize> = array("indptr")?.extract()?;
+    let indices: Vec<usize> = array("indices")?.extract()?;
+    let data: Vec<f64> = array("data")?.extract()?;
+    if indptr.len() != features.len() + 1 {
+        return Err(PyValueError::new_err(
+            "The table's matrix does not match its observation IDs",
+        ));
+    }
+    let mut counts = vec![vec![0.0; samples.len()]; features.len()];
+    for (f, row) in counts.iter_mut().enumerate() {
+        let (start, end) = (indptr[f], indptr[f + 1]);
+        for (&s, &d) in indices[start..end].iter().zip(&data[start..end]) {
+            if s >= row.len() {
+                return Err(PyValueError::new_err(
+                    "The table's matrix does not match its sample IDs",
+                ));
+            }
+            row[s] += d;
+        }
+    }
+    FeatureTable::new(features, samples, counts).map_err(value_error)
+}
+
+/// UniFrac distances between the samples of a `biom.Table`, as an
+/// `skbio.DistanceMatrix`. `metric` and `transform` take the names of the
+/// command line's --metric and --transform.
+#[pyfunction]
+#[pyo3(signature = (table, tree, metric = "unweighted", transform = "none"))]
+fn distance_matrix<'py>(
+    table: &Bound<'py, PyAny>,
+    tree: &Bound<'py, PyAny>,
+    metric: &str,
+    transform: &str,
+) -> PyResult<Bound<'py, PyAny>> {
+    let py = table.py();
+    let metric = Metric::from_name(metric)
+        .ok_or_else(|| PyValueError::new_err(format!("Unknown metric '{}'", metric)))?;
+    let transform = Transform::from_name(transform)
+        .ok_or_else(|| PyValueError::new_err(format!("Unknown transform '{}'", transform)))?;
+    let table = read_biom(table)?;
+    let tree = read_tree(tree)?;
+
+    let dist_matrix = py
+        .allow_threads(|| {
+            table.distance_matrix(&tree, metric, transform, &BranchOptions::default())
+        })
+        .map_err(value_error)?;
+    let n = table.n_samples();
+    let rows: Vec<Vec<f64>> = dist_matrix.chunks(n.max(1)).map(|r| r.to_vec()).collect();
+    py.import_bound("skbio")?
+        .getattr("DistanceMatrix")?
+        .call1((rows, table.sample_names))
+}
+
+#[pymodule]
+#[pyo3(name = "unifrac")]
+fn python_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(distance_matrix, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,79 @@
+//! Arrow integration (feature `arrow`): feature tables from `RecordBatch`es
+//! as produced by Arrow-based ETL.
+
+use crate::table::FeatureTable;
+use anyhow::{bail, Context, Result};
+use arrow::{
+    array::{Array, AsArray},
+    compute::cast,
+    datatypes::{DataType, Float64Type},
+    record_batch::RecordBatch,
+};
+
+impl FeatureTable {
+    /// Table from record batches with one row per feature: the IDs in
+    /// `feature_column`, and one numeric column of counts per sample (every
+    /// other column; nulls count as 0). Batches are concatenated and must
+    /// share their sample columns. Float64 columns are read as they are,
+    /// other numeric types are cast first.
+    pub fn from_record_batches(
+        batches: &[RecordBatch],
+        feature_column: &str,
+    ) -> Result<FeatureTable> {
+        let Some(first) = batches.first() else {
+            bail!("No record batches");
+        };
+        let sample_names: Vec<String> = first
+            .schema()
+            .fields()
+            .iter()
+            .map(|f| f.name().clone())
+            .filter(|name| name != feature_column)
+            .collect();
+
+        let mut taxa_order = Vec::new();
+        let mut counts: Vec<Vec<f64>> = Vec::new();
+        for (b, batch) in batches.iter().enumerate() {
+            if batch.num_columns() != sample_names.len() + 1 {
+                bail!(
+                    "Batch {} has {} columns, the first one {}",
+                    b,
+                    batch.num_columns(),
+                    sample_names.len() + 1
+                );
+            }
+            let ids = batch
+                .column_by_name(feature_column)
+                .with_context(|| format!("Batch {} has no column named '{}'", b, feature_column))?;
+            let ids = cast(ids, &DataType::Utf8)?;
+            for (row, id) in ids.as_string::<i32>().iter().enumerate() {
+                let id = id.with_context(|| format!("Batch {}, row {}: no feature ID", b, row))?;
+                taxa_order.push(id.to_string());
+            }
+
+            let first_row = counts.len();
+            counts.resize(
+                first_row + batch.num_rows(),
+                Vec::with_capacity(sample_names.len()),
+            );
+            for name in &sample_names {
+                let column = batch
+                    .column_by_name(name)
+                    .with_context(|| format!("Batch {} has no sample column '{}'", b, name))?;
+                if !column.data_type().is_numeric() {
+                    bail!(
+                        "Sample column '{}' is {}, not numeric",
+                        name,
+                        column.data_type()
+                    );
+                }
+                let values = cast(column, &DataType::Float64)?;
+                let values = values.as_primitive::<Float64Type>();
+                for (row, value) in counts[first_row..].iter_mut().zip(values.iter()) {
+                    row.push(value.unwrap_or(0.0));
+                }
+            }
+        }
+        FeatureTable::new(taxa_order, sample_names, counts)
+    }
+}
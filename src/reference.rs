@@ -0,0 +1,221 @@
+use crate::{
+    compute::{
+        branch_lengths, length_table, sample_branch_bits, sample_coverage, shared_length,
+        BranchOptions,
+    },
+    hnsw::Hnsw,
+    io::OutputFile,
+};
+use anyhow::{bail, Context, Result};
+use phylotree::tree::Tree;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs::File, io::BufReader};
+
+/// Reference samples prepared once, so that a new sample can be compared
+/// against all of them without re-reading the table or walking the tree
+#[derive(Serialize, Deserialize)]
+pub struct ReferenceSet {
+    pub sample_names: Vec<String>,
+    /// Parent of each node (None for the root)
+    parent: Vec<Option<usize>>,
+    /// Node id of each tip, by name
+    tips: HashMap<String, usize>,
+    /// Branch length above each node (0 for the root unless its edge is included)
+    lengths: Vec<f64>,
+    /// One bitset over branches per reference sample
+    bits: Vec<u64>,
+    /// Total branch length covered by each reference sample
+    totals: Vec<f64>,
+    #[serde(skip)]
+    table: Vec<f64>,
+}
+
+impl ReferenceSet {
+    pub fn build(
+        tree: &Tree,
+        taxa_order: &[String],
+        sample_names: &[String],
+        presence_matrix: &[Vec<f64>],
+        opts: &BranchOptions,
+    ) -> Result<ReferenceSet> {
+        let n_samples = sample_names.len();
+        let lengths = branch_lengths(tree, opts)?;
+        let root = tree.get_root()?;
+
+        let coverage = sample_coverage(tree, taxa_order, presence_matrix, n_samples)?;
+        let bits = sample_branch_bits(&coverage, lengths.len(), n_samples);
+
+        let mut parent = vec![None; lengths.len()];
+        let mut tips = HashMap::new();
+        for idx in tree.postorder(&root)? {
+            let node = tree.get(&idx)?;
+            parent[idx] = node.parent;
+            if node.is_tip() {
+                if let Some(name) = &node.name {
+                    tips.insert(name.clone(), idx);
+                }
+            }
+        }
+
+        let mut reference = ReferenceSet {
+            sample_names: sample_names.to_vec(),
+            parent,
+            tips,
+            lengths,
+            bits,
+            totals: Vec::new(),
+            table: Vec::new(),
+        };
+        reference.table = length_table(&reference.lengths);
+        reference.totals = (0..n_samples)
+            .map(|s| shared_length(reference.sample(s), reference.sample(s), &reference.table))
+            .collect();
+        Ok(reference)
+    }
+
+    fn n_words(&self) -> usize {
+        self.lengths.len().div_ceil(64)
+    }
+
+    fn sample(&self, s: usize) -> &[u64] {
+        let n_words = self.n_words();
+        &self.bits[s * n_words..(s + 1) * n_words]
+    }
+
+    /// Distance between reference samples `i` and `j`
+    pub fn distance(&self, i: usize, j: usize) -> f64 {
+        let shared = shared_length(self.sample(i), self.sample(j), &self.table);
+        1.0 - shared / (self.totals[i] + self.totals[j] - shared)
+    }
+
+    /// Branch bitset of a sample given by its present features, and how many
+    /// of the features are tips of the reference tree
+    pub fn query_bits(&self, features: &[String]) -> (Vec<u64>, usize) {
+        let mut bits = vec![0u64; self.n_words()];
+        let mut matched = 0;
+        for f in features {
+            let Some(&tip) = self.tips.get(f) else {
+                continue;
+            };
+            matched += 1;
+            // Walk up until reaching a branch already marked by another feature
+            let mut node = Some(tip);
+            while let Some(v) = node {
+                if bits[v / 64] & (1 << (v % 64)) != 0 {
+                    break;
+                }
+                bits[v / 64] |= 1 << (v % 64);
+                node = self.parent[v];
+            }
+        }
+        (bits, matched)
+    }
+
+    /// Distance from a query bitset (see [`ReferenceSet::query_bits`]) to
+    /// each reference sample, in reference order, computed lazily
+    pub fn distances<'a>(&'a self, query: &'a [u64]) -> impl Iterator<Item = (&'a str, f64)> + 'a {
+        let total = self.query_length(query);
+        (0..self.sample_names.len()).map(move |s| {
+            (
+                self.sample_names[s].as_str(),
+                self.query_distance(query, total, s),
+            )
+        })
+    }
+
+    /// Total branch length of a query bitset
+    pub fn query_length(&self, query: &[u64]) -> f64 {
+        shared_length(query, query, &self.table)
+    }
+
+    /// Distance from a query bitset of length `total` to reference sample `s`
+    pub fn query_distance(&self, query: &[u64], total: f64, s: usize) -> f64 {
+        let shared = shared_length(query, self.sample(s), &self.table);
+        1.0 - shared / (total + self.totals[s] - shared)
+    }
+
+    /// Check a deserialized set and rebuild what is not saved
+    fn restore(&mut self, filename: &str) -> Result<()> {
+        if self.bits.len() != self.sample_names.len() * self.n_words() {
+            bail!(
+                "{} is corrupt: branch sets do not match the tree size",
+                filename
+            );
+        }
+        self.table = length_table(&self.lengths);
+        Ok(())
+    }
+}
+
+/// A reference set with an HNSW graph over its samples by UniFrac distance,
+/// for finding a query's nearest references without comparing it to all
+#[derive(Serialize, Deserialize)]
+pub struct ReferenceIndex {
+    pub reference: ReferenceSet,
+    pub graph: Hnsw,
+}
+
+impl ReferenceIndex {
+    /// Link each reference sample to up to `m` others (2m on the bottom
+    /// layer), choosing them among `ef_construction` candidates
+    pub fn build(reference: ReferenceSet, m: usize, ef_construction: usize, seed: u64) -> Self {
+        let graph = Hnsw::build(
+            reference.sample_names.len(),
+            m,
+            ef_construction,
+            seed,
+            |i, j| reference.distance(i, j),
+        );
+        ReferenceIndex { reference, graph }
+    }
+
+    /// The (about) `k` references closest to a query bitset (see
+    /// [`ReferenceSet::query_bits`]), ascending by distance, searching `ef`
+    /// candidates wide
+    pub fn nearest(&self, query: &[u64], k: usize, ef: usize) -> Vec<(&str, f64)> {
+        let total = self.reference.query_length(query);
+        self.graph
+            .search(|s| self.reference.query_distance(query, total, s), k, ef)
+            .into_iter()
+            .map(|(s, d)| (self.reference.sample_names[s].as_str(), d))
+            .collect()
+    }
+}
+
+/// Save an indexed reference set (bincode: the branch sets as raw words)
+pub fn write_index(index: &ReferenceIndex, output_file: &str, force: bool) -> Result<()> {
+    let mut file = OutputFile::create(output_file, force)?;
+    bincode::serialize_into(&mut file, index)?;
+    file.commit()
+}
+
+/// Save a reference set (bincode: the branch sets as raw words)
+pub fn write_reference(reference: &ReferenceSet, output_file: &str, force: bool) -> Result<()> {
+    let mut file = OutputFile::create(output_file, force)?;
+    bincode::serialize_into(&mut file, reference)?;
+    file.commit()
+}
+
+/// Load a reference set written by [`write_reference`]
+pub fn read_reference(filename: &str) -> Result<ReferenceSet> {
+    let file = BufReader::new(File::open(filename)?);
+    let mut reference: ReferenceSet = bincode::deserialize_from(file)
+        .with_context(|| format!("{} is not a reference set", filename))?;
+    reference.restore(filename)?;
+    Ok(reference)
+}
+
+/// Load an indexed reference set written by [`write_index`]
+pub fn read_index(filename: &str) -> Result<ReferenceIndex> {
+    let file = BufReader::new(File::open(filename)?);
+    let mut index: ReferenceIndex = bincode::deserialize_from(file)
+        .with_context(|| format!("{} is not an indexed reference set", filename))?;
+    index.reference.restore(filename)?;
+    if index.graph.len() != index.reference.sample_names.len() {
+        bail!(
+            "{} is corrupt: the graph does not match the reference samples",
+            filename
+        );
+    }
+    Ok(index)
+}
@@ -0,0 +1,318 @@
+//! Small REST service (feature `server`): upload or reference a table, start
+//! a matrix computation, poll its progress and download the result.
+//!
+//! POST /tables              body = table text, or ?path=FILE for a file under the workdir
+//! POST /jobs                {"table": ID}
+//! GET  /jobs/:id            status and progress
+//! GET  /jobs/:id/matrix     the finished matrix (tab-delimited)
+//!
+//! At most `MAX_RUNNING_JOBS` matrices are computed at once; further job
+//! requests are refused with 503 until one of them finishes.
+
+use crate::{
+    compute::{compute_unifrac_matrix_progress, BranchOptions, Metric},
+    io::write_matrix,
+    metrics::compute_metrics,
+    weighted::Transform,
+};
+use anyhow::{bail, Context, Result};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use phylotree::tree::Tree;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::Semaphore;
+
+/// Jobs computed at the same time; each already spreads over the rayon pool
+const MAX_RUNNING_JOBS: usize = 2;
+
+/// Reads a table file into (features, samples, counts) with every table
+/// option the server was started with
+pub type TableReader =
+    Box<dyn Fn(&str) -> Result<(Vec<String>, Vec<String>, Vec<Vec<f64>>)> + Send + Sync>;
+
+/// A parsed sample-feature table
+struct Table {
+    taxa_order: Vec<String>,
+    sample_names: Vec<String>,
+    presence_matrix: Vec<Vec<f64>>,
+}
+
+enum JobState {
+    Running,
+    Done(PathBuf),
+    Failed(String),
+}
+
+struct Job {
+    n_pairs: usize,
+    done: AtomicUsize,
+    state: Mutex<JobState>,
+}
+
+struct AppState {
+    tree: Tree,
+    opts: BranchOptions,
+    metric: Metric,
+    transform: Transform,
+    read_table: TableReader,
+    workdir: PathBuf,
+    tables: Mutex<HashMap<String, Arc<Table>>>,
+    jobs: Mutex<HashMap<String, Arc<Job>>>,
+    next_id: AtomicUsize,
+    job_slots: Arc<Semaphore>,
+}
+
+impl AppState {
+    fn new_id(&self, prefix: &str) -> String {
+        format!(
+            "{}{}",
+            prefix,
+            self.next_id.fetch_add(1, Ordering::Relaxed) + 1
+        )
+    }
+}
+
+/// An error reply: status code plus a JSON message
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+fn bad_request(err: anyhow::Error) -> ApiError {
+    ApiError(StatusCode::BAD_REQUEST, format!("{:#}", err))
+}
+
+fn not_found(what: &str, id: &str) -> ApiError {
+    ApiError(
+        StatusCode::NOT_FOUND,
+        format!("No {} with id '{}'", what, id),
+    )
+}
+
+#[derive(Deserialize)]
+struct TableSource {
+    path: Option<String>,
+}
+
+/// `name` resolved inside `workdir`; anything that leads out of it, through
+/// `..`, an absolute path or a symlink, is refused
+fn workdir_file(workdir: &std::path::Path, name: &str) -> Result<PathBuf> {
+    let root = workdir.canonicalize()?;
+    let path = root
+        .join(name)
+        .canonicalize()
+        .with_context(|| format!("No file '{}' in the workdir", name))?;
+    if !path.starts_with(&root) {
+        bail!("'{}' is outside the workdir", name);
+    }
+    Ok(path)
+}
+
+async fn add_table(
+    State(state): State<Arc<AppState>>,
+    Query(source): Query<TableSource>,
+    body: String,
+) -> Result<Json<Value>, ApiError> {
+    let id = state.new_id("table");
+    let path = match source.path {
+        Some(path) => workdir_file(&state.workdir, &path).map_err(bad_request)?,
+        None => {
+            let path = state.workdir.join(format!("{}.tsv", id));
+            std::fs::write(&path, body).map_err(|e| bad_request(e.into()))?;
+            path
+        }
+    };
+    let (taxa_order, sample_names, presence_matrix) =
+        (state.read_table)(&path.to_string_lossy()).map_err(bad_request)?;
+
+    let reply = json!({ "id": id, "samples": sample_names.len(), "features": taxa_order.len() });
+    let table = Table {
+        taxa_order,
+        sample_names,
+        presence_matrix,
+    };
+    state.tables.lock().unwrap().insert(id, Arc::new(table));
+    Ok(Json(reply))
+}
+
+#[derive(Deserialize)]
+struct JobRequest {
+    table: String,
+}
+
+async fn start_job(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<JobRequest>,
+) -> Result<Json<Value>, ApiError> {
+    let table = state
+        .tables
+        .lock()
+        .unwrap()
+        .get(&request.table)
+        .cloned()
+        .ok_or_else(|| not_found("table", &request.table))?;
+    let slot = state.job_slots.clone().try_acquire_owned().map_err(|_| {
+        ApiError(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("{} jobs are already running", MAX_RUNNING_JOBS),
+        )
+    })?;
+
+    let n = table.sample_names.len();
+    let id = state.new_id("job");
+    let job = Arc::new(Job {
+        n_pairs: n * n.saturating_sub(1) / 2,
+        done: AtomicUsize::new(0),
+        state: Mutex::new(JobState::Running),
+    });
+    state.jobs.lock().unwrap().insert(id.clone(), job.clone());
+
+    let output = state.workdir.join(format!("{}.tsv", id));
+    let state = state.clone();
+    tokio::task::spawn_blocking(move || {
+        // The slot is given back when the job ends, also if it panics
+        let _slot = slot;
+        // Progress is only counted pair by pair for the unweighted metric
+        let dist_matrix = match state.metric {
+            Metric::Unweighted => compute_unifrac_matrix_progress(
+                &state.tree,
+                &table.taxa_order,
+                &table.presence_matrix,
+                n,
+                &state.opts,
+                Some(&job.done),
+            )
+            .map(|(dist_matrix, _)| dist_matrix),
+            metric => compute_metrics(
+                &state.tree,
+                &table.taxa_order,
+                &table.presence_matrix,
+                n,
+                &state.opts,
+                &[metric],
+                state.transform,
+            )
+            .map(|mut matrices| matrices.remove(0)),
+        };
+        // Job matrices in the workdir belong to the server, including those of earlier runs
+        let result = dist_matrix.and_then(|dist_matrix| {
+            write_matrix(
+                &table.sample_names,
+                &dist_matrix,
+                n,
+                &output.to_string_lossy(),
+                true,
+            )
+        });
+        *job.state.lock().unwrap() = match result {
+            Ok(()) => JobState::Done(output),
+            Err(err) => JobState::Failed(format!("{:#}", err)),
+        };
+    });
+
+    Ok(Json(json!({ "id": id })))
+}
+
+fn get_job(state: &AppState, id: &str) -> Result<Arc<Job>, ApiError> {
+    state
+        .jobs
+        .lock()
+        .unwrap()
+        .get(id)
+        .cloned()
+        .ok_or_else(|| not_found("job", id))
+}
+
+async fn job_status(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let job = get_job(&state, &id)?;
+    let progress = if job.n_pairs == 0 {
+        1.0
+    } else {
+        job.done.load(Ordering::Relaxed) as f64 / job.n_pairs as f64
+    };
+    let reply = match &*job.state.lock().unwrap() {
+        JobState::Running => json!({ "id": id, "status": "running", "progress": progress }),
+        JobState::Done(_) => json!({ "id": id, "status": "done", "progress": 1.0 }),
+        JobState::Failed(err) => json!({ "id": id, "status": "failed", "error": err }),
+    };
+    Ok(Json(reply))
+}
+
+async fn job_matrix(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<String, ApiError> {
+    let job = get_job(&state, &id)?;
+    let path = match &*job.state.lock().unwrap() {
+        JobState::Done(path) => path.clone(),
+        _ => {
+            return Err(ApiError(
+                StatusCode::CONFLICT,
+                format!("Job '{}' has not finished", id),
+            ))
+        }
+    };
+    std::fs::read_to_string(path)
+        .map_err(|e| ApiError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Serve the REST API on `addr` until the process is stopped; tables are
+/// read with `read_table` and every job computes `metric`. Uploaded tables
+/// and finished matrices are kept as files in `workdir`.
+pub fn serve(
+    tree: Tree,
+    opts: BranchOptions,
+    metric: Metric,
+    transform: Transform,
+    read_table: TableReader,
+    workdir: PathBuf,
+    addr: SocketAddr,
+) -> Result<()> {
+    std::fs::create_dir_all(&workdir)?;
+    let state = Arc::new(AppState {
+        tree,
+        opts,
+        metric,
+        transform,
+        read_table,
+        workdir,
+        tables: Mutex::new(HashMap::new()),
+        jobs: Mutex::new(HashMap::new()),
+        next_id: AtomicUsize::new(0),
+        job_slots: Arc::new(Semaphore::new(MAX_RUNNING_JOBS)),
+    });
+    let app = Router::new()
+        .route("/tables", post(add_table))
+        .route("/jobs", post(start_job))
+        .route("/jobs/:id", get(job_status))
+        .route("/jobs/:id/matrix", get(job_matrix))
+        .with_state(state);
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async move {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        axum::serve(listener, app).await
+    })?;
+    Ok(())
+}
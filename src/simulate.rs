@@ -0,0 +1,256 @@
+use anyhow::{bail, Result};
+use rand::Rng;
+
+/// A simulated rooted tree. Nodes are numbered so that every parent comes
+/// before its children.
+pub struct SimTree {
+    pub parent: Vec<Option<usize>>,
+    pub children: Vec<Vec<usize>>,
+    /// Length of the edge to the parent (0 for the root)
+    pub length: Vec<f64>,
+    /// Tip names; empty for internal nodes
+    pub names: Vec<String>,
+}
+
+impl SimTree {
+    /// Tip node ids in increasing order
+    pub fn tips(&self) -> Vec<usize> {
+        (0..self.children.len())
+            .filter(|v| self.children[*v].is_empty())
+            .collect()
+    }
+
+    pub fn to_newick(&self) -> String {
+        let n = self.children.len();
+        let mut rendered: Vec<String> = vec![String::new(); n];
+        // Children have larger ids than their parent, so this is a postorder
+        for v in (0..n).rev() {
+            let mut s = String::new();
+            if self.children[v].is_empty() {
+                s.push_str(&self.names[v]);
+            } else {
+                let kids: Vec<String> = self.children[v]
+                    .iter()
+                    .map(|c| std::mem::take(&mut rendered[*c]))
+                    .collect();
+                s.push('(');
+                s.push_str(&kids.join(","));
+                s.push(')');
+            }
+            if self.parent[v].is_some() {
+                s.push_str(&format!(":{:.6}", self.length[v]));
+            }
+            rendered[v] = s;
+        }
+        format!("{};", rendered[0])
+    }
+}
+
+fn exponential<R: Rng>(rate: f64, rng: &mut R) -> f64 {
+    -(1.0 - rng.gen::<f64>()).ln() / rate
+}
+
+/// Simulate a reconstructed birth-death tree with `n_tips` extant tips.
+/// Lineages split at rate `birth` and go extinct at rate `death`, starting from
+/// two crown lineages; the process stops one waiting time after `n_tips`
+/// lineages are alive, and extinct lineages are removed from the returned tree.
+pub fn birth_death_tree<R: Rng>(
+    n_tips: usize,
+    birth: f64,
+    death: f64,
+    rng: &mut R,
+) -> Result<SimTree> {
+    if n_tips < 2 {
+        bail!("A simulated tree needs at least 2 tips");
+    }
+    if birth <= 0.0 || death < 0.0 {
+        bail!("Birth rate must be positive and death rate non-negative");
+    }
+
+    const MAX_ATTEMPTS: usize = 10_000;
+    for _ in 0..MAX_ATTEMPTS {
+        // Complete tree, including lineages that later go extinct
+        let mut parent: Vec<Option<usize>> = vec![None, Some(0), Some(0)];
+        let mut kids: Vec<Vec<usize>> = vec![vec![1, 2], Vec::new(), Vec::new()];
+        let mut end = vec![0.0; 3];
+        let mut active = vec![1, 2];
+        let mut t = 0.0;
+
+        while !active.is_empty() && active.len() < n_tips {
+            t += exponential(active.len() as f64 * (birth + death), rng);
+            let k = rng.gen_range(0..active.len());
+            let node = active.swap_remove(k);
+            end[node] = t;
+            if rng.gen::<f64>() < birth / (birth + death) {
+                for _ in 0..2 {
+                    let child = parent.len();
+                    parent.push(Some(node));
+                    kids.push(Vec::new());
+                    end.push(0.0);
+                    kids[node].push(child);
+                    active.push(child);
+                }
+            }
+        }
+        if active.is_empty() {
+            continue; // everything died out, start over
+        }
+        // Let the survivors keep evolving for one more waiting time, so the
+        // lineages born in the last split do not end with zero-length edges
+        t += exponential(active.len() as f64 * (birth + death), rng);
+        for a in &active {
+            end[*a] = t;
+        }
+
+        return Ok(reconstruct(&parent, &kids, &end, &active));
+    }
+
+    bail!(
+        "Birth-death process went extinct {} times in a row; increase the birth rate relative to the death rate",
+        MAX_ATTEMPTS
+    )
+}
+
+/// Keep only lineages with surviving descendants and collapse unary nodes
+fn reconstruct(
+    parent: &[Option<usize>],
+    kids: &[Vec<usize>],
+    end: &[f64],
+    active: &[usize],
+) -> SimTree {
+    let n = parent.len();
+    let mut survives = vec![false; n];
+    let mut surviving_children = vec![0usize; n];
+    for a in active {
+        survives[*a] = true;
+    }
+    // Children always have larger ids than their parent
+    for v in (0..n).rev() {
+        if survives[v] {
+            if let Some(p) = parent[v] {
+                survives[p] = true;
+                surviving_children[p] += 1;
+            }
+        }
+    }
+    let is_node = |v: usize| survives[v] && (kids[v].is_empty() || surviving_children[v] >= 2);
+
+    let mut tree = SimTree {
+        parent: Vec::new(),
+        children: Vec::new(),
+        length: Vec::new(),
+        names: Vec::new(),
+    };
+    // Nearest kept ancestor-or-self of each original node, and its new id
+    let mut attach: Vec<Option<usize>> = vec![None; n];
+    let mut new_id = vec![usize::MAX; n];
+    let mut n_named = 0;
+    for v in 0..n {
+        if !survives[v] {
+            continue;
+        }
+        let up = parent[v].and_then(|p| attach[p]);
+        if is_node(v) {
+            let id = tree.parent.len();
+            new_id[v] = id;
+            tree.parent.push(up.map(|u| new_id[u]));
+            tree.children.push(Vec::new());
+            tree.length.push(up.map(|u| end[v] - end[u]).unwrap_or(0.0));
+            if kids[v].is_empty() {
+                n_named += 1;
+                tree.names.push(format!("T{}", n_named));
+            } else {
+                tree.names.push(String::new());
+            }
+            if let Some(u) = up {
+                tree.children[new_id[u]].push(id);
+            }
+            attach[v] = Some(v);
+        } else {
+            attach[v] = up;
+        }
+    }
+
+    tree
+}
+
+/// Presence/absence of a binary trait evolving along the tree: at
+/// equilibrium a fraction `prevalence` of lineages carry it, and `rate` sets
+/// how quickly it is gained or lost, so closely related tips tend to agree.
+/// Returns one state per node.
+pub fn simulate_trait<R: Rng>(
+    tree: &SimTree,
+    prevalence: f64,
+    rate: f64,
+    rng: &mut R,
+) -> Vec<bool> {
+    let n = tree.parent.len();
+    let mut state = vec![false; n];
+    for v in 0..n {
+        let p_present = match tree.parent[v] {
+            None => prevalence,
+            Some(p) => {
+                let decay = (-rate * tree.length[v]).exp();
+                if state[p] {
+                    prevalence + (1.0 - prevalence) * decay
+                } else {
+                    prevalence * (1.0 - decay)
+                }
+            }
+        };
+        state[v] = rng.gen::<f64>() < p_present;
+    }
+    state
+}
+
+/// A simulated count table with phylogenetically correlated presences
+pub struct SimTable {
+    pub taxa_order: Vec<String>,
+    pub sample_names: Vec<String>,
+    /// taxa x samples counts
+    pub counts: Vec<Vec<f64>>,
+    /// Environment each sample was drawn from
+    pub environments: Vec<usize>,
+}
+
+/// Simulate `n_samples` samples from `n_envs` environments. Each environment
+/// has its own trait-evolution presence pattern over the tips (see
+/// [`simulate_trait`]); each sample copies its environment's pattern, flipping
+/// every tip with probability `noise`, and present taxa get counts in 1..=100.
+pub fn simulate_table<R: Rng>(
+    tree: &SimTree,
+    n_samples: usize,
+    n_envs: usize,
+    prevalence: f64,
+    rate: f64,
+    noise: f64,
+    rng: &mut R,
+) -> Result<SimTable> {
+    if n_envs == 0 {
+        bail!("At least one environment is needed");
+    }
+    let tips = tree.tips();
+    let prototypes: Vec<Vec<bool>> = (0..n_envs)
+        .map(|_| simulate_trait(tree, prevalence, rate, rng))
+        .collect();
+
+    let mut counts = vec![vec![0.0; n_samples]; tips.len()];
+    let mut environments = Vec::with_capacity(n_samples);
+    for s in 0..n_samples {
+        let env = s % n_envs;
+        environments.push(env);
+        for (row, tip) in tips.iter().enumerate() {
+            let present = prototypes[env][*tip] ^ (rng.gen::<f64>() < noise);
+            if present {
+                counts[row][s] = rng.gen_range(1..=100) as f64;
+            }
+        }
+    }
+
+    Ok(SimTable {
+        taxa_order: tips.iter().map(|t| tree.names[*t].clone()).collect(),
+        sample_names: (1..=n_samples).map(|s| format!("S{}", s)).collect(),
+        counts,
+        environments,
+    })
+}
@@ -0,0 +1,109 @@
+//! Approximate unweighted UniFrac from per-sample sketches.
+//!
+//! Unweighted UniFrac is one minus the weighted Jaccard similarity of two
+//! branch sets, with branch lengths as the weights. For each of `k` hash
+//! functions a sample keeps the branch minimizing `-ln(u) / length`, where `u`
+//! is a uniform hash of the branch; two samples keep the same branch with
+//! probability shared length / union length, so the fraction of agreeing
+//! slots estimates the similarity with standard error at most `0.5 / sqrt(k)`.
+//! Sketches cost O(branches x k) per sample to build and O(k) per pair to
+//! compare, whatever the size of the tree.
+
+use crate::compute::{branch_lengths, BranchOptions, BranchSets};
+use anyhow::Result;
+use phylotree::tree::Tree;
+use rayon::prelude::*;
+
+/// Slot value of a sample without any branch of positive length
+const EMPTY: u32 = u32::MAX;
+
+/// `k` sketch slots per sample, samples one after another
+pub fn build_sketches(
+    tree: &Tree,
+    taxa_order: &[String],
+    presence_matrix: &[Vec<f64>],
+    n_samples: usize,
+    opts: &BranchOptions,
+    k: usize,
+    seed: u64,
+) -> Result<Vec<u32>> {
+    let lengths = branch_lengths(tree, opts)?;
+    let sets = BranchSets::new(tree, taxa_order, presence_matrix, n_samples, opts)?;
+
+    let sketches: Vec<Vec<u32>> = (0..n_samples)
+        .into_par_iter()
+        .map(|s| {
+            let mut best = vec![(f64::INFINITY, EMPTY); k];
+            for (w, word) in sets.sample(s).iter().enumerate() {
+                let mut bits = *word;
+                while bits != 0 {
+                    let b = w * 64 + bits.trailing_zeros() as usize;
+                    bits &= bits - 1;
+                    let length = lengths[b];
+                    if length <= 0.0 {
+                        continue;
+                    }
+                    for (slot, entry) in best.iter_mut().enumerate() {
+                        let value = -unit_hash(seed, slot as u64, b as u64).ln() / length;
+                        if value < entry.0 {
+                            *entry = (value, b as u32);
+                        }
+                    }
+                }
+            }
+            best.into_iter().map(|(_, b)| b).collect()
+        })
+        .collect();
+    Ok(sketches.concat())
+}
+
+/// Estimated distance of two sketches of the same size and seed
+pub fn sketch_distance(a: &[u32], b: &[u32]) -> f64 {
+    let empty_a = a.is_empty() || a[0] == EMPTY;
+    let empty_b = b.is_empty() || b[0] == EMPTY;
+    if empty_a || empty_b {
+        // As exact UniFrac: 1 against an empty sample, undefined between two
+        return if empty_a && empty_b { f64::NAN } else { 1.0 };
+    }
+    let agree = a.iter().zip(b.iter()).filter(|(x, y)| x == y).count();
+    1.0 - agree as f64 / a.len() as f64
+}
+
+/// Rows of the condensed upper triangle compared at once
+const ROW_BLOCK: usize = 64;
+
+/// Approximate distances of all pairs i < j in condensed order, from
+/// sketches of `k` slots per sample (see [`build_sketches`]). Blocks of rows
+/// are compared in parallel as the iterator reaches them, so only one block
+/// is held at a time: sketches are for sample counts whose matrix would not
+/// fit in memory.
+pub fn sketch_pairs(sketches: &[u32], k: usize) -> impl Iterator<Item = f64> + '_ {
+    let n_samples = sketches.len() / k;
+    let sketch = move |s: usize| &sketches[s * k..(s + 1) * k];
+    (0..n_samples).step_by(ROW_BLOCK).flat_map(move |start| {
+        let end = (start + ROW_BLOCK).min(n_samples);
+        let rows: Vec<Vec<f64>> = (start..end)
+            .into_par_iter()
+            .map(|i| {
+                (i + 1..n_samples)
+                    .map(|j| sketch_distance(sketch(i), sketch(j)))
+                    .collect()
+            })
+            .collect();
+        rows.into_iter().flatten()
+    })
+}
+
+/// Uniform value in (0, 1) for a (seed, slot, branch) triple
+fn unit_hash(seed: u64, slot: u64, branch: u64) -> f64 {
+    let h = mix(seed ^ mix(slot.wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ branch));
+    ((h >> 11) as f64 + 0.5) / (1u64 << 53) as f64
+}
+
+/// splitmix64 finalizer
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
@@ -0,0 +1,984 @@
+use crate::ordination::{gower_center, symmetric_eigen};
+use anyhow::{bail, Result};
+use rand::{seq::SliceRandom, Rng};
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+
+/// Location and spread of a set of distances
+#[derive(Clone, Debug)]
+pub struct DistanceSummary {
+    pub n: usize,
+    pub mean: f64,
+    pub sd: f64,
+    pub min: f64,
+    pub q1: f64,
+    pub median: f64,
+    pub q3: f64,
+    pub max: f64,
+}
+
+/// Summary statistics of `values`; all fields but `n` are NaN when empty
+pub fn summarize(values: &[f64]) -> DistanceSummary {
+    let n = values.len();
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+
+    let mean = sorted.iter().sum::<f64>() / n as f64;
+    let sd = if n > 1 {
+        (sorted.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / (n - 1) as f64).sqrt()
+    } else {
+        f64::NAN
+    };
+
+    DistanceSummary {
+        n,
+        mean,
+        sd,
+        min: sorted.first().copied().unwrap_or(f64::NAN),
+        q1: quantile(&sorted, 0.25),
+        median: quantile(&sorted, 0.5),
+        q3: quantile(&sorted, 0.75),
+        max: sorted.last().copied().unwrap_or(f64::NAN),
+    }
+}
+
+/// Quantile of sorted values, linearly interpolated between order statistics
+pub fn quantile(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    let pos = q * (sorted.len() - 1) as f64;
+    let lo = pos.floor() as usize;
+    let hi = pos.ceil() as usize;
+    sorted[lo] + (sorted[hi] - sorted[lo]) * (pos - lo as f64)
+}
+
+/// A statistic of a distance distribution estimated from a sample of it,
+/// with a 95% confidence interval
+#[derive(Clone, Debug)]
+pub struct Estimate {
+    pub statistic: String,
+    pub value: f64,
+    pub ci_low: f64,
+    pub ci_high: f64,
+}
+
+/// `k` pairs (i < j) drawn uniformly, with replacement, among `n` samples
+pub fn random_pairs<R: Rng>(n: usize, k: usize, rng: &mut R) -> Vec<(usize, usize)> {
+    (0..k)
+        .map(|_| {
+            let i = rng.gen_range(0..n);
+            let mut j = rng.gen_range(0..n - 1);
+            if j >= i {
+                j += 1;
+            }
+            (i.min(j), i.max(j))
+        })
+        .collect()
+}
+
+/// Mean and `probs` quantiles of a distribution from a random sample of it.
+/// The mean's interval is the normal approximation; the quantiles' come from
+/// the binomial distribution of ranks, so they hold whatever the shape.
+pub fn estimate_distribution(sample: &[f64], probs: &[f64]) -> Vec<Estimate> {
+    let summary = summarize(sample);
+    let n = sample.len() as f64;
+    let half_width = 1.96 * summary.sd / n.sqrt();
+    let mut estimates = vec![Estimate {
+        statistic: "mean".to_string(),
+        value: summary.mean,
+        ci_low: summary.mean - half_width,
+        ci_high: summary.mean + half_width,
+    }];
+
+    let mut sorted = sample.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let last = sorted.len().saturating_sub(1) as f64;
+    // Value at a 0-based rank, clamped to the sample
+    let rank = |r: f64| {
+        sorted
+            .get(r.clamp(0.0, last) as usize)
+            .copied()
+            .unwrap_or(f64::NAN)
+    };
+    for &p in probs {
+        let spread = 1.96 * (n * p * (1.0 - p)).sqrt();
+        estimates.push(Estimate {
+            statistic: format!("q{}", p),
+            value: quantile(&sorted, p),
+            ci_low: rank((n * p - spread).floor() - 1.0),
+            ci_high: rank((n * p + spread).ceil() - 1.0),
+        });
+    }
+    estimates
+}
+
+/// Distances between samples of two groups (or within one group when equal)
+#[derive(Clone, Debug)]
+pub struct GroupComparison {
+    pub group_a: String,
+    pub group_b: String,
+    pub summary: DistanceSummary,
+}
+
+/// Summaries of within-group distances for every group and of between-group
+/// distances for every unordered pair of groups, in sorted group order
+pub fn group_comparisons(dist_matrix: &[f64], n: usize, labels: &[String]) -> Vec<GroupComparison> {
+    let mut values: BTreeMap<(&str, &str), Vec<f64>> = BTreeMap::new();
+    for i in 0..n {
+        for j in i + 1..n {
+            let (a, b) = if labels[i] <= labels[j] {
+                (labels[i].as_str(), labels[j].as_str())
+            } else {
+                (labels[j].as_str(), labels[i].as_str())
+            };
+            values
+                .entry((a, b))
+                .or_default()
+                .push(dist_matrix[i * n + j]);
+        }
+    }
+
+    values
+        .into_iter()
+        .map(|((a, b), v)| GroupComparison {
+            group_a: a.to_string(),
+            group_b: b.to_string(),
+            summary: summarize(&v),
+        })
+        .collect()
+}
+
+/// Pooled summaries of all within-group and all between-group distances
+pub fn within_between(
+    dist_matrix: &[f64],
+    n: usize,
+    labels: &[String],
+) -> (DistanceSummary, DistanceSummary) {
+    let mut within = Vec::new();
+    let mut between = Vec::new();
+    for i in 0..n {
+        for j in i + 1..n {
+            if labels[i] == labels[j] {
+                within.push(dist_matrix[i * n + j]);
+            } else {
+                between.push(dist_matrix[i * n + j]);
+            }
+        }
+    }
+    (summarize(&within), summarize(&between))
+}
+
+/// Silhouette width of every sample under the grouping `labels`:
+/// (b - a) / max(a, b), with `a` the mean distance to the rest of its own group
+/// and `b` the smallest mean distance to another group. Samples alone in their
+/// group score 0; with a single group every score is NaN.
+pub fn silhouette(dist_matrix: &[f64], n: usize, labels: &[String]) -> Vec<f64> {
+    let (groups, group_of) = group_indices(labels);
+    let sizes = group_sizes(&group_of, groups.len());
+
+    (0..n)
+        .map(|i| {
+            let own = group_of[i];
+            if sizes[own] == 1 {
+                return 0.0;
+            }
+            let mut sums = vec![0.0; groups.len()];
+            for j in 0..n {
+                if j != i {
+                    sums[group_of[j]] += dist_matrix[i * n + j];
+                }
+            }
+            let a = sums[own] / (sizes[own] - 1) as f64;
+            let b = (0..groups.len())
+                .filter(|g| *g != own)
+                .map(|g| sums[g] / sizes[g] as f64)
+                .fold(f64::NAN, f64::min);
+            (b - a) / a.max(b)
+        })
+        .collect()
+}
+
+/// Mean distance of each sample to the samples of every group (n x n_groups,
+/// row-major), itself excluded; NaN for its own group when it is alone in
+/// it. Undefined distances are left out of the means.
+pub fn group_mean_distances(
+    dist_matrix: &[f64],
+    n: usize,
+    group_of: &[usize],
+    n_groups: usize,
+) -> Vec<f64> {
+    let mut means = vec![0.0; n * n_groups];
+    for i in 0..n {
+        let mut sums = vec![0.0; n_groups];
+        let mut counts = vec![0usize; n_groups];
+        for j in 0..n {
+            let d = dist_matrix[i * n + j];
+            if j != i && !d.is_nan() {
+                sums[group_of[j]] += d;
+                counts[group_of[j]] += 1;
+            }
+        }
+        for g in 0..n_groups {
+            means[i * n_groups + g] = sums[g] / counts[g] as f64;
+        }
+    }
+    means
+}
+
+/// Summary of each sample's distances to all others (undefined ones left out)
+pub fn sample_summaries(dist_matrix: &[f64], n: usize) -> Vec<DistanceSummary> {
+    (0..n)
+        .map(|i| {
+            let row: Vec<f64> = (0..n)
+                .filter(|j| *j != i)
+                .map(|j| dist_matrix[i * n + j])
+                .filter(|d| !d.is_nan())
+                .collect();
+            summarize(&row)
+        })
+        .collect()
+}
+
+/// Median distance of each sample to all others (undefined distances left out)
+pub fn median_distances(dist_matrix: &[f64], n: usize) -> Vec<f64> {
+    (0..n)
+        .map(|i| {
+            let mut row: Vec<f64> = (0..n)
+                .filter(|j| *j != i)
+                .map(|j| dist_matrix[i * n + j])
+                .filter(|d| !d.is_nan())
+                .collect();
+            row.sort_by(|a, b| a.total_cmp(b));
+            quantile(&row, 0.5)
+        })
+        .collect()
+}
+
+/// Distance of each value from the median in units of the scaled median
+/// absolute deviation (1.4826 MAD, the standard deviation for normal data)
+pub fn robust_z(values: &[f64]) -> Vec<f64> {
+    let mut sorted: Vec<f64> = values.iter().copied().filter(|v| !v.is_nan()).collect();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let median = quantile(&sorted, 0.5);
+    let mut deviations: Vec<f64> = sorted.iter().map(|v| (v - median).abs()).collect();
+    deviations.sort_by(|a, b| a.total_cmp(b));
+    let mad = 1.4826 * quantile(&deviations, 0.5);
+    values.iter().map(|v| (v - median) / mad).collect()
+}
+
+/// Sorted distinct labels, and the index in it of each sample's label
+pub fn group_indices(labels: &[String]) -> (Vec<String>, Vec<usize>) {
+    let mut groups: Vec<String> = labels.to_vec();
+    groups.sort_unstable();
+    groups.dedup();
+    let group_of = labels
+        .iter()
+        .map(|l| groups.binary_search(l).unwrap())
+        .collect();
+    (groups, group_of)
+}
+
+/// Fail unless `labels` form at least two groups and at least one group has
+/// more than one sample, as the F statistics of the group tests need
+pub fn check_grouping(labels: &[String]) -> Result<()> {
+    let (groups, _) = group_indices(labels);
+    if groups.len() < 2 || groups.len() == labels.len() {
+        bail!(
+            "{} groups for {} samples; need at least two groups and one with more than one sample",
+            groups.len(),
+            labels.len()
+        );
+    }
+    Ok(())
+}
+
+fn group_sizes(group_of: &[usize], n_groups: usize) -> Vec<usize> {
+    let mut sizes = vec![0usize; n_groups];
+    for g in group_of {
+        sizes[*g] += 1;
+    }
+    sizes
+}
+
+/// One-way ANOVA F statistic of `values` under the grouping `group_of`
+pub fn anova_f(values: &[f64], group_of: &[usize], n_groups: usize) -> f64 {
+    let n = values.len();
+    let sizes = group_sizes(group_of, n_groups);
+    let mut sums = vec![0.0; n_groups];
+    for (v, g) in values.iter().zip(group_of.iter()) {
+        sums[*g] += v;
+    }
+    let grand_mean = values.iter().sum::<f64>() / n as f64;
+    let means: Vec<f64> = sums
+        .iter()
+        .zip(sizes.iter())
+        .map(|(s, k)| s / *k as f64)
+        .collect();
+
+    let between: f64 = (0..n_groups)
+        .map(|g| sizes[g] as f64 * (means[g] - grand_mean).powi(2))
+        .sum();
+    let within: f64 = values
+        .iter()
+        .zip(group_of.iter())
+        .map(|(v, g)| (v - means[*g]).powi(2))
+        .sum();
+    (between / (n_groups - 1) as f64) / (within / (n - n_groups) as f64)
+}
+
+/// Test for homogeneity of multivariate group dispersions
+pub struct Permdisp {
+    pub groups: Vec<String>,
+    pub group_sizes: Vec<usize>,
+    /// Mean distance of each group's samples to the group centroid
+    pub mean_dispersions: Vec<f64>,
+    /// Distance of every sample to its group centroid
+    pub distances: Vec<f64>,
+    pub f_statistic: f64,
+    pub p_value: f64,
+    pub permutations: usize,
+}
+
+/// PERMDISP (Anderson 2006; vegan's betadisper): distances of the samples to
+/// their group centroid in the full principal coordinate space, where the axes
+/// of negative eigenvalues count negatively, compared across groups by an
+/// ANOVA F whose p-value comes from `permutations` shuffles of the labels
+/// (NaN when F is not finite). See [`check_grouping`] for the groups it needs.
+pub fn permdisp<R: Rng>(
+    dist_matrix: &[f64],
+    n: usize,
+    labels: &[String],
+    permutations: usize,
+    rng: &mut R,
+) -> Result<Permdisp> {
+    check_grouping(labels)?;
+    let (groups, group_of) = group_indices(labels);
+    let sizes = group_sizes(&group_of, groups.len());
+
+    let mut centered = gower_center(dist_matrix, n);
+    let (values, vectors) = symmetric_eigen(&mut centered, n);
+    let tolerance = values.iter().map(|v| v.abs()).fold(0.0, f64::max) * 1e-10;
+    let axes: Vec<usize> = (0..n).filter(|k| values[*k].abs() > tolerance).collect();
+
+    let mut distances = vec![0.0; n];
+    for &k in &axes {
+        let scale = values[k].abs().sqrt();
+        let mut centroids = vec![0.0; groups.len()];
+        for s in 0..n {
+            centroids[group_of[s]] += vectors[s * n + k] * scale;
+        }
+        for (c, size) in centroids.iter_mut().zip(sizes.iter()) {
+            *c /= *size as f64;
+        }
+        for s in 0..n {
+            let sq = (vectors[s * n + k] * scale - centroids[group_of[s]]).powi(2);
+            distances[s] += if values[k] > 0.0 { sq } else { -sq };
+        }
+    }
+    for d in distances.iter_mut() {
+        *d = d.abs().sqrt();
+    }
+
+    let mut mean_dispersions = vec![0.0; groups.len()];
+    for (d, g) in distances.iter().zip(group_of.iter()) {
+        mean_dispersions[*g] += d / sizes[*g] as f64;
+    }
+
+    let f_statistic = anova_f(&distances, &group_of, groups.len());
+    let p_value = if f_statistic.is_finite() {
+        let mut shuffled = group_of.clone();
+        let mut as_extreme = 0;
+        for _ in 0..permutations {
+            shuffled.shuffle(rng);
+            if anova_f(&distances, &shuffled, groups.len()) >= f_statistic {
+                as_extreme += 1;
+            }
+        }
+        (as_extreme + 1) as f64 / (permutations + 1) as f64
+    } else {
+        f64::NAN
+    };
+
+    Ok(Permdisp {
+        groups,
+        group_sizes: sizes,
+        mean_dispersions,
+        distances,
+        f_statistic,
+        p_value,
+        permutations,
+    })
+}
+
+/// PERMANOVA pseudo-F (Anderson 2001) of the samples `members` under the
+/// grouping `group_of` (one group index per member, `n_groups` groups)
+pub fn permanova_f(
+    dist_matrix: &[f64],
+    n: usize,
+    members: &[usize],
+    group_of: &[usize],
+    n_groups: usize,
+) -> f64 {
+    let sizes = group_sizes(group_of, n_groups);
+    let mut total = 0.0;
+    let mut within = 0.0;
+    for (a, &i) in members.iter().enumerate() {
+        for (b, &j) in members.iter().enumerate().skip(a + 1) {
+            let sq = dist_matrix[i * n + j].powi(2);
+            total += sq;
+            if group_of[a] == group_of[b] {
+                within += sq / sizes[group_of[a]] as f64;
+            }
+        }
+    }
+    let total = total / members.len() as f64;
+    let among = total - within;
+    (among / (n_groups - 1) as f64) / (within / (members.len() - n_groups) as f64)
+}
+
+/// Pseudo-F and permutation p-value of a PERMANOVA over `members`
+fn permanova_test<R: Rng>(
+    dist_matrix: &[f64],
+    n: usize,
+    members: &[usize],
+    group_of: &[usize],
+    n_groups: usize,
+    permutations: usize,
+    rng: &mut R,
+) -> (f64, f64) {
+    let f_statistic = permanova_f(dist_matrix, n, members, group_of, n_groups);
+    // No permutation can be compared with an undefined statistic (e.g. from
+    // NaN distances)
+    if !f_statistic.is_finite() {
+        return (f_statistic, f64::NAN);
+    }
+    let mut shuffled = group_of.to_vec();
+    let mut as_extreme = 0;
+    for _ in 0..permutations {
+        shuffled.shuffle(rng);
+        if permanova_f(dist_matrix, n, members, &shuffled, n_groups) >= f_statistic {
+            as_extreme += 1;
+        }
+    }
+    let p_value = (as_extreme + 1) as f64 / (permutations + 1) as f64;
+    (f_statistic, p_value)
+}
+
+/// Result of a PERMANOVA between all groups, or between one pair of them
+#[derive(Clone, Debug)]
+pub struct PermanovaTest {
+    pub group_a: String,
+    pub group_b: String,
+    pub n: usize,
+    pub f_statistic: f64,
+    pub p_value: f64,
+    /// Benjamini-Hochberg adjusted p-value (pairwise tests only)
+    pub q_value: f64,
+}
+
+/// Omnibus PERMANOVA over all groups (reported with group names "*"); see
+/// [`check_grouping`] for the groups it needs. The p-value is NaN when the
+/// pseudo-F is not finite.
+pub fn permanova<R: Rng>(
+    dist_matrix: &[f64],
+    n: usize,
+    labels: &[String],
+    permutations: usize,
+    rng: &mut R,
+) -> Result<PermanovaTest> {
+    check_grouping(labels)?;
+    let (groups, group_of) = group_indices(labels);
+    let members: Vec<usize> = (0..n).collect();
+    let (f_statistic, p_value) = permanova_test(
+        dist_matrix,
+        n,
+        &members,
+        &group_of,
+        groups.len(),
+        permutations,
+        rng,
+    );
+    Ok(PermanovaTest {
+        group_a: "*".to_string(),
+        group_b: "*".to_string(),
+        n,
+        f_statistic,
+        p_value,
+        q_value: f64::NAN,
+    })
+}
+
+/// Correlation measure of the Mantel test
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Correlation {
+    Pearson,
+    /// Pearson correlation of the ranks (ties get their average rank)
+    Spearman,
+}
+
+/// Result of a Mantel test between two distance matrices
+#[derive(Clone, Debug)]
+pub struct MantelTest {
+    pub n: usize,
+    pub statistic: f64,
+    pub p_value: f64,
+}
+
+/// Mantel test: correlation between the upper triangles of two matrices
+/// over the same samples, with a two-sided p-value from relabelling the
+/// samples of `b`
+pub fn mantel<R: Rng>(
+    a: &[f64],
+    b: &[f64],
+    n: usize,
+    method: Correlation,
+    permutations: usize,
+    rng: &mut R,
+) -> MantelTest {
+    let triangle = |m: &[f64]| -> Vec<f64> {
+        (0..n)
+            .flat_map(|i| (i + 1..n).map(move |j| (i, j)))
+            .map(|(i, j)| m[i * n + j])
+            .collect()
+    };
+    let (mut x, mut y) = (triangle(a), triangle(b));
+    if method == Correlation::Spearman {
+        x = average_ranks(&x);
+        y = average_ranks(&y);
+    }
+
+    // y back in matrix form, so that permuting samples permutes its entries
+    let mut y_matrix = vec![0.0; n * n];
+    let mut k = 0;
+    for i in 0..n {
+        for j in i + 1..n {
+            y_matrix[i * n + j] = y[k];
+            y_matrix[j * n + i] = y[k];
+            k += 1;
+        }
+    }
+
+    let statistic = pearson(&x, &y);
+    let mut order: Vec<usize> = (0..n).collect();
+    let mut as_extreme = 0;
+    for _ in 0..permutations {
+        order.shuffle(rng);
+        let mut k = 0;
+        for i in 0..n {
+            for j in i + 1..n {
+                y[k] = y_matrix[order[i] * n + order[j]];
+                k += 1;
+            }
+        }
+        if pearson(&x, &y).abs() >= statistic.abs() {
+            as_extreme += 1;
+        }
+    }
+    MantelTest {
+        n,
+        statistic,
+        p_value: (as_extreme + 1) as f64 / (permutations + 1) as f64,
+    }
+}
+
+/// Best subset of environmental variables of one size found by [`bioenv`]
+#[derive(Clone, Debug)]
+pub struct BioenvSubset {
+    /// Indices of the variables
+    pub variables: Vec<usize>,
+    /// Spearman correlation of their Euclidean distances with the matrix
+    pub correlation: f64,
+}
+
+/// BIOENV (Clarke & Ainsworth 1993): for every subset of at most `max_size`
+/// variables (each given over the n samples, then standardized), the
+/// Spearman correlation between the samples' Euclidean distances over those
+/// variables and `dist_matrix`; returns the best subset of each size.
+/// Variables without variance add nothing to any distance.
+pub fn bioenv(
+    dist_matrix: &[f64],
+    n: usize,
+    variables: &[Vec<f64>],
+    max_size: usize,
+) -> Vec<BioenvSubset> {
+    let pairs: Vec<(usize, usize)> = (0..n)
+        .flat_map(|i| (i + 1..n).map(move |j| (i, j)))
+        .collect();
+    let target = average_ranks(
+        &pairs
+            .iter()
+            .map(|&(i, j)| dist_matrix[i * n + j])
+            .collect::<Vec<_>>(),
+    );
+
+    // Squared differences of each standardized variable over the pairs
+    let squared: Vec<Vec<f64>> = variables
+        .iter()
+        .map(|values| {
+            let summary = summarize(values);
+            let z: Vec<f64> = values
+                .iter()
+                .map(|v| {
+                    if summary.sd > 0.0 {
+                        (v - summary.mean) / summary.sd
+                    } else {
+                        0.0
+                    }
+                })
+                .collect();
+            pairs
+                .iter()
+                .map(|&(i, j)| (z[i] - z[j]) * (z[i] - z[j]))
+                .collect()
+        })
+        .collect();
+
+    let p = variables.len();
+    let max_size = max_size.min(p);
+    let masks: Vec<u32> = (1..1u32 << p)
+        .filter(|m| m.count_ones() as usize <= max_size)
+        .collect();
+    let scored: Vec<(u32, f64)> = masks
+        .par_iter()
+        .map(|&mask| {
+            let mut sums = vec![0.0; pairs.len()];
+            for (v, sq) in squared.iter().enumerate() {
+                if mask & (1 << v) != 0 {
+                    sums.iter_mut().zip(sq).for_each(|(s, d)| *s += d);
+                }
+            }
+            // Ranks of the squared distances are those of the distances
+            (mask, pearson(&average_ranks(&sums), &target))
+        })
+        .collect();
+
+    let mut best: Vec<Option<(u32, f64)>> = vec![None; max_size + 1];
+    for (mask, r) in scored {
+        let size = mask.count_ones() as usize;
+        if best[size].map_or(true, |(_, b)| r > b) {
+            best[size] = Some((mask, r));
+        }
+    }
+    best.into_iter()
+        .flatten()
+        .map(|(mask, correlation)| BioenvSubset {
+            variables: (0..p).filter(|v| mask & (1 << v) != 0).collect(),
+            correlation,
+        })
+        .collect()
+}
+
+/// Pearson correlation coefficient of two equally long samples
+pub fn pearson(x: &[f64], y: &[f64]) -> f64 {
+    let len = x.len() as f64;
+    let (mean_x, mean_y) = (x.iter().sum::<f64>() / len, y.iter().sum::<f64>() / len);
+    let (mut sxy, mut sxx, mut syy) = (0.0, 0.0, 0.0);
+    for (a, b) in x.iter().zip(y.iter()) {
+        let (dx, dy) = (a - mean_x, b - mean_y);
+        sxy += dx * dy;
+        sxx += dx * dx;
+        syy += dy * dy;
+    }
+    sxy / (sxx * syy).sqrt()
+}
+
+/// 1-based ranks, ties sharing their average rank
+pub fn average_ranks(values: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|a, b| values[*a].total_cmp(&values[*b]));
+    let mut ranks = vec![0.0; values.len()];
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start + 1;
+        while end < order.len() && values[order[end]] == values[order[start]] {
+            end += 1;
+        }
+        let rank = (start + end + 1) as f64 / 2.0;
+        for k in &order[start..end] {
+            ranks[*k] = rank;
+        }
+        start = end;
+    }
+    ranks
+}
+
+/// PERMANOVA for every unordered pair of groups, in sorted group order, with
+/// Benjamini-Hochberg adjusted p-values across the pairs
+pub fn pairwise_permanova<R: Rng>(
+    dist_matrix: &[f64],
+    n: usize,
+    labels: &[String],
+    permutations: usize,
+    rng: &mut R,
+) -> Vec<PermanovaTest> {
+    let (groups, group_of) = group_indices(labels);
+    let mut tests = Vec::new();
+    for a in 0..groups.len() {
+        for b in a + 1..groups.len() {
+            let members: Vec<usize> = (0..n)
+                .filter(|s| group_of[*s] == a || group_of[*s] == b)
+                .collect();
+            let pair_of: Vec<usize> = members
+                .iter()
+                .map(|s| usize::from(group_of[*s] == b))
+                .collect();
+            let (f_statistic, p_value) = if members.len() > 2 {
+                permanova_test(dist_matrix, n, &members, &pair_of, 2, permutations, rng)
+            } else {
+                // Two singletons leave no within-group variation to compare against
+                (f64::NAN, f64::NAN)
+            };
+            tests.push(PermanovaTest {
+                group_a: groups[a].clone(),
+                group_b: groups[b].clone(),
+                n: members.len(),
+                f_statistic,
+                p_value,
+                q_value: f64::NAN,
+            });
+        }
+    }
+
+    let p_values: Vec<f64> = tests.iter().map(|t| t.p_value).collect();
+    for (test, q) in tests.iter_mut().zip(benjamini_hochberg(&p_values)) {
+        test.q_value = q;
+    }
+    tests
+}
+
+/// Benjamini-Hochberg adjusted p-values, in the input order; NaN p-values stay
+/// NaN and do not count towards the number of tests
+pub fn benjamini_hochberg(p_values: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..p_values.len())
+        .filter(|k| !p_values[*k].is_nan())
+        .collect();
+    order.sort_by(|a, b| p_values[*a].total_cmp(&p_values[*b]));
+    let m = order.len();
+
+    let mut q_values = vec![f64::NAN; p_values.len()];
+    let mut running_min: f64 = 1.0;
+    for (rank, &k) in order.iter().enumerate().rev() {
+        let q = p_values[k] * m as f64 / (rank + 1) as f64;
+        running_min = running_min.min(q);
+        q_values[k] = running_min;
+    }
+    q_values
+}
+
+/// A pair of samples in a longitudinal design
+#[derive(Clone, Copy, Debug)]
+pub struct LongitudinalPair {
+    /// "baseline" (a subject's first timepoint against a later one),
+    /// "consecutive" (a timepoint against the one before it) or
+    /// "between_baseline" (the first timepoints of two subjects)
+    pub comparison: &'static str,
+    pub i: usize,
+    pub j: usize,
+}
+
+/// Within-subject pairs across timepoints and between-subject pairs of
+/// baselines. Timepoints are ordered numerically when they all parse as
+/// numbers, as text otherwise; subjects are taken in sorted order.
+pub fn longitudinal_pairs(subjects: &[String], timepoints: &[String]) -> Vec<LongitudinalPair> {
+    let numeric: Option<Vec<f64>> = timepoints.iter().map(|t| t.parse().ok()).collect();
+    let mut by_subject: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (s, subject) in subjects.iter().enumerate() {
+        by_subject.entry(subject.as_str()).or_default().push(s);
+    }
+    for samples in by_subject.values_mut() {
+        match &numeric {
+            Some(times) => samples.sort_by(|a, b| times[*a].total_cmp(&times[*b])),
+            None => samples.sort_by(|a, b| timepoints[*a].cmp(&timepoints[*b])),
+        }
+    }
+
+    let mut pairs = Vec::new();
+    for samples in by_subject.values() {
+        for &later in &samples[1..] {
+            pairs.push(LongitudinalPair {
+                comparison: "baseline",
+                i: samples[0],
+                j: later,
+            });
+        }
+        for w in samples.windows(2) {
+            pairs.push(LongitudinalPair {
+                comparison: "consecutive",
+                i: w[0],
+                j: w[1],
+            });
+        }
+    }
+    let baselines: Vec<usize> = by_subject.values().map(|s| s[0]).collect();
+    for (a, &i) in baselines.iter().enumerate() {
+        for &j in &baselines[a + 1..] {
+            pairs.push(LongitudinalPair {
+                comparison: "between_baseline",
+                i,
+                j,
+            });
+        }
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn assert_close(actual: f64, expected: f64, what: &str) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{}: {} instead of {}",
+            what,
+            actual,
+            expected
+        );
+    }
+
+    fn labels(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    /// Distances between points on a line
+    fn line_distances(points: &[f64]) -> Vec<f64> {
+        points
+            .iter()
+            .flat_map(|a| points.iter().map(move |b| (a - b).abs()))
+            .collect()
+    }
+
+    #[test]
+    fn groups_are_sorted() {
+        let (groups, group_of) = group_indices(&labels(&["b", "a", "b", "c"]));
+        assert_eq!(groups, labels(&["a", "b", "c"]));
+        assert_eq!(group_of, vec![1, 0, 1, 2]);
+    }
+
+    #[test]
+    fn grouping_needs_two_groups_and_a_replicate() {
+        assert!(check_grouping(&labels(&["a", "a", "b"])).is_ok());
+        assert!(check_grouping(&labels(&["a", "a", "a"])).is_err());
+        assert!(check_grouping(&labels(&["a", "b", "c"])).is_err());
+    }
+
+    #[test]
+    fn anova_f_by_hand() {
+        let values = [1.0, 2.0, 3.0, 5.0, 6.0, 7.0];
+        // Between groups 24 on 1 df, within 4 on 4 df
+        assert_close(anova_f(&values, &[0, 0, 0, 1, 1, 1], 2), 24.0, "F");
+    }
+
+    #[test]
+    fn permanova_f_by_hand() {
+        let dist_matrix = line_distances(&[0.0, 1.0, 10.0, 11.0]);
+        // Total sum of squares 404 / 4 = 101, within 1/2 + 1/2 = 1
+        let f = permanova_f(&dist_matrix, 4, &[0, 1, 2, 3], &[0, 0, 1, 1], 2);
+        assert_close(f, 200.0, "pseudo-F");
+    }
+
+    #[test]
+    fn permanova_p_value_counts_the_observed_grouping() {
+        let dist_matrix = line_distances(&[0.0, 1.0, 10.0, 11.0]);
+        let mut rng = StdRng::seed_from_u64(1);
+        let test = permanova(
+            &dist_matrix,
+            4,
+            &labels(&["a", "a", "b", "b"]),
+            99,
+            &mut rng,
+        )
+        .unwrap();
+        assert_close(test.f_statistic, 200.0, "pseudo-F");
+        assert!(
+            test.p_value >= 0.01 && test.p_value <= 1.0,
+            "{}",
+            test.p_value
+        );
+        assert!(test.q_value.is_nan());
+    }
+
+    #[test]
+    fn permanova_p_value_is_nan_for_nan_distances() {
+        let mut dist_matrix = line_distances(&[0.0, 1.0, 10.0, 11.0]);
+        dist_matrix[1] = f64::NAN;
+        dist_matrix[4] = f64::NAN;
+        let mut rng = StdRng::seed_from_u64(1);
+        let test = permanova(
+            &dist_matrix,
+            4,
+            &labels(&["a", "a", "b", "b"]),
+            99,
+            &mut rng,
+        )
+        .unwrap();
+        assert!(test.f_statistic.is_nan());
+        assert!(test.p_value.is_nan());
+    }
+
+    #[test]
+    fn permdisp_distances_to_centroids() {
+        let dist_matrix = line_distances(&[0.0, 2.0, 4.0, 10.0, 13.0, 16.0]);
+        let mut rng = StdRng::seed_from_u64(1);
+        let labels = labels(&["a", "a", "a", "b", "b", "b"]);
+        let result = permdisp(&dist_matrix, 6, &labels, 99, &mut rng).unwrap();
+        for (s, expected) in [2.0, 0.0, 2.0, 3.0, 0.0, 3.0].iter().enumerate() {
+            assert_close(result.distances[s], *expected, &format!("sample {}", s));
+        }
+        assert_eq!(result.group_sizes, vec![3, 3]);
+        assert_close(result.mean_dispersions[0], 4.0 / 3.0, "dispersion of a");
+        assert_close(result.mean_dispersions[1], 2.0, "dispersion of b");
+        assert_close(result.f_statistic, 4.0 / 13.0, "F");
+        assert!(result.p_value > 0.0 && result.p_value <= 1.0);
+    }
+
+    #[test]
+    fn permdisp_p_value_is_nan_without_dispersion() {
+        // Identical samples all sit on their group centroids, so F is 0 / 0
+        let dist_matrix = [0.0; 16];
+        let mut rng = StdRng::seed_from_u64(1);
+        let result = permdisp(
+            &dist_matrix,
+            4,
+            &labels(&["a", "a", "b", "b"]),
+            99,
+            &mut rng,
+        )
+        .unwrap();
+        assert!(result.f_statistic.is_nan());
+        assert!(result.p_value.is_nan());
+    }
+
+    #[test]
+    fn pairwise_permanova_skips_two_singletons() {
+        let dist_matrix = line_distances(&[0.0, 1.0, 10.0, 20.0]);
+        let mut rng = StdRng::seed_from_u64(1);
+        let tests = pairwise_permanova(
+            &dist_matrix,
+            4,
+            &labels(&["a", "a", "b", "c"]),
+            99,
+            &mut rng,
+        );
+        let pairs: Vec<(&str, &str, usize)> = tests
+            .iter()
+            .map(|t| (t.group_a.as_str(), t.group_b.as_str(), t.n))
+            .collect();
+        assert_eq!(pairs, vec![("a", "b", 3), ("a", "c", 3), ("b", "c", 2)]);
+        assert!(tests[0].p_value.is_finite() && tests[1].p_value.is_finite());
+        assert!(tests[2].p_value.is_nan() && tests[2].q_value.is_nan());
+        // Two tests are adjusted, so no q-value is below its p-value
+        assert!(tests[0].q_value >= tests[0].p_value);
+    }
+
+    #[test]
+    fn benjamini_hochberg_by_hand() {
+        let q = benjamini_hochberg(&[0.01, 0.04, 0.03, f64::NAN]);
+        assert_close(q[0], 0.03, "q of 0.01");
+        assert_close(q[1], 0.04, "q of 0.04");
+        assert_close(q[2], 0.04, "q of 0.03");
+        assert!(q[3].is_nan());
+    }
+}
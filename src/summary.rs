@@ -0,0 +1,206 @@
+use crate::{compute::missing_branch_lengths, io::OutputFile, phylo::tip_names, stats::summarize};
+use anyhow::{Context, Result};
+use phylotree::tree::Tree;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, Read},
+};
+
+/// An input file and its content hash
+#[derive(Clone, Debug, Serialize)]
+pub struct InputFile {
+    pub path: String,
+    pub sha256: String,
+}
+
+/// Memory used by a run, for sizing cluster jobs
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct MemoryReport {
+    /// Peak resident set size of the process (Linux only)
+    pub peak_rss_bytes: Option<u64>,
+    /// Table values (features x samples)
+    pub presence_matrix_bytes: usize,
+    /// Per-branch structure of the chosen algorithm (B matrix, bitsets, ...)
+    pub branch_matrix_bytes: usize,
+    /// Full square distance matrix
+    pub distance_matrix_bytes: usize,
+}
+
+/// Peak resident set size of this process so far, from /proc/self/status
+pub fn peak_rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// Memory the kernel considers available for new allocations, from
+/// /proc/meminfo (Linux only)
+pub fn available_memory_bytes() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+/// Machine-readable record of one run, for provenance and QC
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RunSummary {
+    pub version: String,
+    pub inputs: Vec<InputFile>,
+    pub n_samples: usize,
+    pub n_features: usize,
+    pub n_tips: usize,
+    pub n_branches: usize,
+    /// Table features with no matching tip in the tree; they do not contribute
+    pub features_dropped: Vec<String>,
+    pub total_branch_length: f64,
+    /// Branches without a length in the tree (handled per --missing-brlen)
+    pub branches_missing_length: usize,
+    pub wall_time_secs: f64,
+    pub memory: MemoryReport,
+    /// Command line options as given (or defaulted)
+    pub options: BTreeMap<String, String>,
+}
+
+impl RunSummary {
+    /// Fill in the counts describing the tree and table of this run
+    pub fn describe_inputs(
+        &mut self,
+        tree: &Tree,
+        taxa_order: &[String],
+        n_samples: usize,
+    ) -> Result<()> {
+        let tips = tip_names(tree);
+
+        self.n_samples = n_samples;
+        self.n_features = taxa_order.len();
+        self.n_tips = tips.len();
+        self.n_branches = tree.size();
+        self.features_dropped = taxa_order
+            .iter()
+            .filter(|t| !tips.contains(*t))
+            .cloned()
+            .collect();
+        self.total_branch_length = total_branch_length(tree)?;
+        self.branches_missing_length = missing_branch_lengths(tree)?;
+        Ok(())
+    }
+
+    /// Hash and record an input file
+    pub fn add_input(&mut self, path: &str) -> Result<()> {
+        self.inputs.push(InputFile {
+            path: path.to_string(),
+            sha256: sha256_file(path)?,
+        });
+        Ok(())
+    }
+}
+
+/// Sum of all branch lengths in the tree
+pub fn total_branch_length(tree: &Tree) -> Result<f64> {
+    let root = tree.get_root()?;
+    let mut total = 0.0;
+    for idx in tree.postorder(&root)? {
+        total += tree.get(&idx)?.parent_edge.unwrap_or_default();
+    }
+    Ok(total)
+}
+
+/// Shape of a tree, for a quick look before an expensive run
+#[derive(Clone, Debug, Serialize)]
+pub struct TreeStats {
+    pub n_tips: usize,
+    pub n_internal: usize,
+    pub total_branch_length: f64,
+    /// Root-to-tip path lengths
+    pub depth_min: f64,
+    pub depth_q1: f64,
+    pub depth_median: f64,
+    pub depth_q3: f64,
+    pub depth_max: f64,
+    /// Internal nodes with more than two children (the root of an unrooted
+    /// tree, with its three, counts)
+    pub polytomies: usize,
+    /// Internal nodes with a single child
+    pub unary_nodes: usize,
+    pub zero_length_branches: usize,
+    pub branches_missing_length: usize,
+}
+
+pub fn tree_stats(tree: &Tree) -> Result<TreeStats> {
+    let root = tree.get_root()?;
+    let order = tree.postorder(&root)?;
+
+    let mut depth = vec![0.0; tree.size()];
+    for idx in order.iter().rev() {
+        let node = tree.get(idx)?;
+        if let Some(parent) = node.parent {
+            depth[*idx] = depth[parent] + node.parent_edge.unwrap_or_default();
+        }
+    }
+
+    let mut tip_depths = Vec::new();
+    let mut n_internal = 0;
+    let mut polytomies = 0;
+    let mut unary_nodes = 0;
+    let mut zero_length_branches = 0;
+    for idx in &order {
+        let node = tree.get(idx)?;
+        if node.is_tip() {
+            tip_depths.push(depth[*idx]);
+        } else {
+            n_internal += 1;
+            match node.children.len() {
+                1 => unary_nodes += 1,
+                2 => {}
+                _ => polytomies += 1,
+            }
+        }
+        if *idx != root && node.parent_edge == Some(0.0) {
+            zero_length_branches += 1;
+        }
+    }
+    let depths = summarize(&tip_depths);
+
+    Ok(TreeStats {
+        n_tips: tip_depths.len(),
+        n_internal,
+        total_branch_length: total_branch_length(tree)?,
+        depth_min: depths.min,
+        depth_q1: depths.q1,
+        depth_median: depths.median,
+        depth_q3: depths.q3,
+        depth_max: depths.max,
+        polytomies,
+        unary_nodes,
+        zero_length_branches,
+        branches_missing_length: missing_branch_lengths(tree)?,
+    })
+}
+
+/// Hex SHA-256 digest of a file's content
+pub fn sha256_file(path: &str) -> Result<String> {
+    let mut reader =
+        BufReader::new(File::open(path).with_context(|| format!("Cannot open {}", path))?);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Write the summary as pretty-printed JSON
+pub fn write_summary(summary: &RunSummary, output_file: &str, force: bool) -> Result<()> {
+    let mut file = OutputFile::create(output_file, force)?;
+    serde_json::to_writer_pretty(&mut file, summary)?;
+    file.commit()
+}
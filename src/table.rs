@@ -0,0 +1,109 @@
+//! A sample-feature table held in memory, for library users who already have
+//! their data parsed (a service, a notebook) rather than in files.
+
+use crate::{
+    compute::{BranchOptions, BranchSets, Metric},
+    io::read_count_table,
+    metrics::compute_metrics,
+    weighted::Transform,
+};
+use anyhow::{bail, Result};
+use ndarray::Array2;
+use phylotree::tree::Tree;
+
+/// Counts of each feature (row) in each sample (column)
+#[derive(Clone, Debug)]
+pub struct FeatureTable {
+    pub taxa_order: Vec<String>,
+    pub sample_names: Vec<String>,
+    /// taxa x samples
+    pub counts: Vec<Vec<f64>>,
+}
+
+impl FeatureTable {
+    /// Table from one row of counts per feature
+    pub fn new(
+        taxa_order: Vec<String>,
+        sample_names: Vec<String>,
+        counts: Vec<Vec<f64>>,
+    ) -> Result<FeatureTable> {
+        if counts.len() != taxa_order.len() {
+            bail!(
+                "{} rows of counts for {} features",
+                counts.len(),
+                taxa_order.len()
+            );
+        }
+        if let Some((t, row)) = counts
+            .iter()
+            .enumerate()
+            .find(|(_, row)| row.len() != sample_names.len())
+        {
+            bail!(
+                "Feature '{}' has {} counts for {} samples",
+                taxa_order[t],
+                row.len(),
+                sample_names.len()
+            );
+        }
+        Ok(FeatureTable {
+            taxa_order,
+            sample_names,
+            counts,
+        })
+    }
+
+    /// Table from a features x samples array
+    pub fn from_array(
+        taxa_order: Vec<String>,
+        sample_names: Vec<String>,
+        counts: &Array2<f64>,
+    ) -> Result<FeatureTable> {
+        if counts.dim() != (taxa_order.len(), sample_names.len()) {
+            bail!(
+                "Array is {} x {} but there are {} features and {} samples",
+                counts.nrows(),
+                counts.ncols(),
+                taxa_order.len(),
+                sample_names.len()
+            );
+        }
+        let rows = counts.outer_iter().map(|row| row.to_vec()).collect();
+        FeatureTable::new(taxa_order, sample_names, rows)
+    }
+
+    /// Table read from a tab-delimited file (see [`read_count_table`])
+    pub fn read(filename: &str) -> Result<FeatureTable> {
+        let (taxa_order, sample_names, counts) = read_count_table(filename)?;
+        FeatureTable::new(taxa_order, sample_names, counts)
+    }
+
+    pub fn n_samples(&self) -> usize {
+        self.sample_names.len()
+    }
+
+    /// Per-sample branch sets on `tree`, for unweighted distances
+    pub fn branch_sets(&self, tree: &Tree, opts: &BranchOptions) -> Result<BranchSets> {
+        BranchSets::new(tree, &self.taxa_order, &self.counts, self.n_samples(), opts)
+    }
+
+    /// Distance matrix (row-major, n_samples x n_samples) of one metric
+    pub fn distance_matrix(
+        &self,
+        tree: &Tree,
+        metric: Metric,
+        transform: Transform,
+        opts: &BranchOptions,
+    ) -> Result<Vec<f64>> {
+        let mut matrices = compute_metrics(
+            tree,
+            &self.taxa_order,
+            &self.counts,
+            self.n_samples(),
+            opts,
+            &[metric],
+            transform,
+        )?;
+        Ok(matrices.remove(0))
+    }
+}
@@ -0,0 +1,188 @@
+//! Unweighted UniFrac on the unrooted tree.
+//!
+//! Each edge splits the tips in two. A sample spans an edge when it has
+//! present tips on both sides, i.e. the edge lies on the subtree connecting
+//! its tips; no path to a root is involved. For a pair, the shared length is
+//! that of the edges both samples span, and the total is that of the edges
+//! spanned by their pooled tips.
+
+use crate::compute::{
+    branch_lengths, length_table, sample_branch_bits, sample_coverage, word_length, BranchOptions,
+    PairStats,
+};
+use anyhow::Result;
+use phylotree::tree::Tree;
+use rayon::prelude::*;
+
+/// Each sample's spanned edges, as bitsets of the edges with a present tip
+/// below and of those with one outside, with what is needed to turn a pair
+/// into a distance
+pub struct UnrootedSets {
+    below: Vec<u64>,
+    outside: Vec<u64>,
+    n_words: usize,
+    /// Per-nibble branch-length sums (see [`length_table`])
+    table: Vec<f64>,
+    /// Number of edges each sample spans
+    counts: Vec<usize>,
+}
+
+impl UnrootedSets {
+    pub fn new(
+        tree: &Tree,
+        taxa_order: &[String],
+        presence_matrix: &[Vec<f64>],
+        n_samples: usize,
+        opts: &BranchOptions,
+    ) -> Result<UnrootedSets> {
+        let lengths = branch_lengths(tree, opts)?;
+        let n_nodes = lengths.len();
+        let cov_words = n_samples.div_ceil(64);
+
+        // Samples with a present tip below each edge, and outside of it
+        let below = sample_coverage(tree, taxa_order, presence_matrix, n_samples)?;
+        let mut outside = vec![0u64; n_nodes * cov_words];
+        let root = tree.get_root()?;
+        // Reverse postorder visits parents before children
+        for p in tree.postorder(&root)?.into_iter().rev() {
+            let children = &tree.get(&p)?.children;
+            for c in children {
+                for w in 0..cov_words {
+                    let mut word = outside[p * cov_words + w];
+                    for sibling in children.iter().filter(|s| *s != c) {
+                        word |= below[sibling * cov_words + w];
+                    }
+                    outside[c * cov_words + w] = word;
+                }
+            }
+        }
+
+        let mut sets = UnrootedSets {
+            below: sample_branch_bits(&below, n_nodes, n_samples),
+            outside: sample_branch_bits(&outside, n_nodes, n_samples),
+            n_words: n_nodes.div_ceil(64),
+            table: length_table(&lengths),
+            counts: Vec::new(),
+        };
+        sets.counts = (0..n_samples)
+            .map(|s| {
+                (0..sets.n_words)
+                    .map(|w| sets.spanned(s, w).count_ones() as usize)
+                    .sum()
+            })
+            .collect();
+        Ok(sets)
+    }
+
+    fn word(&self, bits: &[u64], s: usize, w: usize) -> u64 {
+        bits[s * self.n_words + w]
+    }
+
+    /// Word `w` of the edges sample `s` spans. The root has nothing outside
+    /// it, so its edge is never spanned.
+    fn spanned(&self, s: usize, w: usize) -> u64 {
+        self.word(&self.below, s, w) & self.word(&self.outside, s, w)
+    }
+
+    /// Unrooted distance of samples i and j
+    pub fn distance(&self, i: usize, j: usize) -> f64 {
+        self.pair_stats(i, j).distance
+    }
+
+    /// Distance of samples i and j with the edge counts behind it
+    pub fn pair_stats(&self, i: usize, j: usize) -> PairStats {
+        let (mut shared, mut union, mut shared_count) = (0.0, 0.0, 0);
+        for w in 0..self.n_words {
+            let both = self.spanned(i, w) & self.spanned(j, w);
+            let pooled = (self.word(&self.below, i, w) | self.word(&self.below, j, w))
+                & (self.word(&self.outside, i, w) | self.word(&self.outside, j, w));
+            shared += word_length(w, both, &self.table);
+            union += word_length(w, pooled, &self.table);
+            shared_count += both.count_ones() as usize;
+        }
+        // Two samples spanning no edge together (empty, or the same single
+        // tip) are identical
+        let distance = if union == 0.0 {
+            0.0
+        } else {
+            1.0 - shared / union
+        };
+        PairStats {
+            i,
+            j,
+            distance,
+            shared_branches: shared_count,
+            branches_i: self.counts[i],
+            branches_j: self.counts[j],
+        }
+    }
+}
+
+/// Compute the unrooted distance matrix and per-pair branch statistics
+/// (pairs i < j, in row-major order)
+pub fn compute_unrooted_matrix_stats(
+    tree: &Tree,
+    taxa_order: &[String],
+    presence_matrix: &[Vec<f64>],
+    n_samples: usize,
+    opts: &BranchOptions,
+) -> Result<(Vec<f64>, Vec<PairStats>)> {
+    let sets = UnrootedSets::new(tree, taxa_order, presence_matrix, n_samples, opts)?;
+    let rows: Vec<Vec<PairStats>> = (0..n_samples)
+        .into_par_iter()
+        .map(|i| (i + 1..n_samples).map(|j| sets.pair_stats(i, j)).collect())
+        .collect();
+
+    let mut dist_matrix = vec![0.0; n_samples * n_samples];
+    let stats: Vec<PairStats> = rows.into_iter().flatten().collect();
+    for pair in &stats {
+        dist_matrix[pair.i * n_samples + pair.j] = pair.distance;
+        dist_matrix[pair.j * n_samples + pair.i] = pair.distance; // symmetric
+    }
+
+    Ok((dist_matrix, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::newick::read_newick;
+
+    const NEWICK: &str = "((A:1,B:2):0.5,(C:1.5,D:0.25):1);";
+
+    /// Features x samples: {A, B}, {A, C}, {}, {D}, {A}
+    fn sets() -> UnrootedSets {
+        let tree = read_newick(NEWICK.as_bytes()).unwrap();
+        let taxa: Vec<String> = ["A", "B", "C", "D"].iter().map(|t| t.to_string()).collect();
+        let counts = vec![
+            vec![1.0, 1.0, 0.0, 0.0, 1.0],
+            vec![1.0, 0.0, 0.0, 0.0, 0.0],
+            vec![0.0, 1.0, 0.0, 0.0, 0.0],
+            vec![0.0, 0.0, 0.0, 1.0, 0.0],
+        ];
+        UnrootedSets::new(&tree, &taxa, &counts, 5, &BranchOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn spanned_edges_connect_the_tips() {
+        let sets = sets();
+        // {A, B} spans the two tip edges only; {A, C} the path A-C
+        assert_eq!(sets.counts, vec![2, 4, 0, 0, 0]);
+        let stats = sets.pair_stats(0, 1);
+        assert_eq!(stats.shared_branches, 1);
+        assert!((stats.distance - (1.0 - 1.0 / 6.0)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn single_tips_are_the_path_between_them_apart() {
+        assert_eq!(sets().distance(3, 4), 1.0);
+    }
+
+    #[test]
+    fn samples_spanning_nothing_together_are_identical() {
+        let sets = sets();
+        assert_eq!(sets.distance(2, 3), 0.0);
+        assert_eq!(sets.distance(2, 4), 0.0);
+        assert_eq!(sets.distance(4, 4), 0.0);
+    }
+}
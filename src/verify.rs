@@ -0,0 +1,134 @@
+//! Slow reference distances written straight from the definitions, with no
+//! bitsets, B matrix or GEMM, for cross-checking the optimized paths
+//! (`--verify`).
+
+use crate::{
+    compute::{branch_lengths, BranchOptions, Metric},
+    weighted::{normalize_samples, Transform},
+};
+use anyhow::{bail, Result};
+use phylotree::tree::Tree;
+
+/// Distance between samples i and j: every feature's (transformed)
+/// abundance is added to each branch on its path to the root, then the
+/// metric's formula is summed over all branches
+pub fn reference_distance(
+    tree: &Tree,
+    taxa_order: &[String],
+    counts: &[Vec<f64>],
+    i: usize,
+    j: usize,
+    metric: Metric,
+    transform: Transform,
+    opts: &BranchOptions,
+) -> Result<f64> {
+    let lengths = branch_lengths(tree, opts)?;
+    let normalized = match metric {
+        Metric::Unweighted => None,
+        _ => normalize_samples(counts, counts.first().map_or(0, |row| row.len()), transform),
+    };
+    let counts = normalized.as_deref().unwrap_or(counts);
+    let mut below_i = vec![0.0; tree.size()];
+    let mut below_j = vec![0.0; tree.size()];
+    for tip in tree.get_leaves() {
+        let Some(name) = tree.get(&tip)?.name.as_deref() else {
+            continue;
+        };
+        let Some(t) = taxa_order.iter().position(|taxon| taxon == name) else {
+            continue;
+        };
+        let (a, b) = match metric {
+            Metric::Unweighted => (counts[t][i], counts[t][j]),
+            _ => (
+                transform.apply(counts[t][i].max(0.0)),
+                transform.apply(counts[t][j].max(0.0)),
+            ),
+        };
+        let mut node = Some(tip);
+        while let Some(idx) = node {
+            below_i[idx] += a.max(0.0);
+            below_j[idx] += b.max(0.0);
+            node = tree.get(&idx)?.parent;
+        }
+    }
+
+    match metric {
+        Metric::Unweighted => {
+            let (mut shared, mut union) = (0.0, 0.0);
+            for b in 0..lengths.len() {
+                if below_i[b] > 0.0 || below_j[b] > 0.0 {
+                    union += lengths[b];
+                }
+                if below_i[b] > 0.0 && below_j[b] > 0.0 {
+                    shared += lengths[b];
+                }
+            }
+            Ok(1.0 - shared / union)
+        }
+        Metric::Weighted | Metric::Generalized(_) => {
+            let alpha = metric.alpha().unwrap();
+            let root = tree.get_root()?;
+            let (total_i, total_j) = (below_i[root], below_j[root]);
+            let (mut num, mut den) = (0.0, 0.0);
+            for b in 0..lengths.len() {
+                let p_a = if total_i > 0.0 {
+                    below_i[b] / total_i
+                } else {
+                    0.0
+                };
+                let p_b = if total_j > 0.0 {
+                    below_j[b] / total_j
+                } else {
+                    0.0
+                };
+                let sum = p_a + p_b;
+                if sum <= 0.0 || lengths[b] == 0.0 {
+                    continue;
+                }
+                num += lengths[b] * sum.powf(alpha) * (p_a - p_b).abs() / sum;
+                den += lengths[b] * sum.powf(alpha);
+            }
+            Ok(if den == 0.0 { 0.0 } else { num / den })
+        }
+        Metric::Unrooted => bail!("No reference implementation for the unrooted metric"),
+    }
+}
+
+/// A pair whose computed distance is off from the reference
+#[derive(Clone, Copy, Debug)]
+pub struct Mismatch {
+    pub i: usize,
+    pub j: usize,
+    pub computed: f64,
+    pub reference: f64,
+}
+
+/// Pairs of `dist_matrix` farther than `tolerance` from `reference` (for
+/// instance [`reference_distance`]); NaN matches only NaN
+pub fn verify_pairs(
+    dist_matrix: &[f64],
+    n: usize,
+    pairs: &[(usize, usize)],
+    tolerance: f64,
+    reference: impl Fn(usize, usize) -> Result<f64>,
+) -> Result<Vec<Mismatch>> {
+    let mut mismatches = Vec::new();
+    for &(i, j) in pairs {
+        let computed = dist_matrix[i * n + j];
+        let reference = reference(i, j)?;
+        let agree = if computed.is_nan() || reference.is_nan() {
+            computed.is_nan() && reference.is_nan()
+        } else {
+            (computed - reference).abs() <= tolerance
+        };
+        if !agree {
+            mismatches.push(Mismatch {
+                i,
+                j,
+                computed,
+                reference,
+            });
+        }
+    }
+    Ok(mismatches)
+}
@@ -0,0 +1,189 @@
+//! Tables delivered to a directory over time: noticing new or rewritten
+//! files, and extending a table's distance matrix when a delivery only
+//! added samples to it.
+
+use anyhow::{Context, Result};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Extensions of the files read as tables
+pub const TABLE_EXTENSIONS: [&str; 3] = ["tsv", "txt", "shared"];
+
+/// Modification time and size of a file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Stamp {
+    modified: SystemTime,
+    len: u64,
+}
+
+/// Polls a directory for table files. A new or changed file is reported once
+/// it looks the same on two consecutive polls, so that tables still being
+/// copied in are not read half-written.
+pub struct DirWatcher {
+    dir: PathBuf,
+    settle: bool,
+    pending: HashMap<PathBuf, Stamp>,
+    reported: HashMap<PathBuf, Stamp>,
+}
+
+impl DirWatcher {
+    /// Without `settle`, files are reported on the first poll that sees them
+    pub fn new(dir: &Path, settle: bool) -> DirWatcher {
+        DirWatcher {
+            dir: dir.to_path_buf(),
+            settle,
+            pending: HashMap::new(),
+            reported: HashMap::new(),
+        }
+    }
+
+    /// Table files that are new or changed since they were last reported,
+    /// in name order
+    pub fn poll(&mut self) -> Result<Vec<PathBuf>> {
+        let entries = fs::read_dir(&self.dir)
+            .with_context(|| format!("Failed to list {}", self.dir.display()))?;
+        let mut ready = Vec::new();
+        let mut pending = HashMap::new();
+        for entry in entries {
+            let path = entry?.path();
+            let is_table = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| TABLE_EXTENSIONS.contains(&e));
+            // Files can disappear between listing and reading their metadata
+            let Ok(meta) = fs::metadata(&path) else {
+                continue;
+            };
+            if !is_table || !meta.is_file() {
+                continue;
+            }
+            let stamp = Stamp {
+                modified: meta.modified()?,
+                len: meta.len(),
+            };
+            if self.reported.get(&path) == Some(&stamp) {
+                continue;
+            }
+            if !self.settle || self.pending.get(&path) == Some(&stamp) {
+                self.reported.insert(path.clone(), stamp);
+                ready.push(path);
+            } else {
+                pending.insert(path, stamp);
+            }
+        }
+        self.pending = pending;
+        ready.sort();
+        Ok(ready)
+    }
+}
+
+/// Hash of each sample's nonzero counts by feature ID, independent of the
+/// feature order, to tell whether a sample changed between deliveries
+pub fn sample_fingerprints(
+    taxa_order: &[String],
+    counts: &[Vec<f64>],
+    n_samples: usize,
+) -> Vec<u64> {
+    let mut order: Vec<usize> = (0..taxa_order.len()).collect();
+    order.sort_by(|&a, &b| taxa_order[a].cmp(&taxa_order[b]));
+    (0..n_samples)
+        .map(|s| {
+            let mut hasher = DefaultHasher::new();
+            for &t in &order {
+                let count = counts[t][s];
+                if count != 0.0 {
+                    taxa_order[t].hash(&mut hasher);
+                    count.to_bits().hash(&mut hasher);
+                }
+            }
+            hasher.finish()
+        })
+        .collect()
+}
+
+/// A table's samples and matrix as last written
+pub struct Delivery {
+    pub sample_names: Vec<String>,
+    pub fingerprints: Vec<u64>,
+    pub dist_matrix: Vec<f64>,
+}
+
+/// What a new delivery of a table needs
+#[derive(Debug, PartialEq, Eq)]
+pub enum Update {
+    /// Same samples with the same counts
+    Unchanged,
+    /// Every earlier sample is unchanged; these (indices in the new
+    /// delivery) were added
+    Extend(Vec<usize>),
+    /// Samples were removed or their counts changed
+    Recompute,
+}
+
+/// Compare a new delivery of a table with the previous one
+pub fn plan_update(previous: &Delivery, sample_names: &[String], fingerprints: &[u64]) -> Update {
+    let index: HashMap<&str, usize> = sample_names
+        .iter()
+        .enumerate()
+        .map(|(s, name)| (name.as_str(), s))
+        .collect();
+    for (name, fingerprint) in previous.sample_names.iter().zip(&previous.fingerprints) {
+        match index.get(name.as_str()) {
+            Some(&s) if fingerprints[s] == *fingerprint => {}
+            _ => return Update::Recompute,
+        }
+    }
+    let known: HashSet<&str> = previous.sample_names.iter().map(|s| s.as_str()).collect();
+    let added: Vec<usize> = (0..sample_names.len())
+        .filter(|&s| !known.contains(sample_names[s].as_str()))
+        .collect();
+    if added.is_empty() {
+        Update::Unchanged
+    } else {
+        Update::Extend(added)
+    }
+}
+
+/// The matrix over `sample_names` from the previous delivery's matrix and the
+/// distances of the `added` samples to every sample (`added.len() x n`,
+/// row-major, columns in `sample_names` order)
+pub fn extend_matrix(
+    previous: &Delivery,
+    sample_names: &[String],
+    added: &[usize],
+    added_rows: &[f64],
+) -> Vec<f64> {
+    let n = sample_names.len();
+    let m = previous.sample_names.len();
+    let old_index: HashMap<&str, usize> = previous
+        .sample_names
+        .iter()
+        .enumerate()
+        .map(|(s, name)| (name.as_str(), s))
+        .collect();
+    let mut added_row = vec![None; n];
+    for (r, &s) in added.iter().enumerate() {
+        added_row[s] = Some(r);
+    }
+    let old: Vec<usize> = sample_names
+        .iter()
+        .map(|name| old_index.get(name.as_str()).copied().unwrap_or(0))
+        .collect();
+
+    let mut dist_matrix = vec![0.0; n * n];
+    for i in 0..n {
+        for j in 0..n {
+            dist_matrix[i * n + j] = match (added_row[i], added_row[j]) {
+                (Some(r), _) => added_rows[r * n + j],
+                (None, Some(r)) => added_rows[r * n + i],
+                (None, None) => previous.dist_matrix[old[i] * m + old[j]],
+            };
+        }
+    }
+    dist_matrix
+}
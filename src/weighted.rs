@@ -0,0 +1,245 @@
+//! Abundance-weighted UniFrac variants.
+//!
+//! Counts are (optionally) transformed, turned into per-sample proportions,
+//! and summed up the tree so that each branch holds the fraction of every
+//! sample's abundance below it.
+
+use crate::compute::{branch_lengths, BranchOptions};
+use anyhow::Result;
+use ndarray::Array2;
+use phylotree::tree::Tree;
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+/// Transform applied to counts before they are turned into proportions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transform {
+    #[default]
+    None,
+    /// Square root, which tames dominant taxa
+    Sqrt,
+    /// ln(1 + x)
+    Log1p,
+    /// Cumulative-sum scaling (Paulson et al. 2013): each sample's counts
+    /// divided by the sum of its nonzero counts up to their median, times
+    /// 1000, then log2(1 + x). The median is the lower of the two middle
+    /// counts, an observed count; the interpolated median sums the same counts.
+    Css,
+}
+
+impl Transform {
+    pub fn from_name(name: &str) -> Option<Transform> {
+        match name {
+            "none" => Some(Transform::None),
+            "sqrt" => Some(Transform::Sqrt),
+            "log1p" => Some(Transform::Log1p),
+            "css" => Some(Transform::Css),
+            _ => None,
+        }
+    }
+
+    /// The transform of one count; the per-sample one (CSS) is applied by
+    /// [`normalize_samples`] and leaves counts unchanged here
+    pub fn apply(self, x: f64) -> f64 {
+        match self {
+            Transform::None | Transform::Css => x,
+            Transform::Sqrt => x.sqrt(),
+            Transform::Log1p => x.ln_1p(),
+        }
+    }
+}
+
+/// Counts (taxa x samples) after a transform that needs each sample's whole
+/// column (CSS); `None` for the count-by-count transforms. Negative counts
+/// count as 0.
+pub fn normalize_samples(
+    counts: &[Vec<f64>],
+    n_samples: usize,
+    transform: Transform,
+) -> Option<Vec<Vec<f64>>> {
+    if transform != Transform::Css {
+        return None;
+    }
+    let mut normalized = vec![vec![0.0; n_samples]; counts.len()];
+    for s in 0..n_samples {
+        let column: Vec<f64> = counts.iter().map(|row| row[s].max(0.0)).collect();
+        let mut nonzero: Vec<f64> = column.iter().copied().filter(|v| *v > 0.0).collect();
+        if nonzero.is_empty() {
+            continue;
+        }
+        nonzero.sort_by(f64::total_cmp);
+        let median = nonzero[(nonzero.len() - 1) / 2];
+        let scale: f64 = nonzero.iter().take_while(|v| **v <= median).sum();
+        for (t, v) in column.iter().enumerate() {
+            normalized[t][s] = (v / scale * 1000.0).ln_1p() / std::f64::consts::LN_2;
+        }
+    }
+    Some(normalized)
+}
+
+/// Fraction of each sample's (transformed) abundance below each branch,
+/// samples x branches. Negative and unparseable counts count as 0.
+pub fn branch_proportions(
+    tree: &Tree,
+    taxa_order: &[String],
+    counts: &[Vec<f64>],
+    n_samples: usize,
+    transform: Transform,
+) -> Result<Array2<f64>> {
+    let normalized = normalize_samples(counts, n_samples, transform);
+    let counts = normalized.as_deref().unwrap_or(counts);
+    let taxon_index: HashMap<&str, usize> = taxa_order
+        .iter()
+        .enumerate()
+        .map(|(t, name)| (name.as_str(), t))
+        .collect();
+
+    let mut props = Array2::<f64>::zeros((n_samples, tree.size()));
+    let root = tree.get_root()?;
+    for idx in tree.postorder(&root)? {
+        let node = tree.get(&idx)?;
+        if node.is_tip() {
+            let name = node.name.as_deref().unwrap_or_default();
+            if let Some(t) = taxon_index.get(name) {
+                for s in 0..n_samples {
+                    props[(s, idx)] = transform.apply(counts[*t][s].max(0.0));
+                }
+            }
+        } else {
+            for c in node.children.iter() {
+                for s in 0..n_samples {
+                    props[(s, idx)] += props[(s, *c)];
+                }
+            }
+        }
+    }
+
+    // Everything present is below the root, so it holds each sample's total
+    for s in 0..n_samples {
+        let total = props[(s, root)];
+        if total > 0.0 {
+            props.row_mut(s).mapv_inplace(|v| v / total);
+        }
+    }
+    Ok(props)
+}
+
+/// Each sample's branch-length-weighted abundance over branches, l x p
+/// (samples x branches): the vectors whose L1 distance, over the sum of
+/// both samples' totals, is weighted UniFrac. Mostly zeros for large trees.
+pub fn branch_embeddings(
+    tree: &Tree,
+    taxa_order: &[String],
+    counts: &[Vec<f64>],
+    n_samples: usize,
+    opts: &BranchOptions,
+    transform: Transform,
+) -> Result<Array2<f64>> {
+    let lengths = branch_lengths(tree, opts)?;
+    let mut props = branch_proportions(tree, taxa_order, counts, n_samples, transform)?;
+    for mut row in props.rows_mut() {
+        row.iter_mut().zip(&lengths).for_each(|(p, l)| *p *= l);
+    }
+    Ok(props)
+}
+
+/// Generalized UniFrac (Chen et al. 2012) between two samples' branch
+/// proportions:
+/// Σ l (pa + pb)^α |pa - pb| / (pa + pb)  over  Σ l (pa + pb)^α.
+/// With α = 1 this is normalized weighted UniFrac. Two samples with nothing
+/// on any branch of nonzero length (both empty, say) are 0 apart.
+pub fn generalized_distance(p_a: &[f64], p_b: &[f64], lengths: &[f64], alpha: f64) -> f64 {
+    let mut num = 0.0;
+    let mut den = 0.0;
+    for ((a, b), l) in p_a.iter().zip(p_b.iter()).zip(lengths.iter()) {
+        let sum = a + b;
+        if sum <= 0.0 || *l == 0.0 {
+            continue;
+        }
+        let w = if alpha == 1.0 { sum } else { sum.powf(alpha) };
+        num += l * w * (a - b).abs() / sum;
+        den += l * w;
+    }
+    if den == 0.0 {
+        0.0
+    } else {
+        num / den
+    }
+}
+
+/// [`generalized_distance`] for several alphas in one pass over the branches
+pub fn generalized_distances(
+    p_a: &[f64],
+    p_b: &[f64],
+    lengths: &[f64],
+    alphas: &[f64],
+) -> Vec<f64> {
+    let mut out = vec![0.0; alphas.len()];
+    let mut den = vec![0.0; alphas.len()];
+    generalized_distances_into(p_a, p_b, lengths, alphas, &mut out, &mut den);
+    out
+}
+
+/// [`generalized_distances`] written into `out`, with `den` as scratch (both
+/// as long as `alphas`), so that the buffers can be reused across pairs
+pub fn generalized_distances_into(
+    p_a: &[f64],
+    p_b: &[f64],
+    lengths: &[f64],
+    alphas: &[f64],
+    out: &mut [f64],
+    den: &mut [f64],
+) {
+    out.fill(0.0);
+    den.fill(0.0);
+    for ((a, b), l) in p_a.iter().zip(p_b.iter()).zip(lengths.iter()) {
+        let sum = a + b;
+        if sum <= 0.0 || *l == 0.0 {
+            continue;
+        }
+        let unique = (a - b).abs() / sum;
+        for (k, alpha) in alphas.iter().enumerate() {
+            let w = if *alpha == 1.0 { sum } else { sum.powf(*alpha) };
+            out[k] += l * w * unique;
+            den[k] += l * w;
+        }
+    }
+    for (num, d) in out.iter_mut().zip(den.iter()) {
+        *num = if *d == 0.0 { 0.0 } else { *num / d };
+    }
+}
+
+/// Full generalized UniFrac matrix (row-major, n_samples x n_samples); use
+/// `alpha` = 1 for normalized weighted UniFrac
+pub fn compute_generalized_matrix(
+    tree: &Tree,
+    taxa_order: &[String],
+    counts: &[Vec<f64>],
+    n_samples: usize,
+    opts: &BranchOptions,
+    transform: Transform,
+    alpha: f64,
+) -> Result<Vec<f64>> {
+    let lengths = branch_lengths(tree, opts)?;
+    let props = branch_proportions(tree, taxa_order, counts, n_samples, transform)?;
+    let sample = |s: usize| props.row(s).to_slice().unwrap();
+
+    let rows: Vec<Vec<f64>> = (0..n_samples)
+        .into_par_iter()
+        .map(|i| {
+            (i + 1..n_samples)
+                .map(|j| generalized_distance(sample(i), sample(j), &lengths, alpha))
+                .collect()
+        })
+        .collect();
+
+    let mut dist_matrix = vec![0.0; n_samples * n_samples];
+    for (i, row) in rows.into_iter().enumerate() {
+        for (k, d) in row.into_iter().enumerate() {
+            let j = i + 1 + k;
+            dist_matrix[i * n_samples + j] = d;
+            dist_matrix[j * n_samples + i] = d; // symmetric
+        }
+    }
+    Ok(dist_matrix)
+}